@@ -0,0 +1,274 @@
+use std::{
+    path::Path,
+    str::FromStr,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use serde::Deserialize;
+use solana_program::pubkey::Pubkey;
+use solana_sdk::signature::Keypair;
+
+use crate::{
+    error::CrankError, metrics::Metrics, Context, DEFAULT_COMPUTE_UNIT_LIMIT,
+    DEFAULT_COMPUTE_UNIT_PRICE,
+};
+
+/// The default RPC endpoint of the cranker
+pub const DEFAULT_ENDPOINT: &str = "https://solana-api.projectserum.com";
+
+/// The content of a cranker configuration file, in JSON
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct Config {
+    /// The RPC endpoint url
+    pub endpoint: Option<String>,
+    /// The pubkey of the dex program
+    pub program_id: Option<String>,
+    /// The path to the keypair of the fee payer
+    pub fee_payer: Option<String>,
+    /// The markets to crank
+    #[serde(default)]
+    pub markets: Vec<MarketConfig>,
+}
+
+/// The cranking settings of a single market
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct MarketConfig {
+    /// The pubkey of the market
+    pub market: String,
+    /// The pubkey of the target account for SOL cranking rewards
+    pub reward_target: Option<String>,
+    /// The compute unit limit requested by cranking transactions
+    pub compute_unit_limit: Option<u32>,
+    /// The priority fee paid by cranking transactions, in micro-lamports per compute unit
+    pub compute_unit_price: Option<u64>,
+}
+
+/// The settings given on the command line, which take precedence over the configuration file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Overrides {
+    pub endpoint: Option<String>,
+    pub program_id: Option<Pubkey>,
+    pub reward_target: Option<Pubkey>,
+    pub compute_unit_limit: Option<u32>,
+    pub compute_unit_price: Option<u64>,
+    /// Additional markets to crank
+    pub markets: Vec<Pubkey>,
+}
+
+fn parse_pubkey(s: &str) -> Result<Pubkey, CrankError> {
+    Pubkey::from_str(s).map_err(|_| {
+        println!("Invalid pubkey in the configuration: {}", s);
+        CrankError::InvalidConfig
+    })
+}
+
+impl Config {
+    /// Reads a configuration file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, CrankError> {
+        let data = std::fs::read(path).map_err(|e| {
+            println!("Failed to read the configuration file with {:#?}", e);
+            CrankError::InvalidConfig
+        })?;
+        serde_json::from_slice(&data).map_err(|e| {
+            println!("Failed to parse the configuration file with {}", e);
+            CrankError::InvalidConfig
+        })
+    }
+
+    /// Returns the RPC endpoint url
+    pub fn endpoint(&self, overrides: &Overrides) -> String {
+        overrides
+            .endpoint
+            .clone()
+            .or_else(|| self.endpoint.clone())
+            .unwrap_or_else(|| String::from(DEFAULT_ENDPOINT))
+    }
+
+    /// Returns the pubkey of the dex program
+    pub fn program_id(&self, overrides: &Overrides) -> Result<Pubkey, CrankError> {
+        match (overrides.program_id, &self.program_id) {
+            (Some(program_id), _) => Ok(program_id),
+            (None, Some(program_id)) => parse_pubkey(program_id),
+            (None, None) => {
+                println!("The program id is missing from the configuration");
+                Err(CrankError::InvalidConfig)
+            }
+        }
+    }
+
+    /// Builds the cranking contexts, markets which share the same reward target and priority fee settings being cranked
+    /// by the same context
+    pub fn into_contexts(
+        self,
+        fee_payer: &Keypair,
+        overrides: &Overrides,
+    ) -> Result<Vec<Context>, CrankError> {
+        let endpoint = self.endpoint(overrides);
+        let program_id = self.program_id(overrides)?;
+
+        let mut markets = Vec::with_capacity(self.markets.len() + overrides.markets.len());
+        for m in self.markets {
+            markets.push((
+                parse_pubkey(&m.market)?,
+                m.reward_target.as_deref().map(parse_pubkey).transpose()?,
+                m.compute_unit_limit,
+                m.compute_unit_price,
+            ));
+        }
+        for m in &overrides.markets {
+            if !markets.iter().any(|(market, _, _, _)| market == m) {
+                markets.push((*m, None, None, None));
+            }
+        }
+
+        let mut contexts: Vec<Context> = Vec::new();
+        for (market, reward_target, compute_unit_limit, compute_unit_price) in markets {
+            let reward_target = overrides.reward_target.or(reward_target).ok_or_else(|| {
+                println!("The reward target of market {} is missing", market);
+                CrankError::InvalidConfig
+            })?;
+            let compute_unit_limit = overrides
+                .compute_unit_limit
+                .or(compute_unit_limit)
+                .unwrap_or(DEFAULT_COMPUTE_UNIT_LIMIT);
+            let compute_unit_price = overrides
+                .compute_unit_price
+                .or(compute_unit_price)
+                .unwrap_or(DEFAULT_COMPUTE_UNIT_PRICE);
+            if let Some(context) = contexts.iter_mut().find(|c| {
+                c.reward_target == reward_target
+                    && c.compute_unit_limit == compute_unit_limit
+                    && c.compute_unit_price == compute_unit_price
+            }) {
+                if !context.markets.contains(&market) {
+                    context.markets.push(market);
+                }
+                continue;
+            }
+            contexts.push(Context {
+                program_id,
+                markets: vec![market],
+                reward_target,
+                fee_payer: Keypair::from_bytes(&fee_payer.to_bytes()).unwrap(),
+                endpoint: endpoint.clone(),
+                compute_unit_limit,
+                compute_unit_price,
+                metrics: Arc::new(Metrics::default()),
+                shutdown: Arc::new(AtomicBool::new(false)),
+                fill_log: None,
+            });
+        }
+        Ok(contexts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::signer::Signer;
+
+    #[test]
+    fn test_into_contexts() {
+        let program_id = Pubkey::new_unique();
+        let (market_0, market_1, market_2) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let (reward_target_0, reward_target_1) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let config: Config = serde_json::from_str(&format!(
+            r#"{{
+                "endpoint": "http://localhost:8899",
+                "program_id": "{}",
+                "fee_payer": "~/.config/solana/id.json",
+                "markets": [
+                    {{ "market": "{}", "reward_target": "{}" }},
+                    {{ "market": "{}", "reward_target": "{}", "compute_unit_price": 10000 }},
+                    {{ "market": "{}", "reward_target": "{}" }}
+                ]
+            }}"#,
+            program_id,
+            market_0,
+            reward_target_0,
+            market_1,
+            reward_target_0,
+            market_2,
+            reward_target_1
+        ))
+        .unwrap();
+        assert_eq!(
+            config.fee_payer.as_deref(),
+            Some("~/.config/solana/id.json")
+        );
+        let fee_payer = Keypair::new();
+
+        // Markets sharing the same settings are cranked by the same context
+        let contexts = config
+            .clone()
+            .into_contexts(&fee_payer, &Overrides::default())
+            .unwrap();
+        let summary = contexts
+            .iter()
+            .map(|c| {
+                assert_eq!(c.program_id, program_id);
+                assert_eq!(c.endpoint, "http://localhost:8899");
+                assert_eq!(c.fee_payer.pubkey(), fee_payer.pubkey());
+                assert_eq!(c.compute_unit_limit, DEFAULT_COMPUTE_UNIT_LIMIT);
+                (c.markets.clone(), c.reward_target, c.compute_unit_price)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (vec![market_0], reward_target_0, DEFAULT_COMPUTE_UNIT_PRICE),
+                (vec![market_1], reward_target_0, 10_000),
+                (vec![market_2], reward_target_1, DEFAULT_COMPUTE_UNIT_PRICE),
+            ]
+        );
+
+        // Command line settings take precedence over the file
+        let market_3 = Pubkey::new_unique();
+        let reward_target = Pubkey::new_unique();
+        let contexts = config
+            .into_contexts(
+                &fee_payer,
+                &Overrides {
+                    endpoint: Some(String::from("http://localhost:8900")),
+                    reward_target: Some(reward_target),
+                    compute_unit_price: Some(42),
+                    markets: vec![market_3],
+                    ..Overrides::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(contexts.len(), 1);
+        assert_eq!(contexts[0].endpoint, "http://localhost:8900");
+        assert_eq!(contexts[0].reward_target, reward_target);
+        assert_eq!(contexts[0].compute_unit_price, 42);
+        assert_eq!(
+            contexts[0].markets,
+            vec![market_0, market_1, market_2, market_3]
+        );
+
+        // The reward target is required
+        let config = Config {
+            endpoint: None,
+            program_id: Some(program_id.to_string()),
+            fee_payer: None,
+            markets: vec![MarketConfig {
+                market: market_0.to_string(),
+                reward_target: None,
+                compute_unit_limit: None,
+                compute_unit_price: None,
+            }],
+        };
+        assert_eq!(
+            config
+                .into_contexts(&fee_payer, &Overrides::default())
+                .err(),
+            Some(CrankError::InvalidConfig)
+        );
+    }
+}
@@ -5,4 +5,10 @@ pub enum CrankError {
     ConnectionError,
     #[error("The parsed market state is invalid")]
     InvalidMarketState,
+    #[error("The parsed event queue is invalid")]
+    InvalidEventQueue,
+    #[error("The cranking transaction failed")]
+    TransactionFailed,
+    #[error("The configuration is invalid")]
+    InvalidConfig,
 }
@@ -0,0 +1,163 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use agnostic_orderbook::state::Side;
+use dex_v4::state::DexState;
+use serde::Serialize;
+use solana_program::pubkey::Pubkey;
+
+/// A fill consumed by the cranker, in native units
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FillRecord {
+    pub market: String,
+    /// The user account of the maker
+    pub maker: String,
+    /// The callback identifier of the taker
+    pub taker: String,
+    /// Either `bid` or `ask`
+    pub taker_side: &'static str,
+    /// The FP32 price of the maker order, in native quote per native base
+    pub price: u64,
+    /// The filled quantity, in native base
+    pub base_size: u64,
+    /// The filled quantity, in native quote
+    pub quote_size: u64,
+    /// The order id of the maker, as a string since it doesn't fit in a JSON number
+    pub maker_order_id: String,
+}
+
+impl FillRecord {
+    /// Converts the fill event of a market's event queue
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        market: &Pubkey,
+        market_state: &DexState,
+        taker_side: Side,
+        maker_order_id: u128,
+        quote_size: u64,
+        base_size: u64,
+        maker: &Pubkey,
+        taker: &Pubkey,
+    ) -> Self {
+        let price = (maker_order_id >> 64)
+            .saturating_mul(market_state.quote_currency_multiplier as u128)
+            .checked_div(market_state.base_currency_multiplier as u128)
+            .unwrap_or_default();
+        Self {
+            market: market.to_string(),
+            maker: maker.to_string(),
+            taker: taker.to_string(),
+            taker_side: match taker_side {
+                Side::Bid => "bid",
+                Side::Ask => "ask",
+            },
+            price: price.min(u64::MAX as u128) as u64,
+            base_size: base_size.saturating_mul(market_state.base_currency_multiplier),
+            quote_size: quote_size.saturating_mul(market_state.quote_currency_multiplier),
+            maker_order_id: maker_order_id.to_string(),
+        }
+    }
+}
+
+/// Writes the consumed fills as JSON lines
+pub struct FillLog {
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl FillLog {
+    /// Logs the fills to the standard output
+    pub fn stdout() -> Self {
+        Self::new(Box::new(io::stdout()))
+    }
+
+    /// Appends the fills to a file, which is created if needed
+    pub fn file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(Box::new(file)))
+    }
+
+    pub fn new(output: Box<dyn Write + Send>) -> Self {
+        Self {
+            output: Mutex::new(output),
+        }
+    }
+
+    /// Writes one JSON line per fill
+    pub fn write(&self, fills: &[FillRecord]) -> io::Result<()> {
+        let mut output = self.output.lock().unwrap();
+        for fill in fills {
+            serde_json::to_writer(&mut *output, fill)?;
+            output.write_all(b"\n")?;
+        }
+        output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytemuck::Zeroable;
+    use std::sync::Arc;
+
+    /// A writer whose content can be read back
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_fill_record() {
+        let mut market_state = DexState::zeroed();
+        market_state.base_currency_multiplier = 1;
+        market_state.quote_currency_multiplier = 10;
+        let (market, maker, taker) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        // 100 base @ 2.0
+        let maker_order_id = ((2u128 << 32) << 64) | 7;
+        let fill = FillRecord::new(
+            &market,
+            &market_state,
+            Side::Ask,
+            maker_order_id,
+            20,
+            100,
+            &maker,
+            &taker,
+        );
+
+        let buffer = SharedBuffer::default();
+        let fill_log = FillLog::new(Box::new(buffer.clone()));
+        fill_log.write(&[fill.clone(), fill]).unwrap();
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(lines[0]).unwrap(),
+            serde_json::json!({
+                "market": market.to_string(),
+                "maker": maker.to_string(),
+                "taker": taker.to_string(),
+                "taker_side": "ask",
+                "price": 20u64 << 32,
+                "base_size": 100,
+                "quote_size": 200,
+                "maker_order_id": maker_order_id.to_string(),
+            })
+        );
+    }
+}
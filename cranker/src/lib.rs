@@ -1,4 +1,12 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use agnostic_orderbook::state::{
     Event, EventQueue, EventQueueHeader, MarketState, MARKET_STATE_LEN,
@@ -7,88 +15,288 @@ use borsh::BorshDeserialize;
 use dex_v4::instruction_auto::consume_events;
 use dex_v4::{
     instruction_auto::consume_events::Accounts,
-    state::{CallBackInfo, DexState, DEX_STATE_LEN},
+    state::{
+        decode::{decode_callback_info, decode_dex_state},
+        DexState, MarketStatus, DEX_STATE_LEN,
+    },
     CALLBACK_INFO_LEN,
 };
 use error::CrankError;
+use fills::{FillLog, FillRecord};
+use metrics::Metrics;
+use solana_account_decoder::UiAccountEncoding;
 use solana_client::{
-    client_error::ClientError, rpc_client::RpcClient, rpc_config::RpcSendTransactionConfig,
+    rpc_client::RpcClient,
+    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig, RpcSendTransactionConfig},
+    rpc_filter::RpcFilterType,
 };
+use solana_program::instruction::Instruction;
 use solana_program::pubkey::Pubkey;
 use solana_sdk::{
+    account::Account,
     commitment_config::{CommitmentConfig, CommitmentLevel},
-    signature::{Keypair, Signature},
+    compute_budget::ComputeBudgetInstruction,
+    packet::PACKET_DATA_SIZE,
+    signature::{Keypair, Signature, SIGNATURE_BYTES},
     signer::Signer,
     transaction::Transaction,
 };
+use tokio::time::sleep;
+use utils::{is_transient_error, no_op_filter, retry, retry_with_backoff, INITIAL_RETRY_DELAY};
 
+pub mod config;
 pub mod error;
+pub mod fills;
+pub mod metrics;
 pub mod utils;
 
 pub struct Context {
     pub program_id: Pubkey,
-    pub market: Pubkey,
+    /// The markets to crank, in round-robin order
+    pub markets: Vec<Pubkey>,
     pub reward_target: Pubkey,
     pub fee_payer: Keypair,
     pub endpoint: String,
+    /// The compute unit limit requested by cranking transactions
+    pub compute_unit_limit: u32,
+    /// The priority fee paid by cranking transactions, in micro-lamports per compute unit
+    pub compute_unit_price: u64,
+    /// The cranking metrics, updated as the markets are cranked
+    pub metrics: Arc<Metrics>,
+    /// Set to stop cranking, see [`Context::request_shutdown`]
+    pub shutdown: Arc<AtomicBool>,
+    /// When set, the consumed fills are logged as JSON lines
+    pub fill_log: Option<Arc<FillLog>>,
+}
+
+/// The state of a cranked market, resolved once when the cranker starts
+pub struct MarketAccounts {
+    pub market: Pubkey,
+    pub market_state: DexState,
+    pub orderbook: MarketState,
+}
+
+/// A cranking transaction, along with the state of the event queue it consumes
+pub struct CrankTransaction {
+    pub transaction: Transaction,
+    /// The number of events consumed by the transaction
+    pub events: u64,
+    /// Whether the event queue is full
+    pub event_queue_full: bool,
+    /// The fills consumed by the transaction, only collected when the fills are logged
+    pub fills: Vec<FillRecord>,
 }
 
+/// The maximum number of events consumed by a single transaction, bounded by the compute budget
 pub const MAX_ITERATIONS: u64 = 10;
+/// The maximum number of distinct user accounts referenced by a single transaction, bounded by the transaction size
 pub const MAX_NUMBER_OF_USER_ACCOUNTS: usize = 20;
+/// The maximum number of consume_events instructions batched in a single transaction, bounded by the compute budget
+pub const MAX_INSTRUCTIONS_PER_TRANSACTION: usize = 4;
+/// The default compute unit limit of a cranking transaction
+pub const DEFAULT_COMPUTE_UNIT_LIMIT: u32 = 200_000;
+/// The default priority fee of a cranking transaction, in micro-lamports per compute unit
+pub const DEFAULT_COMPUTE_UNIT_PRICE: u64 = 1_000;
+/// The maximum number of attempts at sending a cranking transaction when the RPC node can't be reached
+pub const MAX_SEND_ATTEMPTS: u32 = 3;
+/// The delay between two rounds when the event queues of all markets are empty
+pub const IDLE_DELAY: Duration = Duration::from_millis(500);
 
 impl Context {
-    pub fn crank(self) {
-        let connection =
-            RpcClient::new_with_commitment(self.endpoint.clone(), CommitmentConfig::confirmed());
+    /// Opens a connection to the configured RPC endpoint
+    pub fn connect(&self) -> RpcClient {
+        RpcClient::new_with_commitment(self.endpoint.clone(), CommitmentConfig::confirmed())
+    }
+
+    /// Requests the cranker to stop.
+    ///
+    /// [`Context::run_forever`] returns once the transaction of the market being cranked has been sent, no transaction
+    /// being interrupted. Embedders driving [`Context::run_once`] should check [`Context::is_shutting_down`] between
+    /// iterations.
+    pub fn request_shutdown(&self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns true when a shutdown has been requested
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.load(Ordering::Relaxed)
+    }
+
+    /// Cranks all markets in a round-robin fashion, until a shutdown is requested
+    pub async fn run_forever(self) {
+        let connection = self.connect();
 
+        let mut markets = Vec::with_capacity(self.markets.len());
+        for market in &self.markets {
+            markets.push(retry(market, |m| Self::load_market(&connection, m), |r| r).await);
+        }
+        while !self.is_shutting_down() {
+            let mut idle = true;
+            for market in &markets {
+                // Markets with a full event queue reject new orders, so they are cranked until they have room again
+                loop {
+                    if self.is_shutting_down() {
+                        println!("Shutting down");
+                        return;
+                    }
+                    let res = self.run_once(&connection, market).await;
+                    if let Ok(None) = res {
+                        break;
+                    }
+                    idle = false;
+                    println!("{}: {:#?}", market.market, res);
+                    if !matches!(res, Ok(Some((_, true)))) {
+                        break;
+                    }
+                }
+            }
+            if idle {
+                sleep(IDLE_DELAY).await;
+            }
+        }
+    }
+
+    pub fn load_market(
+        connection: &RpcClient,
+        market: &Pubkey,
+    ) -> Result<MarketAccounts, CrankError> {
         let market_state_data = connection
-            .get_account_data(&self.market)
-            .map_err(|_| CrankError::ConnectionError)
-            .unwrap();
+            .get_account_data(market)
+            .map_err(|_| CrankError::ConnectionError)?;
         let market_state =
-            bytemuck::try_from_bytes::<DexState>(&market_state_data[..DEX_STATE_LEN]).unwrap();
+            *decode_dex_state(&market_state_data).map_err(|_| CrankError::InvalidMarketState)?;
 
         let orderbook_data = connection
             .get_account_data(&market_state.orderbook)
-            .unwrap();
-        let orderbook =
-            bytemuck::try_from_bytes::<MarketState>(&orderbook_data[..MARKET_STATE_LEN]).unwrap();
-        loop {
-            let res = self.consume_events_iteration(&connection, &orderbook, &market_state);
-            println!("{:#?}", res);
+            .map_err(|_| CrankError::ConnectionError)?;
+        let orderbook = *bytemuck::try_from_bytes::<MarketState>(
+            orderbook_data
+                .get(..MARKET_STATE_LEN)
+                .ok_or(CrankError::InvalidMarketState)?,
+        )
+        .map_err(|_| CrankError::InvalidMarketState)?;
+        Ok(MarketAccounts {
+            market: *market,
+            market_state,
+            orderbook,
+        })
+    }
+
+    /// Cranks the given market once, returns `None` when there was nothing to consume.
+    ///
+    /// The signature is returned along with whether the event queue was full before cranking.
+    pub async fn run_once(
+        &self,
+        connection: &RpcClient,
+        market: &MarketAccounts,
+    ) -> Result<Option<(Signature, bool)>, CrankError> {
+        match self.consume_events_iteration(connection, market)? {
+            Some(CrankTransaction {
+                transaction,
+                events,
+                event_queue_full,
+                fills,
+            }) => {
+                // Empty queues are reported as no-ops by the preflight simulation, which is not a failure
+                let signature = no_op_filter(
+                    retry_with_backoff(
+                        MAX_SEND_ATTEMPTS,
+                        INITIAL_RETRY_DELAY,
+                        is_transient_error,
+                        || {
+                            connection.send_transaction_with_config(
+                                &transaction,
+                                RpcSendTransactionConfig {
+                                    skip_preflight: false,
+                                    preflight_commitment: Some(CommitmentLevel::Processed),
+                                    ..RpcSendTransactionConfig::default()
+                                },
+                            )
+                        },
+                    )
+                    .await,
+                )
+                .map_err(|_| {
+                    self.metrics.record_transaction_failed(&market.market);
+                    CrankError::TransactionFailed
+                })?;
+                if signature == Signature::default() {
+                    return Ok(None);
+                }
+                self.metrics.record_transaction_sent(&market.market, events);
+                if let Some(fill_log) = &self.fill_log {
+                    if let Err(e) = fill_log.write(&fills) {
+                        println!("Failed to log the fills with {:#?}", e);
+                    }
+                }
+                Ok(Some((signature, event_queue_full)))
+            }
+            None => Ok(None),
         }
     }
 
+    /// Builds a consume_events transaction for the given market, returns `None` when its event queue is empty
     pub fn consume_events_iteration(
         &self,
         connection: &RpcClient,
-        orderbook: &MarketState,
-        market_state: &DexState,
-    ) -> Result<Signature, ClientError> {
-        let mut event_queue_data =
-            connection.get_account_data(&Pubkey::new(&orderbook.event_queue))?;
-        let event_queue_header =
-            EventQueueHeader::deserialize(&mut (&event_queue_data as &[u8])).unwrap();
+        market: &MarketAccounts,
+    ) -> Result<Option<CrankTransaction>, CrankError> {
+        let MarketAccounts {
+            market,
+            market_state,
+            orderbook,
+        } = market;
+        let mut event_queue_data = connection
+            .get_account_data(&Pubkey::new(&orderbook.event_queue))
+            .map_err(|_| CrankError::ConnectionError)?;
+        let event_queue_header = EventQueueHeader::deserialize(&mut (&event_queue_data as &[u8]))
+            .map_err(|_| CrankError::InvalidEventQueue)?;
         let length = event_queue_header.count as usize;
+        self.metrics
+            .record_event_queue_length(market, event_queue_header.count);
+        if length == 0 {
+            return Ok(None);
+        }
         let event_queue = EventQueue::new(
             event_queue_header,
             Rc::new(RefCell::new(&mut event_queue_data)),
             CALLBACK_INFO_LEN as usize,
         );
-        let mut user_accounts = Vec::with_capacity(length << 1);
-        for e in event_queue.iter() {
+        let event_queue_full = event_queue.full();
+        let max_events = MAX_ITERATIONS as usize * MAX_INSTRUCTIONS_PER_TRANSACTION;
+        let mut event_user_accounts = Vec::with_capacity(length.min(max_events));
+        // The fills are indexed by their position in the queue, to only keep the ones consumed by the transaction
+        let mut fills = Vec::new();
+        for (i, e) in event_queue.iter().take(max_events).enumerate() {
             match e {
                 Event::Fill {
-                    taker_side: _,
-                    maker_order_id: _,
-                    quote_size: _,
-                    base_size: _,
+                    taker_side,
+                    maker_order_id,
+                    quote_size,
+                    base_size,
                     maker_callback_info,
-                    taker_callback_info: _,
+                    taker_callback_info,
                 } => {
-                    let maker_callback_info =
-                        CallBackInfo::deserialize(&mut (&maker_callback_info as &[u8])).unwrap();
-                    user_accounts.push(maker_callback_info.user_account);
+                    let maker_callback_info = decode_callback_info(&maker_callback_info)
+                        .map_err(|_| CrankError::InvalidEventQueue)?;
+                    event_user_accounts.push(maker_callback_info.user_account);
+                    if self.fill_log.is_some() {
+                        let taker_callback_info = decode_callback_info(&taker_callback_info)
+                            .map_err(|_| CrankError::InvalidEventQueue)?;
+                        fills.push((
+                            i,
+                            FillRecord::new(
+                                market,
+                                market_state,
+                                taker_side,
+                                maker_order_id,
+                                quote_size,
+                                base_size,
+                                &maker_callback_info.user_account,
+                                &taker_callback_info.user_account,
+                            ),
+                        ));
+                    }
                 }
                 Event::Out {
                     side: _,
@@ -97,48 +305,395 @@ impl Context {
                     delete: _,
                     callback_info,
                 } => {
-                    let callback_info =
-                        CallBackInfo::deserialize(&mut (&callback_info as &[u8])).unwrap();
-                    user_accounts.push(callback_info.user_account);
+                    let callback_info = decode_callback_info(&callback_info)
+                        .map_err(|_| CrankError::InvalidEventQueue)?;
+                    event_user_accounts.push(callback_info.user_account);
                 }
             }
         }
 
-        user_accounts.truncate(MAX_NUMBER_OF_USER_ACCOUNTS);
-
-        // We don't use the default sort since the initial ordering of the pubkeys is completely random
-        user_accounts.sort_unstable();
-        // Since the array is sorted, this removes all duplicate accounts, which shrinks the array.
-        user_accounts.dedup();
-
-        let consume_events_instruction = consume_events(
-            self.program_id,
-            Accounts {
-                orderbook: &market_state.orderbook,
-                market: &self.market,
-                event_queue: &Pubkey::new(&orderbook.event_queue),
-                reward_target: &self.reward_target,
-                user_accounts: &user_accounts,
-            },
-            consume_events::Params {
-                max_iterations: MAX_ITERATIONS,
-                no_op_err: 1,
-            },
+        let windows = select_windows(
+            event_user_accounts,
+            MAX_ITERATIONS,
+            MAX_NUMBER_OF_USER_ACCOUNTS,
+            MAX_INSTRUCTIONS_PER_TRANSACTION,
         );
 
-        let mut transaction = Transaction::new_with_payer(
-            &[consume_events_instruction],
-            Some(&self.fee_payer.pubkey()),
-        );
-        let (recent_blockhash, _) = connection.get_recent_blockhash()?;
+        let mut consume_events_instructions = windows
+            .iter()
+            .map(|(max_iterations, user_accounts)| {
+                consume_events(
+                    self.program_id,
+                    Accounts {
+                        orderbook: &market_state.orderbook,
+                        market,
+                        event_queue: &Pubkey::new(&orderbook.event_queue),
+                        reward_target: &self.reward_target,
+                        candle_history: None,
+                        user_accounts,
+                    },
+                    consume_events::Params {
+                        max_iterations: *max_iterations,
+                        no_op_err: 1,
+                        has_candle_history: 0,
+                        skip_missing: 0,
+                        _padding: [0; 7],
+                    },
+                )
+            })
+            .collect::<Vec<_>>();
+
+        // The windows only account for the user accounts, the last ones are dropped if the transaction is still too large
+        let mut transaction = loop {
+            let transaction = Transaction::new_with_payer(
+                &self.with_compute_budget(consume_events_instructions.clone()),
+                Some(&self.fee_payer.pubkey()),
+            );
+            if consume_events_instructions.len() == 1
+                || transaction_size(&transaction) <= PACKET_DATA_SIZE
+            {
+                break transaction;
+            }
+            consume_events_instructions.pop();
+        };
+        let (recent_blockhash, _) = connection
+            .get_recent_blockhash()
+            .map_err(|_| CrankError::ConnectionError)?;
         transaction.partial_sign(&[&self.fee_payer], recent_blockhash);
-        connection.send_transaction_with_config(
-            &transaction,
-            RpcSendTransactionConfig {
-                skip_preflight: false,
-                preflight_commitment: Some(CommitmentLevel::Processed),
-                ..RpcSendTransactionConfig::default()
+        let events = windows
+            .iter()
+            .take(consume_events_instructions.len())
+            .map(|(iterations, _)| iterations)
+            .sum::<u64>();
+        let fills = fills
+            .into_iter()
+            .filter(|(i, _)| (*i as u64) < events)
+            .map(|(_, fill)| fill)
+            .collect();
+        Ok(Some(CrankTransaction {
+            transaction,
+            events,
+            event_queue_full,
+            fills,
+        }))
+    }
+
+    /// Prepends the compute budget instructions to the given cranking instructions
+    pub fn with_compute_budget(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        let mut res = vec![
+            ComputeBudgetInstruction::set_compute_unit_limit(self.compute_unit_limit),
+            ComputeBudgetInstruction::set_compute_unit_price(self.compute_unit_price),
+        ];
+        res.extend(instructions);
+        res
+    }
+}
+
+/// Splits the longest prefix of the event queue which can be consumed by a single transaction into successive
+/// consume_events windows.
+///
+/// Takes the user account referenced by each event, in queue order, and returns for each window the number of events
+/// to consume along with the distinct user accounts they require, in order of first reference. Accounts shared by
+/// several windows only count once against the transaction's user account limit.
+pub fn select_windows<I: IntoIterator<Item = Pubkey>>(
+    event_user_accounts: I,
+    max_iterations: u64,
+    max_user_accounts: usize,
+    max_windows: usize,
+) -> Vec<(u64, Vec<Pubkey>)> {
+    let mut windows = Vec::with_capacity(max_windows);
+    let mut transaction_accounts = Vec::with_capacity(max_user_accounts);
+    let mut window_accounts = Vec::new();
+    let mut iterations = 0;
+    for user_account in event_user_accounts {
+        if iterations == max_iterations {
+            windows.push((iterations, std::mem::take(&mut window_accounts)));
+            iterations = 0;
+            if windows.len() == max_windows {
+                break;
+            }
+        }
+        if !transaction_accounts.contains(&user_account) {
+            if transaction_accounts.len() == max_user_accounts {
+                break;
+            }
+            transaction_accounts.push(user_account);
+        }
+        // The program doesn't require the user accounts to be sorted
+        if !window_accounts.contains(&user_account) {
+            window_accounts.push(user_account);
+        }
+        iterations += 1;
+    }
+    if iterations != 0 && windows.len() < max_windows {
+        windows.push((iterations, window_accounts));
+    }
+    windows
+}
+
+/// Lists the markets of the DEX program which are not closed
+pub fn discover_markets(
+    connection: &RpcClient,
+    program_id: &Pubkey,
+) -> Result<Vec<Pubkey>, CrankError> {
+    let accounts = connection
+        .get_program_accounts_with_config(
+            program_id,
+            RpcProgramAccountsConfig {
+                filters: Some(vec![RpcFilterType::DataSize(DEX_STATE_LEN as u64)]),
+                account_config: RpcAccountInfoConfig {
+                    encoding: Some(UiAccountEncoding::Base64),
+                    ..RpcAccountInfoConfig::default()
+                },
+                ..RpcProgramAccountsConfig::default()
             },
         )
+        .map_err(|_| CrankError::ConnectionError)?;
+    Ok(extract_markets(&accounts))
+}
+
+/// Selects the markets which are not closed among the program accounts of the DEX, sorted by key.
+///
+/// Other accounts of the same size are skipped as their tag doesn't match.
+pub fn extract_markets(accounts: &[(Pubkey, Account)]) -> Vec<Pubkey> {
+    let mut markets = accounts
+        .iter()
+        .filter(|(_, account)| match decode_dex_state(&account.data) {
+            Ok(market_state) => market_state.status != MarketStatus::Closed as u8,
+            Err(_) => false,
+        })
+        .map(|(key, _)| *key)
+        .collect::<Vec<_>>();
+    markets.sort_unstable();
+    markets
+}
+
+/// The size of the serialized transaction, which must fit in a single packet
+pub fn transaction_size(transaction: &Transaction) -> usize {
+    // The signatures are prefixed by their compact-u16 encoded count
+    1 + transaction.signatures.len() * SIGNATURE_BYTES + transaction.message.serialize().len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytemuck::Zeroable;
+    use dex_v4::state::AccountTag;
+    use solana_account_decoder::UiAccount;
+    use solana_client::{
+        mock_sender::Mocks, rpc_request::RpcRequest, rpc_response::RpcKeyedAccount,
+    };
+
+    #[test]
+    fn test_select_windows() {
+        let accounts = (0..4).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        let [a, b, c, d] = [accounts[0], accounts[1], accounts[2], accounts[3]];
+
+        // An empty queue
+        assert_eq!(select_windows(vec![], 10, 20, 1), vec![]);
+
+        // A short queue with repeated accounts is consumed entirely
+        assert_eq!(
+            select_windows(vec![c, a, c, b], 10, 20, 1),
+            vec![(4, vec![c, a, b])]
+        );
+
+        // A long queue is capped by the number of iterations
+        assert_eq!(select_windows(vec![a; 15], 10, 20, 1), vec![(10, vec![a])]);
+
+        // The window stops right before the first event whose account doesn't fit,
+        // events referencing already selected accounts don't count against the limit
+        assert_eq!(
+            select_windows(vec![d, b, d, b, a, c, a], 10, 2, 1),
+            vec![(4, vec![d, b])]
+        );
+
+        // Successive windows only hold the accounts of their own events,
+        // accounts shared between windows only count once against the limit
+        assert_eq!(
+            select_windows(vec![d, b, a, b, d, a, c, d], 3, 3, 4),
+            vec![(3, vec![d, b, a]), (3, vec![b, d, a])]
+        );
+        assert_eq!(
+            select_windows(vec![d, b, a, b, d, a, c, d], 3, 4, 4),
+            vec![(3, vec![d, b, a]), (3, vec![b, d, a]), (2, vec![c, d])]
+        );
+
+        // The number of windows is capped
+        assert_eq!(
+            select_windows(vec![a; 15], 3, 20, 2),
+            vec![(3, vec![a]), (3, vec![a])]
+        );
+    }
+
+    #[test]
+    fn test_batched_events_per_transaction() {
+        // A seeded queue of 40 events, spread over 8 user accounts
+        let accounts = (0..8).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        let queue = (0..40).map(|i| accounts[(i * 7) % 8]).collect::<Vec<_>>();
+
+        let count_events = |windows: Vec<(u64, Vec<Pubkey>)>| -> u64 {
+            windows.iter().map(|(iterations, _)| iterations).sum()
+        };
+
+        // A single consume_events instruction per transaction
+        let unbatched = count_events(select_windows(
+            queue.clone(),
+            MAX_ITERATIONS,
+            MAX_NUMBER_OF_USER_ACCOUNTS,
+            1,
+        ));
+        // Batched consume_events instructions
+        let windows = select_windows(
+            queue,
+            MAX_ITERATIONS,
+            MAX_NUMBER_OF_USER_ACCOUNTS,
+            MAX_INSTRUCTIONS_PER_TRANSACTION,
+        );
+        assert_eq!(windows.len(), MAX_INSTRUCTIONS_PER_TRANSACTION);
+        let batched = count_events(windows.clone());
+
+        assert_eq!(unbatched, 10);
+        assert_eq!(batched, 40);
+
+        // The batched transaction still fits in a single packet
+        let context = test_context();
+        let (market, orderbook, event_queue) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let instructions = windows
+            .iter()
+            .map(|(max_iterations, user_accounts)| {
+                consume_events(
+                    context.program_id,
+                    Accounts {
+                        orderbook: &orderbook,
+                        market: &market,
+                        event_queue: &event_queue,
+                        reward_target: &context.reward_target,
+                        candle_history: None,
+                        user_accounts,
+                    },
+                    consume_events::Params {
+                        max_iterations: *max_iterations,
+                        no_op_err: 1,
+                        has_candle_history: 0,
+                        skip_missing: 0,
+                        _padding: [0; 7],
+                    },
+                )
+            })
+            .collect::<Vec<_>>();
+        let transaction = Transaction::new_with_payer(
+            &context.with_compute_budget(instructions),
+            Some(&context.fee_payer.pubkey()),
+        );
+        assert!(transaction_size(&transaction) <= PACKET_DATA_SIZE);
+    }
+
+    fn test_context() -> Context {
+        Context {
+            program_id: Pubkey::new_unique(),
+            markets: vec![],
+            reward_target: Pubkey::new_unique(),
+            fee_payer: Keypair::new(),
+            endpoint: String::new(),
+            compute_unit_limit: 300_000,
+            compute_unit_price: 42,
+            metrics: Arc::new(Metrics::default()),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            fill_log: None,
+        }
+    }
+
+    #[test]
+    fn test_discover_markets() {
+        let market_account = |tag: AccountTag, status: MarketStatus| {
+            let mut market_state = DexState::zeroed();
+            market_state.tag = tag as u64;
+            market_state.status = status as u8;
+            (
+                Pubkey::new_unique(),
+                Account {
+                    lamports: 1,
+                    data: bytemuck::bytes_of(&market_state).to_vec(),
+                    owner: Pubkey::new_unique(),
+                    executable: false,
+                    rent_epoch: 0,
+                },
+            )
+        };
+        let active = market_account(AccountTag::DexState, MarketStatus::Active);
+        let paused = market_account(AccountTag::DexState, MarketStatus::Paused);
+        let closed = market_account(AccountTag::Closed, MarketStatus::Closed);
+        let closing = market_account(AccountTag::DexState, MarketStatus::Closed);
+        let user_account = market_account(AccountTag::UserAccount, MarketStatus::Active);
+        let accounts = vec![active, closed, user_account, paused, closing];
+
+        let response = accounts
+            .iter()
+            .map(|(key, account)| RpcKeyedAccount {
+                pubkey: key.to_string(),
+                account: UiAccount::encode(key, account, UiAccountEncoding::Base64, None, None),
+            })
+            .collect::<Vec<_>>();
+        let mut mocks = Mocks::new();
+        mocks.insert(
+            RpcRequest::GetProgramAccounts,
+            serde_json::to_value(response).unwrap(),
+        );
+        let connection = RpcClient::new_mock_with_mocks(String::from("succeeds"), mocks);
+
+        let mut expected = vec![accounts[0].0, accounts[3].0];
+        expected.sort_unstable();
+        assert_eq!(
+            discover_markets(&connection, &Pubkey::new_unique()).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut context = test_context();
+        context.endpoint = String::from("http://localhost:8899");
+        let shutdown = context.shutdown.clone();
+        let mut handle = runtime.spawn(context.run_forever());
+
+        // The idle loop keeps running until the flag is set
+        assert!(runtime
+            .block_on(tokio::time::timeout(2 * IDLE_DELAY, &mut handle))
+            .is_err());
+
+        shutdown.store(true, Ordering::Relaxed);
+        runtime
+            .block_on(tokio::time::timeout(2 * IDLE_DELAY, handle))
+            .expect("The cranker should stop once a shutdown is requested")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_compute_budget() {
+        let context = test_context();
+        let instruction = Instruction::new_with_bytes(context.program_id, &[], vec![]);
+        let instructions = context.with_compute_budget(vec![instruction.clone()]);
+
+        assert_eq!(instructions.len(), 3);
+        assert_eq!(
+            instructions[0],
+            ComputeBudgetInstruction::set_compute_unit_limit(300_000)
+        );
+        assert_eq!(
+            instructions[1],
+            ComputeBudgetInstruction::set_compute_unit_price(42)
+        );
+        assert_eq!(instructions[2], instruction);
+
+        // The budget instructions don't prevent the transaction from being signed by the fee payer alone
+        let transaction =
+            Transaction::new_with_payer(&instructions, Some(&context.fee_payer.pubkey()));
+        assert_eq!(transaction.message.header.num_required_signatures, 1);
     }
 }
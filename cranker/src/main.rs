@@ -1,10 +1,26 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
 use clap::{App, Arg};
-use dex_cranker::Context;
+use dex_cranker::{
+    config::{Config, Overrides},
+    discover_markets,
+    fills::FillLog,
+    metrics::{self, Metrics},
+};
 use solana_clap_utils::{
     fee_payer::{fee_payer_arg, FEE_PAYER_ARG},
-    input_parsers::{keypair_of, pubkey_of},
-    input_validators::is_pubkey,
+    input_parsers::{keypair_of, pubkey_of, pubkeys_of, value_of},
+    input_validators::{is_parsable, is_pubkey},
 };
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::signature::read_keypair_file;
+use tokio::signal::unix::{signal, SignalKind};
 
 fn main() {
     let matches = App::new("dex-crank")
@@ -18,7 +34,14 @@ fn main() {
                 .help("A Solana RPC endpoint url")
                 .takes_value(true),
         )
-        .arg(fee_payer_arg().required(true))
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .help("The path to a JSON configuration file, whose settings are overridden by the command line arguments")
+                .takes_value(true),
+        )
+        .arg(fee_payer_arg().required_unless("config"))
         .arg(
             Arg::with_name("program_id")
                 .short("p")
@@ -26,16 +49,23 @@ fn main() {
                 .help("The pubkey of the dex program")
                 .takes_value(true)
                 .validator(is_pubkey)
-                .required(true),
+                .required_unless("config"),
         )
         .arg(
             Arg::with_name("market")
                 .short("m")
                 .long("market")
-                .help("The pubkey of the dex market to interact with")
+                .help("The pubkey of the dex market to interact with, can be repeated to crank several markets")
                 .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
                 .validator(is_pubkey)
-                .required(true),
+                .required_unless_one(&["discover-markets", "config"]),
+        )
+        .arg(
+            Arg::with_name("discover-markets")
+                .long("discover-markets")
+                .help("Crank all the markets of the dex program which are not closed, along with the given markets"),
         )
         .arg(
             Arg::with_name("reward-target")
@@ -44,22 +74,115 @@ fn main() {
                 .help("The pubkey of the target account for SOL cranking rewards")
                 .takes_value(true)
                 .validator(is_pubkey)
-                .required(true),
+                .required_unless("config"),
+        )
+        .arg(
+            Arg::with_name("compute-unit-limit")
+                .long("compute-unit-limit")
+                .help("The compute unit limit requested by cranking transactions")
+                .takes_value(true)
+                .validator(is_parsable::<u32>),
+        )
+        .arg(
+            Arg::with_name("compute-unit-price")
+                .long("compute-unit-price")
+                .help("The priority fee of cranking transactions, in micro-lamports per compute unit")
+                .takes_value(true)
+                .validator(is_parsable::<u64>),
+        )
+        .arg(
+            Arg::with_name("metrics-address")
+                .long("metrics-address")
+                .help("The address on which to serve the cranking metrics over HTTP, in the Prometheus text format")
+                .takes_value(true)
+                .validator(is_parsable::<SocketAddr>),
+        )
+        .arg(
+            Arg::with_name("log-fills")
+                .long("log-fills")
+                .help("Log the consumed fills as JSON lines, to the standard output unless --fill-log-file is given"),
+        )
+        .arg(
+            Arg::with_name("fill-log-file")
+                .long("fill-log-file")
+                .help("The file to which the consumed fills are appended")
+                .takes_value(true)
+                .requires("log-fills"),
         )
         .get_matches();
-    let endpoint = matches
-        .value_of("url")
-        .unwrap_or("https://solana-api.projectserum.com");
-    let program_id = pubkey_of(&matches, "program_id").unwrap();
-    let market = pubkey_of(&matches, "market").expect("Invalid market Pubkey");
-    let reward_target = pubkey_of(&matches, "reward-target").expect("Invalid reward target pubkey");
-    let fee_payer = keypair_of(&matches, FEE_PAYER_ARG.name).unwrap();
-    let context = Context {
-        market,
-        fee_payer,
-        endpoint: String::from(endpoint),
-        program_id,
-        reward_target,
+    let config = matches
+        .value_of("config")
+        .map(|path| Config::load(path).expect("Invalid configuration file"))
+        .unwrap_or_default();
+    let mut overrides = Overrides {
+        endpoint: matches.value_of("url").map(String::from),
+        program_id: pubkey_of(&matches, "program_id"),
+        reward_target: pubkey_of(&matches, "reward-target"),
+        compute_unit_limit: value_of(&matches, "compute-unit-limit"),
+        compute_unit_price: value_of(&matches, "compute-unit-price"),
+        markets: pubkeys_of(&matches, "market").unwrap_or_default(),
+    };
+    let fee_payer = keypair_of(&matches, FEE_PAYER_ARG.name).unwrap_or_else(|| {
+        let path = config
+            .fee_payer
+            .as_ref()
+            .expect("The fee payer is missing from the configuration");
+        read_keypair_file(path).expect("Invalid fee payer keypair file")
+    });
+    let metrics_address: Option<SocketAddr> = value_of(&matches, "metrics-address");
+    if matches.is_present("discover-markets") {
+        let connection = RpcClient::new(config.endpoint(&overrides));
+        let program_id = config.program_id(&overrides).unwrap();
+        let discovered =
+            discover_markets(&connection, &program_id).expect("Failed to discover the markets");
+        println!("Discovered {} markets", discovered.len());
+        overrides.markets.extend(discovered);
+    }
+    let mut contexts = config
+        .into_contexts(&fee_payer, &overrides)
+        .expect("Invalid configuration");
+    // The contexts share their metrics, shutdown flag and fill log
+    let metrics = Arc::new(Metrics::default());
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let fill_log = if matches.is_present("log-fills") {
+        Some(Arc::new(match matches.value_of("fill-log-file") {
+            Some(path) => FillLog::file(path).expect("Failed to open the fill log file"),
+            None => FillLog::stdout(),
+        }))
+    } else {
+        None
     };
-    context.crank();
+    for context in contexts.iter_mut() {
+        context.metrics = metrics.clone();
+        context.shutdown = shutdown.clone();
+        context.fill_log = fill_log.clone();
+    }
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    if let Some(address) = metrics_address {
+        runtime.spawn(async move {
+            if let Err(e) = metrics::serve(metrics, address).await {
+                println!("The metrics server stopped with {:#?}", e);
+            }
+        });
+    }
+    // The cranker stops between two transactions on SIGINT or SIGTERM
+    for kind in &[SignalKind::interrupt(), SignalKind::terminate()] {
+        let kind = *kind;
+        let shutdown = shutdown.clone();
+        runtime.spawn(async move {
+            let mut stream = signal(kind).expect("Failed to register the signal handler");
+            stream.recv().await;
+            println!("Received a shutdown signal, stopping after the current transaction");
+            shutdown.store(true, Ordering::Relaxed);
+        });
+    }
+    runtime.block_on(async move {
+        let handles = contexts
+            .into_iter()
+            .map(|context| tokio::spawn(context.run_forever()))
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+    });
 }
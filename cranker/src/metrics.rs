@@ -0,0 +1,178 @@
+use std::{collections::BTreeMap, fmt::Write, net::SocketAddr, sync::Arc, sync::Mutex};
+
+use solana_program::pubkey::Pubkey;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// The cranking metrics of a single market
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MarketMetrics {
+    /// The number of events consumed by the sent transactions
+    pub events_consumed: u64,
+    /// The number of sent cranking transactions
+    pub transactions_sent: u64,
+    /// The number of cranking transactions which failed to be sent
+    pub transactions_failed: u64,
+    /// The number of pending events when the event queue was last read
+    pub event_queue_length: u64,
+}
+
+/// The cranking metrics of all markets, exposed in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    markets: Mutex<BTreeMap<Pubkey, MarketMetrics>>,
+}
+
+impl Metrics {
+    fn update<F: FnOnce(&mut MarketMetrics)>(&self, market: &Pubkey, f: F) {
+        let mut markets = self.markets.lock().unwrap();
+        f(markets.entry(*market).or_default())
+    }
+
+    /// Records the number of pending events of a market's event queue
+    pub fn record_event_queue_length(&self, market: &Pubkey, length: u64) {
+        self.update(market, |m| m.event_queue_length = length)
+    }
+
+    /// Records a cranking transaction which was sent, along with the number of events it consumes
+    pub fn record_transaction_sent(&self, market: &Pubkey, events: u64) {
+        self.update(market, |m| {
+            m.transactions_sent += 1;
+            m.events_consumed += events;
+        })
+    }
+
+    /// Records a cranking transaction which failed to be sent
+    pub fn record_transaction_failed(&self, market: &Pubkey) {
+        self.update(market, |m| m.transactions_failed += 1)
+    }
+
+    /// Returns the metrics of a market
+    pub fn get(&self, market: &Pubkey) -> MarketMetrics {
+        self.markets
+            .lock()
+            .unwrap()
+            .get(market)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let markets = self.markets.lock().unwrap();
+        let families: [(&str, &str, &str, fn(&MarketMetrics) -> u64); 4] = [
+            (
+                "dex_cranker_events_consumed_total",
+                "counter",
+                "The number of events consumed by the sent transactions",
+                |m| m.events_consumed,
+            ),
+            (
+                "dex_cranker_transactions_sent_total",
+                "counter",
+                "The number of sent cranking transactions",
+                |m| m.transactions_sent,
+            ),
+            (
+                "dex_cranker_transactions_failed_total",
+                "counter",
+                "The number of cranking transactions which failed to be sent",
+                |m| m.transactions_failed,
+            ),
+            (
+                "dex_cranker_event_queue_length",
+                "gauge",
+                "The number of pending events in the event queue",
+                |m| m.event_queue_length,
+            ),
+        ];
+        let mut res = String::new();
+        for (name, kind, help, value) in families.iter() {
+            writeln!(res, "# HELP {} {}", name, help).unwrap();
+            writeln!(res, "# TYPE {} {}", name, kind).unwrap();
+            for (market, metrics) in markets.iter() {
+                writeln!(res, "{}{{market=\"{}\"}} {}", name, market, value(metrics)).unwrap();
+            }
+        }
+        res
+    }
+}
+
+/// Serves the metrics over HTTP on the given address, answering every request with the exposition
+pub async fn serve(metrics: Arc<Metrics>, address: SocketAddr) -> std::io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            // The request itself is not inspected, the exposition is the only resource
+            let mut request = [0; 1024];
+            if stream.read(&mut request).await.is_err() {
+                return;
+            }
+            let body = metrics.render();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            if let Err(e) = stream.write_all(response.as_bytes()).await {
+                println!("Failed to serve the metrics with {:#?}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        let mut markets = [Pubkey::new_unique(), Pubkey::new_unique()];
+        markets.sort_unstable();
+        let [a, b] = markets;
+
+        metrics.record_event_queue_length(&b, 7);
+        metrics.record_transaction_sent(&b, 4);
+        metrics.record_transaction_sent(&b, 3);
+        metrics.record_transaction_failed(&b);
+        metrics.record_event_queue_length(&a, 0);
+
+        assert_eq!(
+            metrics.get(&b),
+            MarketMetrics {
+                events_consumed: 7,
+                transactions_sent: 2,
+                transactions_failed: 1,
+                event_queue_length: 7,
+            }
+        );
+
+        // The markets are listed in a stable order under each metric family
+        let expected = format!(
+            "# HELP dex_cranker_events_consumed_total The number of events consumed by the sent transactions\n\
+             # TYPE dex_cranker_events_consumed_total counter\n\
+             dex_cranker_events_consumed_total{{market=\"{a}\"}} 0\n\
+             dex_cranker_events_consumed_total{{market=\"{b}\"}} 7\n\
+             # HELP dex_cranker_transactions_sent_total The number of sent cranking transactions\n\
+             # TYPE dex_cranker_transactions_sent_total counter\n\
+             dex_cranker_transactions_sent_total{{market=\"{a}\"}} 0\n\
+             dex_cranker_transactions_sent_total{{market=\"{b}\"}} 2\n\
+             # HELP dex_cranker_transactions_failed_total The number of cranking transactions which failed to be sent\n\
+             # TYPE dex_cranker_transactions_failed_total counter\n\
+             dex_cranker_transactions_failed_total{{market=\"{a}\"}} 0\n\
+             dex_cranker_transactions_failed_total{{market=\"{b}\"}} 1\n\
+             # HELP dex_cranker_event_queue_length The number of pending events in the event queue\n\
+             # TYPE dex_cranker_event_queue_length gauge\n\
+             dex_cranker_event_queue_length{{market=\"{a}\"}} 0\n\
+             dex_cranker_event_queue_length{{market=\"{b}\"}} 7\n",
+            a = a,
+            b = b
+        );
+        assert_eq!(metrics.render(), expected);
+    }
+}
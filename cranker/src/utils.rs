@@ -1,8 +1,17 @@
-use solana_client::client_error::ClientError;
+use dex_v4::error::DexError;
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind},
+    rpc_request::{RpcError, RpcResponseErrorData},
+};
 use solana_program::instruction::InstructionError;
-use solana_sdk::signature::Signature;
-use std::fmt::Debug;
-use tokio::task;
+use solana_sdk::{signature::Signature, transaction::TransactionError};
+use std::{fmt::Debug, time::Duration};
+use tokio::time::sleep;
+
+/// The delay before the first retry, doubled after each failed attempt
+pub const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// The maximum delay between two attempts
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
 
 pub async fn retry<F, T, K, E, R>(arg: T, f: F, e: R) -> K
 where
@@ -10,6 +19,7 @@ where
     E: Debug,
     R: Fn(Result<K, E>) -> Result<K, E>,
 {
+    let mut delay = INITIAL_RETRY_DELAY;
     loop {
         let res = e(f(&arg));
         if res.is_ok() {
@@ -17,36 +27,180 @@ where
         }
         let error = res.err().unwrap();
 
-        println!("Failed task with {:#?}, retrying", error);
-        task::yield_now().await;
+        println!("Failed task with {:#?}, retrying in {:?}", error, delay);
+        sleep(delay).await;
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+    }
+}
+
+/// Calls `f` until it succeeds, `max_attempts` is reached or it returns an error which `is_transient` rejects.
+///
+/// The delay between two attempts starts at `initial_delay` and is doubled after each failure. The task yields while
+/// waiting, so that the other tasks of the runtime keep running.
+pub async fn retry_with_backoff<K, E, F, R>(
+    max_attempts: u32,
+    initial_delay: Duration,
+    is_transient: R,
+    mut f: F,
+) -> Result<K, E>
+where
+    F: FnMut() -> Result<K, E>,
+    E: Debug,
+    R: Fn(&E) -> bool,
+{
+    let mut delay = initial_delay;
+    let mut attempts = 1;
+    loop {
+        match f() {
+            Err(e) if attempts < max_attempts && is_transient(&e) => {
+                println!("Failed task with {:#?}, retrying in {:?}", e, delay);
+                sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+                attempts += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Returns true when the error didn't come from the simulation of the transaction, in which case sending it again can
+/// succeed
+pub fn is_transient_error(e: &ClientError) -> bool {
+    preflight_error(e).is_none()
+}
+
+/// Returns the transaction error reported by a failed preflight simulation, if any
+fn preflight_error(e: &ClientError) -> Option<&TransactionError> {
+    match &e.kind {
+        ClientErrorKind::RpcError(RpcError::RpcResponseError {
+            data: RpcResponseErrorData::SendTransactionPreflightFailure(f),
+            ..
+        }) => f.err.as_ref(),
+        _ => None,
     }
 }
 
 pub fn no_op_filter(r: Result<Signature, ClientError>) -> Result<Signature, ClientError> {
     if let Err(e) = &r {
-        match &e.kind {
-            solana_client::client_error::ClientErrorKind::RpcError(
-                solana_client::rpc_request::RpcError::RpcResponseError {
-                    code: _,
-                    message: _,
-                    data,
-                },
-            ) => {
-                if let solana_client::rpc_request::RpcResponseErrorData::SendTransactionPreflightFailure(f) = data {
-                    match f.err {
-                        Some(solana_sdk::transaction::TransactionError::InstructionError(_, InstructionError::Custom(0x5))) => {
-                            println!("Operation was a no-op");
-                            Ok(Signature::new(&[0;64]))
-                        }
-                        _ => r
-                    }
+        if let Some(TransactionError::InstructionError(_, InstructionError::Custom(0x5))) =
+            preflight_error(e)
+        {
+            println!("Operation was a no-op");
+            return Ok(Signature::new(&[0; 64]));
+        }
+    }
+    r
+}
+
+/// Returns true when a transaction was rejected because the event queue of the market is full, which means that the
+/// market should be cranked before new orders can be matched
+pub fn is_event_queue_full(e: &ClientError) -> bool {
+    matches!(
+        preflight_error(e),
+        Some(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(c)
+        )) if *c == DexError::EventQueueFull as u32
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_client::rpc_response::RpcSimulateTransactionResult;
+    use std::cell::Cell;
+
+    fn preflight_failure(err: TransactionError) -> ClientError {
+        let result: RpcSimulateTransactionResult = serde_json::from_value(serde_json::json!({
+            "err": err,
+            "logs": null,
+            "accounts": null,
+        }))
+        .unwrap();
+        ClientErrorKind::RpcError(RpcError::RpcResponseError {
+            code: -32002,
+            message: String::from("Transaction simulation failed"),
+            data: RpcResponseErrorData::SendTransactionPreflightFailure(result),
+        })
+        .into()
+    }
+
+    #[test]
+    fn test_send_retries() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let no_op = || {
+            preflight_failure(TransactionError::InstructionError(
+                1,
+                InstructionError::Custom(DexError::NoOp as u32),
+            ))
+        };
+        let signature = Signature::new(&[1; 64]);
+
+        // An empty queue is not an error
+        let attempts = Cell::new(0);
+        let res = no_op_filter(runtime.block_on(retry_with_backoff(
+            3,
+            Duration::from_millis(1),
+            is_transient_error,
+            || {
+                attempts.set(attempts.get() + 1);
+                Err(no_op())
+            },
+        )));
+        assert_eq!(res.unwrap(), Signature::default());
+        assert_eq!(attempts.get(), 1);
+
+        // Transient errors are retried
+        let attempts = Cell::new(0);
+        let res = runtime.block_on(retry_with_backoff(
+            3,
+            Duration::from_millis(1),
+            is_transient_error,
+            || {
+                attempts.set(attempts.get() + 1);
+                if attempts.get() < 3 {
+                    Err(ClientError::from(ClientErrorKind::Custom(String::from(
+                        "Connection reset",
+                    ))))
                 } else {
-                    r
+                    Ok(signature)
                 }
-            }
-            _ => r,
-        }
-    } else {
-        r
+            },
+        ));
+        assert_eq!(res.unwrap(), signature);
+        assert_eq!(attempts.get(), 3);
+
+        // Up to the maximum number of attempts
+        let attempts = Cell::new(0);
+        let res = runtime.block_on(retry_with_backoff(
+            3,
+            Duration::from_millis(1),
+            is_transient_error,
+            || {
+                attempts.set(attempts.get() + 1);
+                Err::<Signature, _>(ClientError::from(ClientErrorKind::Custom(String::from(
+                    "Connection reset",
+                ))))
+            },
+        ));
+        assert!(res.is_err());
+        assert_eq!(attempts.get(), 3);
+
+        // Transactions rejected by the program are not sent again
+        let attempts = Cell::new(0);
+        let res = runtime.block_on(retry_with_backoff(
+            3,
+            Duration::from_millis(1),
+            is_transient_error,
+            || {
+                attempts.set(attempts.get() + 1);
+                Err::<Signature, _>(preflight_failure(TransactionError::InstructionError(
+                    1,
+                    InstructionError::Custom(DexError::EventQueueMismatch as u32),
+                )))
+            },
+        ));
+        assert!(no_op_filter(res).is_err());
+        assert_eq!(attempts.get(), 1);
     }
 }
@@ -339,7 +339,7 @@ export class settleInstruction {
     keys.push({
       pubkey: market,
       isSigner: false,
-      isWritable: false,
+      isWritable: true,
     });
     keys.push({
       pubkey: baseVault,
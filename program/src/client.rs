@@ -0,0 +1,260 @@
+use asset_agnostic_orderbook::state::{critbit::Slab, AccountTag, Side};
+use solana_program::{program_error::ProgramError, pubkey::Pubkey};
+
+use crate::state::CallBackInfo;
+
+/// Describes an order resting on the orderbook
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SlabOrder {
+    /// The order id
+    pub order_id: u128,
+    /// The order's limit price (as a FP32), in scaled units
+    pub price: u64,
+    /// The order's remaining base quantity, in scaled units
+    pub base_size: u64,
+    /// The order's side
+    pub side: Side,
+}
+
+/// Walks the bids and asks slabs and returns all orders belonging to a given user account.
+///
+/// Unlike the order list stored in the user account, this reads the orderbook directly and is
+/// therefore the ground truth when reconciling a user's open orders.
+pub fn user_orders_from_slab(
+    bids_data: &mut [u8],
+    asks_data: &mut [u8],
+    user_account: &Pubkey,
+) -> Result<Vec<SlabOrder>, ProgramError> {
+    let mut orders = Vec::new();
+    for (data, tag, side) in [
+        (bids_data, AccountTag::Bids, Side::Bid),
+        (asks_data, AccountTag::Asks, Side::Ask),
+    ] {
+        let slab = Slab::<CallBackInfo>::from_buffer(data, tag)?;
+        for leaf in slab.iter(side == Side::Ask) {
+            let handle = slab.find_by_key(leaf.key).unwrap();
+            if &slab.get_callback_info(handle).user_account != user_account {
+                continue;
+            }
+            orders.push(SlabOrder {
+                order_id: leaf.key,
+                price: leaf.price(),
+                base_size: leaf.base_quantity,
+                side,
+            });
+        }
+    }
+    Ok(orders)
+}
+
+/// The factors which convert the orderbook's scaled quantities of a market to UI units
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Multipliers {
+    /// The market's base currency multiplier
+    pub base_currency_multiplier: u64,
+    /// The market's quote currency multiplier
+    pub quote_currency_multiplier: u64,
+    /// The number of decimals of the base mint
+    pub base_decimals: u8,
+    /// The number of decimals of the quote mint
+    pub quote_decimals: u8,
+}
+
+impl Multipliers {
+    /// Converts an orderbook price (as a FP32) to UI quote units per UI base unit
+    pub fn ui_price(&self, price: u64) -> f64 {
+        (price as f64 / (1u64 << 32) as f64) * self.quote_currency_multiplier as f64
+            / self.base_currency_multiplier as f64
+            * 10f64.powi(self.base_decimals as i32 - self.quote_decimals as i32)
+    }
+
+    /// Converts a scaled base quantity to UI base units
+    pub fn ui_base_size(&self, base_size: u64) -> f64 {
+        base_size as f64 * self.base_currency_multiplier as f64
+            / 10f64.powi(self.base_decimals as i32)
+    }
+}
+
+/// The total size resting at a price level of the orderbook, in UI units
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DepthLevel {
+    /// The level's price, in UI quote units per UI base unit
+    pub price: f64,
+    /// The total base quantity of the orders at this price, in UI base units
+    pub size: f64,
+}
+
+/// The aggregated depth of both sides of the orderbook, from the best price outwards
+#[derive(Debug, Clone, PartialEq)]
+pub struct DepthSnapshot {
+    /// The bid levels, from the highest price
+    pub bids: Vec<DepthLevel>,
+    /// The ask levels, from the lowest price
+    pub asks: Vec<DepthLevel>,
+}
+
+/// Walks the bids and asks slabs and aggregates the orders into up to `levels` price levels per side.
+pub fn depth_snapshot(
+    bids_data: &mut [u8],
+    asks_data: &mut [u8],
+    levels: usize,
+    multipliers: &Multipliers,
+) -> Result<DepthSnapshot, ProgramError> {
+    let (mut bids, mut asks) = (Vec::new(), Vec::new());
+    for (data, tag, side, depth) in [
+        (bids_data, AccountTag::Bids, Side::Bid, &mut bids),
+        (asks_data, AccountTag::Asks, Side::Ask, &mut asks),
+    ] {
+        let slab = Slab::<CallBackInfo>::from_buffer(data, tag)?;
+        // The sizes are summed in scaled units and converted once the level is complete
+        let mut scaled_levels: Vec<(u64, u64)> = Vec::with_capacity(levels);
+        for leaf in slab.iter(side == Side::Ask) {
+            if let Some((price, size)) = scaled_levels.last_mut() {
+                if *price == leaf.price() {
+                    *size = size.saturating_add(leaf.base_quantity);
+                    continue;
+                }
+            }
+            if scaled_levels.len() == levels {
+                break;
+            }
+            scaled_levels.push((leaf.price(), leaf.base_quantity));
+        }
+        *depth = scaled_levels
+            .into_iter()
+            .map(|(price, size)| DepthLevel {
+                price: multipliers.ui_price(price),
+                size: multipliers.ui_base_size(size),
+            })
+            .collect();
+    }
+    Ok(DepthSnapshot { bids, asks })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asset_agnostic_orderbook::state::critbit::LeafNode;
+
+    fn craft_slab(tag: AccountTag, orders: &[(u128, u64, Pubkey)]) -> Vec<u8> {
+        let mut data = vec![0; Slab::<CallBackInfo>::compute_allocation_size(16)];
+        Slab::<CallBackInfo>::initialize(&mut data, tag).unwrap();
+        let mut slab = Slab::<CallBackInfo>::from_buffer(&mut data, tag).unwrap();
+        for (key, base_quantity, user_account) in orders {
+            let (handle, _) = slab
+                .insert_leaf(&LeafNode::new(*key, *base_quantity))
+                .unwrap();
+            *slab.get_callback_info_mut(handle) = CallBackInfo {
+                user_account: *user_account,
+                fee_tier: 0,
+            };
+        }
+        data
+    }
+
+    #[test]
+    fn test_depth_snapshot() {
+        let user = Pubkey::new_unique();
+        let price = |p: u128| (p << 32) << 64;
+        let mut bids = craft_slab(
+            AccountTag::Bids,
+            &[
+                (price(10) | 1, 5, user),
+                (price(9) | 2, 4, user),
+                (price(10) | 3, 3, user),
+                (price(8) | 4, 1, user),
+            ],
+        );
+        let mut asks = craft_slab(
+            AccountTag::Asks,
+            &[
+                (price(12) | 5, 2, user),
+                (price(11) | 6, 6, user),
+                (price(12) | 7, 2, user),
+            ],
+        );
+        let multipliers = Multipliers {
+            base_currency_multiplier: 100,
+            quote_currency_multiplier: 1,
+            base_decimals: 2,
+            quote_decimals: 2,
+        };
+
+        let snapshot = depth_snapshot(&mut bids, &mut asks, 2, &multipliers).unwrap();
+        // A price of 10 scaled quote per scaled base is 0.1 quote per base with these multipliers
+        assert_eq!(
+            snapshot,
+            DepthSnapshot {
+                bids: vec![
+                    DepthLevel {
+                        price: 0.1,
+                        size: 8.0,
+                    },
+                    DepthLevel {
+                        price: 0.09,
+                        size: 4.0,
+                    },
+                ],
+                asks: vec![
+                    DepthLevel {
+                        price: 0.11,
+                        size: 6.0,
+                    },
+                    DepthLevel {
+                        price: 0.12,
+                        size: 4.0,
+                    },
+                ],
+            }
+        );
+
+        let mut empty_asks = craft_slab(AccountTag::Asks, &[]);
+        let snapshot = depth_snapshot(&mut bids, &mut empty_asks, 10, &multipliers).unwrap();
+        assert_eq!(snapshot.bids.len(), 3);
+        assert!(snapshot.asks.is_empty());
+    }
+
+    #[test]
+    fn test_user_orders_from_slab() {
+        let user = Pubkey::new_unique();
+        let other_user = Pubkey::new_unique();
+        let mut bids = craft_slab(
+            AccountTag::Bids,
+            &[
+                ((10u128 << 64) | 1, 5, user),
+                ((11u128 << 64) | 2, 7, other_user),
+            ],
+        );
+        let mut asks = craft_slab(
+            AccountTag::Asks,
+            &[
+                ((12u128 << 64) | 3, 3, other_user),
+                ((13u128 << 64) | 4, 9, user),
+            ],
+        );
+
+        let orders = user_orders_from_slab(&mut bids, &mut asks, &user).unwrap();
+        assert_eq!(
+            orders,
+            vec![
+                SlabOrder {
+                    order_id: (10u128 << 64) | 1,
+                    price: 10,
+                    base_size: 5,
+                    side: Side::Bid,
+                },
+                SlabOrder {
+                    order_id: (13u128 << 64) | 4,
+                    price: 13,
+                    base_size: 9,
+                    side: Side::Ask,
+                },
+            ]
+        );
+        assert!(
+            user_orders_from_slab(&mut bids, &mut asks, &Pubkey::new_unique())
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
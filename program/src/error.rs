@@ -1,7 +1,12 @@
+use asset_agnostic_orderbook::error::AoError;
 use num_derive::FromPrimitive;
+use num_traits::FromPrimitive as _;
 use thiserror::Error;
 
-use solana_program::{decode_error::DecodeError, program_error::ProgramError};
+use solana_program::{
+    decode_error::DecodeError,
+    program_error::{PrintProgramError, ProgramError},
+};
 
 pub type AoResult<T = ()> = Result<T, DexError>;
 
@@ -57,6 +62,75 @@ pub enum DexError {
     EventQueueMustBeEmpty,
     #[error("Event queue mismatch")]
     EventQueueMismatch,
+    #[error("The market close grace period has not elapsed")]
+    CloseGracePeriodNotElapsed,
+    #[error("The quote vault balance does not cover the amount to be swept")]
+    InsufficientVaultBalance,
+    #[error("The orderbook must be empty")]
+    OrderbookMustBeEmpty,
+    #[error("The market is paused")]
+    MarketPaused,
+    #[error("The destination token account does not hold wrapped SOL")]
+    NonNativeMint,
+    #[error("The requested amount exceeds the free balance of the user account")]
+    InsufficientFreeBalance,
+    #[error("The user's base token account does not hold the market's base mint")]
+    InvalidBaseTokenAccountMint,
+    #[error("The user's quote token account does not hold the market's quote mint")]
+    InvalidQuoteTokenAccountMint,
+    #[error("The post only order would cross the orderbook")]
+    PostOnlyWouldCross,
+    #[error("Invalid base mint account provided")]
+    InvalidBaseMintAccount,
+    #[error("Invalid quote mint account provided")]
+    InvalidQuoteMintAccount,
+    #[error("The vault's mint has a Token-2022 extension which the market does not support")]
+    UnsupportedMintExtension,
+    #[error("The average fill price deviates from the last price by more than the market allows")]
+    PriceDeviationExceeded,
+    #[error("The token account of a creator is missing")]
+    MissingCreatorTokenAccount,
+    #[error("The referral account cannot be the quote vault or belong to the taker")]
+    InvalidReferralAccount,
+    #[error("The candle history does not belong to the market")]
+    InvalidCandleHistoryAccount,
+    #[error("The AOB event queue is full and should be cranked")]
+    EventQueueFull,
+    #[error("The orderbook has no space left for new orders")]
+    OrderbookFull,
+    #[error("A provided user account does not belong to the market")]
+    InvalidUserAccountMarket,
+    #[error("The referrer account does not match the market and the referral account")]
+    InvalidReferrerAccount,
+    #[error("The client order id is already used by an active order of the user account")]
+    DuplicateClientId,
+    #[error("The discount token account should hold SRM or MSRM")]
+    InvalidDiscountTokenMint,
+    #[error("The discount token account should be owned by the user wallet")]
+    InvalidDiscountTokenOwner,
+    #[error("The rent payer account does not match the one recorded in the user account")]
+    InvalidRentPayerAccount,
+    #[error("A creator which is still owed royalties cannot be removed")]
+    UnpaidRoyalties,
+}
+
+impl DexError {
+    /// Logs an error returned by the asset agnostic orderbook and maps it to a dedicated variant when there is one, or
+    /// to [`DexError::AOBError`] otherwise
+    pub(crate) fn from_aob_error(error: ProgramError) -> Self {
+        error.print::<AoError>();
+        let ao_error = match error {
+            ProgramError::Custom(code) => AoError::from_u32(code),
+            _ => None,
+        };
+        match ao_error {
+            Some(AoError::EventQueueFull) => DexError::EventQueueFull,
+            Some(AoError::SlabOutOfSpace) => DexError::OrderbookFull,
+            Some(AoError::OrderNotFound) => DexError::OrderNotFound,
+            Some(AoError::WouldSelfTrade) => DexError::TransactionAborted,
+            _ => DexError::AOBError,
+        }
+    }
 }
 
 impl From<DexError> for ProgramError {
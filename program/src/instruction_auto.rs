@@ -1,8 +1,13 @@
 #![allow(clippy::too_many_arguments)]
 use crate::processor::close_account;
 pub use crate::processor::{
-    cancel_order, close_market, consume_events, create_market, initialize_account, new_order,
-    settle, swap, sweep_fees, update_royalties,
+    accept_admin, admin_cancel_orders, batch_new_order, cancel_order, close_market, consume_events,
+    consume_events_and_settle, create_market, force_close_market, get_best_prices, get_market_info,
+    initialize_account, initialize_candle_history, migrate_market_state, migrate_user_account,
+    new_order, nominate_admin, reduce_order, register_referrer, repost, resize_user_account,
+    set_delegate, set_market_metadata, set_market_status, settle, swap, sweep_fees,
+    sweep_royalties, update_min_base_order_size, update_promo_end_ts, update_royalties,
+    update_tick_size,
 };
 use bonfida_utils::InstructionsAccount;
 use num_derive::{FromPrimitive, ToPrimitive};
@@ -18,11 +23,13 @@ pub enum DexInstruction {
     /// | 1     | ✅        | ❌      | The orderbook account       |
     /// | 2     | ❌        | ❌      | The base vault account      |
     /// | 3     | ❌        | ❌      | The quote vault account     |
-    /// | 4     | ❌        | ❌      | The market admin account    |
-    /// | 5     | ✅        | ❌      | The AOB event queue account |
-    /// | 6     | ✅        | ❌      | The AOB asks account        |
-    /// | 7     | ✅        | ❌      | The AOB bids account        |
-    /// | 8     | ❌        | ❌      | The metaplex token metadata |
+    /// | 4     | ❌        | ❌      | The base mint               |
+    /// | 5     | ❌        | ❌      | The quote mint              |
+    /// | 6     | ❌        | ❌      | The market admin account    |
+    /// | 7     | ✅        | ❌      | The AOB event queue account |
+    /// | 8     | ✅        | ❌      | The AOB asks account        |
+    /// | 9     | ✅        | ❌      | The AOB bids account        |
+    /// | 10    | ❌        | ❌      | The metaplex token metadata |
     CreateMarket,
     /// Execute a new order instruction. Supported types include Limit, IOC, FOK, or Post only.
     ///
@@ -37,11 +44,15 @@ pub enum DexInstruction {
     /// | 6     | ✅        | ❌      | The AOB asks shared memory                                                         |
     /// | 7     | ✅        | ❌      | The base token vault                                                               |
     /// | 8     | ✅        | ❌      | The quote token vault                                                              |
-    /// | 9     | ✅        | ❌      | The DEX user account                                                               |
-    /// | 10    | ✅        | ❌      | The user source token account                                                      |
-    /// | 11    | ✅        | ✅      | The user wallet                                                                    |
-    /// | 12    | ❌        | ❌      | The optional SRM or MSRM discount token account (must be owned by the user wallet) |
-    /// | 13    | ✅        | ❌      | The optional referrer's token account which will receive a 20% cut of the fees     |
+    /// | 9     | ❌        | ❌      | The base token mint                                                                |
+    /// | 10    | ❌        | ❌      | The quote token mint                                                               |
+    /// | 11    | ❌        | ❌      | The DEX market signer                                                              |
+    /// | 12    | ✅        | ❌      | The DEX user account                                                               |
+    /// | 13    | ✅        | ❌      | The user source token account                                                      |
+    /// | 14    | ✅        | ✅      | The user wallet, or the user account's delegate                                    |
+    /// | 15    | ❌        | ❌      | The optional SRM or MSRM discount token account (must be owned by the user wallet) |
+    /// | 16    | ✅        | ❌      | The optional referrer's token account which will receive a 20% cut of the fees     |
+    /// | 17    | ✅        | ❌      | The optional referrer account tracking the referral volume and fees                |
     NewOrder,
     ///
     /// | Index | Writable | Signer | Description                                                                        |
@@ -55,77 +66,83 @@ pub enum DexInstruction {
     /// | 6     | ✅        | ❌      | The AOB asks shared memory                                                         |
     /// | 7     | ✅        | ❌      | The base token vault                                                               |
     /// | 8     | ✅        | ❌      | The quote token vault                                                              |
-    /// | 9     | ❌        | ❌      | The DEX market signer                                                              |
-    /// | 10    | ✅        | ❌      | The user base token account                                                        |
-    /// | 11    | ✅        | ❌      | The user quote token account                                                       |
-    /// | 12    | ✅        | ✅      | The user wallet                                                                    |
-    /// | 13    | ❌        | ❌      | The optional SRM or MSRM discount token account (must be owned by the user wallet) |
-    /// | 14    | ✅        | ❌      | The optional referrer's token account which will receive a 20% cut of the fees     |
+    /// | 9     | ❌        | ❌      | The base token mint                                                                |
+    /// | 10    | ❌        | ❌      | The quote token mint                                                               |
+    /// | 11    | ❌        | ❌      | The DEX market signer                                                              |
+    /// | 12    | ✅        | ❌      | The user base token account                                                        |
+    /// | 13    | ✅        | ❌      | The user quote token account                                                       |
+    /// | 14    | ✅        | ✅      | The user wallet                                                                    |
+    /// | 15    | ❌        | ❌      | The optional SRM or MSRM discount token account (must be owned by the user wallet) |
+    /// | 16    | ✅        | ❌      | The optional referrer's token account which will receive a 20% cut of the fees     |
+    /// | 17    | ✅        | ❌      | The optional referrer account tracking the referral volume and fees                |
     Swap,
     /// Cancel an existing order and remove it from the orderbook.
     ///
-    /// | Index | Writable | Signer | Description                |
-    /// | ------------------------------------------------------ |
-    /// | 0     | ❌        | ❌      | The DEX market             |
-    /// | 1     | ✅        | ❌      | The orderbook              |
-    /// | 2     | ✅        | ❌      | The AOB event queue        |
-    /// | 3     | ✅        | ❌      | The AOB bids shared memory |
-    /// | 4     | ✅        | ❌      | The AOB asks shared memory |
-    /// | 5     | ✅        | ❌      | The DEX user account       |
-    /// | 6     | ❌        | ✅      | The user wallet            |
+    /// | Index | Writable | Signer | Description                                     |
+    /// | ----------------------------------------------------------------------------- |
+    /// | 0     | ❌        | ❌      | The DEX market                                  |
+    /// | 1     | ✅        | ❌      | The orderbook                                   |
+    /// | 2     | ✅        | ❌      | The AOB event queue                             |
+    /// | 3     | ✅        | ❌      | The AOB bids shared memory                      |
+    /// | 4     | ✅        | ❌      | The AOB asks shared memory                      |
+    /// | 5     | ✅        | ❌      | The DEX user account                            |
+    /// | 6     | ❌        | ✅      | The user wallet, or the user account's delegate |
     CancelOrder,
     /// Crank the processing of DEX events.
     ///
-    /// | Index    | Writable | Signer | Description                |
-    /// | --------------------------------------------------------- |
-    /// | 0        | ✅        | ❌      | The DEX market             |
-    /// | 1        | ✅        | ❌      | The orderbook              |
-    /// | 2        | ✅        | ❌      | The AOB event queue        |
-    /// | 3        | ✅        | ❌      | The reward target          |
-    /// | 4..4 + N | ✅        | ❌      | The relevant user accounts |
+    /// | Index    | Writable | Signer | Description                                |
+    /// | --------------------------------------------------------------------- |
+    /// | 0        | ✅        | ❌      | The DEX market                             |
+    /// | 1        | ✅        | ❌      | The orderbook                              |
+    /// | 2        | ✅        | ❌      | The AOB event queue                        |
+    /// | 3        | ✅        | ❌      | The reward target                          |
+    /// | 4        | ✅        | ❌      | The optional candle history of the market  |
+    /// | 5..5 + N | ✅        | ❌      | The relevant user accounts, in any order   |
     ConsumeEvents,
     /// Extract available base and quote token assets from a user account
     ///
     /// | Index | Writable | Signer | Description                         |
     /// | --------------------------------------------------------------- |
     /// | 0     | ❌        | ❌      | The spl token program               |
-    /// | 1     | ❌        | ❌      | The DEX market                      |
+    /// | 1     | ✅        | ❌      | The DEX market                      |
     /// | 2     | ✅        | ❌      | The base token vault                |
     /// | 3     | ✅        | ❌      | The quote token vault               |
-    /// | 4     | ❌        | ❌      | The DEX market signer account       |
-    /// | 5     | ✅        | ❌      | The DEX user account                |
-    /// | 6     | ❌        | ✅      | The DEX user account owner wallet   |
-    /// | 7     | ✅        | ❌      | The destination base token account  |
-    /// | 8     | ✅        | ❌      | The destination quote token account |
+    /// | 4     | ❌        | ❌      | The base token mint                 |
+    /// | 5     | ❌        | ❌      | The quote token mint                |
+    /// | 6     | ❌        | ❌      | The DEX market signer account       |
+    /// | 7     | ✅        | ❌      | The DEX user account                |
+    /// | 8     | ✅        | ✅      | The DEX user account owner wallet   |
+    /// | 9     | ✅        | ❌      | The destination base token account  |
+    /// | 10    | ✅        | ❌      | The destination quote token account |
     Settle,
     /// Initialize a new user account
     ///
-    /// | Index | Writable | Signer | Description                    |
-    /// | ---------------------------------------------------------- |
-    /// | 0     | ❌        | ❌      | The system program             |
-    /// | 1     | ✅        | ❌      | The user account to initialize |
-    /// | 2     | ❌        | ✅      | The owner of the user account  |
-    /// | 3     | ✅        | ✅      | The fee payer                  |
+    /// | Index | Writable | Signer | Description                                                            |
+    /// | -------------------------------------------------------------------------------------------------- |
+    /// | 0     | ❌        | ❌      | The system program                                                     |
+    /// | 1     | ✅        | ❌      | The user account to initialize                                         |
+    /// | 2     | ❌        | ✅      | The owner of the user account                                          |
+    /// | 3     | ✅        | ✅      | The fee payer, which is recorded as the rent payer of the user account |
     InitializeAccount,
     /// Extract accumulated fees from the market. This is an admin instruction
     ///
-    /// | Index    | Writable | Signer | Description                   |
-    /// | ------------------------------------------------------------ |
-    /// | 0        | ✅        | ❌      | The DEX market                |
-    /// | 1        | ❌        | ❌      | The DEX market signer         |
-    /// | 2        | ✅        | ❌      | The market quote token vault  |
-    /// | 3        | ✅        | ❌      | The destination token account |
-    /// | 4        | ❌        | ❌      | The spl token program         |
-    /// | 5        | ❌        | ❌      | The metadata account          |
-    /// | 6..6 + N | ✅        | ❌      | The creator token account     |
+    /// | Index | Writable | Signer | Description                   |
+    /// | --------------------------------------------------------- |
+    /// | 0     | ✅        | ❌      | The DEX market                |
+    /// | 1     | ❌        | ❌      | The DEX market signer         |
+    /// | 2     | ✅        | ❌      | The market quote token vault  |
+    /// | 3     | ❌        | ❌      | The quote token mint          |
+    /// | 4     | ✅        | ❌      | The destination token account |
+    /// | 5     | ❌        | ❌      | The spl token program         |
     SweepFees,
     /// Close an inactive and empty user account
     ///
-    /// | Index | Writable | Signer | Description                            |
-    /// | ------------------------------------------------------------------ |
-    /// | 0     | ✅        | ❌      | The user account to close              |
-    /// | 1     | ❌        | ✅      | The owner of the user account to close |
-    /// | 2     | ✅        | ❌      | The target lamports account            |
+    /// | Index | Writable | Signer | Description                                                                            |
+    /// | ------------------------------------------------------------------------------------------------------------------ |
+    /// | 0     | ✅        | ❌      | The user account to close                                                              |
+    /// | 1     | ❌        | ✅      | The owner of the user account to close                                                 |
+    /// | 2     | ✅        | ❌      | The rent payer recorded in the user account, it is ignored when none was recorded      |
+    /// | 3     | ✅        | ❌      | An optional target lamports account, which receives the rent instead of the rent payer |
     CloseAccount,
     /// Close an existing market
     ///
@@ -145,13 +162,273 @@ pub enum DexInstruction {
     CloseMarket,
     /// Update market royalties.
     ///
-    /// | Index | Writable | Signer | Description             |
-    /// | --------------------------------------------------- |
-    /// | 0     | ✅        | ❌      | The DEX market          |
-    /// | 1     | ❌        | ❌      | The event queue account |
-    /// | 2     | ❌        | ❌      | The AOB market account  |
-    /// | 3     | ❌        | ❌      | The token metadata      |
+    /// | Index | Writable | Signer | Description              |
+    /// | ---------------------------------------------------- |
+    /// | 0     | ✅        | ❌      | The DEX market           |
+    /// | 1     | ❌        | ❌      | The event queue account  |
+    /// | 2     | ❌        | ❌      | The AOB market account   |
+    /// | 3     | ❌        | ❌      | The token metadata       |
+    /// | 4     | ❌        | ✅      | The market admin account |
     UpdateRoyalties,
+    /// Update the market tick size. This is an admin instruction which requires the orderbook to be empty.
+    ///
+    /// | Index | Writable | Signer | Description                 |
+    /// | ------------------------------------------------------- |
+    /// | 0     | ❌        | ❌      | The DEX market              |
+    /// | 1     | ✅        | ❌      | The AOB market account      |
+    /// | 2     | ❌        | ❌      | The AOB event queue account |
+    /// | 3     | ❌        | ❌      | The AOB bids account        |
+    /// | 4     | ❌        | ❌      | The AOB asks account        |
+    /// | 5     | ❌        | ✅      | The market admin account    |
+    UpdateTickSize,
+    /// Update the market's minimum base order size. This is an admin instruction.
+    ///
+    /// | Index | Writable | Signer | Description              |
+    /// | ---------------------------------------------------- |
+    /// | 0     | ✅        | ❌      | The DEX market           |
+    /// | 1     | ✅        | ❌      | The AOB market account   |
+    /// | 2     | ❌        | ✅      | The market admin account |
+    UpdateMinBaseOrderSize,
+    /// Pause or resume trading on a market. This is an admin instruction.
+    ///
+    /// | Index | Writable | Signer | Description              |
+    /// | ---------------------------------------------------- |
+    /// | 0     | ✅        | ❌      | The DEX market           |
+    /// | 1     | ❌        | ✅      | The market admin account |
+    SetMarketStatus,
+    /// Nominate a new market admin. The nominee becomes the market admin once it accepts the role.
+    ///
+    /// | Index | Writable | Signer | Description                        |
+    /// | -------------------------------------------------------------- |
+    /// | 0     | ✅        | ❌      | The DEX market                     |
+    /// | 1     | ❌        | ✅      | The current market admin account   |
+    /// | 2     | ❌        | ❌      | The nominated market admin account |
+    NominateAdmin,
+    /// Accept the market admin role, following a nomination by the current market admin.
+    ///
+    /// | Index | Writable | Signer | Description                        |
+    /// | -------------------------------------------------------------- |
+    /// | 0     | ✅        | ❌      | The DEX market                     |
+    /// | 1     | ❌        | ✅      | The nominated market admin account |
+    AcceptAdmin,
+    /// Distribute the accumulated royalties to the creators of the base token.
+    ///
+    /// | Index    | Writable | Signer | Description                  |
+    /// | ----------------------------------------------------------- |
+    /// | 0        | ✅        | ❌      | The DEX market               |
+    /// | 1        | ❌        | ❌      | The DEX market signer        |
+    /// | 2        | ✅        | ❌      | The market quote token vault |
+    /// | 3        | ❌        | ❌      | The quote token mint         |
+    /// | 4        | ❌        | ❌      | The spl token program        |
+    /// | 5..5 + N | ✅        | ❌      | The creator token account    |
+    SweepRoyalties,
+    /// Grow a user account to hold a larger number of orders
+    ///
+    /// | Index | Writable | Signer | Description                   |
+    /// | --------------------------------------------------------- |
+    /// | 0     | ❌        | ❌      | The system program            |
+    /// | 1     | ✅        | ❌      | The user account to resize    |
+    /// | 2     | ❌        | ✅      | The owner of the user account |
+    /// | 3     | ✅        | ✅      | The fee payer                 |
+    ResizeUserAccount,
+    /// Set or clear the delegate authority of a user account
+    ///
+    /// | Index | Writable | Signer | Description          |
+    /// | ------------------------------------------------ |
+    /// | 0     | ✅        | ❌      | The DEX user account |
+    /// | 1     | ❌        | ✅      | The user wallet      |
+    SetDelegate,
+    /// Crank the processing of DEX events, then settle the available funds of a set of users.
+    ///
+    /// | Index      | Writable | Signer | Description                                                                                                                                               |
+    /// | ------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------------ |
+    /// | 0          | ❌        | ❌      | The spl token program                                                                                                                                     |
+    /// | 1          | ✅        | ❌      | The DEX market                                                                                                                                            |
+    /// | 2          | ✅        | ❌      | The orderbook                                                                                                                                             |
+    /// | 3          | ✅        | ❌      | The AOB event queue                                                                                                                                       |
+    /// | 4          | ✅        | ❌      | The reward target                                                                                                                                         |
+    /// | 5          | ✅        | ❌      | The base token vault                                                                                                                                      |
+    /// | 6          | ✅        | ❌      | The quote token vault                                                                                                                                     |
+    /// | 7          | ❌        | ❌      | The base token mint                                                                                                                                       |
+    /// | 8          | ❌        | ❌      | The quote token mint                                                                                                                                      |
+    /// | 9          | ❌        | ❌      | The DEX market signer account                                                                                                                             |
+    /// | 10..10 + N | ✅        | ❌      | The relevant user accounts in any order, followed by a (user account, destination base token account, destination quote token account) triplet for each user to settle |
+    ConsumeEventsAndSettle,
+    /// Execute a batch of new orders, which are all posted or aborted together. Supported types include Limit and Post only.
+    ///
+    /// | Index | Writable | Signer | Description                                                                        |
+    /// | -------------------------------------------------------------------------------------------------------------- |
+    /// | 0     | ❌        | ❌      | The SPL token program                                                              |
+    /// | 1     | ❌        | ❌      | The system program                                                                 |
+    /// | 2     | ✅        | ❌      | The DEX market                                                                     |
+    /// | 3     | ✅        | ❌      | The orderbook                                                                      |
+    /// | 4     | ✅        | ❌      | The AOB event queue                                                                |
+    /// | 5     | ✅        | ❌      | The AOB bids shared memory                                                         |
+    /// | 6     | ✅        | ❌      | The AOB asks shared memory                                                         |
+    /// | 7     | ✅        | ❌      | The base token vault                                                               |
+    /// | 8     | ✅        | ❌      | The quote token vault                                                              |
+    /// | 9     | ❌        | ❌      | The base token mint                                                                |
+    /// | 10    | ❌        | ❌      | The quote token mint                                                               |
+    /// | 11    | ❌        | ❌      | The DEX market signer                                                              |
+    /// | 12    | ✅        | ❌      | The DEX user account                                                               |
+    /// | 13    | ✅        | ❌      | The user base token account, which funds the asks                                  |
+    /// | 14    | ✅        | ❌      | The user quote token account, which funds the bids                                 |
+    /// | 15    | ✅        | ✅      | The user wallet, or the user account's delegate                                    |
+    /// | 16    | ❌        | ❌      | The optional SRM or MSRM discount token account (must be owned by the user wallet) |
+    /// | 17    | ✅        | ❌      | The optional referrer's token account which will receive a 20% cut of the fees     |
+    /// | 18    | ✅        | ❌      | The optional referrer account tracking the referral volume and fees                |
+    BatchNewOrder,
+    /// Initialize the candle history of a market, which consume_events updates when it is given. This is an admin instruction.
+    ///
+    /// | Index | Writable | Signer | Description                      |
+    /// | ------------------------------------------------------------ |
+    /// | 0     | ❌        | ❌      | The system program               |
+    /// | 1     | ❌        | ❌      | The DEX market                   |
+    /// | 2     | ✅        | ❌      | The candle history to initialize |
+    /// | 3     | ❌        | ✅      | The market admin account         |
+    /// | 4     | ✅        | ✅      | The fee payer                    |
+    InitializeCandleHistory,
+    /// Returns the program version and the layout information of a market with `set_return_data`, so that clients can detect
+    /// incompatible markets before trading. This instruction does not modify any account.
+    ///
+    /// | Index | Writable | Signer | Description    |
+    /// | ------------------------------------------ |
+    /// | 0     | ❌        | ❌      | The DEX market |
+    GetMarketInfo,
+    /// Upgrade the dex state of a market created under the legacy layout to the current one. This is an admin instruction.
+    ///
+    /// | Index | Writable | Signer | Description              |
+    /// | ---------------------------------------------------- |
+    /// | 0     | ❌        | ❌      | The system program       |
+    /// | 1     | ✅        | ❌      | The DEX market           |
+    /// | 2     | ❌        | ❌      | The quote token vault    |
+    /// | 3     | ❌        | ✅      | The market admin account |
+    /// | 4     | ✅        | ✅      | The fee payer            |
+    MigrateMarketState,
+    /// Returns the best bid and ask of a market with `set_return_data`, so that clients and other programs can read the top
+    /// of the book without parsing the orderbook. This instruction does not modify any account.
+    ///
+    /// | Index | Writable | Signer | Description                |
+    /// | ------------------------------------------------------ |
+    /// | 0     | ❌        | ❌      | The DEX market             |
+    /// | 1     | ❌        | ❌      | The orderbook              |
+    /// | 2     | ❌        | ❌      | The AOB bids shared memory |
+    /// | 3     | ❌        | ❌      | The AOB asks shared memory |
+    GetBestPrices,
+    /// Cancel the resting orders of a user account without the owner's signature, for delistings and emergencies. This is an
+    /// admin instruction.
+    ///
+    /// | Index | Writable | Signer | Description                |
+    /// | ------------------------------------------------------ |
+    /// | 0     | ❌        | ❌      | The DEX market             |
+    /// | 1     | ✅        | ❌      | The orderbook              |
+    /// | 2     | ✅        | ❌      | The AOB event queue        |
+    /// | 3     | ✅        | ❌      | The AOB bids shared memory |
+    /// | 4     | ✅        | ❌      | The AOB asks shared memory |
+    /// | 5     | ✅        | ❌      | The DEX user account       |
+    /// | 6     | ❌        | ✅      | The market admin account   |
+    AdminCancelOrders,
+    /// Set the human-readable name and ticker of a market. This is an admin instruction.
+    ///
+    /// | Index | Writable | Signer | Description              |
+    /// | ---------------------------------------------------- |
+    /// | 0     | ✅        | ❌      | The DEX market           |
+    /// | 1     | ❌        | ✅      | The market admin account |
+    SetMarketMetadata,
+    /// Register a quote token account as a referrer of a market, so that the referral volume and fees it receives are tracked
+    /// on-chain.
+    ///
+    /// | Index | Writable | Signer | Description                                              |
+    /// | ------------------------------------------------------------------------------------ |
+    /// | 0     | ❌        | ❌      | The system program                                       |
+    /// | 1     | ❌        | ❌      | The DEX market                                           |
+    /// | 2     | ✅        | ❌      | The referrer account to create                           |
+    /// | 3     | ❌        | ❌      | The quote token account which receives the referral fees |
+    /// | 4     | ❌        | ✅      | The owner of the token account                           |
+    /// | 5     | ✅        | ✅      | The fee payer                                            |
+    RegisterReferrer,
+    /// Reduce the size of an existing order in place, keeping its priority in the orderbook.
+    ///
+    /// The unlocked amounts are credited to the free balance of the user account and returned as a
+    /// [`CancelOrderSummary`](crate::processor::cancel_order::CancelOrderSummary). Reducing an order by its whole remaining
+    /// size cancels it.
+    ///
+    /// | Index | Writable | Signer | Description                                     |
+    /// | ----------------------------------------------------------------------------- |
+    /// | 0     | ❌        | ❌      | The DEX market                                  |
+    /// | 1     | ✅        | ❌      | The orderbook                                   |
+    /// | 2     | ✅        | ❌      | The AOB event queue                             |
+    /// | 3     | ✅        | ❌      | The AOB bids shared memory                      |
+    /// | 4     | ✅        | ❌      | The AOB asks shared memory                      |
+    /// | 5     | ✅        | ❌      | The DEX user account                            |
+    /// | 6     | ❌        | ✅      | The user wallet, or the user account's delegate |
+    ReduceOrder,
+    /// Set the end of the market's promotional window, during which takers pay no fees. This is an admin instruction.
+    ///
+    /// | Index | Writable | Signer | Description              |
+    /// | ---------------------------------------------------- |
+    /// | 0     | ✅        | ❌      | The DEX market           |
+    /// | 1     | ❌        | ✅      | The market admin account |
+    UpdatePromoEndTs,
+    /// Close a market in an emergency, sweeping its uncollected fees and undistributed royalties to the market admin
+    /// beforehand. This is an admin instruction.
+    ///
+    /// The vaults must not hold any user funds once the fees and royalties are swept, the other checks of close_market
+    /// apply as well.
+    ///
+    /// | Index | Writable | Signer | Description                                                                       |
+    /// | ------------------------------------------------------------------------------------------------------------- |
+    /// | 0     | ✅        | ❌      | The market account                                                                |
+    /// | 1     | ✅        | ❌      | The market base vault account                                                     |
+    /// | 2     | ✅        | ❌      | The market quote vault account                                                    |
+    /// | 3     | ❌        | ❌      | The quote token mint                                                              |
+    /// | 4     | ✅        | ❌      | The AOB orderbook account                                                         |
+    /// | 5     | ✅        | ❌      | The AOB event queue account                                                       |
+    /// | 6     | ✅        | ❌      | The AOB bids account                                                              |
+    /// | 7     | ✅        | ❌      | The AOB asks account                                                              |
+    /// | 8     | ❌        | ✅      | The market admin account                                                          |
+    /// | 9     | ✅        | ❌      | The quote token account of the market admin which receives the fees and royalties |
+    /// | 10    | ✅        | ❌      | The target lamports account                                                       |
+    /// | 11    | ❌        | ❌      | The market signer                                                                 |
+    /// | 12    | ❌        | ❌      | The SPL token program ID                                                          |
+    ForceCloseMarket,
+    /// Upgrade a user account created under the legacy header layout to the current one.
+    ///
+    /// The header is grown in place and the orders are moved after it, the fee payer funding the additional rent. Anyone can
+    /// migrate a user account, so that cranks can upgrade the accounts referenced by the event queue. User accounts which
+    /// already use the current layout are left untouched.
+    ///
+    /// | Index | Writable | Signer | Description                 |
+    /// | ------------------------------------------------------- |
+    /// | 0     | ❌        | ❌      | The system program          |
+    /// | 1     | ✅        | ❌      | The user account to migrate |
+    /// | 2     | ✅        | ✅      | The fee payer               |
+    MigrateUserAccount,
+    /// Execute a new order funded from the free balance of the user account.
+    ///
+    /// The order is matched and posted as a new_order would, but the user token account is only required when the free
+    /// balance of the user account doesn't cover the order, in which case the remainder is transferred from it. Makers can
+    /// then post their settled proceeds again without a round trip through their wallet.
+    ///
+    /// | Index | Writable | Signer | Description                                                                                    |
+    /// | -------------------------------------------------------------------------------------------------------------------------- |
+    /// | 0     | ❌        | ❌      | The SPL token program                                                                          |
+    /// | 1     | ❌        | ❌      | The system program                                                                             |
+    /// | 2     | ✅        | ❌      | The DEX market                                                                                 |
+    /// | 3     | ✅        | ❌      | The orderbook                                                                                  |
+    /// | 4     | ✅        | ❌      | The AOB event queue                                                                            |
+    /// | 5     | ✅        | ❌      | The AOB bids shared memory                                                                     |
+    /// | 6     | ✅        | ❌      | The AOB asks shared memory                                                                     |
+    /// | 7     | ✅        | ❌      | The base token vault                                                                           |
+    /// | 8     | ✅        | ❌      | The quote token vault                                                                          |
+    /// | 9     | ❌        | ❌      | The base token mint                                                                            |
+    /// | 10    | ❌        | ❌      | The quote token mint                                                                           |
+    /// | 11    | ❌        | ❌      | The DEX market signer                                                                          |
+    /// | 12    | ✅        | ❌      | The DEX user account                                                                           |
+    /// | 13    | ✅        | ✅      | The user wallet, or the user account's delegate                                                |
+    /// | 14    | ❌        | ❌      | The optional SRM or MSRM discount token account (must be owned by the user wallet)             |
+    /// | 15    | ✅        | ❌      | The optional user source token account, required when the free balance doesn't cover the order |
+    Repost,
 }
 ///          Create a new DEX market
 ///         
@@ -243,3 +520,211 @@ pub fn update_royalties(
 ) -> Instruction {
     accounts.get_instruction_cast(program_id, DexInstruction::UpdateRoyalties as u8, params)
 }
+///          Update the market tick size. This is an admin instruction which requires the orderbook to be empty.
+pub fn update_tick_size(
+    program_id: Pubkey,
+    accounts: update_tick_size::Accounts<Pubkey>,
+    params: update_tick_size::Params,
+) -> Instruction {
+    accounts.get_instruction_cast(program_id, DexInstruction::UpdateTickSize as u8, params)
+}
+///          Update the market's minimum base order size. This is an admin instruction.
+pub fn update_min_base_order_size(
+    program_id: Pubkey,
+    accounts: update_min_base_order_size::Accounts<Pubkey>,
+    params: update_min_base_order_size::Params,
+) -> Instruction {
+    accounts.get_instruction_cast(
+        program_id,
+        DexInstruction::UpdateMinBaseOrderSize as u8,
+        params,
+    )
+}
+///          Pause or resume trading on a market. This is an admin instruction.
+pub fn set_market_status(
+    program_id: Pubkey,
+    accounts: set_market_status::Accounts<Pubkey>,
+    params: set_market_status::Params,
+) -> Instruction {
+    accounts.get_instruction_cast(program_id, DexInstruction::SetMarketStatus as u8, params)
+}
+///          Nominate a new market admin. The nominee becomes the market admin once it accepts the role.
+pub fn nominate_admin(
+    program_id: Pubkey,
+    accounts: nominate_admin::Accounts<Pubkey>,
+    params: nominate_admin::Params,
+) -> Instruction {
+    accounts.get_instruction_cast(program_id, DexInstruction::NominateAdmin as u8, params)
+}
+///          Accept the market admin role, following a nomination by the current market admin.
+pub fn accept_admin(
+    program_id: Pubkey,
+    accounts: accept_admin::Accounts<Pubkey>,
+    params: accept_admin::Params,
+) -> Instruction {
+    accounts.get_instruction_cast(program_id, DexInstruction::AcceptAdmin as u8, params)
+}
+///          Distribute the accumulated royalties to the creators of the base token.
+pub fn sweep_royalties(
+    program_id: Pubkey,
+    accounts: sweep_royalties::Accounts<Pubkey>,
+    params: sweep_royalties::Params,
+) -> Instruction {
+    accounts.get_instruction_cast(program_id, DexInstruction::SweepRoyalties as u8, params)
+}
+///          Grow a user account to hold a larger number of orders
+pub fn resize_user_account(
+    program_id: Pubkey,
+    accounts: resize_user_account::Accounts<Pubkey>,
+    params: resize_user_account::Params,
+) -> Instruction {
+    accounts.get_instruction_cast(program_id, DexInstruction::ResizeUserAccount as u8, params)
+}
+///          Set or clear the delegate authority of a user account
+pub fn set_delegate(
+    program_id: Pubkey,
+    accounts: set_delegate::Accounts<Pubkey>,
+    params: set_delegate::Params,
+) -> Instruction {
+    accounts.get_instruction_cast(program_id, DexInstruction::SetDelegate as u8, params)
+}
+///          Crank the processing of DEX events, then settle the available funds of a set of users.
+pub fn consume_events_and_settle(
+    program_id: Pubkey,
+    accounts: consume_events_and_settle::Accounts<Pubkey>,
+    params: consume_events_and_settle::Params,
+) -> Instruction {
+    accounts.get_instruction_cast(
+        program_id,
+        DexInstruction::ConsumeEventsAndSettle as u8,
+        params,
+    )
+}
+///          Execute a batch of new orders, which are all posted or aborted together. Supported types include Limit and Post only.
+pub fn batch_new_order(
+    program_id: Pubkey,
+    accounts: batch_new_order::Accounts<Pubkey>,
+    params: batch_new_order::Params,
+) -> Instruction {
+    accounts.get_instruction_cast(program_id, DexInstruction::BatchNewOrder as u8, params)
+}
+///          Initialize the candle history of a market, which consume_events updates when it is given. This is an admin instruction.
+pub fn initialize_candle_history(
+    program_id: Pubkey,
+    accounts: initialize_candle_history::Accounts<Pubkey>,
+    params: initialize_candle_history::Params,
+) -> Instruction {
+    accounts.get_instruction_cast(
+        program_id,
+        DexInstruction::InitializeCandleHistory as u8,
+        params,
+    )
+}
+///          Returns the program version and the layout information of a market with `set_return_data`, so that clients can detect
+///          incompatible markets before trading. This instruction does not modify any account.
+pub fn get_market_info(
+    program_id: Pubkey,
+    accounts: get_market_info::Accounts<Pubkey>,
+    params: get_market_info::Params,
+) -> Instruction {
+    accounts.get_instruction_cast(program_id, DexInstruction::GetMarketInfo as u8, params)
+}
+///          Upgrade the dex state of a market created under the legacy layout to the current one. This is an admin instruction.
+pub fn migrate_market_state(
+    program_id: Pubkey,
+    accounts: migrate_market_state::Accounts<Pubkey>,
+    params: migrate_market_state::Params,
+) -> Instruction {
+    accounts.get_instruction_cast(program_id, DexInstruction::MigrateMarketState as u8, params)
+}
+///          Returns the best bid and ask of a market with `set_return_data`, so that clients and other programs can read the top
+///          of the book without parsing the orderbook. This instruction does not modify any account.
+pub fn get_best_prices(
+    program_id: Pubkey,
+    accounts: get_best_prices::Accounts<Pubkey>,
+    params: get_best_prices::Params,
+) -> Instruction {
+    accounts.get_instruction_cast(program_id, DexInstruction::GetBestPrices as u8, params)
+}
+///          Cancel the resting orders of a user account without the owner's signature, for delistings and emergencies. This is an
+///          admin instruction.
+pub fn admin_cancel_orders(
+    program_id: Pubkey,
+    accounts: admin_cancel_orders::Accounts<Pubkey>,
+    params: admin_cancel_orders::Params,
+) -> Instruction {
+    accounts.get_instruction_cast(program_id, DexInstruction::AdminCancelOrders as u8, params)
+}
+///          Set the human-readable name and ticker of a market. This is an admin instruction.
+pub fn set_market_metadata(
+    program_id: Pubkey,
+    accounts: set_market_metadata::Accounts<Pubkey>,
+    params: set_market_metadata::Params,
+) -> Instruction {
+    accounts.get_instruction_cast(program_id, DexInstruction::SetMarketMetadata as u8, params)
+}
+///          Register a quote token account as a referrer of a market, so that the referral volume and fees it receives are tracked
+///          on-chain.
+pub fn register_referrer(
+    program_id: Pubkey,
+    accounts: register_referrer::Accounts<Pubkey>,
+    params: register_referrer::Params,
+) -> Instruction {
+    accounts.get_instruction_cast(program_id, DexInstruction::RegisterReferrer as u8, params)
+}
+///          Reduce the size of an existing order in place, keeping its priority in the orderbook.
+///         
+///          The unlocked amounts are credited to the free balance of the user account and returned as a
+///          [`CancelOrderSummary`](crate::processor::cancel_order::CancelOrderSummary). Reducing an order by its whole remaining
+///          size cancels it.
+pub fn reduce_order(
+    program_id: Pubkey,
+    accounts: reduce_order::Accounts<Pubkey>,
+    params: reduce_order::Params,
+) -> Instruction {
+    accounts.get_instruction_cast(program_id, DexInstruction::ReduceOrder as u8, params)
+}
+///          Set the end of the market's promotional window, during which takers pay no fees. This is an admin instruction.
+pub fn update_promo_end_ts(
+    program_id: Pubkey,
+    accounts: update_promo_end_ts::Accounts<Pubkey>,
+    params: update_promo_end_ts::Params,
+) -> Instruction {
+    accounts.get_instruction_cast(program_id, DexInstruction::UpdatePromoEndTs as u8, params)
+}
+///          Close a market in an emergency, sweeping its uncollected fees and undistributed royalties to the market admin
+///          beforehand. This is an admin instruction.
+///         
+///          The vaults must not hold any user funds once the fees and royalties are swept, the other checks of close_market
+///          apply as well.
+pub fn force_close_market(
+    program_id: Pubkey,
+    accounts: force_close_market::Accounts<Pubkey>,
+    params: force_close_market::Params,
+) -> Instruction {
+    accounts.get_instruction_cast(program_id, DexInstruction::ForceCloseMarket as u8, params)
+}
+///          Upgrade a user account created under the legacy header layout to the current one.
+///         
+///          The header is grown in place and the orders are moved after it, the fee payer funding the additional rent. Anyone can
+///          migrate a user account, so that cranks can upgrade the accounts referenced by the event queue. User accounts which
+///          already use the current layout are left untouched.
+pub fn migrate_user_account(
+    program_id: Pubkey,
+    accounts: migrate_user_account::Accounts<Pubkey>,
+    params: migrate_user_account::Params,
+) -> Instruction {
+    accounts.get_instruction_cast(program_id, DexInstruction::MigrateUserAccount as u8, params)
+}
+///          Execute a new order funded from the free balance of the user account.
+///         
+///          The order is matched and posted as a new_order would, but the user token account is only required when the free
+///          balance of the user account doesn't cover the order, in which case the remainder is transferred from it. Makers can
+///          then post their settled proceeds again without a round trip through their wallet.
+pub fn repost(
+    program_id: Pubkey,
+    accounts: repost::Accounts<Pubkey>,
+    params: repost::Params,
+) -> Instruction {
+    accounts.get_instruction_cast(program_id, DexInstruction::Repost as u8, params)
+}
@@ -5,6 +5,9 @@ Orderbook-based on-chain SPL token swap market
 This program is intended for use to build a decentralized exchange (DEX) specialized on SPL token swaps.
 */
 
+/// Off-chain helpers to read and reconcile program state
+#[cfg(feature = "client")]
+pub mod client;
 #[doc(hidden)]
 pub mod entrypoint;
 #[doc(hidden)]
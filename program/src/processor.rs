@@ -5,6 +5,8 @@ use solana_program::{
 };
 
 use crate::instruction_auto::DexInstruction;
+#[cfg(feature = "bench")]
+use solana_program::log::sol_log_compute_units;
 
 ////////////////////////////////////////////////////////////
 // Constants
@@ -13,7 +15,7 @@ pub static SRM_MINT: Pubkey =
 pub static MSRM_MINT: Pubkey =
     solana_program::pubkey!("MSRMcoVyrFxnSgo5uXwone5SKcGhT1KEJMFEkMEWf9L");
 
-/// The sweep authority for the DEX program
+/// The legacy sweep authority for the DEX program, used by markets which do not define their own
 pub static SWEEP_AUTHORITY: Pubkey =
     solana_program::pubkey!("DjXsn34uz8hnC4KLiSkEVNmzqX5ZFP2Q7aErTBH8LWxe");
 
@@ -26,30 +28,75 @@ pub static REFERRAL_MASK: u8 = 1 << 7;
 
 ////////////////////////////////////////////////////////////
 
+#[allow(missing_docs)]
+pub mod batch_new_order;
 #[allow(missing_docs)]
 pub mod cancel_order;
 #[allow(missing_docs)]
 pub mod consume_events;
 #[allow(missing_docs)]
+pub mod consume_events_and_settle;
+#[allow(missing_docs)]
 pub mod create_market;
 #[allow(missing_docs)]
+pub mod get_best_prices;
+#[allow(missing_docs)]
+pub mod get_market_info;
+#[allow(missing_docs)]
 pub mod initialize_account;
 #[allow(missing_docs)]
+pub mod initialize_candle_history;
+#[allow(missing_docs)]
+pub mod migrate_market_state;
+#[allow(missing_docs)]
+pub mod migrate_user_account;
+#[allow(missing_docs)]
 pub mod new_order;
 #[allow(missing_docs)]
+pub mod reduce_order;
+#[allow(missing_docs)]
+pub mod register_referrer;
+#[allow(missing_docs)]
+pub mod repost;
+#[allow(missing_docs)]
+pub mod resize_user_account;
+#[allow(missing_docs)]
+pub mod set_delegate;
+#[allow(missing_docs)]
 pub mod settle;
 #[allow(missing_docs)]
 pub mod swap;
 #[allow(missing_docs)]
 pub mod sweep_fees;
+#[allow(missing_docs)]
+pub mod sweep_royalties;
 
 #[allow(missing_docs)]
 pub mod close_account;
 #[allow(missing_docs)]
 pub mod close_market;
+#[allow(missing_docs)]
+pub mod force_close_market;
+
+#[allow(missing_docs)]
+pub mod set_market_metadata;
+#[allow(missing_docs)]
+pub mod set_market_status;
 
+#[allow(missing_docs)]
+pub mod accept_admin;
+#[allow(missing_docs)]
+pub mod admin_cancel_orders;
+#[allow(missing_docs)]
+pub mod nominate_admin;
+#[allow(missing_docs)]
+pub mod update_min_base_order_size;
+#[allow(missing_docs)]
+pub mod update_promo_end_ts;
 #[allow(missing_docs)]
 pub mod update_royalties;
+#[allow(missing_docs)]
+pub mod update_tick_size;
 
 pub struct Processor {}
 
@@ -63,6 +110,10 @@ impl Processor {
         instruction_data: &[u8],
     ) -> ProgramResult {
         msg!("Beginning processing");
+        if instruction_data.len() < INSTRUCTION_TAG_OFFSET {
+            msg!("The instruction data is too short to hold an instruction tag");
+            return Err(ProgramError::InvalidInstructionData);
+        }
         let instruction_tag = FromPrimitive::from_u8(instruction_data[0])
             .ok_or(ProgramError::InvalidInstructionData)?;
         let instruction_data = &instruction_data[INSTRUCTION_TAG_OFFSET..];
@@ -74,15 +125,27 @@ impl Processor {
             }
             DexInstruction::NewOrder => {
                 msg!("Instruction: New Order");
+                #[cfg(feature = "bench")]
+                sol_log_compute_units();
                 new_order::process(program_id, accounts, instruction_data)?;
+                #[cfg(feature = "bench")]
+                sol_log_compute_units();
             }
             DexInstruction::Swap => {
                 msg!("Instruction: Swap");
+                #[cfg(feature = "bench")]
+                sol_log_compute_units();
                 swap::process(program_id, accounts, instruction_data)?;
+                #[cfg(feature = "bench")]
+                sol_log_compute_units();
             }
             DexInstruction::ConsumeEvents => {
                 msg!("Instruction: Consume Events");
+                #[cfg(feature = "bench")]
+                sol_log_compute_units();
                 consume_events::process(program_id, accounts, instruction_data)?;
+                #[cfg(feature = "bench")]
+                sol_log_compute_units();
             }
             DexInstruction::CancelOrder => {
                 msg!("Instruction: Cancel Order");
@@ -90,7 +153,7 @@ impl Processor {
             }
             DexInstruction::Settle => {
                 msg!("Instruction: Settle");
-                settle::process(program_id, accounts)?;
+                settle::process(program_id, accounts, instruction_data)?;
             }
             DexInstruction::InitializeAccount => {
                 msg!("Instruction: Initialize account");
@@ -98,7 +161,11 @@ impl Processor {
             }
             DexInstruction::SweepFees => {
                 msg!("Instruction: Sweep fees");
-                sweep_fees::process(program_id, accounts)?;
+                sweep_fees::process(program_id, accounts, instruction_data)?;
+            }
+            DexInstruction::SweepRoyalties => {
+                msg!("Instruction: Sweep royalties");
+                sweep_royalties::process(program_id, accounts, instruction_data)?;
             }
             DexInstruction::CloseAccount => {
                 msg!("Instruction: Close Account");
@@ -112,6 +179,90 @@ impl Processor {
                 msg!("Instruction: Update royalties");
                 update_royalties::process(program_id, accounts)?
             }
+            DexInstruction::UpdateTickSize => {
+                msg!("Instruction: Update tick size");
+                update_tick_size::process(program_id, accounts, instruction_data)?
+            }
+            DexInstruction::UpdateMinBaseOrderSize => {
+                msg!("Instruction: Update min base order size");
+                update_min_base_order_size::process(program_id, accounts, instruction_data)?
+            }
+            DexInstruction::SetMarketStatus => {
+                msg!("Instruction: Set market status");
+                set_market_status::process(program_id, accounts, instruction_data)?
+            }
+            DexInstruction::NominateAdmin => {
+                msg!("Instruction: Nominate admin");
+                nominate_admin::process(program_id, accounts)?
+            }
+            DexInstruction::AcceptAdmin => {
+                msg!("Instruction: Accept admin");
+                accept_admin::process(program_id, accounts)?
+            }
+            DexInstruction::ResizeUserAccount => {
+                msg!("Instruction: Resize user account");
+                resize_user_account::process(program_id, accounts, instruction_data)?
+            }
+            DexInstruction::SetDelegate => {
+                msg!("Instruction: Set delegate");
+                set_delegate::process(program_id, accounts, instruction_data)?
+            }
+            DexInstruction::ConsumeEventsAndSettle => {
+                msg!("Instruction: Consume events and settle");
+                consume_events_and_settle::process(program_id, accounts, instruction_data)?
+            }
+            DexInstruction::BatchNewOrder => {
+                msg!("Instruction: Batch new order");
+                batch_new_order::process(program_id, accounts, instruction_data)?
+            }
+            DexInstruction::InitializeCandleHistory => {
+                msg!("Instruction: Initialize candle history");
+                initialize_candle_history::process(program_id, accounts, instruction_data)?
+            }
+            DexInstruction::GetMarketInfo => {
+                msg!("Instruction: Get market info");
+                get_market_info::process(program_id, accounts)?
+            }
+            DexInstruction::MigrateMarketState => {
+                msg!("Instruction: Migrate market state");
+                migrate_market_state::process(program_id, accounts)?
+            }
+            DexInstruction::GetBestPrices => {
+                msg!("Instruction: Get best prices");
+                get_best_prices::process(program_id, accounts)?
+            }
+            DexInstruction::AdminCancelOrders => {
+                msg!("Instruction: Admin cancel orders");
+                admin_cancel_orders::process(program_id, accounts, instruction_data)?
+            }
+            DexInstruction::SetMarketMetadata => {
+                msg!("Instruction: Set market metadata");
+                set_market_metadata::process(program_id, accounts, instruction_data)?
+            }
+            DexInstruction::RegisterReferrer => {
+                msg!("Instruction: Register referrer");
+                register_referrer::process(program_id, accounts)?
+            }
+            DexInstruction::ReduceOrder => {
+                msg!("Instruction: Reduce order");
+                reduce_order::process(program_id, accounts, instruction_data)?
+            }
+            DexInstruction::UpdatePromoEndTs => {
+                msg!("Instruction: Update promo end timestamp");
+                update_promo_end_ts::process(program_id, accounts, instruction_data)?
+            }
+            DexInstruction::ForceCloseMarket => {
+                msg!("Instruction: Force close market");
+                force_close_market::process(program_id, accounts)?
+            }
+            DexInstruction::MigrateUserAccount => {
+                msg!("Instruction: Migrate user account");
+                migrate_user_account::process(program_id, accounts)?
+            }
+            DexInstruction::Repost => {
+                msg!("Instruction: Repost");
+                repost::process(program_id, accounts, instruction_data)?
+            }
         }
         Ok(())
     }
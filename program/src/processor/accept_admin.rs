@@ -0,0 +1,77 @@
+//! Accept the market admin role, following a nomination by the current market admin.
+use {
+    bonfida_utils::{BorshSize, InstructionsAccount},
+    borsh::{BorshDeserialize, BorshSerialize},
+    bytemuck::{Pod, Zeroable},
+    solana_program::{
+        account_info::{next_account_info, AccountInfo},
+        entrypoint::ProgramResult,
+        msg,
+        program_error::ProgramError,
+        pubkey::Pubkey,
+    },
+};
+
+use crate::{
+    error::DexError,
+    state::DexState,
+    utils::{check_account_key, check_account_owner, check_signer},
+};
+
+#[derive(Copy, Clone, Zeroable, Pod, BorshDeserialize, BorshSerialize, BorshSize)]
+#[repr(C)]
+pub struct Params {}
+
+#[derive(InstructionsAccount)]
+pub struct Accounts<'a, T> {
+    /// The DEX market
+    #[cons(writable)]
+    pub market: &'a T,
+
+    /// The nominated market admin account
+    #[cons(signer)]
+    pub new_admin: &'a T,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
+    pub(crate) fn parse(
+        accounts: &'a [AccountInfo<'b>],
+        program_id: &Pubkey,
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let a = Self {
+            market: next_account_info(accounts_iter)?,
+            new_admin: next_account_info(accounts_iter)?,
+        };
+
+        // Check keys
+
+        // Check owners
+        check_account_owner(a.market, program_id, DexError::InvalidStateAccountOwner)?;
+
+        // Check signers
+        check_signer(a.new_admin).map_err(|e| {
+            msg!("The nominated admin should be a signer for this transaction!");
+            e
+        })?;
+
+        Ok(a)
+    }
+}
+
+pub(crate) fn process(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts = Accounts::parse(accounts, program_id)?;
+
+    let mut market_state = DexState::get(accounts.market)?;
+
+    check_account_key(
+        accounts.new_admin,
+        &market_state.pending_admin,
+        DexError::InvalidMarketAdminAccount,
+    )?;
+
+    market_state.admin = market_state.pending_admin;
+    market_state.pending_admin = Pubkey::default();
+
+    Ok(())
+}
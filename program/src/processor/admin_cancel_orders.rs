@@ -0,0 +1,172 @@
+//! Cancel the resting orders of a user account without the owner's signature, for delistings and emergencies. This is an
+//! admin instruction.
+//!
+//! The unlocked amounts are credited to the free balance of the user account, which only its owner can settle.
+use crate::{
+    error::DexError,
+    processor::cancel_order::unlock_order_funds,
+    state::{CallBackInfo, DexState, NativeQty, ScaledQty, UserAccount},
+    utils::{check_account_key, check_account_owner, check_signer},
+};
+use asset_agnostic_orderbook::{error::AoError, state::get_side_from_order_id};
+use bonfida_utils::BorshSize;
+use bonfida_utils::InstructionsAccount;
+use borsh::BorshDeserialize;
+use borsh::BorshSerialize;
+use bytemuck::{try_from_bytes, Pod, Zeroable};
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+#[derive(Copy, Clone, Zeroable, Pod, BorshDeserialize, BorshSerialize, BorshSize)]
+#[repr(C)]
+/**
+The required arguments for an admin_cancel_orders instruction.
+*/
+pub struct Params {
+    /// The maximum number of orders to cancel, starting from the last order of the user account. `u64::MAX` cancels
+    /// all orders.
+    pub max_orders: u64,
+}
+
+#[derive(InstructionsAccount)]
+pub struct Accounts<'a, T> {
+    /// The DEX market
+    pub market: &'a T,
+
+    /// The orderbook
+    #[cons(writable)]
+    pub orderbook: &'a T,
+
+    /// The AOB event queue
+    #[cons(writable)]
+    pub event_queue: &'a T,
+
+    /// The AOB bids shared memory
+    #[cons(writable)]
+    pub bids: &'a T,
+
+    /// The AOB asks shared memory
+    #[cons(writable)]
+    pub asks: &'a T,
+
+    /// The DEX user account
+    #[cons(writable)]
+    pub user: &'a T,
+
+    /// The market admin account
+    #[cons(signer)]
+    pub market_admin: &'a T,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let a = Self {
+            market: next_account_info(accounts_iter)?,
+            orderbook: next_account_info(accounts_iter)?,
+            event_queue: next_account_info(accounts_iter)?,
+            bids: next_account_info(accounts_iter)?,
+            asks: next_account_info(accounts_iter)?,
+            user: next_account_info(accounts_iter)?,
+            market_admin: next_account_info(accounts_iter)?,
+        };
+        check_signer(a.market_admin).map_err(|e| {
+            msg!("The market admin should be a signer for this transaction!");
+            e
+        })?;
+        check_account_owner(a.market, program_id, DexError::InvalidStateAccountOwner)?;
+        check_account_owner(a.user, program_id, DexError::InvalidStateAccountOwner)?;
+
+        Ok(a)
+    }
+}
+
+pub(crate) fn process(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let Params { max_orders } =
+        try_from_bytes(instruction_data).map_err(|_| ProgramError::InvalidInstructionData)?;
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let market_state = DexState::get(accounts.market)?;
+    check_accounts(&market_state, &accounts)?;
+
+    let mut user_account_data = accounts.user.data.borrow_mut();
+    let mut user_account = UserAccount::from_buffer(&mut user_account_data)?;
+    if &user_account.header.market != accounts.market.key {
+        msg!("The provided user account doesn't match the current market");
+        return Err(DexError::InvalidUserAccountMarket.into());
+    }
+
+    let number_of_orders = user_account.header.number_of_orders as usize;
+    let mut cancelled_orders = 0;
+    // Iterating backwards ensures that removing an order only moves orders which were already visited
+    for order_index in (0..number_of_orders)
+        .rev()
+        .take((*max_orders).min(number_of_orders as u64) as usize)
+    {
+        let order_id = user_account.read_order(order_index)?.id;
+        let invoke_params =
+            asset_agnostic_orderbook::instruction::cancel_order::Params { order_id };
+        let invoke_accounts = asset_agnostic_orderbook::instruction::cancel_order::Accounts {
+            market: accounts.orderbook,
+            event_queue: accounts.event_queue,
+            bids: accounts.bids,
+            asks: accounts.asks,
+        };
+
+        let order_summary = match asset_agnostic_orderbook::instruction::cancel_order::process::<
+            CallBackInfo,
+        >(program_id, invoke_accounts, invoke_params)
+        {
+            // The order was filled, it will be removed from the user account when its events are consumed
+            Err(ProgramError::Custom(code)) if code == AoError::OrderNotFound as u32 => continue,
+            Err(error) => {
+                return Err(DexError::from_aob_error(error).into());
+            }
+            Ok(s) => s,
+        };
+        let NativeQty(base_qty) =
+            market_state.unscale_base_amount(ScaledQty(order_summary.total_base_qty))?;
+        let NativeQty(quote_qty) =
+            market_state.unscale_quote_amount(ScaledQty(order_summary.total_quote_qty))?;
+
+        unlock_order_funds(
+            &mut user_account,
+            get_side_from_order_id(order_id),
+            base_qty,
+            quote_qty,
+        );
+
+        user_account.remove_order(order_index)?;
+        cancelled_orders += 1;
+    }
+    msg!("Cancelled {} orders", cancelled_orders);
+
+    Ok(())
+}
+
+fn check_accounts(market_state: &DexState, accounts: &Accounts<AccountInfo>) -> ProgramResult {
+    check_account_key(
+        accounts.orderbook,
+        &market_state.orderbook,
+        DexError::InvalidOrderbookAccount,
+    )?;
+    check_account_key(
+        accounts.market_admin,
+        &market_state.admin,
+        DexError::InvalidMarketAdminAccount,
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,393 @@
+//! Execute a batch of new orders, which are all posted or aborted together. Supported types include Limit and Post only.
+use crate::{
+    error::DexError,
+    processor::new_order::{
+        self, check_referral_account, execute_order, record_referral, transfer_referral_fee,
+        transfer_to_vault, OrderRequest, OrderTransfer, OrderType,
+    },
+    state::{DexState, DuplicateClientIdPolicy, FeeTier, MarketStatus},
+    utils::{
+        check_account_key, check_account_owner, check_discount_token_account, check_signer,
+        check_token_account_mint,
+    },
+};
+use asset_agnostic_orderbook::state::{SelfTradeBehavior, Side};
+use bonfida_utils::BorshSize;
+use bonfida_utils::InstructionsAccount;
+use borsh::BorshDeserialize;
+use borsh::BorshSerialize;
+use bytemuck::{try_from_bytes, Pod, Zeroable};
+use num_traits::FromPrimitive;
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    clock::Clock,
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_program,
+    sysvar::Sysvar,
+};
+
+/// The maximum number of orders in a single batch
+pub const MAX_BATCH_ORDERS: usize = 8;
+
+#[derive(Copy, Clone, Zeroable, Pod, BorshDeserialize, BorshSerialize, BorshSize)]
+#[repr(C)]
+/**
+A single order of a batch_new_order instruction.
+*/
+pub struct BatchOrder {
+    /// The order's limit price (as a FP32)
+    pub limit_price: u64,
+    /// The max quantity of base token to match and post
+    pub max_base_qty: u64,
+    /// The order's side (Bid or Ask)
+    pub side: u8,
+    /// To eliminate implicit padding
+    pub _padding: [u8; 7],
+}
+
+#[derive(Copy, Clone, Zeroable, Pod, BorshDeserialize, BorshSerialize, BorshSize)]
+#[repr(C)]
+/**
+The required arguments for a batch_new_order instruction.
+*/
+pub struct Params {
+    /// The orders to execute, only the first `number_of_orders` are read
+    pub orders: [BatchOrder; MAX_BATCH_ORDERS],
+    /// The maximum number of orders to be matched against, for each order of the batch.
+    ///
+    /// Setting this number too high can sometimes lead to excessive resource consumption which can cause a failure.
+    /// It is capped to [`MAX_MATCH_LIMIT`](crate::processor::new_order::MAX_MATCH_LIMIT), and a zero value is replaced with
+    /// [`DEFAULT_MATCH_LIMIT`](crate::processor::new_order::DEFAULT_MATCH_LIMIT).
+    pub match_limit: u64,
+    /// The number of orders in the batch
+    pub number_of_orders: u8,
+    /// Whether the orders of the batch are PostOnly orders instead of Limit orders
+    pub post_only: u8,
+    /// Configures what happens when an order is at least partially matched against an order belonging to the same user account
+    pub self_trade_behavior: u8,
+    /// Whether or not the optional discount token account was given
+    pub has_discount_token_account: u8,
+    /// To eliminate implicit padding
+    pub _padding: [u8; 4],
+}
+
+#[derive(InstructionsAccount)]
+pub struct Accounts<'a, T> {
+    /// The SPL token program
+    pub spl_token_program: &'a T,
+
+    /// The system program
+    pub system_program: &'a T,
+
+    /// The DEX market
+    #[cons(writable)]
+    pub market: &'a T,
+
+    /// The orderbook
+    #[cons(writable)]
+    pub orderbook: &'a T,
+
+    /// The AOB event queue
+    #[cons(writable)]
+    pub event_queue: &'a T,
+
+    /// The AOB bids shared memory
+    #[cons(writable)]
+    pub bids: &'a T,
+
+    /// The AOB asks shared memory
+    #[cons(writable)]
+    pub asks: &'a T,
+
+    /// The base token vault
+    #[cons(writable)]
+    pub base_vault: &'a T,
+
+    /// The quote token vault
+    #[cons(writable)]
+    pub quote_vault: &'a T,
+
+    /// The base token mint
+    pub base_mint: &'a T,
+
+    /// The quote token mint
+    pub quote_mint: &'a T,
+
+    /// The DEX market signer, which transfers the referral fee out of the quote vault
+    pub market_signer: &'a T,
+
+    /// The DEX user account
+    #[cons(writable)]
+    pub user: &'a T,
+
+    /// The user base token account, which funds the asks
+    #[cons(writable)]
+    pub user_base_account: &'a T,
+
+    /// The user quote token account, which funds the bids
+    #[cons(writable)]
+    pub user_quote_account: &'a T,
+
+    /// The user wallet, or the user account's delegate
+    #[cons(writable, signer)]
+    pub user_owner: &'a T,
+
+    /// The optional SRM or MSRM discount token account (must be owned by the user wallet)
+    pub discount_token_account: Option<&'a T>,
+
+    /// The optional referrer's token account which will receive a 20% cut of the fees
+    #[cons(writable)]
+    pub fee_referral_account: Option<&'a T>,
+
+    /// The optional referrer account tracking the referral volume and fees, which requires the referral account
+    #[cons(writable)]
+    pub referrer: Option<&'a T>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+        has_discount_token_account: bool,
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let a = Self {
+            spl_token_program: next_account_info(accounts_iter)?,
+            system_program: next_account_info(accounts_iter)?,
+            market: next_account_info(accounts_iter)?,
+            orderbook: next_account_info(accounts_iter)?,
+            event_queue: next_account_info(accounts_iter)?,
+            bids: next_account_info(accounts_iter)?,
+            asks: next_account_info(accounts_iter)?,
+            base_vault: next_account_info(accounts_iter)?,
+            quote_vault: next_account_info(accounts_iter)?,
+            base_mint: next_account_info(accounts_iter)?,
+            quote_mint: next_account_info(accounts_iter)?,
+            market_signer: next_account_info(accounts_iter)?,
+            user: next_account_info(accounts_iter)?,
+            user_base_account: next_account_info(accounts_iter)?,
+            user_quote_account: next_account_info(accounts_iter)?,
+            user_owner: next_account_info(accounts_iter)?,
+            discount_token_account: if has_discount_token_account {
+                next_account_info(accounts_iter).ok()
+            } else {
+                None
+            },
+            fee_referral_account: next_account_info(accounts_iter).ok(),
+            referrer: next_account_info(accounts_iter).ok(),
+        };
+
+        check_signer(a.user_owner).map_err(|e| {
+            msg!("The user account owner should be a signer for this transaction!");
+            e
+        })?;
+
+        check_account_key(
+            a.system_program,
+            &system_program::ID,
+            DexError::InvalidSystemProgramAccount,
+        )?;
+
+        if let Some(discount_account) = a.discount_token_account {
+            check_account_owner(
+                discount_account,
+                &spl_token::ID,
+                DexError::InvalidSplTokenProgram,
+            )?;
+        }
+        check_account_owner(a.user, program_id, DexError::InvalidStateAccountOwner)?;
+        check_account_owner(a.market, program_id, DexError::InvalidStateAccountOwner)?;
+
+        Ok(a)
+    }
+
+    /// The new_order accounts used to execute an order of the given side
+    fn order_accounts(&self, side: Side) -> new_order::Accounts<'a, AccountInfo<'b>> {
+        new_order::Accounts {
+            spl_token_program: self.spl_token_program,
+            system_program: self.system_program,
+            market: self.market,
+            orderbook: self.orderbook,
+            event_queue: self.event_queue,
+            bids: self.bids,
+            asks: self.asks,
+            base_vault: self.base_vault,
+            quote_vault: self.quote_vault,
+            base_mint: self.base_mint,
+            quote_mint: self.quote_mint,
+            market_signer: self.market_signer,
+            user: self.user,
+            user_token_account: match side {
+                Side::Bid => self.user_quote_account,
+                Side::Ask => self.user_base_account,
+            },
+            user_owner: self.user_owner,
+            discount_token_account: self.discount_token_account,
+            fee_referral_account: self.fee_referral_account,
+            referrer: self.referrer,
+        }
+    }
+}
+
+pub(crate) fn process(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let Params {
+        orders,
+        match_limit,
+        number_of_orders,
+        post_only,
+        self_trade_behavior,
+        has_discount_token_account,
+        ..
+    } = try_from_bytes(instruction_data).map_err(|_| ProgramError::InvalidInstructionData)?;
+    let orders = orders
+        .get(..*number_of_orders as usize)
+        .ok_or(ProgramError::InvalidInstructionData)?;
+    let sides = orders
+        .iter()
+        .map(|o| FromPrimitive::from_u8(o.side).ok_or(ProgramError::InvalidInstructionData))
+        .collect::<Result<Vec<Side>, ProgramError>>()?;
+    let order_type = if *post_only != 0 {
+        OrderType::PostOnly
+    } else {
+        OrderType::Limit
+    };
+    let self_trade_behavior: SelfTradeBehavior =
+        FromPrimitive::from_u8(*self_trade_behavior).ok_or(ProgramError::InvalidInstructionData)?;
+    let accounts = Accounts::parse(program_id, accounts, *has_discount_token_account != 0)?;
+
+    let mut market_state = DexState::get(accounts.market)?;
+
+    if market_state.status != MarketStatus::Active as u8 {
+        msg!("The market is not accepting new orders.");
+        return Err(DexError::MarketPaused.into());
+    }
+
+    let bid_accounts = accounts.order_accounts(Side::Bid);
+    let ask_accounts = accounts.order_accounts(Side::Ask);
+
+    let mut user_account_data = accounts.user.data.borrow_mut();
+    let mut user_account = bid_accounts.load_user_account(&mut user_account_data)?;
+
+    new_order::check_accounts(program_id, &market_state, &bid_accounts)?;
+    if let Some(a) = accounts.fee_referral_account {
+        check_referral_account(a, accounts.quote_vault, &user_account.header.owner)?;
+    }
+    if sides.contains(&Side::Bid) {
+        check_token_account_mint(
+            accounts.user_quote_account,
+            &market_state.quote_mint,
+            DexError::InvalidQuoteTokenAccountMint,
+        )?;
+    }
+    if sides.contains(&Side::Ask) {
+        check_token_account_mint(
+            accounts.user_base_account,
+            &market_state.base_mint,
+            DexError::InvalidBaseTokenAccountMint,
+        )?;
+    }
+    // The fee tier is the one of the user account owner, even when a delegate places the order
+    if let Some(a) = accounts.discount_token_account {
+        check_discount_token_account(a, &user_account.header.owner)?;
+    }
+    let fee_tier = FeeTier::get_for_taker(
+        &market_state,
+        accounts.discount_token_account,
+        &user_account.header.owner,
+        Some(&*user_account.header),
+        Clock::get()?.unix_timestamp,
+    )?;
+
+    // The token transfers of all orders are netted into a single transfer per side
+    let mut base_qty_to_transfer = 0u64;
+    let mut quote_qty_to_transfer = 0u64;
+    let mut total_referral_fee = 0u64;
+    let mut total_matched_quote_qty = 0u64;
+    for (order, side) in orders.iter().zip(sides) {
+        let order_accounts = match side {
+            Side::Bid => &bid_accounts,
+            Side::Ask => &ask_accounts,
+        };
+        let OrderTransfer {
+            qty_to_transfer,
+            referral_fee,
+            matched_quote_qty,
+        } = execute_order(
+            program_id,
+            order_accounts,
+            &mut market_state,
+            &mut user_account,
+            fee_tier,
+            OrderRequest {
+                client_order_id: 0,
+                side,
+                limit_price: order.limit_price,
+                max_base_qty: order.max_base_qty,
+                max_quote_qty: u64::MAX,
+                match_limit: *match_limit,
+                order_type,
+                self_trade_behavior,
+                fok_fallback_ioc: false,
+                reduce_only: false,
+                duplicate_client_id_policy: DuplicateClientIdPolicy::Reject,
+            },
+        )?;
+        let total_qty_to_transfer = match side {
+            Side::Bid => &mut quote_qty_to_transfer,
+            Side::Ask => &mut base_qty_to_transfer,
+        };
+        *total_qty_to_transfer = total_qty_to_transfer
+            .checked_add(qty_to_transfer)
+            .ok_or(DexError::NumericalOverflow)?;
+        total_referral_fee = total_referral_fee
+            .checked_add(referral_fee)
+            .ok_or(DexError::NumericalOverflow)?;
+        total_matched_quote_qty = total_matched_quote_qty
+            .checked_add(matched_quote_qty)
+            .ok_or(DexError::NumericalOverflow)?;
+    }
+
+    if quote_qty_to_transfer != 0 {
+        transfer_to_vault(
+            &bid_accounts,
+            accounts.user_quote_account,
+            accounts.quote_vault,
+            accounts.quote_mint,
+            quote_qty_to_transfer,
+        )?;
+    }
+    if base_qty_to_transfer != 0 {
+        transfer_to_vault(
+            &ask_accounts,
+            accounts.user_base_account,
+            accounts.base_vault,
+            accounts.base_mint,
+            base_qty_to_transfer,
+        )?;
+    }
+
+    if let Some(a) = accounts.fee_referral_account {
+        transfer_referral_fee(&market_state, &bid_accounts, a, total_referral_fee)?;
+        if let Some(referrer) = accounts.referrer {
+            record_referral(
+                program_id,
+                accounts.market,
+                a,
+                referrer,
+                total_matched_quote_qty,
+                total_referral_fee,
+            )?;
+        }
+    }
+
+    market_state.last_order_slot = Clock::get()?.slot;
+
+    Ok(())
+}
@@ -1,13 +1,10 @@
 //! Cancel an existing order and remove it from the orderbook.
 use crate::{
     error::DexError,
-    state::{CallBackInfo, DexState, UserAccount},
+    state::{CallBackInfo, DexState, NativeQty, ScaledQty, UserAccount},
     utils::{check_account_key, check_account_owner, check_signer},
 };
-use asset_agnostic_orderbook::{
-    error::AoError,
-    state::{get_side_from_order_id, Side},
-};
+use asset_agnostic_orderbook::state::{get_side_from_order_id, Side};
 use bonfida_utils::BorshSize;
 use bonfida_utils::InstructionsAccount;
 use borsh::BorshDeserialize;
@@ -17,7 +14,8 @@ use solana_program::{
     account_info::{next_account_info, AccountInfo},
     entrypoint::ProgramResult,
     msg,
-    program_error::{PrintProgramError, ProgramError},
+    program::set_return_data,
+    program_error::ProgramError,
     pubkey::Pubkey,
 };
 
@@ -39,6 +37,17 @@ pub struct Params {
     pub _padding: [u8; 7],
 }
 
+/// The remaining size of a cancelled order in native token amounts, emitted as the instruction's return data.
+///
+/// The base quantity is unlocked when cancelling an ask, and the quote quantity when cancelling a bid.
+#[derive(Clone, Copy, Debug, PartialEq, BorshDeserialize, BorshSerialize)]
+pub struct CancelOrderSummary {
+    /// The remaining base quantity of the order
+    pub total_base_qty: u64,
+    /// The remaining quote quantity of the order
+    pub total_quote_qty: u64,
+}
+
 #[derive(InstructionsAccount)]
 pub struct Accounts<'a, T> {
     /// The DEX market
@@ -64,7 +73,7 @@ pub struct Accounts<'a, T> {
     #[cons(writable)]
     pub user: &'a T,
 
-    /// The user wallet
+    /// The user wallet, or the user account's delegate
     #[cons(signer)]
     pub user_owner: &'a T,
 }
@@ -99,8 +108,11 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
         user_account_data: &'a mut [u8],
     ) -> Result<UserAccount<'a>, ProgramError> {
         let user_account = UserAccount::from_buffer(user_account_data)?;
-        if &user_account.header.owner != self.user_owner.key {
-            msg!("Invalid user account owner provided!");
+        if !user_account
+            .header
+            .is_owner_or_delegate(self.user_owner.key)
+        {
+            msg!("Invalid user account owner or delegate provided!");
             return Err(ProgramError::InvalidArgument);
         }
         if &user_account.header.market != self.market.key {
@@ -153,52 +165,67 @@ pub(crate) fn process(
         asks: accounts.asks,
     };
 
-    let mut order_summary = match asset_agnostic_orderbook::instruction::cancel_order::process::<
+    let order_summary = match asset_agnostic_orderbook::instruction::cancel_order::process::<
         CallBackInfo,
     >(program_id, invoke_accounts, invoke_params)
     {
         Err(error) => {
-            error.print::<AoError>();
-            return Err(DexError::AOBError.into());
+            return Err(DexError::from_aob_error(error).into());
         }
         Ok(s) => s,
     };
     let side = get_side_from_order_id(order_id);
+    let NativeQty(base_qty) =
+        market_state.unscale_base_amount(ScaledQty(order_summary.total_base_qty))?;
+    let NativeQty(quote_qty) =
+        market_state.unscale_quote_amount(ScaledQty(order_summary.total_quote_qty))?;
+
+    unlock_order_funds(&mut user_account, side, base_qty, quote_qty);
+
+    user_account.remove_order(order_index as usize)?;
+
+    let summary = CancelOrderSummary {
+        total_base_qty: base_qty,
+        total_quote_qty: quote_qty,
+    };
+    set_return_data(&summary.try_to_vec()?);
 
-    market_state
-        .unscale_order_summary(&mut order_summary)
-        .unwrap();
+    Ok(())
+}
 
+/// Moves the unlocked amounts of a cancelled or reduced order from the locked to the free balance of the user account
+pub(crate) fn unlock_order_funds(
+    user_account: &mut UserAccount,
+    side: Side,
+    base_qty: u64,
+    quote_qty: u64,
+) {
     match side {
         Side::Bid => {
             user_account.header.quote_token_free = user_account
                 .header
                 .quote_token_free
-                .checked_add(order_summary.total_quote_qty)
+                .checked_add(quote_qty)
                 .unwrap();
             user_account.header.quote_token_locked = user_account
                 .header
                 .quote_token_locked
-                .checked_sub(order_summary.total_quote_qty)
+                .checked_sub(quote_qty)
                 .unwrap();
         }
         Side::Ask => {
             user_account.header.base_token_free = user_account
                 .header
                 .base_token_free
-                .checked_add(order_summary.total_base_qty)
+                .checked_add(base_qty)
                 .unwrap();
             user_account.header.base_token_locked = user_account
                 .header
                 .base_token_locked
-                .checked_sub(order_summary.total_base_qty)
+                .checked_sub(base_qty)
                 .unwrap();
         }
-    };
-
-    user_account.remove_order(order_index as usize)?;
-
-    Ok(())
+    }
 }
 
 fn check_accounts(market_state: &DexState, accounts: &Accounts<AccountInfo>) -> ProgramResult {
@@ -1,4 +1,9 @@
-//! Close an inactive and empty user account
+//! Close an inactive and empty user account.
+//!
+//! The account data is zeroed and tagged as closed so that it can't be mistaken for an active account
+//! before it is garbage collected. The rent is refunded to the rent payer recorded when the account was initialized,
+//! unless a target lamports account is given to override it. Migrated user accounts have no recorded rent payer, so
+//! they can only be closed to a target lamports account.
 use crate::{
     error::DexError,
     state::{AccountTag, UserAccount},
@@ -24,15 +29,19 @@ pub struct Params {}
 pub struct Accounts<'a, T> {
     /// The user account to close
     #[cons(writable)]
-    user: &'a T,
+    pub user: &'a T,
 
     /// The owner of the user account to close
     #[cons(signer)]
-    user_owner: &'a T,
+    pub user_owner: &'a T,
 
-    /// The target lamports account
+    /// The rent payer recorded in the user account, it is ignored when none was recorded
     #[cons(writable)]
-    target_lamports_account: &'a T,
+    pub rent_payer: &'a T,
+
+    /// An optional target lamports account, which receives the rent instead of the rent payer
+    #[cons(writable)]
+    pub target_lamports_account: Option<&'a T>,
 }
 
 impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
@@ -44,7 +53,8 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
         let a = Self {
             user: next_account_info(accounts_iter)?,
             user_owner: next_account_info(accounts_iter)?,
-            target_lamports_account: next_account_info(accounts_iter)?,
+            rent_payer: next_account_info(accounts_iter)?,
+            target_lamports_account: next_account_info(accounts_iter).ok(),
         };
         check_signer(a.user_owner).map_err(|e| {
             msg!("The user account owner should be a signer for this transaction!");
@@ -66,6 +76,16 @@ pub(crate) fn process(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramR
         return Err(ProgramError::InvalidArgument);
     };
 
+    if user_account.header.rent_payer == Pubkey::default() {
+        if accounts.target_lamports_account.is_none() {
+            msg!("The user account has no recorded rent payer, a target lamports account should be given");
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+    } else if &user_account.header.rent_payer != accounts.rent_payer.key {
+        msg!("The rent payer account does not match the user account");
+        return Err(DexError::InvalidRentPayerAccount.into());
+    }
+
     if user_account.header.number_of_orders != 0
         || user_account.header.quote_token_free != 0
         || user_account.header.base_token_free != 0
@@ -74,10 +94,17 @@ pub(crate) fn process(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramR
         return Err(DexError::UserAccountStillActive.into());
     }
 
-    user_account.header.tag = AccountTag::Closed as u64;
+    user_account_data.fill(0);
+    UserAccount::from_buffer_unchecked(&mut user_account_data)?
+        .header
+        .tag = AccountTag::Closed as u64;
 
     let mut lamports = accounts.user.lamports.borrow_mut();
-    let mut target_lamports = accounts.target_lamports_account.lamports.borrow_mut();
+    let mut target_lamports = accounts
+        .target_lamports_account
+        .unwrap_or(accounts.rent_payer)
+        .lamports
+        .borrow_mut();
 
     **target_lamports += **lamports;
     **lamports = 0;
@@ -1,10 +1,10 @@
 //! Close an existing market
 use crate::{
     error::DexError,
-    state::{AccountTag, CallBackInfo, DexState},
-    utils::{check_account_key, check_account_owner, check_signer},
+    state::{AccountTag, CallBackInfo, DexState, MarketStatus},
+    utils::{check_account_key, check_account_owner, check_signer, unpack_token_account},
 };
-use asset_agnostic_orderbook::error::AoError;
+use asset_agnostic_orderbook::state::{event_queue::EventQueue, AccountTag as AobAccountTag};
 use bonfida_utils::BorshSize;
 use bonfida_utils::InstructionsAccount;
 use borsh::BorshDeserialize;
@@ -12,15 +12,15 @@ use borsh::BorshSerialize;
 use bytemuck::{Pod, Zeroable};
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
+    clock::Clock,
     entrypoint::ProgramResult,
     msg,
     program::invoke_signed,
-    program_error::{PrintProgramError, ProgramError},
-    program_pack::Pack,
+    program_error::ProgramError,
     pubkey::Pubkey,
+    sysvar::Sysvar,
 };
-use spl_token::instruction::close_account;
-use spl_token::state::Account;
+use spl_token_2022::instruction::close_account;
 
 #[derive(Clone, Copy, BorshDeserialize, BorshSerialize, BorshSize, Pod, Zeroable)]
 #[repr(C)]
@@ -92,13 +92,6 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
             spl_token_program: next_account_info(accounts_iter)?,
         };
 
-        // Check keys
-        check_account_key(
-            a.spl_token_program,
-            &spl_token::ID,
-            DexError::InvalidStateAccountOwner,
-        )?;
-
         // Check owners
         check_account_owner(a.market, program_id, DexError::InvalidStateAccountOwner)?;
 
@@ -115,18 +108,36 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
 pub(crate) fn process(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
     let accounts = Accounts::parse(program_id, accounts)?;
 
+    close(program_id, &accounts)
+}
+
+/// Closes the market along with its orderbook and vaults, provided that nothing is left to consume, sweep or settle
+pub(crate) fn close(program_id: &Pubkey, accounts: &Accounts<AccountInfo>) -> ProgramResult {
     let mut market_state = DexState::get(accounts.market)?;
 
-    check_accounts(program_id, &market_state, &accounts).unwrap();
+    check_accounts(program_id, &market_state, accounts).unwrap();
 
-    let base_vault_data = Account::unpack_from_slice(&accounts.base_vault.data.borrow_mut())?;
-    let quote_vault_data = Account::unpack_from_slice(&accounts.quote_vault.data.borrow_mut())?;
+    let mut event_queue_guard = accounts.event_queue.data.borrow_mut();
+    let event_queue =
+        EventQueue::<CallBackInfo>::from_buffer(&mut event_queue_guard, AobAccountTag::EventQueue)?;
+    if !event_queue.is_empty() {
+        msg!("The event queue needs to be empty");
+        return Err(DexError::EventQueueMustBeEmpty.into());
+    }
+    drop(event_queue_guard);
 
-    if base_vault_data.amount != 0 && quote_vault_data.amount != 0 {
-        msg!("Market vaults need to be empty");
-        return Err(ProgramError::from(DexError::MarketStillActive));
+    let elapsed_slots = Clock::get()?
+        .slot
+        .saturating_sub(market_state.last_order_slot);
+    if elapsed_slots < market_state.close_grace_slots {
+        msg!(
+            "The market can only be closed {} slots after the last order placement",
+            market_state.close_grace_slots
+        );
+        return Err(DexError::CloseGracePeriodNotElapsed.into());
     }
 
+    // Uncollected fees and royalties are held in the quote vault, so they are reported first
     if market_state.accumulated_fees != 0 {
         msg!(
             "There are {:?} uncollected fees",
@@ -135,6 +146,22 @@ pub(crate) fn process(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramR
         return Err(ProgramError::from(DexError::MarketStillActive));
     }
 
+    if market_state.accumulated_royalties != 0 {
+        msg!(
+            "There are {:?} undistributed royalties, they need to be swept before closing the market",
+            market_state.accumulated_royalties
+        );
+        return Err(ProgramError::from(DexError::MarketStillActive));
+    }
+
+    let base_vault_data = unpack_token_account(accounts.base_vault)?;
+    let quote_vault_data = unpack_token_account(accounts.quote_vault)?;
+
+    if base_vault_data.amount != 0 || quote_vault_data.amount != 0 {
+        msg!("Market vaults need to be empty");
+        return Err(ProgramError::from(DexError::MarketStillActive));
+    }
+
     let invoke_accounts = asset_agnostic_orderbook::instruction::close_market::Accounts {
         market: accounts.orderbook,
         event_queue: accounts.event_queue,
@@ -149,17 +176,17 @@ pub(crate) fn process(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramR
         invoke_accounts,
         invoke_params,
     ) {
-        error.print::<AoError>();
-        return Err(DexError::AOBError.into());
+        return Err(DexError::from_aob_error(error).into());
     }
 
     market_state.tag = AccountTag::Closed as u64;
+    market_state.status = MarketStatus::Closed as u8;
     let nonce = market_state.signer_nonce;
     drop(market_state);
 
     // Close token accounts
     let ix = close_account(
-        &spl_token::ID,
+        accounts.spl_token_program.key,
         accounts.base_vault.key,
         accounts.market.key,
         accounts.market_signer.key,
@@ -176,7 +203,7 @@ pub(crate) fn process(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramR
         &[&[&accounts.market.key.to_bytes(), &[nonce]]],
     )?;
     let ix = close_account(
-        &spl_token::ID,
+        accounts.spl_token_program.key,
         accounts.quote_vault.key,
         accounts.market.key,
         accounts.market_signer.key,
@@ -240,6 +267,11 @@ fn check_accounts(
         &market_state.quote_vault,
         DexError::InvalidQuoteVaultAccount,
     )?;
+    check_account_key(
+        accounts.spl_token_program,
+        &market_state.token_program_id(),
+        DexError::InvalidSplTokenProgram,
+    )?;
 
     Ok(())
 }
@@ -4,27 +4,32 @@ use num_traits::FromPrimitive;
 
 use crate::{
     error::DexError,
-    state::{CallBackInfo, DexState, FeeTier, UserAccount},
-    utils::{check_account_key, check_account_owner, fp32_mul},
-};
-use asset_agnostic_orderbook::{
-    error::AoError,
     state::{
-        event_queue::{EventQueue, EventRef, FillEvent, FillEventRef, OutEvent, OutEventRef},
-        AccountTag, Side,
+        CallBackInfo, CandleHistory, DexState, FeeTier, FillLog, NativeQty, ScaledQty, UserAccount,
+        FILL_LOG_VERSION,
     },
+    utils::{check_account_key, check_account_owner, fp32_div},
+};
+use asset_agnostic_orderbook::state::{
+    event_queue::{EventQueue, EventRef, FillEvent, FillEventRef, OutEvent, OutEventRef},
+    market_state::MarketState,
+    AccountTag, Side,
 };
 use bonfida_utils::BorshSize;
 use bonfida_utils::InstructionsAccount;
 use borsh::BorshDeserialize;
 use borsh::BorshSerialize;
-use bytemuck::{try_from_bytes, Pod, Zeroable};
+use bytemuck::{bytes_of, try_from_bytes, Pod, Zeroable};
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
+    clock::Clock,
     entrypoint::ProgramResult,
+    log::sol_log_data,
     msg,
-    program_error::{PrintProgramError, ProgramError},
+    program::set_return_data,
+    program_error::ProgramError,
     pubkey::Pubkey,
+    sysvar::Sysvar,
 };
 
 #[derive(Copy, Clone, Zeroable, Pod, BorshDeserialize, BorshSerialize, BorshSize)]
@@ -40,6 +45,14 @@ pub struct Params {
     /// Value should be 0 or 1.
     /// Is u64 to allow for type casting.
     pub no_op_err: u64,
+    /// Whether the optional candle history account was given, in which case the fills are recorded in it
+    pub has_candle_history: u64,
+    /// Decide if the events referencing a missing user account are skipped instead of stopping the processing.
+    /// Skipped events are moved to the back of the queue, to be consumed by a later crank.
+    /// Value should be 0 or 1.
+    pub skip_missing: u8,
+    #[allow(missing_docs)]
+    pub _padding: [u8; 7],
 }
 
 #[derive(InstructionsAccount)]
@@ -60,7 +73,11 @@ pub struct Accounts<'a, T> {
     #[cons(writable)]
     pub reward_target: &'a T,
 
-    /// The relevant user accounts
+    /// The optional candle history of the market
+    #[cons(writable)]
+    pub candle_history: Option<&'a T>,
+
+    /// The relevant user accounts, in any order
     #[cons(writable)]
     pub user_accounts: &'a [T],
 }
@@ -69,6 +86,7 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
     pub fn parse(
         program_id: &Pubkey,
         accounts: &'a [AccountInfo<'b>],
+        has_candle_history: bool,
     ) -> Result<Self, ProgramError> {
         let accounts_iter = &mut accounts.iter();
         let a = Self {
@@ -76,10 +94,22 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
             orderbook: next_account_info(accounts_iter)?,
             event_queue: next_account_info(accounts_iter)?,
             reward_target: next_account_info(accounts_iter)?,
+            candle_history: if has_candle_history {
+                Some(next_account_info(accounts_iter)?)
+            } else {
+                None
+            },
             user_accounts: accounts_iter.as_slice(),
         };
 
         check_account_owner(a.market, program_id, DexError::InvalidStateAccountOwner)?;
+        if let Some(candle_history) = a.candle_history {
+            check_account_owner(
+                candle_history,
+                program_id,
+                DexError::InvalidStateAccountOwner,
+            )?;
+        }
 
         Ok(a)
     }
@@ -90,73 +120,189 @@ pub(crate) fn process(
     accounts: &[AccountInfo],
     instruction_data: &[u8],
 ) -> ProgramResult {
-    let accounts = Accounts::parse(program_id, accounts)?;
-
     let Params {
         max_iterations,
         no_op_err,
+        has_candle_history,
+        skip_missing,
+        _padding: _,
     } = try_from_bytes(instruction_data).map_err(|_| ProgramError::InvalidInstructionData)?;
 
+    let accounts = Accounts::parse(program_id, accounts, *has_candle_history != 0)?;
+
+    let total_iterations = consume(
+        program_id,
+        &accounts,
+        *max_iterations,
+        *no_op_err,
+        *skip_missing != 0,
+    )?;
+    set_return_data(&total_iterations.try_to_vec()?);
+
+    Ok(())
+}
+
+/// An event which was skipped because it references a missing user account
+enum SkippedEvent {
+    Fill(FillEvent, CallBackInfo, CallBackInfo),
+    Out(OutEvent, CallBackInfo),
+}
+
+impl SkippedEvent {
+    fn new(event: &EventRef<CallBackInfo>) -> Self {
+        match event {
+            EventRef::Fill(FillEventRef {
+                event,
+                maker_callback_info,
+                taker_callback_info,
+            }) => Self::Fill(**event, **maker_callback_info, **taker_callback_info),
+            EventRef::Out(OutEventRef {
+                event,
+                callback_info,
+            }) => Self::Out(**event, **callback_info),
+        }
+    }
+}
+
+/// Consumes up to `max_iterations` events, returning the number of consumed events.
+///
+/// The processing stops at the first event referencing a missing user account, unless `skip_missing` is set in which
+/// case such events are moved to the back of the queue.
+pub(crate) fn consume(
+    program_id: &Pubkey,
+    accounts: &Accounts<AccountInfo>,
+    max_iterations: u64,
+    no_op_err: u64,
+    skip_missing: bool,
+) -> Result<u64, ProgramError> {
     let mut market_state = DexState::get(accounts.market)?;
 
+    check_accounts(program_id, &market_state, accounts)?;
+
+    let mut candle_history = accounts
+        .candle_history
+        .map(CandleHistory::get)
+        .transpose()?;
+    if let Some(h) = &candle_history {
+        if &h.market != accounts.market.key {
+            msg!("The candle history doesn't match the market");
+            return Err(DexError::InvalidCandleHistoryAccount.into());
+        }
+    }
+
     let mut event_queue_guard = accounts.event_queue.data.borrow_mut();
-    let event_queue =
+    let mut event_queue =
         EventQueue::<CallBackInfo>::from_buffer(&mut event_queue_guard, AccountTag::EventQueue)?;
 
-    check_accounts(&market_state, &accounts).unwrap();
-
+    let timestamp = Clock::get()?.unix_timestamp;
     let mut total_iterations = 0;
-
-    for event in event_queue.iter().take(*max_iterations as usize) {
-        if consume_event(accounts.user_accounts, event, &mut market_state).is_err() {
-            break;
+    let mut skipped_events = Vec::new();
+
+    for event in event_queue.iter().take(max_iterations as usize) {
+        match consume_event(
+            accounts.user_accounts,
+            &event,
+            &mut market_state,
+            candle_history.as_deref_mut(),
+            timestamp,
+        ) {
+            Ok(()) => total_iterations += 1,
+            Err(DexError::MissingUserAccount) if skip_missing => {
+                skipped_events.push(SkippedEvent::new(&event))
+            }
+            Err(_) => break,
         }
-        total_iterations += 1;
     }
 
-    if total_iterations == 0 {
+    if total_iterations == 0 && skipped_events.is_empty() {
         msg!("Failed to complete one iteration");
-        if *no_op_err == 1 {
+        if no_op_err == 1 {
             return Err(DexError::NoOp.into());
         }
-        return Ok(());
+        return Ok(0);
     }
 
-    drop(event_queue_guard);
-
-    let invoke_params = asset_agnostic_orderbook::instruction::consume_events::Params {
-        number_of_entries_to_consume: total_iterations,
-    };
-    let invoke_accounts = asset_agnostic_orderbook::instruction::consume_events::Accounts {
-        market: accounts.orderbook,
-        event_queue: accounts.event_queue,
-    };
+    // The processed events are popped in place, advancing the queue head exactly once. The skipped events are
+    // moved to the back of the queue even when none could be consumed, so that they don't block the ones behind them.
+    event_queue.pop_n(total_iterations + skipped_events.len() as u64);
 
-    if let Err(error) = asset_agnostic_orderbook::instruction::consume_events::process::<CallBackInfo>(
-        program_id,
-        invoke_accounts,
-        invoke_params,
-    ) {
-        error.print::<AoError>();
-        return Err(DexError::AOBError.into());
+    if !skipped_events.is_empty() {
+        msg!(
+            "Skipped {} events with missing user accounts",
+            skipped_events.len()
+        );
+    }
+    for skipped_event in skipped_events {
+        match skipped_event {
+            SkippedEvent::Fill(event, maker_callback_info, taker_callback_info) => event_queue
+                .push_back(
+                    event,
+                    Some(&maker_callback_info),
+                    Some(&taker_callback_info),
+                )
+                .map_err(|_| DexError::EventQueueFull)?,
+            SkippedEvent::Out(event, callback_info) => event_queue
+                .push_back(event, Some(&callback_info), None)
+                .map_err(|_| DexError::EventQueueFull)?,
+        }
     }
 
-    Ok(())
+    Ok(total_iterations)
 }
 
-fn check_accounts(market_state: &DexState, accounts: &Accounts<AccountInfo>) -> ProgramResult {
+fn check_accounts(
+    program_id: &Pubkey,
+    market_state: &DexState,
+    accounts: &Accounts<AccountInfo>,
+) -> ProgramResult {
     check_account_key(
         accounts.orderbook,
         &market_state.orderbook,
         DexError::InvalidOrderbookAccount,
     )?;
+    let mut orderbook_guard = accounts.orderbook.data.borrow_mut();
+    let aob_state = MarketState::from_buffer(&mut orderbook_guard, AccountTag::Market)?;
+    if &aob_state.event_queue != accounts.event_queue.key {
+        return Err(DexError::EventQueueMismatch.into());
+    }
+    for user_account_info in accounts.user_accounts {
+        check_account_owner(
+            user_account_info,
+            program_id,
+            DexError::InvalidStateAccountOwner,
+        )?;
+        let mut user_account_data = user_account_info.data.borrow_mut();
+        let user_account = UserAccount::from_buffer(&mut user_account_data)?;
+        if &user_account.header.market != accounts.market.key {
+            msg!(
+                "The user account {} doesn't match the current market",
+                user_account_info.key
+            );
+            return Err(DexError::InvalidUserAccountMarket.into());
+        }
+    }
     Ok(())
 }
 
+/// Finds the account of a user among the user accounts given to the instruction, which don't have to be sorted.
+///
+/// The transaction size bounds the number of accounts, so a linear search is cheaper than building an index.
+fn find_user_account<'a, 'b>(
+    accounts: &'a [AccountInfo<'b>],
+    user_account: &Pubkey,
+) -> Result<&'a AccountInfo<'b>, DexError> {
+    accounts
+        .iter()
+        .find(|a| a.key == user_account)
+        .ok_or(DexError::MissingUserAccount)
+}
+
 fn consume_event(
     accounts: &[AccountInfo],
-    event: EventRef<CallBackInfo>,
+    event: &EventRef<CallBackInfo>,
     market_state: &mut DexState,
+    candle_history: Option<&mut CandleHistory>,
+    timestamp: i64,
 ) -> Result<(), DexError> {
     match event {
         EventRef::Fill(FillEventRef {
@@ -168,32 +314,32 @@ fn consume_event(
                 tag: _,
                 taker_side,
                 mut quote_size,
-                maker_order_id: _,
+                maker_order_id,
                 mut base_size,
                 ..
             } = event;
-            quote_size = quote_size
-                .checked_mul(market_state.quote_currency_multiplier)
-                .unwrap();
-            base_size = base_size
-                .checked_mul(market_state.base_currency_multiplier)
-                .unwrap();
-            let maker_account_info = &accounts[accounts
-                .binary_search_by_key(&maker_callback_info.user_account, |k| *k.key)
-                .map_err(|_| DexError::MissingUserAccount)?];
+            quote_size = market_state.unscale_quote_amount(ScaledQty(quote_size))?.0;
+            base_size = market_state.unscale_base_amount(ScaledQty(base_size))?.0;
+            let maker_account_info =
+                find_user_account(accounts, &maker_callback_info.user_account)?;
+            // The taker tier was fixed when the order matched, fills of the promotional window stay fee-free
             let (taker_fee_tier, is_referred) = FeeTier::from_u8(taker_callback_info.fee_tier);
             let mut maker_account_data = maker_account_info.data.borrow_mut();
             let mut maker_account = UserAccount::from_buffer(&mut maker_account_data).unwrap();
             let (maker_fee_tier, _) = FeeTier::from_u8(maker_callback_info.fee_tier);
-            let taker_fee = taker_fee_tier.taker_fee(quote_size);
-            let maker_rebate = maker_fee_tier.maker_rebate(quote_size);
-            let royalties_fee =
-                market_state.royalties_bps.checked_mul(quote_size).unwrap() / 10_000;
+            // Rounded down, as the fee charged to the taker was rounded up for the whole order
+            let taker_fee = taker_fee_tier.taker_fee_floor(market_state, quote_size);
             let referral_fee = if is_referred {
-                taker_fee_tier.referral_fee(quote_size)
+                taker_fee_tier.referral_fee(market_state, quote_size)
             } else {
                 0
             };
+            // The maker rebate is funded by the taker fee, which the fee schedule checks guarantee to cover it along with
+            // the referral fee. Fee-free fills of the promotional window earn no rebate.
+            let maker_rebate = match taker_fee_tier {
+                FeeTier::Promo => 0,
+                _ => maker_fee_tier.maker_rebate(market_state, quote_size),
+            };
             let total_fees = taker_fee
                 .checked_sub(maker_rebate)
                 .and_then(|n| n.checked_sub(referral_fee))
@@ -203,11 +349,32 @@ fn consume_event(
                 .accumulated_fees
                 .checked_add(total_fees)
                 .unwrap();
+            // The quote of the fill was accounted for when the order matched, only the rebate is new
+            market_state.update_quote_backing(0, maker_rebate as u128)?;
+
+            let fill_price = fp32_div(quote_size, base_size);
+            // Self trades don't move the reference price
+            if maker_callback_info.user_account != taker_callback_info.user_account {
+                if let Some(price) = fill_price {
+                    market_state.record_fill_price(price, timestamp);
+                }
+            }
+            if let (Some(history), Some(price)) = (candle_history, fill_price) {
+                history.record_candle(price, base_size, quote_size, timestamp);
+            }
 
-            market_state.accumulated_royalties = market_state
-                .accumulated_royalties
-                .checked_add(royalties_fee)
-                .unwrap();
+            sol_log_data(&[bytes_of(&FillLog {
+                version: FILL_LOG_VERSION,
+                taker_side: *taker_side,
+                _padding: [0; 6],
+                maker: maker_callback_info.user_account,
+                taker: taker_callback_info.user_account,
+                price: (*maker_order_id >> 64) as u64,
+                base_size,
+                quote_size,
+                taker_fee,
+                maker_rebate,
+            })]);
 
             match Side::from_u8(*taker_side).unwrap() {
                 Side::Bid => {
@@ -234,7 +401,7 @@ fn consume_event(
                         .quote_token_locked
                         .checked_sub(quote_size)
                         .unwrap();
-                    maker_account
+                    maker_account.header.quote_token_free = maker_account
                         .header
                         .quote_token_free
                         .checked_add(maker_rebate)
@@ -268,15 +435,11 @@ fn consume_event(
                 mut base_size,
                 ..
             } = event;
-            let user_account_info = &accounts[accounts
-                .binary_search_by_key(&callback_info.user_account, |k| *k.key)
-                .map_err(|_| DexError::MissingUserAccount)?];
+            let user_account_info = find_user_account(accounts, &callback_info.user_account)?;
             let mut user_account_data = user_account_info.data.borrow_mut();
             let mut user_account = UserAccount::from_buffer(&mut user_account_data).unwrap();
 
-            base_size = base_size
-                .checked_mul(market_state.base_currency_multiplier)
-                .unwrap();
+            base_size = market_state.unscale_base_amount(ScaledQty(base_size))?.0;
 
             if base_size != 0 {
                 match Side::from_u8(*side).unwrap() {
@@ -293,18 +456,20 @@ fn consume_event(
                             .unwrap();
                     }
                     Side::Bid => {
-                        let price = (order_id >> 64) as u64;
-                        let qty_to_transfer = fp32_mul(base_size, price);
+                        let quote_token_locked = user_account.header.quote_token_locked;
+                        let qty_to_transfer = get_bid_out_quote_qty(
+                            market_state,
+                            base_size,
+                            *order_id,
+                            quote_token_locked,
+                        );
                         user_account.header.quote_token_free = user_account
                             .header
                             .quote_token_free
-                            .checked_add(qty_to_transfer.unwrap())
-                            .unwrap();
-                        user_account.header.quote_token_locked = user_account
-                            .header
-                            .quote_token_locked
-                            .checked_sub(qty_to_transfer.unwrap())
-                            .unwrap();
+                            .checked_add(qty_to_transfer)
+                            .ok_or(DexError::NumericalOverflow)?;
+                        user_account.header.quote_token_locked =
+                            quote_token_locked - qty_to_transfer;
                     }
                 }
             }
@@ -314,3 +479,66 @@ fn consume_event(
     };
     Ok(())
 }
+
+/// Computes the quote quantity to unlock when a bid order leaves the book.
+///
+/// The quantity is capped by the quote tokens locked by the user account, which an overflowing quantity falls back to.
+/// The event is then still consumed, so that it doesn't hold the events behind it at the head of the queue.
+fn get_bid_out_quote_qty(
+    market_state: &DexState,
+    base_size: u64,
+    order_id: u128,
+    quote_token_locked: u64,
+) -> u64 {
+    let price = (order_id >> 64) as u64;
+    match market_state.get_quote_from_base(NativeQty(base_size), price) {
+        Ok(NativeQty(q)) if q <= quote_token_locked => q,
+        Ok(NativeQty(q)) => {
+            msg!(
+                "The {} quote tokens to unlock exceed the {} locked by the user account",
+                q,
+                quote_token_locked
+            );
+            quote_token_locked
+        }
+        Err(_) => {
+            msg!(
+                "Overflow when computing the unlocked quote quantity, unlocking {} instead",
+                quote_token_locked
+            );
+            quote_token_locked
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bid_out_quote_qty() {
+        let mut market_state = DexState::zeroed();
+        market_state.base_currency_multiplier = 1;
+        market_state.quote_currency_multiplier = 10;
+
+        // 100 base @ 2.0
+        let order_id = (2u128 << 32) << 64;
+        assert_eq!(
+            get_bid_out_quote_qty(&market_state, 100, order_id, 5_000),
+            2_000
+        );
+
+        // The unlocked quantity never exceeds the locked one
+        assert_eq!(
+            get_bid_out_quote_qty(&market_state, 100, order_id, 1_500),
+            1_500
+        );
+
+        // A huge price overflows, the locked quantity is unlocked instead of panicking
+        let order_id = (u64::MAX as u128) << 64;
+        assert_eq!(
+            get_bid_out_quote_qty(&market_state, u64::MAX, order_id, 5_000),
+            5_000
+        );
+    }
+}
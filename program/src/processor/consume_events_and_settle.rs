@@ -0,0 +1,271 @@
+//! Crank the processing of DEX events, then settle the available funds of a set of users.
+use crate::{
+    error::DexError,
+    processor::{consume_events, settle::transfer_from_vault},
+    state::{DexState, UserAccount},
+    utils::{check_account_key, check_account_owner, unpack_token_account},
+};
+use bonfida_utils::BorshSize;
+use bonfida_utils::InstructionsAccount;
+use borsh::BorshDeserialize;
+use borsh::BorshSerialize;
+use bytemuck::{try_from_bytes, Pod, Zeroable};
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+#[derive(Copy, Clone, Zeroable, Pod, BorshDeserialize, BorshSerialize, BorshSize)]
+#[repr(C)]
+/**
+The required arguments for a consume_events_and_settle instruction.
+*/
+pub struct Params {
+    /// The maximum number of events to consume
+    pub max_iterations: u64,
+    /// Decide if the transaction will fail when there are no events to consume.
+    /// Value should be 0 or 1.
+    /// Is u64 to allow for type casting.
+    pub no_op_err: u64,
+    /// The number of users to settle once the events have been consumed
+    pub number_of_settled_users: u64,
+}
+
+#[derive(InstructionsAccount)]
+pub struct Accounts<'a, T> {
+    /// The spl token program
+    pub spl_token_program: &'a T,
+
+    /// The DEX market
+    #[cons(writable)]
+    pub market: &'a T,
+
+    /// The orderbook
+    #[cons(writable)]
+    pub orderbook: &'a T,
+
+    /// The AOB event queue
+    #[cons(writable)]
+    pub event_queue: &'a T,
+
+    /// The reward target
+    #[cons(writable)]
+    pub reward_target: &'a T,
+
+    /// The base token vault
+    #[cons(writable)]
+    pub base_vault: &'a T,
+
+    /// The quote token vault
+    #[cons(writable)]
+    pub quote_vault: &'a T,
+
+    /// The base token mint
+    pub base_mint: &'a T,
+
+    /// The quote token mint
+    pub quote_mint: &'a T,
+
+    /// The DEX market signer account
+    pub market_signer: &'a T,
+
+    /// The relevant user accounts in any order, followed by a (user account, destination base token account,
+    /// destination quote token account) triplet for each user to settle
+    #[cons(writable)]
+    pub user_accounts: &'a [T],
+}
+
+impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let a = Self {
+            spl_token_program: next_account_info(accounts_iter)?,
+            market: next_account_info(accounts_iter)?,
+            orderbook: next_account_info(accounts_iter)?,
+            event_queue: next_account_info(accounts_iter)?,
+            reward_target: next_account_info(accounts_iter)?,
+            base_vault: next_account_info(accounts_iter)?,
+            quote_vault: next_account_info(accounts_iter)?,
+            base_mint: next_account_info(accounts_iter)?,
+            quote_mint: next_account_info(accounts_iter)?,
+            market_signer: next_account_info(accounts_iter)?,
+            user_accounts: accounts_iter.as_slice(),
+        };
+
+        check_account_owner(a.market, program_id, DexError::InvalidStateAccountOwner)?;
+
+        Ok(a)
+    }
+}
+
+pub(crate) fn process(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let Params {
+        max_iterations,
+        no_op_err,
+        number_of_settled_users,
+    } = try_from_bytes(instruction_data).map_err(|_| ProgramError::InvalidInstructionData)?;
+
+    let settled_accounts_len = (*number_of_settled_users as usize)
+        .checked_mul(3)
+        .ok_or(DexError::NumericalOverflow)?;
+    let split_index = accounts
+        .user_accounts
+        .len()
+        .checked_sub(settled_accounts_len)
+        .ok_or(ProgramError::NotEnoughAccountKeys)?;
+    let (user_accounts, settled_accounts) = accounts.user_accounts.split_at(split_index);
+
+    consume_events::consume(
+        program_id,
+        &consume_events::Accounts {
+            market: accounts.market,
+            orderbook: accounts.orderbook,
+            event_queue: accounts.event_queue,
+            reward_target: accounts.reward_target,
+            candle_history: None,
+            user_accounts,
+        },
+        *max_iterations,
+        *no_op_err,
+        false,
+    )?;
+
+    let mut market_state = DexState::get(accounts.market)?;
+
+    check_accounts(program_id, &market_state, &accounts)?;
+
+    for settled in settled_accounts.chunks_exact(3) {
+        settle_user(program_id, &mut market_state, &accounts, settled)?;
+    }
+
+    Ok(())
+}
+
+fn check_accounts(
+    program_id: &Pubkey,
+    market_state: &DexState,
+    accounts: &Accounts<AccountInfo>,
+) -> ProgramResult {
+    let market_signer = Pubkey::create_program_address(
+        &[
+            &accounts.market.key.to_bytes(),
+            &[market_state.signer_nonce as u8],
+        ],
+        program_id,
+    )?;
+    check_account_key(
+        accounts.market_signer,
+        &market_signer,
+        DexError::InvalidMarketSignerAccount,
+    )?;
+    check_account_key(
+        accounts.base_vault,
+        &market_state.base_vault,
+        DexError::InvalidBaseVaultAccount,
+    )?;
+    check_account_key(
+        accounts.quote_vault,
+        &market_state.quote_vault,
+        DexError::InvalidQuoteVaultAccount,
+    )?;
+    check_account_key(
+        accounts.base_mint,
+        &market_state.base_mint,
+        DexError::InvalidBaseMintAccount,
+    )?;
+    check_account_key(
+        accounts.quote_mint,
+        &market_state.quote_mint,
+        DexError::InvalidQuoteMintAccount,
+    )?;
+    check_account_key(
+        accounts.spl_token_program,
+        &market_state.token_program_id(),
+        DexError::InvalidSplTokenProgram,
+    )?;
+
+    Ok(())
+}
+
+/// Settles a single user, which is skipped altogether when any of its accounts is invalid
+fn settle_user(
+    program_id: &Pubkey,
+    market_state: &mut DexState,
+    accounts: &Accounts<AccountInfo>,
+    settled: &[AccountInfo],
+) -> ProgramResult {
+    let (user, destination_base_account, destination_quote_account) =
+        (&settled[0], &settled[1], &settled[2]);
+    if user.owner != program_id {
+        msg!("Skipping the settlement of {}: invalid owner", user.key);
+        return Ok(());
+    }
+    let mut user_account_data = user.data.borrow_mut();
+    let mut user_account = match UserAccount::from_buffer(&mut user_account_data) {
+        Ok(u) if &u.header.market == accounts.market.key => u,
+        _ => {
+            msg!(
+                "Skipping the settlement of {}: invalid user account",
+                user.key
+            );
+            return Ok(());
+        }
+    };
+    let owner = user_account.header.owner;
+    if !is_destination_valid(destination_base_account, &owner, &market_state.base_mint)
+        || !is_destination_valid(destination_quote_account, &owner, &market_state.quote_mint)
+    {
+        msg!(
+            "Skipping the settlement of {}: invalid destination token accounts",
+            user.key
+        );
+        return Ok(());
+    }
+
+    transfer_from_vault(
+        market_state,
+        accounts.spl_token_program,
+        accounts.market,
+        accounts.market_signer,
+        accounts.quote_vault,
+        accounts.quote_mint,
+        destination_quote_account,
+        user_account.header.quote_token_free,
+    )?;
+    transfer_from_vault(
+        market_state,
+        accounts.spl_token_program,
+        accounts.market,
+        accounts.market_signer,
+        accounts.base_vault,
+        accounts.base_mint,
+        destination_base_account,
+        user_account.header.base_token_free,
+    )?;
+
+    market_state.update_quote_backing(user_account.header.quote_token_free as u128, 0)?;
+    user_account.header.quote_token_free = 0;
+    user_account.header.base_token_free = 0;
+
+    Ok(())
+}
+
+/// The destination token accounts must belong to the owner of the settled user account
+fn is_destination_valid(account: &AccountInfo, owner: &Pubkey, mint: &Pubkey) -> bool {
+    match unpack_token_account(account) {
+        Ok(a) => &a.owner == owner && &a.mint == mint,
+        Err(_) => false,
+    }
+}
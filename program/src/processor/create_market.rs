@@ -1,27 +1,37 @@
 //! Creates a new DEX market
 use crate::{
     error::DexError,
-    state::{AccountTag, CallBackInfo, DexState, MarketFeeType},
-    utils::{check_account_owner, check_metadata_account, verify_metadata},
+    state::{
+        AccountTag, CallBackInfo, DexState, MarketFeeModel, MarketFeeType, MarketStatus, NativeQty,
+        DEFAULT_FEE_TIER_MAKER_REBATE_RATES, DEFAULT_FEE_TIER_TAKER_RATES,
+        DEFAULT_FEE_TIER_THRESHOLDS, MARKET_NAME_LEN, MARKET_TICKER_LEN,
+    },
+    utils::{
+        check_account_key, check_account_owner, check_metadata_account, is_token_program,
+        verify_metadata,
+    },
 };
-use asset_agnostic_orderbook::error::AoError;
 use bonfida_utils::checks::check_rent_exempt;
 use bonfida_utils::BorshSize;
 use bonfida_utils::InstructionsAccount;
 use borsh::BorshDeserialize;
 use borsh::BorshSerialize;
 use bytemuck::{try_from_bytes, Pod, Zeroable};
-use mpl_token_metadata::state::{Metadata, TokenMetadataAccount};
+use mpl_token_metadata::state::{Metadata, TokenMetadataAccount, MAX_CREATOR_LIMIT};
+use num_traits::FromPrimitive;
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
     clock::Clock,
     entrypoint::ProgramResult,
     msg,
-    program_error::{PrintProgramError, ProgramError},
-    program_pack::Pack,
+    program_error::ProgramError,
     pubkey::Pubkey,
     sysvar::Sysvar,
 };
+use spl_token_2022::{
+    extension::{ExtensionType, StateWithExtensions},
+    state::{Account, Mint},
+};
 
 #[derive(Copy, Clone, Zeroable, Pod, BorshDeserialize, BorshSerialize, BorshSize)]
 #[repr(C)]
@@ -36,6 +46,36 @@ pub struct Params {
     pub tick_size: u64,
     pub base_currency_multiplier: u64,
     pub quote_currency_multiplier: u64,
+    /// The minimum number of slots between the last order placement and the closing of the market
+    pub close_grace_slots: u64,
+    /// The minimum SRM balances (or lifetime taker quote volumes for volume-based markets) required to access the Srm2 to Srm6
+    /// fee tiers, defaults to the standard schedule when zeroed
+    pub fee_tier_thresholds: [u64; 5],
+    /// The taker fee rates (as FP32) for each fee tier, defaults to the standard schedule when zeroed
+    pub fee_tier_taker_rates: [u64; 8],
+    /// The maker rebate rates (as FP32) for each fee tier, defaults to the standard schedule when zeroed
+    pub fee_tier_maker_rebate_rates: [u64; 8],
+    /// The authority which must own the destination token account when sweeping fees, defaults to the market admin when zeroed
+    pub sweep_authority: Pubkey,
+    /// The minimum allowed order size in quote token amount, disabled when zero
+    pub min_quote_order_size: u64,
+    /// The maximum deviation (in bps) of an order's average fill price from the last price, disabled when zero
+    pub max_deviation_bps: u16,
+    /// The referrer's cut (in bps) of the taker fees, [`DEFAULT_REFERRAL_BPS`](crate::state::DEFAULT_REFERRAL_BPS) when zero
+    pub referral_bps: u16,
+    /// Whether the fee tiers are based on token holdings (0) or on lifetime taker volume (1), or whether all takers pay
+    /// `flat_taker_bps` (2)
+    pub fee_model: u8,
+    /// The market's fee schedule, either the default (0) or the low-fee stable pair schedule (1)
+    pub fee_type: u8,
+    /// The market's human-readable name, as UTF-8 padded with trailing zeroes
+    pub name: [u8; MARKET_NAME_LEN],
+    /// The market's ticker, as UTF-8 padded with trailing zeroes
+    pub ticker: [u8; MARKET_TICKER_LEN],
+    /// The taker fee (in bps) paid by every taker on flat-fee markets, must be zero for the other fee models
+    pub flat_taker_bps: u16,
+    /// The unix timestamp until which takers pay no fees, disabled when zero
+    pub promo_end_ts: i64,
 }
 
 #[derive(InstructionsAccount)]
@@ -54,6 +94,12 @@ pub struct Accounts<'a, T> {
     /// The quote vault account
     pub quote_vault: &'a T,
 
+    /// The base mint
+    pub base_mint: &'a T,
+
+    /// The quote mint
+    pub quote_mint: &'a T,
+
     /// The market admin account
     pub market_admin: &'a T,
 
@@ -85,6 +131,8 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
             orderbook: next_account_info(accounts_iter)?,
             base_vault: next_account_info(accounts_iter)?,
             quote_vault: next_account_info(accounts_iter)?,
+            base_mint: next_account_info(accounts_iter)?,
+            quote_mint: next_account_info(accounts_iter)?,
             market_admin: next_account_info(accounts_iter)?,
             event_queue: next_account_info(accounts_iter)?,
             asks: next_account_info(accounts_iter)?,
@@ -94,14 +142,27 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
 
         check_account_owner(a.market, program_id, DexError::InvalidStateAccountOwner)?;
         check_account_owner(a.orderbook, program_id, DexError::InvalidStateAccountOwner)?;
+        if !is_token_program(a.base_vault.owner) {
+            msg!("The vaults should be owned by the SPL token program or Token-2022");
+            return Err(DexError::InvalidStateAccountOwner.into());
+        }
+        check_account_owner(
+            a.quote_vault,
+            a.base_vault.owner,
+            DexError::InvalidStateAccountOwner,
+        )
+        .map_err(|e| {
+            msg!("Both vaults should be owned by the same token program");
+            e
+        })?;
         check_account_owner(
-            a.base_vault,
-            &spl_token::ID,
+            a.base_mint,
+            a.base_vault.owner,
             DexError::InvalidStateAccountOwner,
         )?;
         check_account_owner(
-            a.quote_vault,
-            &spl_token::ID,
+            a.quote_mint,
+            a.base_vault.owner,
             DexError::InvalidStateAccountOwner,
         )?;
 
@@ -124,6 +185,20 @@ pub(crate) fn process(
         tick_size,
         base_currency_multiplier,
         quote_currency_multiplier,
+        close_grace_slots,
+        fee_tier_thresholds,
+        fee_tier_taker_rates,
+        fee_tier_maker_rebate_rates,
+        sweep_authority,
+        min_quote_order_size,
+        max_deviation_bps,
+        referral_bps,
+        fee_model,
+        fee_type,
+        name,
+        ticker,
+        flat_taker_bps,
+        promo_end_ts,
     } = try_from_bytes(instruction_data).map_err(|_| ProgramError::InvalidInstructionData)?;
 
     if base_currency_multiplier == &0 || quote_currency_multiplier == &0 || tick_size == &0 {
@@ -131,17 +206,44 @@ pub(crate) fn process(
         return Err(ProgramError::InvalidArgument);
     }
 
+    let fee_model: MarketFeeModel =
+        FromPrimitive::from_u8(*fee_model).ok_or(ProgramError::InvalidInstructionData)?;
+    let fee_type: MarketFeeType =
+        FromPrimitive::from_u8(*fee_type).ok_or(ProgramError::InvalidInstructionData)?;
+
+    if fee_model != MarketFeeModel::Flat && *flat_taker_bps != 0 {
+        msg!("The flat taker fee only applies to flat-fee markets!");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if *referral_bps > 10_000 {
+        msg!("The referral cut cannot exceed the taker fees!");
+        return Err(ProgramError::InvalidArgument);
+    }
+
     let market_signer = Pubkey::create_program_address(
         &[&accounts.market.key.to_bytes(), &[*signer_nonce as u8]],
         program_id,
     )?;
     let base_mint = check_vault_account_and_get_mint(accounts.base_vault, &market_signer)?;
     let quote_mint = check_vault_account_and_get_mint(accounts.quote_vault, &market_signer)?;
+    check_account_key(
+        accounts.base_mint,
+        &base_mint,
+        DexError::InvalidBaseMintAccount,
+    )?;
+    check_account_key(
+        accounts.quote_mint,
+        &quote_mint,
+        DexError::InvalidQuoteMintAccount,
+    )?;
+    check_mint_extensions(accounts.base_mint)?;
+    check_mint_extensions(accounts.quote_mint)?;
 
     #[cfg(not(feature = "disable-mpl-checks"))]
     check_metadata_account(accounts.token_metadata, &base_mint)?;
 
-    let current_timestamp = Clock::get()?.unix_timestamp;
+    let clock = Clock::get()?;
     if accounts.market.data.borrow()[0] != AccountTag::Uninitialized as u8 {
         // Checking the first byte is sufficient as there is a small number of AccountTags
         msg!("The market account contains initialized state!");
@@ -150,17 +252,17 @@ pub(crate) fn process(
 
     let mut market_state = DexState::get_unchecked(accounts.market);
 
-    let royalties_bps = if accounts.token_metadata.data_len() != 0 {
+    let (royalties_bps, creators) = if accounts.token_metadata.data_len() != 0 {
         let metadata: Metadata = Metadata::from_account_info(accounts.token_metadata)?;
-        if let Some(creators) = &metadata.data.creators {
+        if let Some(creators) = metadata.data.creators {
             #[cfg(not(feature = "disable-mpl-checks"))]
-            verify_metadata(creators)?;
-            metadata.data.seller_fee_basis_points
+            verify_metadata(&creators)?;
+            (metadata.data.seller_fee_basis_points, creators)
         } else {
-            0
+            (0, vec![])
         }
     } else {
-        0
+        (0, vec![])
     };
 
     *market_state = DexState {
@@ -172,21 +274,70 @@ pub(crate) fn process(
         quote_vault: *accounts.quote_vault.key,
         orderbook: *accounts.orderbook.key,
         admin: *accounts.market_admin.key,
-        creation_timestamp: current_timestamp,
+        creation_timestamp: clock.unix_timestamp,
         base_volume: 0,
         quote_volume: 0,
         accumulated_fees: 0,
         min_base_order_size: *min_base_order_size,
-        fee_type: MarketFeeType::Default as u8,
-        _padding: [0; 6],
+        fee_type: fee_type as u8,
+        status: MarketStatus::Active as u8,
+        fee_model: fee_model as u8,
+        _padding: [0; 4],
         royalties_bps: royalties_bps as u64,
         accumulated_royalties: 0,
         base_currency_multiplier: *base_currency_multiplier,
         quote_currency_multiplier: *quote_currency_multiplier,
+        last_order_slot: clock.slot,
+        close_grace_slots: *close_grace_slots,
+        fee_tier_thresholds: if fee_tier_thresholds == &[0; 5] {
+            DEFAULT_FEE_TIER_THRESHOLDS
+        } else {
+            *fee_tier_thresholds
+        },
+        fee_tier_taker_rates: if fee_tier_taker_rates == &[0; 8] {
+            DEFAULT_FEE_TIER_TAKER_RATES
+        } else {
+            *fee_tier_taker_rates
+        },
+        fee_tier_maker_rebate_rates: if fee_tier_maker_rebate_rates == &[0; 8] {
+            DEFAULT_FEE_TIER_MAKER_REBATE_RATES
+        } else {
+            *fee_tier_maker_rebate_rates
+        },
+        pending_admin: Pubkey::default(),
+        sweep_authority: if sweep_authority == &Pubkey::default() {
+            *accounts.market_admin.key
+        } else {
+            *sweep_authority
+        },
+        token_program: *accounts.base_vault.owner,
+        min_quote_order_size: *min_quote_order_size,
+        last_price: 0,
+        cumulative_price: 0,
+        last_update_ts: 0,
+        max_deviation_bps: *max_deviation_bps,
+        referral_bps: *referral_bps,
+        flat_taker_bps: *flat_taker_bps,
+        _padding2: [0; 2],
+        creators: [Pubkey::default(); MAX_CREATOR_LIMIT],
+        creator_shares: [0; MAX_CREATOR_LIMIT],
+        number_of_creators: 0,
+        name: [0; MARKET_NAME_LEN],
+        ticker: [0; MARKET_TICKER_LEN],
+        _padding3: [0; 2],
+        promo_end_ts: *promo_end_ts,
+        unpaid_royalties: [0; MAX_CREATOR_LIMIT],
+        total_quote_backing: 0,
+        _padding4: [0; 8],
     };
+    market_state.cache_creators(&creators)?;
+    market_state.set_labels(name, ticker)?;
+    market_state.check_fee_schedule()?;
 
     let invoke_params = asset_agnostic_orderbook::instruction::create_market::Params {
-        min_base_order_size: *min_base_order_size / *base_currency_multiplier,
+        min_base_order_size: NativeQty(*min_base_order_size)
+            .to_scaled(*base_currency_multiplier)
+            .0,
         tick_size: *tick_size,
     };
     let invoke_accounts = asset_agnostic_orderbook::instruction::create_market::Accounts {
@@ -201,8 +352,7 @@ pub(crate) fn process(
         invoke_accounts,
         invoke_params,
     ) {
-        error.print::<AoError>();
-        return Err(DexError::AOBError.into());
+        return Err(DexError::from_aob_error(error).into());
     }
 
     Ok(())
@@ -212,7 +362,9 @@ fn check_vault_account_and_get_mint(
     account: &AccountInfo,
     market_signer: &Pubkey,
 ) -> Result<Pubkey, ProgramError> {
-    let acc = spl_token::state::Account::unpack(&account.data.borrow())?;
+    let data = account.data.borrow();
+    let state = StateWithExtensions::<Account>::unpack(&data)?;
+    let acc = state.base;
     if &acc.owner != market_signer {
         msg!("The vault account should be owned by the market signer");
         return Err(ProgramError::InvalidArgument);
@@ -224,6 +376,31 @@ fn check_vault_account_and_get_mint(
     Ok(acc.mint)
 }
 
+/// Only the mint extensions which cannot alter the amounts moved in and out of the vaults are accepted, any other
+/// extension (including the ones unknown to this program) could break the market's accounting.
+fn check_mint_extensions(mint: &AccountInfo) -> ProgramResult {
+    let data = mint.data.borrow();
+    let state = StateWithExtensions::<Mint>::unpack(&data)?;
+    // The transfer hook extension is unknown to the spl-token-2022 version this program is built against, its transfers
+    // could not be resolved so such mints must fail here
+    let extension_types = state.get_extension_types().map_err(|_| {
+        msg!("Mints with transfer hooks or other unknown extensions are not supported");
+        DexError::UnsupportedMintExtension
+    })?;
+    for extension_type in extension_types {
+        match extension_type {
+            ExtensionType::Uninitialized
+            | ExtensionType::MintCloseAuthority
+            | ExtensionType::DefaultAccountState => (),
+            _ => {
+                msg!("Unsupported mint extension: {:?}", extension_type);
+                return Err(DexError::UnsupportedMintExtension.into());
+            }
+        }
+    }
+    Ok(())
+}
+
 fn check_rent<'a>(accounts: &Accounts<'a, AccountInfo>) -> ProgramResult {
     check_rent_exempt(accounts.market)?;
     check_rent_exempt(accounts.orderbook)?;
@@ -0,0 +1,197 @@
+//! Close a market in an emergency, sweeping its uncollected fees and undistributed royalties to the market admin
+//! beforehand. This is an admin instruction.
+//!
+//! The vaults must not hold any user funds once the fees and royalties are swept, the other checks of close_market
+//! apply as well.
+use crate::{
+    error::DexError,
+    processor::{close_market, sweep_fees::check_vault_balance},
+    state::DexState,
+    utils::{
+        check_account_key, check_account_owner, check_signer, check_token_account_owner,
+        transfer_checked,
+    },
+};
+use bonfida_utils::BorshSize;
+use bonfida_utils::InstructionsAccount;
+use borsh::BorshDeserialize;
+use borsh::BorshSerialize;
+use bytemuck::{Pod, Zeroable};
+use mpl_token_metadata::state::MAX_CREATOR_LIMIT;
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+#[derive(Clone, Copy, BorshDeserialize, BorshSerialize, BorshSize, Pod, Zeroable)]
+#[repr(C)]
+pub struct Params {}
+
+#[derive(InstructionsAccount)]
+pub struct Accounts<'a, T> {
+    /// The market account
+    #[cons(writable)]
+    pub market: &'a T,
+
+    /// The market base vault account
+    #[cons(writable)]
+    pub base_vault: &'a T,
+
+    /// The market quote vault account
+    #[cons(writable)]
+    pub quote_vault: &'a T,
+
+    /// The quote token mint
+    pub quote_mint: &'a T,
+
+    /// The AOB orderbook account
+    #[cons(writable)]
+    pub orderbook: &'a T,
+
+    /// The AOB event queue account
+    #[cons(writable)]
+    pub event_queue: &'a T,
+
+    /// The AOB bids account
+    #[cons(writable)]
+    pub bids: &'a T,
+
+    /// The AOB asks account
+    #[cons(writable)]
+    pub asks: &'a T,
+
+    /// The market admin account
+    #[cons(signer)]
+    pub market_admin: &'a T,
+
+    /// The quote token account of the market admin which receives the fees and royalties
+    #[cons(writable)]
+    pub admin_quote_account: &'a T,
+
+    /// The target lamports account
+    #[cons(writable)]
+    pub target_lamports_account: &'a T,
+
+    /// The market signer
+    pub market_signer: &'a T,
+
+    /// The SPL token program ID
+    pub spl_token_program: &'a T,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+
+        let a = Self {
+            market: next_account_info(accounts_iter)?,
+            base_vault: next_account_info(accounts_iter)?,
+            quote_vault: next_account_info(accounts_iter)?,
+            quote_mint: next_account_info(accounts_iter)?,
+            orderbook: next_account_info(accounts_iter)?,
+            event_queue: next_account_info(accounts_iter)?,
+            bids: next_account_info(accounts_iter)?,
+            asks: next_account_info(accounts_iter)?,
+            market_admin: next_account_info(accounts_iter)?,
+            admin_quote_account: next_account_info(accounts_iter)?,
+            target_lamports_account: next_account_info(accounts_iter)?,
+            market_signer: next_account_info(accounts_iter)?,
+            spl_token_program: next_account_info(accounts_iter)?,
+        };
+
+        // Check owners
+        check_account_owner(a.market, program_id, DexError::InvalidStateAccountOwner)?;
+
+        // Check signers
+        check_signer(a.market_admin).map_err(|e| {
+            msg!("The market admin should be a signer for this transaction!");
+            e
+        })?;
+
+        Ok(a)
+    }
+
+    /// The accounts of the close_market instruction
+    fn close_market_accounts(&self) -> close_market::Accounts<'a, AccountInfo<'b>> {
+        close_market::Accounts {
+            market: self.market,
+            base_vault: self.base_vault,
+            quote_vault: self.quote_vault,
+            orderbook: self.orderbook,
+            event_queue: self.event_queue,
+            bids: self.bids,
+            asks: self.asks,
+            market_admin: self.market_admin,
+            target_lamports_account: self.target_lamports_account,
+            market_signer: self.market_signer,
+            spl_token_program: self.spl_token_program,
+        }
+    }
+}
+
+pub(crate) fn process(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let mut market_state = DexState::get(accounts.market)?;
+    check_accounts(&market_state, &accounts)?;
+    check_vault_balance(&market_state, accounts.quote_vault)?;
+
+    let amount = market_state.accumulated_fees + market_state.accumulated_royalties;
+    if amount != 0 {
+        msg!(
+            "Sweeping {} fees and {} royalties to the market admin",
+            market_state.accumulated_fees,
+            market_state.accumulated_royalties
+        );
+        transfer_checked(
+            accounts.spl_token_program,
+            accounts.quote_vault,
+            accounts.quote_mint,
+            accounts.admin_quote_account,
+            accounts.market_signer,
+            amount,
+            &[&[
+                &accounts.market.key.to_bytes(),
+                &[market_state.signer_nonce as u8],
+            ]],
+        )?;
+        market_state.accumulated_fees = 0;
+        market_state.accumulated_royalties = 0;
+        market_state.unpaid_royalties = [0; MAX_CREATOR_LIMIT];
+    }
+    drop(market_state);
+
+    close_market::close(program_id, &accounts.close_market_accounts())
+}
+
+fn check_accounts(market_state: &DexState, accounts: &Accounts<AccountInfo>) -> ProgramResult {
+    check_account_key(
+        accounts.market_admin,
+        &market_state.admin,
+        DexError::InvalidMarketAdminAccount,
+    )?;
+    check_account_key(
+        accounts.quote_vault,
+        &market_state.quote_vault,
+        DexError::InvalidQuoteVaultAccount,
+    )?;
+    check_account_key(
+        accounts.quote_mint,
+        &market_state.quote_mint,
+        DexError::InvalidQuoteMintAccount,
+    )?;
+    check_account_key(
+        accounts.spl_token_program,
+        &market_state.token_program_id(),
+        DexError::InvalidSplTokenProgram,
+    )?;
+    check_token_account_owner(accounts.admin_quote_account, accounts.market_admin.key)?;
+
+    Ok(())
+}
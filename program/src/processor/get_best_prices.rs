@@ -0,0 +1,147 @@
+//! Returns the best bid and ask of a market with `set_return_data`, so that clients and other programs can read the top
+//! of the book without parsing the orderbook. This instruction does not modify any account.
+use crate::{
+    error::DexError,
+    state::{CallBackInfo, DexState, ScaledQty},
+    utils::{check_account_key, check_account_owner},
+};
+use asset_agnostic_orderbook::state::{critbit::Slab, market_state::MarketState, AccountTag, Side};
+use bonfida_utils::BorshSize;
+use bonfida_utils::InstructionsAccount;
+use borsh::BorshDeserialize;
+use borsh::BorshSerialize;
+use bytemuck::{Pod, Zeroable};
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    program::set_return_data,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+use std::convert::TryInto;
+
+#[derive(Clone, Copy, BorshDeserialize, BorshSerialize, BorshSize, Pod, Zeroable)]
+#[repr(C)]
+pub struct Params {}
+
+/// The top of the book returned by the get_best_prices instruction.
+///
+/// The prices are FP32s of native quote per native base and the sizes are in native base units. Both are zero when the
+/// corresponding side of the book is empty.
+#[derive(Clone, Copy, Debug, PartialEq, BorshDeserialize, BorshSerialize)]
+pub struct BestPrices {
+    /// The price of the best bid
+    pub best_bid_price: u64,
+    /// The remaining base quantity of the best bid
+    pub best_bid_size: u64,
+    /// The price of the best ask
+    pub best_ask_price: u64,
+    /// The remaining base quantity of the best ask
+    pub best_ask_size: u64,
+}
+
+#[derive(InstructionsAccount)]
+pub struct Accounts<'a, T> {
+    /// The DEX market
+    pub market: &'a T,
+
+    /// The orderbook
+    pub orderbook: &'a T,
+
+    /// The AOB bids shared memory
+    pub bids: &'a T,
+
+    /// The AOB asks shared memory
+    pub asks: &'a T,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let a = Self {
+            market: next_account_info(accounts_iter)?,
+            orderbook: next_account_info(accounts_iter)?,
+            bids: next_account_info(accounts_iter)?,
+            asks: next_account_info(accounts_iter)?,
+        };
+        check_account_owner(a.market, program_id, DexError::InvalidStateAccountOwner)?;
+
+        Ok(a)
+    }
+}
+
+pub(crate) fn process(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let market_state = DexState::get(accounts.market)?;
+    check_accounts(&market_state, &accounts)?;
+
+    let (best_bid_price, best_bid_size) = get_best_order(&market_state, &accounts, Side::Bid)?;
+    let (best_ask_price, best_ask_size) = get_best_order(&market_state, &accounts, Side::Ask)?;
+
+    let best_prices = BestPrices {
+        best_bid_price,
+        best_bid_size,
+        best_ask_price,
+        best_ask_size,
+    };
+    set_return_data(&best_prices.try_to_vec()?);
+
+    Ok(())
+}
+
+/// Returns the native price and size of the best order on a side of the book, or zeroes when it is empty
+fn get_best_order(
+    market_state: &DexState,
+    accounts: &Accounts<AccountInfo>,
+    side: Side,
+) -> Result<(u64, u64), ProgramError> {
+    let (book, tag) = match side {
+        Side::Bid => (accounts.bids, AccountTag::Bids),
+        Side::Ask => (accounts.asks, AccountTag::Asks),
+    };
+    let mut book_guard = book.data.borrow_mut();
+    let slab = Slab::<CallBackInfo>::from_buffer(&mut book_guard, tag)?;
+    let best_handle = match side {
+        Side::Bid => slab.find_max(),
+        Side::Ask => slab.find_min(),
+    };
+    let leaf = match best_handle {
+        Some(h) => &slab.leaf_nodes[h as usize],
+        None => return Ok((0, 0)),
+    };
+    let price = (leaf.price() as u128)
+        .checked_mul(market_state.quote_currency_multiplier as u128)
+        .and_then(|n| n.checked_div(market_state.base_currency_multiplier as u128))
+        .and_then(|n| n.try_into().ok())
+        .ok_or(DexError::NumericalOverflow)?;
+    let size = market_state
+        .unscale_base_amount(ScaledQty(leaf.base_quantity))?
+        .0;
+    Ok((price, size))
+}
+
+fn check_accounts(market_state: &DexState, accounts: &Accounts<AccountInfo>) -> ProgramResult {
+    check_account_key(
+        accounts.orderbook,
+        &market_state.orderbook,
+        DexError::InvalidOrderbookAccount,
+    )?;
+    let mut orderbook_guard = accounts.orderbook.data.borrow_mut();
+    let orderbook = MarketState::from_buffer(&mut orderbook_guard, AccountTag::Market)?;
+    check_account_key(
+        accounts.bids,
+        &orderbook.bids,
+        DexError::InvalidOrderbookAccount,
+    )?;
+    check_account_key(
+        accounts.asks,
+        &orderbook.asks,
+        DexError::InvalidOrderbookAccount,
+    )?;
+
+    Ok(())
+}
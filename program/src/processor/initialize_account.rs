@@ -19,7 +19,7 @@ use solana_program::{
 
 use crate::{
     error::DexError,
-    state::{Order, UserAccount, UserAccountHeader, USER_ACCOUNT_HEADER_LEN},
+    state::{find_user_account, UserAccount, UserAccountHeader},
     utils::{check_account_key, check_account_owner, check_signer},
 };
 
@@ -48,7 +48,7 @@ pub struct Accounts<'a, T> {
     #[cons(signer)]
     pub user_owner: &'a T,
 
-    /// The fee payer
+    /// The fee payer, which is recorded as the rent payer of the user account
     #[cons(writable, signer)]
     pub fee_payer: &'a T,
 }
@@ -95,10 +95,8 @@ pub(crate) fn process(
         try_from_bytes(instruction_data).map_err(|_| ProgramError::InvalidInstructionData)?;
 
     let market_key_bytes = market.to_bytes();
-    let (user_account_key, user_account_nonce) = Pubkey::find_program_address(
-        &[&market_key_bytes, &accounts.user_owner.key.to_bytes()],
-        program_id,
-    );
+    let (user_account_key, user_account_nonce) =
+        find_user_account(program_id, market, accounts.user_owner.key);
 
     if &user_account_key != accounts.user.key {
         msg!("Provided an invalid user account for the specified market and owner");
@@ -110,19 +108,16 @@ pub(crate) fn process(
         return Err(ProgramError::InvalidArgument);
     }
 
-    // (USER_ACCOUNT_HEADER_LEN as u64) + max_orders * (Order::LEN as u64);
-    let space = max_orders
-        .checked_mul(Order::LEN as u64)
-        .and_then(|n| n.checked_add(USER_ACCOUNT_HEADER_LEN as u64))
+    let space = UserAccount::compute_allocation_size(*max_orders as usize)
         .ok_or(DexError::NumericalOverflow)?;
 
-    let lamports = Rent::get()?.minimum_balance(space as usize);
+    let lamports = Rent::get()?.minimum_balance(space);
 
     let allocate_account = create_account(
         accounts.fee_payer.key,
         accounts.user.key,
         lamports,
-        space,
+        space as u64,
         program_id,
     );
 
@@ -143,7 +138,7 @@ pub(crate) fn process(
     let mut user_account_data = accounts.user.data.borrow_mut();
     let u = UserAccount::from_buffer_unchecked(&mut user_account_data)?;
 
-    *(u.header) = UserAccountHeader::new(market, accounts.user_owner.key);
+    *(u.header) = UserAccountHeader::new(market, accounts.user_owner.key, accounts.fee_payer.key);
 
     Ok(())
 }
@@ -0,0 +1,155 @@
+//! Initialize the candle history of a market, which consume_events updates when it is given. This is an admin instruction.
+use bonfida_utils::BorshSize;
+use bonfida_utils::InstructionsAccount;
+use borsh::BorshDeserialize;
+use borsh::BorshSerialize;
+use bytemuck::{try_from_bytes, try_from_bytes_mut, Pod, Zeroable};
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::invoke_signed,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction::create_account,
+    system_program,
+    sysvar::Sysvar,
+};
+
+use crate::{
+    error::DexError,
+    state::{
+        find_candle_history, AccountTag, CandleHistory, DexState, CANDLE_HISTORY_LEN,
+        CANDLE_HISTORY_SEED,
+    },
+    utils::{check_account_key, check_account_owner, check_signer},
+};
+
+#[derive(Clone, Copy, Zeroable, Pod, BorshDeserialize, BorshSerialize, BorshSize)]
+#[repr(C)]
+/**
+The required arguments for a initialize_candle_history instruction.
+*/
+pub struct Params {
+    /// The length in seconds of a candle's interval
+    pub interval: i64,
+}
+
+#[derive(InstructionsAccount)]
+pub struct Accounts<'a, T> {
+    /// The system program
+    pub system_program: &'a T,
+
+    /// The DEX market
+    pub market: &'a T,
+
+    /// The candle history to initialize
+    #[cons(writable)]
+    pub candle_history: &'a T,
+
+    /// The market admin account
+    #[cons(signer)]
+    pub market_admin: &'a T,
+
+    /// The fee payer
+    #[cons(writable, signer)]
+    pub fee_payer: &'a T,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let a = Self {
+            system_program: next_account_info(accounts_iter)?,
+            market: next_account_info(accounts_iter)?,
+            candle_history: next_account_info(accounts_iter)?,
+            market_admin: next_account_info(accounts_iter)?,
+            fee_payer: next_account_info(accounts_iter)?,
+        };
+        check_signer(a.market_admin).map_err(|e| {
+            msg!("The market admin should be a signer for this transaction!");
+            e
+        })?;
+        check_account_key(
+            a.system_program,
+            &system_program::ID,
+            DexError::InvalidSystemProgramAccount,
+        )?;
+        check_account_owner(a.market, program_id, DexError::InvalidStateAccountOwner)?;
+        check_account_owner(
+            a.candle_history,
+            &system_program::ID,
+            DexError::InvalidStateAccountOwner,
+        )?;
+
+        Ok(a)
+    }
+}
+
+pub(crate) fn process(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let Params { interval } =
+        try_from_bytes(instruction_data).map_err(|_| ProgramError::InvalidInstructionData)?;
+
+    if *interval <= 0 {
+        msg!("The candle interval should be positive");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let market_state = DexState::get(accounts.market)?;
+    check_account_key(
+        accounts.market_admin,
+        &market_state.admin,
+        DexError::InvalidMarketAdminAccount,
+    )?;
+
+    let (candle_history_key, candle_history_nonce) =
+        find_candle_history(program_id, accounts.market.key);
+    if &candle_history_key != accounts.candle_history.key {
+        msg!("Provided an invalid candle history account for the specified market");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let allocate_account = create_account(
+        accounts.fee_payer.key,
+        accounts.candle_history.key,
+        Rent::get()?.minimum_balance(CANDLE_HISTORY_LEN),
+        CANDLE_HISTORY_LEN as u64,
+        program_id,
+    );
+
+    invoke_signed(
+        &allocate_account,
+        &[
+            accounts.system_program.clone(),
+            accounts.fee_payer.clone(),
+            accounts.candle_history.clone(),
+        ],
+        &[&[
+            &accounts.market.key.to_bytes(),
+            CANDLE_HISTORY_SEED,
+            &[candle_history_nonce],
+        ]],
+    )?;
+
+    let mut candle_history_data = accounts.candle_history.data.borrow_mut();
+    let candle_history: &mut CandleHistory = try_from_bytes_mut(&mut candle_history_data)
+        .map_err(|_| ProgramError::InvalidAccountData)?;
+    *candle_history = CandleHistory {
+        tag: AccountTag::CandleHistory as u64,
+        market: *accounts.market.key,
+        interval: *interval,
+        ..CandleHistory::zeroed()
+    };
+
+    Ok(())
+}
@@ -0,0 +1,144 @@
+//! Upgrade the dex state of a market created under the legacy layout to the current one. This is an admin instruction.
+//!
+//! The market account is grown to the current size, the fee payer funding the additional rent. Markets which already use
+//! the current layout are left untouched.
+//!
+//! The quote tokens held on behalf of the user accounts aren't tracked by the legacy layout, they are initialized to the
+//! quote vault balance which isn't accounted for as fees or royalties.
+use bonfida_utils::BorshSize;
+use bonfida_utils::InstructionsAccount;
+use borsh::BorshDeserialize;
+use borsh::BorshSerialize;
+use bytemuck::{try_from_bytes, Pod, Zeroable};
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::invoke,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction::transfer,
+    system_program,
+    sysvar::Sysvar,
+};
+
+use crate::{
+    error::DexError,
+    state::{AccountTag, DexState, LegacyDexState, DEX_STATE_LEN, LEGACY_DEX_STATE_LEN},
+    utils::{check_account_key, check_account_owner, check_signer, unpack_token_account},
+};
+
+#[derive(Clone, Copy, BorshDeserialize, BorshSerialize, BorshSize, Pod, Zeroable)]
+#[repr(C)]
+pub struct Params {}
+
+#[derive(InstructionsAccount)]
+pub struct Accounts<'a, T> {
+    /// The system program
+    pub system_program: &'a T,
+
+    /// The DEX market
+    #[cons(writable)]
+    pub market: &'a T,
+
+    /// The quote token vault
+    pub quote_vault: &'a T,
+
+    /// The market admin account
+    #[cons(signer)]
+    pub market_admin: &'a T,
+
+    /// The fee payer
+    #[cons(writable, signer)]
+    pub fee_payer: &'a T,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let a = Self {
+            system_program: next_account_info(accounts_iter)?,
+            market: next_account_info(accounts_iter)?,
+            quote_vault: next_account_info(accounts_iter)?,
+            market_admin: next_account_info(accounts_iter)?,
+            fee_payer: next_account_info(accounts_iter)?,
+        };
+        check_signer(a.market_admin).map_err(|e| {
+            msg!("The market admin should be a signer for this transaction!");
+            e
+        })?;
+        check_account_key(
+            a.system_program,
+            &system_program::ID,
+            DexError::InvalidSystemProgramAccount,
+        )?;
+        check_account_owner(a.market, program_id, DexError::InvalidStateAccountOwner)?;
+
+        Ok(a)
+    }
+}
+
+pub(crate) fn process(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    if accounts.market.data_len() == DEX_STATE_LEN {
+        let market_state = DexState::get(accounts.market)?;
+        check_account_key(
+            accounts.market_admin,
+            &market_state.admin,
+            DexError::InvalidMarketAdminAccount,
+        )?;
+        msg!("The market state already uses the current layout");
+        return Ok(());
+    }
+
+    if accounts.market.data_len() != LEGACY_DEX_STATE_LEN {
+        msg!("The market state layout is not recognized");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let legacy_state = *try_from_bytes::<LegacyDexState>(&accounts.market.data.borrow())
+        .map_err(|_| ProgramError::InvalidAccountData)?;
+    if legacy_state.tag != AccountTag::DexState as u64 {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    check_account_key(
+        accounts.market_admin,
+        &legacy_state.admin,
+        DexError::InvalidMarketAdminAccount,
+    )?;
+    check_account_key(
+        accounts.quote_vault,
+        &legacy_state.quote_vault,
+        DexError::InvalidQuoteVaultAccount,
+    )?;
+    let total_quote_backing = unpack_token_account(accounts.quote_vault)?
+        .amount
+        .saturating_sub(legacy_state.accumulated_fees)
+        .saturating_sub(legacy_state.accumulated_royalties);
+
+    let lamports_needed = Rent::get()?
+        .minimum_balance(DEX_STATE_LEN)
+        .saturating_sub(accounts.market.lamports());
+    if lamports_needed != 0 {
+        invoke(
+            &transfer(accounts.fee_payer.key, accounts.market.key, lamports_needed),
+            &[
+                accounts.system_program.clone(),
+                accounts.fee_payer.clone(),
+                accounts.market.clone(),
+            ],
+        )?;
+    }
+
+    accounts.market.realloc(DEX_STATE_LEN, true)?;
+    let mut market_state = DexState::get_unchecked(accounts.market);
+    *market_state = DexState::from_legacy(&legacy_state);
+    market_state.total_quote_backing = total_quote_backing;
+
+    Ok(())
+}
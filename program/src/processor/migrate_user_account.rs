@@ -0,0 +1,129 @@
+//! Upgrade a user account created under the legacy header layout to the current one.
+//!
+//! The header is grown in place and the orders are moved after it, the fee payer funding the additional rent. Anyone can
+//! migrate a user account, so that cranks can upgrade the accounts referenced by the event queue. User accounts which
+//! already use the current layout are left untouched.
+use bonfida_utils::BorshSize;
+use bonfida_utils::InstructionsAccount;
+use borsh::BorshDeserialize;
+use borsh::BorshSerialize;
+use bytemuck::{try_from_bytes, try_from_bytes_mut, Pod, Zeroable};
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::invoke,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction::transfer,
+    system_program,
+    sysvar::Sysvar,
+};
+
+use crate::{
+    error::DexError,
+    state::{
+        AccountTag, LegacyUserAccountHeader, Order, UserAccountHeader,
+        LEGACY_USER_ACCOUNT_HEADER_LEN, USER_ACCOUNT_HEADER_LEN,
+    },
+    utils::{check_account_key, check_account_owner, check_signer},
+};
+
+#[derive(Clone, Copy, BorshDeserialize, BorshSerialize, BorshSize, Pod, Zeroable)]
+#[repr(C)]
+pub struct Params {}
+
+#[derive(InstructionsAccount)]
+pub struct Accounts<'a, T> {
+    /// The system program
+    pub system_program: &'a T,
+
+    /// The user account to migrate
+    #[cons(writable)]
+    pub user: &'a T,
+
+    /// The fee payer
+    #[cons(writable, signer)]
+    pub fee_payer: &'a T,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let a = Self {
+            system_program: next_account_info(accounts_iter)?,
+            user: next_account_info(accounts_iter)?,
+            fee_payer: next_account_info(accounts_iter)?,
+        };
+        check_signer(a.fee_payer).map_err(|e| {
+            msg!("The fee payer should be a signer for this transaction!");
+            e
+        })?;
+        check_account_key(
+            a.system_program,
+            &system_program::ID,
+            DexError::InvalidSystemProgramAccount,
+        )?;
+        check_account_owner(a.user, program_id, DexError::InvalidStateAccountOwner)?;
+
+        Ok(a)
+    }
+}
+
+pub(crate) fn process(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let legacy_header = *accounts
+        .user
+        .data
+        .borrow()
+        .get(..LEGACY_USER_ACCOUNT_HEADER_LEN)
+        .and_then(|d| try_from_bytes::<LegacyUserAccountHeader>(d).ok())
+        .ok_or(ProgramError::InvalidAccountData)?;
+    if legacy_header.tag == AccountTag::UserAccount as u64 {
+        msg!("The user account already uses the current layout");
+        return Ok(());
+    }
+    if legacy_header.tag != AccountTag::LegacyUserAccount as u64 {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let legacy_len = accounts.user.data_len();
+    let orders_len = legacy_len - LEGACY_USER_ACCOUNT_HEADER_LEN;
+    if orders_len % Order::LEN != 0 {
+        msg!("The user account layout is not recognized");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let new_space = USER_ACCOUNT_HEADER_LEN + orders_len;
+    let lamports_needed = Rent::get()?
+        .minimum_balance(new_space)
+        .saturating_sub(accounts.user.lamports());
+    if lamports_needed != 0 {
+        invoke(
+            &transfer(accounts.fee_payer.key, accounts.user.key, lamports_needed),
+            &[
+                accounts.system_program.clone(),
+                accounts.fee_payer.clone(),
+                accounts.user.clone(),
+            ],
+        )?;
+    }
+
+    accounts.user.realloc(new_space, false)?;
+    let mut user_account_data = accounts.user.data.borrow_mut();
+    // The order slots are moved after the grown header, the legacy header is then overwritten
+    user_account_data.copy_within(
+        LEGACY_USER_ACCOUNT_HEADER_LEN..legacy_len,
+        USER_ACCOUNT_HEADER_LEN,
+    );
+    *try_from_bytes_mut::<UserAccountHeader>(&mut user_account_data[..USER_ACCOUNT_HEADER_LEN])
+        .map_err(|_| ProgramError::InvalidAccountData)? =
+        UserAccountHeader::from_legacy(&legacy_header);
+
+    Ok(())
+}
@@ -1,12 +1,30 @@
 //! Execute a new order instruction. Supported types include Limit, IOC, FOK, or Post only.
+//!
+//! The order is funded from the free balance of the user account first, only the remainder being transferred from the
+//! user token account. Makers can then repost their settled proceeds without a round trip through their wallet, the
+//! repost instruction doing so without requiring a user token account at all.
+//!
+//! The user owner can be a program derived address, in which case the owning program places the order through a CPI,
+//! signing for the PDA with `invoke_signed` and its seeds, e.g. `invoke_signed(&ix, accounts, &[&[b"seed", &[bump]]])`.
+//! The DEX then transfers the order's funds from the user token account with the PDA as authority, so the PDA must own
+//! the token account or be its delegate. The user account itself is created by an initialize_account CPI signed the same way.
 use crate::{
     error::DexError,
-    state::{CallBackInfo, DexState, FeeTier, Order, UserAccount},
+    state::{
+        CallBackInfo, DexState, DuplicateClientIdPolicy, FeeTier, MarketStatus, NativeQty, Order,
+        Referrer, ScaledQty, UserAccount,
+    },
     utils::check_account_owner,
-    utils::{check_account_key, check_signer},
+    utils::{
+        check_account_key, check_discount_token_account, check_signer, check_token_account_mint,
+        transfer_checked, unpack_token_account,
+    },
+};
+use asset_agnostic_orderbook::state::{
+    critbit::Slab,
+    event_queue::{EventQueue, EventRef, FillEventRef},
+    AccountTag, SelfTradeBehavior, Side,
 };
-use asset_agnostic_orderbook::error::AoError;
-use asset_agnostic_orderbook::state::Side;
 use bonfida_utils::BorshSize;
 use bonfida_utils::InstructionsAccount;
 use borsh::BorshDeserialize;
@@ -16,16 +34,22 @@ use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
+    clock::Clock,
     entrypoint::ProgramResult,
     msg,
-    program::{invoke, invoke_signed},
-    program_error::{PrintProgramError, ProgramError},
+    program_error::ProgramError,
     pubkey::Pubkey,
     system_program,
+    sysvar::Sysvar,
 };
 
 use super::REFERRAL_MASK;
 
+/// The number of orders to match against when the given match limit is zero
+pub const DEFAULT_MATCH_LIMIT: u64 = 10;
+/// The maximum number of orders to match against, higher match limits are capped to it
+pub const MAX_MATCH_LIMIT: u64 = 64;
+
 #[derive(Copy, Clone, Zeroable, Pod, BorshDeserialize, BorshSerialize, BorshSize)]
 #[repr(C)]
 /**
@@ -46,6 +70,7 @@ pub struct Params {
     /// The maximum number of orders to be matched against.
     ///
     /// Setting this number too high can sometimes lead to excessive resource consumption which can cause a failure.
+    /// It is capped to [`MAX_MATCH_LIMIT`], and a zero value is replaced with [`DEFAULT_MATCH_LIMIT`].
     pub match_limit: u64,
     /// The order's side (Bid or Ask)
     pub side: u8,
@@ -55,12 +80,21 @@ pub struct Params {
     pub self_trade_behavior: u8,
     /// Whether or not the optional discount token account was given
     pub has_discount_token_account: u8,
+    /// When set, a FillOrKill order which cannot be fully filled keeps its partial fill (as an ImmediateOrCancel order would) instead of aborting
+    pub fok_fallback_ioc: u8,
+    /// When set, the order can only reduce the user's exposure: the base quantity of an ask is capped to the
+    /// user account's base balance (free and locked), and the quote quantity of a bid to its quote balance.
+    /// The order is trimmed to this cap instead of being rejected.
+    pub reduce_only: u8,
+    /// Configures what happens when an active order of the user account already uses the same nonzero client order id,
+    /// see [`DuplicateClientIdPolicy`]
+    pub duplicate_client_id_policy: u8,
     /// To eliminate implicit padding
-    pub _padding: u32,
+    pub _padding: [u8; 1],
 }
 
 /// This enum describes all supported order types
-#[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq, FromPrimitive)]
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy, Debug, PartialEq, FromPrimitive)]
 pub enum OrderType {
     #[allow(missing_docs)]
     Limit,
@@ -108,6 +142,15 @@ pub struct Accounts<'a, T> {
     #[cons(writable)]
     pub quote_vault: &'a T,
 
+    /// The base token mint
+    pub base_mint: &'a T,
+
+    /// The quote token mint
+    pub quote_mint: &'a T,
+
+    /// The DEX market signer, which transfers the referral fee out of the quote vault
+    pub market_signer: &'a T,
+
     /// The DEX user account
     #[cons(writable)]
     pub user: &'a T,
@@ -116,7 +159,7 @@ pub struct Accounts<'a, T> {
     #[cons(writable)]
     pub user_token_account: &'a T,
 
-    /// The user wallet
+    /// The user wallet, or the user account's delegate
     #[cons(writable, signer)]
     pub user_owner: &'a T,
 
@@ -126,6 +169,10 @@ pub struct Accounts<'a, T> {
     /// The optional referrer's token account which will receive a 20% cut of the fees
     #[cons(writable)]
     pub fee_referral_account: Option<&'a T>,
+
+    /// The optional referrer account tracking the referral volume and fees, which requires the referral account
+    #[cons(writable)]
+    pub referrer: Option<&'a T>,
 }
 
 impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
@@ -145,6 +192,9 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
             asks: next_account_info(accounts_iter)?,
             base_vault: next_account_info(accounts_iter)?,
             quote_vault: next_account_info(accounts_iter)?,
+            base_mint: next_account_info(accounts_iter)?,
+            quote_mint: next_account_info(accounts_iter)?,
+            market_signer: next_account_info(accounts_iter)?,
             user: next_account_info(accounts_iter)?,
             user_token_account: next_account_info(accounts_iter)?,
             user_owner: next_account_info(accounts_iter)?,
@@ -154,6 +204,7 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
                 None
             },
             fee_referral_account: next_account_info(accounts_iter).ok(),
+            referrer: next_account_info(accounts_iter).ok(),
         };
 
         check_signer(a.user_owner).map_err(|e| {
@@ -161,11 +212,6 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
             e
         })?;
 
-        check_account_key(
-            a.spl_token_program,
-            &spl_token::ID,
-            DexError::InvalidSplTokenProgram,
-        )?;
         check_account_key(
             a.system_program,
             &system_program::ID,
@@ -177,7 +223,7 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
                 discount_account,
                 &spl_token::ID,
                 DexError::InvalidSplTokenProgram,
-            )?
+            )?;
         }
         check_account_owner(a.user, program_id, DexError::InvalidStateAccountOwner)?;
         check_account_owner(a.market, program_id, DexError::InvalidStateAccountOwner)?;
@@ -190,16 +236,11 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
         user_account_data: &'a mut [u8],
     ) -> Result<UserAccount<'a>, ProgramError> {
         let user_account = UserAccount::from_buffer(user_account_data)?;
-        if &user_account.header.owner != self.user_owner.key {
-            msg!("Invalid user account owner provided!");
-            return Err(ProgramError::InvalidArgument);
-        }
-        if &user_account.header.market != self.market.key {
-            msg!("The provided user account doesn't match the current market");
-            return Err(ProgramError::InvalidArgument);
-        };
-        if &user_account.header.owner != self.user_owner.key {
-            msg!("Invalid user account owner provided!");
+        if !user_account
+            .header
+            .is_owner_or_delegate(self.user_owner.key)
+        {
+            msg!("Invalid user account owner or delegate provided!");
             return Err(ProgramError::InvalidArgument);
         }
         if &user_account.header.market != self.market.key {
@@ -210,6 +251,31 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
     }
 }
 
+/// A single order to be matched against the orderbook, then posted
+pub(crate) struct OrderRequest {
+    pub client_order_id: u128,
+    pub side: Side,
+    pub limit_price: u64,
+    pub max_base_qty: u64,
+    pub max_quote_qty: u64,
+    pub match_limit: u64,
+    pub order_type: OrderType,
+    pub self_trade_behavior: SelfTradeBehavior,
+    pub fok_fallback_ioc: bool,
+    pub reduce_only: bool,
+    pub duplicate_client_id_policy: DuplicateClientIdPolicy,
+}
+
+/// The token amounts owed by the user once an order has been matched and posted
+pub(crate) struct OrderTransfer {
+    /// The quantity to transfer from the user to the vault of the order's side
+    pub qty_to_transfer: u64,
+    /// The cut of the taker fees owed to the optional referrer
+    pub referral_fee: u64,
+    /// The quote quantity matched by the order, on which the referral fee is taken
+    pub matched_quote_qty: u64,
+}
+
 pub(crate) fn process(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
@@ -219,58 +285,213 @@ pub(crate) fn process(
         side,
         limit_price,
         max_base_qty,
-        mut max_quote_qty,
+        max_quote_qty,
         order_type,
         self_trade_behavior,
         match_limit,
         has_discount_token_account,
         client_order_id,
+        fok_fallback_ioc,
+        reduce_only,
+        duplicate_client_id_policy,
         ..
     } = try_from_bytes(instruction_data).map_err(|_| ProgramError::InvalidInstructionData)?;
     #[cfg(any(target_arch = "aarch64", feature = "aarch64-test"))]
     let client_order_id: &u128 = bytemuck::cast_ref(client_order_id);
+    let side: Side = FromPrimitive::from_u8(*side).ok_or(ProgramError::InvalidInstructionData)?;
+    let order_type: OrderType =
+        FromPrimitive::from_u8(*order_type).ok_or(ProgramError::InvalidInstructionData)?;
+    let self_trade_behavior: SelfTradeBehavior =
+        FromPrimitive::from_u8(*self_trade_behavior).ok_or(ProgramError::InvalidInstructionData)?;
+    let duplicate_client_id_policy: DuplicateClientIdPolicy =
+        FromPrimitive::from_u8(*duplicate_client_id_policy)
+            .ok_or(ProgramError::InvalidInstructionData)?;
     let accounts = Accounts::parse(program_id, accounts, *has_discount_token_account != 0)?;
 
-    let market_state = DexState::get(accounts.market)?;
+    let mut market_state = DexState::get(accounts.market)?;
+
+    if market_state.status != MarketStatus::Active as u8 {
+        msg!("The market is not accepting new orders.");
+        return Err(DexError::MarketPaused.into());
+    }
+
     let mut user_account_data = accounts.user.data.borrow_mut();
     let mut user_account = accounts.load_user_account(&mut user_account_data)?;
 
+    check_accounts(program_id, &market_state, &accounts)?;
+    if let Some(a) = accounts.fee_referral_account {
+        check_referral_account(a, accounts.quote_vault, &user_account.header.owner)?;
+    }
+    if side == Side::Bid {
+        check_token_account_mint(
+            accounts.user_token_account,
+            &market_state.quote_mint,
+            DexError::InvalidQuoteTokenAccountMint,
+        )?;
+    } else {
+        check_token_account_mint(
+            accounts.user_token_account,
+            &market_state.base_mint,
+            DexError::InvalidBaseTokenAccountMint,
+        )?;
+    }
+    // The fee tier is the one of the user account owner, even when a delegate places the order
+    if let Some(a) = accounts.discount_token_account {
+        check_discount_token_account(a, &user_account.header.owner)?;
+    }
+    let fee_tier = FeeTier::get_for_taker(
+        &market_state,
+        accounts.discount_token_account,
+        &user_account.header.owner,
+        Some(&*user_account.header),
+        Clock::get()?.unix_timestamp,
+    )?;
+
+    let OrderTransfer {
+        qty_to_transfer,
+        referral_fee,
+        matched_quote_qty,
+    } = execute_order(
+        program_id,
+        &accounts,
+        &mut market_state,
+        &mut user_account,
+        fee_tier,
+        OrderRequest {
+            client_order_id: *client_order_id,
+            side,
+            limit_price: *limit_price,
+            max_base_qty: *max_base_qty,
+            max_quote_qty: *max_quote_qty,
+            match_limit: *match_limit,
+            order_type,
+            self_trade_behavior,
+            fok_fallback_ioc: *fok_fallback_ioc != 0,
+            reduce_only: *reduce_only != 0,
+            duplicate_client_id_policy,
+        },
+    )?;
+
+    let (transfer_destination, transfer_mint) = match side {
+        Side::Bid => (accounts.quote_vault, accounts.quote_mint),
+        Side::Ask => (accounts.base_vault, accounts.base_mint),
+    };
+    if qty_to_transfer != 0 {
+        transfer_to_vault(
+            &accounts,
+            accounts.user_token_account,
+            transfer_destination,
+            transfer_mint,
+            qty_to_transfer,
+        )?;
+    }
+
+    if let Some(a) = accounts.fee_referral_account {
+        transfer_referral_fee(&market_state, &accounts, a, referral_fee)?;
+        if let Some(referrer) = accounts.referrer {
+            record_referral(
+                program_id,
+                accounts.market,
+                a,
+                referrer,
+                matched_quote_qty,
+                referral_fee,
+            )?;
+        }
+    }
+
+    market_state.last_order_slot = Clock::get()?.slot;
+
+    Ok(())
+}
+
+/// Matches a single order against the orderbook and posts the rest of it, updating the market and user account balances.
+///
+/// No tokens are transferred, the amounts owed by the user are returned instead.
+pub(crate) fn execute_order(
+    program_id: &Pubkey,
+    accounts: &Accounts<AccountInfo>,
+    market_state: &mut DexState,
+    user_account: &mut UserAccount,
+    fee_tier: FeeTier,
+    order: OrderRequest,
+) -> Result<OrderTransfer, ProgramError> {
+    let OrderRequest {
+        client_order_id,
+        side,
+        limit_price,
+        mut max_base_qty,
+        mut max_quote_qty,
+        match_limit,
+        order_type,
+        self_trade_behavior,
+        fok_fallback_ioc,
+        reduce_only,
+        duplicate_client_id_policy,
+    } = order;
+    let quote_balance_before = user_account.header.quote_balance();
+
+    if reduce_only {
+        match side {
+            Side::Bid => {
+                let quote_balance = user_account
+                    .header
+                    .quote_token_free
+                    .saturating_add(user_account.header.quote_token_locked);
+                max_quote_qty = max_quote_qty.min(quote_balance);
+            }
+            Side::Ask => {
+                let base_balance = user_account
+                    .header
+                    .base_token_free
+                    .saturating_add(user_account.header.base_token_locked);
+                max_base_qty = max_base_qty.min(base_balance);
+            }
+        }
+    }
+
     // Check the order size
-    if max_base_qty < &market_state.min_base_order_size {
+    if max_base_qty < market_state.min_base_order_size {
         msg!("The base order size is too small.");
         return Err(ProgramError::InvalidArgument);
     }
+    if market_state.min_quote_order_size != 0 {
+        // An overflowing quote value is necessarily above the minimum
+        let order_quote_qty = market_state
+            .get_quote_from_base(NativeQty(max_base_qty), limit_price)
+            .map_or(u64::MAX, |q| q.0)
+            .min(max_quote_qty);
+        if order_quote_qty < market_state.min_quote_order_size {
+            msg!("The quote order size is too small.");
+            return Err(ProgramError::InvalidArgument);
+        }
+    }
 
-    check_accounts(&market_state, &accounts).unwrap();
-    let (post_only, post_allowed) = match FromPrimitive::from_u8(*order_type).unwrap() {
+    let (post_only, post_allowed) = match order_type {
         OrderType::Limit => (false, true),
         OrderType::ImmediateOrCancel | OrderType::FillOrKill => (false, false),
         OrderType::PostOnly => (true, true),
     };
-    let fee_tier = accounts
-        .discount_token_account
-        .map(|a| FeeTier::get(&market_state, a, accounts.user_owner.key))
-        .unwrap_or(Ok(FeeTier::Base))?;
     let callback_info = CallBackInfo {
         user_account: *accounts.user.key,
         fee_tier: fee_tier as u8
             | ((accounts.fee_referral_account.is_some() as u8) * REFERRAL_MASK),
     };
-    if *side == Side::Bid as u8 && *order_type != OrderType::PostOnly as u8 {
-        // We make sure to leave enough quote quantity to pay for taker fees in the worst case
-        max_quote_qty = fee_tier.remove_taker_fee(max_quote_qty);
+    if side == Side::Bid && order_type != OrderType::PostOnly {
+        // We make sure to leave enough quote quantity to pay for taker fees and royalties in the worst case
+        max_quote_qty = fee_tier.remove_fees(market_state, max_quote_qty);
     }
 
     let invoke_params = asset_agnostic_orderbook::instruction::new_order::Params {
-        max_base_qty: market_state.scale_base_amount(*max_base_qty),
-        max_quote_qty: market_state.scale_quote_amount(max_quote_qty),
-        limit_price: *limit_price,
-        side: FromPrimitive::from_u8(*side).unwrap(),
-        match_limit: *match_limit,
+        max_base_qty: market_state.scale_base_amount(NativeQty(max_base_qty)).0,
+        max_quote_qty: market_state.scale_quote_amount(NativeQty(max_quote_qty)).0,
+        limit_price,
+        side,
+        match_limit: capped_match_limit(match_limit),
         callback_info,
         post_only,
         post_allowed,
-        self_trade_behavior: FromPrimitive::from_u8(*self_trade_behavior).unwrap(),
+        self_trade_behavior,
     };
     let invoke_accounts = asset_agnostic_orderbook::instruction::new_order::Accounts {
         market: accounts.orderbook,
@@ -279,85 +500,94 @@ pub(crate) fn process(
         asks: accounts.asks,
     };
 
+    let events_before = event_queue_len(accounts.event_queue)?;
     let mut order_summary = match asset_agnostic_orderbook::instruction::new_order::process(
         program_id,
         invoke_accounts,
         invoke_params,
     ) {
         Err(error) => {
-            error.print::<AoError>();
-            return Err(DexError::AOBError.into());
+            let error = DexError::from_aob_error(error);
+            if let DexError::TransactionAborted = error {
+                // The orderbook is left untouched, and so are the user account balances
+                msg!("The order would self trade and has caused an abort");
+            }
+            return Err(error.into());
         }
         Ok(s) => s,
     };
 
-    market_state
-        .unscale_order_summary(&mut order_summary)
-        .unwrap();
+    market_state.unscale_order_summary(&mut order_summary)?;
 
     let posted_quote_qty = market_state
-        .get_quote_from_base(order_summary.total_base_qty_posted, *limit_price)
-        .unwrap();
+        .get_quote_from_base(NativeQty(order_summary.total_base_qty_posted), limit_price)?
+        .0;
+    market_state.check_price_deviation(
+        order_summary.total_base_qty - order_summary.total_base_qty_posted,
+        order_summary.total_quote_qty - posted_quote_qty,
+    )?;
 
-    let (qty_to_transfer, transfer_destination, referral_fee) =
-        match FromPrimitive::from_u8(*side).unwrap() {
-            Side::Bid => {
-                // We update the order summary to properly handle the FOK order type
-                let matched_quote_qty = order_summary.total_quote_qty - posted_quote_qty;
-                let taker_fee = fee_tier.taker_fee(matched_quote_qty);
-                let royalties_fees = matched_quote_qty
-                    .checked_mul(market_state.royalties_bps)
-                    .unwrap()
-                    / 10_000;
-                order_summary.total_quote_qty += taker_fee + royalties_fees;
-                let referral_fee = fee_tier.referral_fee(matched_quote_qty);
-                let q = order_summary
-                    .total_quote_qty
-                    .saturating_sub(user_account.header.quote_token_free);
-                user_account.header.quote_token_free = user_account
-                    .header
-                    .quote_token_free
-                    .saturating_sub(order_summary.total_quote_qty);
-                user_account.header.quote_token_locked += posted_quote_qty;
-                user_account.header.base_token_free = order_summary
-                    .total_base_qty
-                    .checked_sub(order_summary.total_base_qty_posted)
-                    .and_then(|n| n.checked_add(user_account.header.base_token_free))
-                    .unwrap();
-
-                (q, accounts.quote_vault, referral_fee)
-            }
-            Side::Ask => {
-                let q = order_summary
-                    .total_base_qty
-                    .saturating_sub(user_account.header.base_token_free);
-                user_account.header.base_token_free = user_account
-                    .header
-                    .base_token_free
-                    .saturating_sub(order_summary.total_base_qty);
-                user_account.header.base_token_locked += order_summary.total_base_qty_posted;
-                let taken_quote_qty = order_summary.total_quote_qty - posted_quote_qty;
-                let taker_fee = fee_tier.taker_fee(taken_quote_qty);
-                let royalties_fees = taken_quote_qty
-                    .checked_mul(market_state.royalties_bps)
-                    .unwrap()
-                    / 10_000;
-                let referral_fee = fee_tier.referral_fee(taken_quote_qty);
-                user_account.header.quote_token_free = taken_quote_qty
-                    .checked_sub(taker_fee + royalties_fees)
-                    .and_then(|n| n.checked_add(user_account.header.quote_token_free))
-                    .unwrap();
-                (q, accounts.base_vault, referral_fee)
-            }
-        };
+    let (qty_to_transfer, referral_fee, matched_quote_qty) = match side {
+        Side::Bid => {
+            // We update the order summary to properly handle the FOK order type
+            let matched_quote_qty = order_summary.total_quote_qty - posted_quote_qty;
+            let royalties_fees = FeeTier::royalties_fee(market_state, matched_quote_qty);
+            order_summary.total_quote_qty =
+                posted_quote_qty + fee_tier.quote_with_fees(market_state, matched_quote_qty);
+            market_state.accumulated_royalties = market_state
+                .accumulated_royalties
+                .checked_add(royalties_fees)
+                .ok_or(DexError::NumericalOverflow)?;
+            let referral_fee = fee_tier.referral_fee(market_state, matched_quote_qty);
+            let q = order_summary
+                .total_quote_qty
+                .saturating_sub(user_account.header.quote_token_free);
+            user_account.header.quote_token_free = user_account
+                .header
+                .quote_token_free
+                .saturating_sub(order_summary.total_quote_qty);
+            user_account.header.quote_token_locked += posted_quote_qty;
+            user_account.header.base_token_free = order_summary
+                .total_base_qty
+                .checked_sub(order_summary.total_base_qty_posted)
+                .and_then(|n| n.checked_add(user_account.header.base_token_free))
+                .unwrap();
+
+            (q, referral_fee, matched_quote_qty)
+        }
+        Side::Ask => {
+            let q = order_summary
+                .total_base_qty
+                .saturating_sub(user_account.header.base_token_free);
+            user_account.header.base_token_free = user_account
+                .header
+                .base_token_free
+                .saturating_sub(order_summary.total_base_qty);
+            user_account.header.base_token_locked += order_summary.total_base_qty_posted;
+            let taken_quote_qty = order_summary.total_quote_qty - posted_quote_qty;
+            let taker_fee = fee_tier.taker_fee(market_state, taken_quote_qty);
+            let royalties_fees = FeeTier::royalties_fee(market_state, taken_quote_qty);
+            market_state.accumulated_royalties = market_state
+                .accumulated_royalties
+                .checked_add(royalties_fees)
+                .ok_or(DexError::NumericalOverflow)?;
+            let referral_fee = fee_tier.referral_fee(market_state, taken_quote_qty);
+            user_account.header.quote_token_free = taken_quote_qty
+                .checked_sub(taker_fee + royalties_fees)
+                .and_then(|n| n.checked_add(user_account.header.quote_token_free))
+                .ok_or(DexError::NumericalOverflow)?;
+            (q, referral_fee, taken_quote_qty)
+        }
+    };
 
-    let abort = match FromPrimitive::from_u8(*order_type).unwrap() {
+    let abort = match order_type {
         OrderType::ImmediateOrCancel => order_summary.total_base_qty == 0,
+        OrderType::FillOrKill if fok_fallback_ioc => order_summary.total_base_qty == 0,
         OrderType::FillOrKill => {
-            if *side == Side::Bid as u8 {
+            if side == Side::Bid {
                 order_summary.total_quote_qty < max_quote_qty
             } else {
-                &order_summary.total_base_qty < max_base_qty
+                order_summary.total_base_qty < max_base_qty
             }
         }
         OrderType::PostOnly => order_summary.posted_order_id.is_none(),
@@ -365,6 +595,10 @@ pub(crate) fn process(
     };
 
     if abort {
+        if order_type == OrderType::PostOnly && would_cross(accounts, side, limit_price)? {
+            msg!("The post only order would cross the orderbook");
+            return Err(DexError::PostOnlyWouldCross.into());
+        }
         msg!(
             "The specified order type {:?} has caused an abort",
             order_type
@@ -372,55 +606,14 @@ pub(crate) fn process(
         return Err(DexError::TransactionAborted.into());
     }
 
-    let token_transfer_instruction = spl_token::instruction::transfer(
-        accounts.spl_token_program.key,
-        accounts.user_token_account.key,
-        transfer_destination.key,
-        accounts.user_owner.key,
-        &[],
-        qty_to_transfer,
-    )?;
-
-    invoke(
-        &token_transfer_instruction,
-        &[
-            accounts.spl_token_program.clone(),
-            accounts.user_token_account.clone(),
-            transfer_destination.clone(),
-            accounts.user_owner.clone(),
-        ],
-    )?;
-
-    if let Some(a) = accounts.fee_referral_account {
-        let referral_fee_transfer_instruction = spl_token::instruction::transfer(
-            accounts.spl_token_program.key,
-            accounts.quote_vault.key,
-            a.key,
-            accounts.user_owner.key,
-            &[],
-            referral_fee,
-        )?;
-
-        invoke_signed(
-            &referral_fee_transfer_instruction,
-            &[
-                accounts.spl_token_program.clone(),
-                accounts.quote_vault.clone(),
-                a.clone(),
-                accounts.user_owner.clone(),
-            ],
-            &[&[
-                &accounts.market.key.to_bytes(),
-                &[market_state.signer_nonce as u8],
-            ]],
-        )?;
-    }
-
     if let Some(order_id) = order_summary.posted_order_id {
-        user_account.add_order(Order {
-            id: order_id,
-            client_id: *client_order_id,
-        })?;
+        user_account.add_order(
+            Order {
+                id: order_id,
+                client_id: client_order_id,
+            },
+            duplicate_client_id_policy,
+        )?;
         msg!("Added new order with order_id {:?}", order_id);
     }
 
@@ -431,10 +624,170 @@ pub(crate) fn process(
         .total_quote_qty
         .saturating_sub(posted_quote_qty);
 
+    // The quote balances of the makers only change when the fill events are consumed, but they are accounted for now
+    let filled_quote_qty =
+        fill_events_quote_qty(market_state, accounts.event_queue, events_before)? as u128;
+    let quote_balance_after = user_account.header.quote_balance();
+    match side {
+        Side::Bid => market_state
+            .update_quote_backing(quote_balance_before, quote_balance_after + filled_quote_qty)?,
+        Side::Ask => market_state
+            .update_quote_backing(quote_balance_before + filled_quote_qty, quote_balance_after)?,
+    }
+
+    Ok(OrderTransfer {
+        qty_to_transfer,
+        referral_fee,
+        matched_quote_qty,
+    })
+}
+
+/// The number of events in the event queue
+pub(crate) fn event_queue_len(event_queue: &AccountInfo) -> Result<u64, ProgramError> {
+    let mut event_queue_guard = event_queue.data.borrow_mut();
+    let event_queue =
+        EventQueue::<CallBackInfo>::from_buffer(&mut event_queue_guard, AccountTag::EventQueue)?;
+    Ok(event_queue.len())
+}
+
+/// Sums the native quote quantity of the fill events pushed to the event queue after its first `events_before` events,
+/// i.e. of the fills of the order which was just matched
+pub(crate) fn fill_events_quote_qty(
+    market_state: &DexState,
+    event_queue: &AccountInfo,
+    events_before: u64,
+) -> Result<u64, ProgramError> {
+    let mut event_queue_guard = event_queue.data.borrow_mut();
+    let event_queue =
+        EventQueue::<CallBackInfo>::from_buffer(&mut event_queue_guard, AccountTag::EventQueue)?;
+    let mut quote_qty = 0u64;
+    for event in event_queue.iter().skip(events_before as usize) {
+        if let EventRef::Fill(FillEventRef { event, .. }) = event {
+            quote_qty = quote_qty
+                .checked_add(event.quote_size)
+                .ok_or(DexError::NumericalOverflow)?;
+        }
+    }
+    Ok(market_state.unscale_quote_amount(ScaledQty(quote_qty))?.0)
+}
+
+/// Transfers the given quantity from a user token account, signed by the user owner
+pub(crate) fn transfer_to_vault<'a>(
+    accounts: &Accounts<AccountInfo<'a>>,
+    source: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    mint: &AccountInfo<'a>,
+    amount: u64,
+) -> ProgramResult {
+    transfer_checked(
+        accounts.spl_token_program,
+        source,
+        mint,
+        vault,
+        accounts.user_owner,
+        amount,
+        &[],
+    )
+}
+
+/// Checks that the referral account is neither the quote vault nor a token account of the taker
+pub(crate) fn check_referral_account(
+    fee_referral_account: &AccountInfo,
+    quote_vault: &AccountInfo,
+    taker: &Pubkey,
+) -> ProgramResult {
+    if fee_referral_account.key == quote_vault.key {
+        msg!("The referral account cannot be the quote vault");
+        return Err(DexError::InvalidReferralAccount.into());
+    }
+    if &unpack_token_account(fee_referral_account)?.owner == taker {
+        msg!("The referral account cannot be owned by the taker");
+        return Err(DexError::InvalidReferralAccount.into());
+    }
+    Ok(())
+}
+
+/// Transfers the referrer's cut of the taker fees from the quote vault
+pub(crate) fn transfer_referral_fee<'a>(
+    market_state: &DexState,
+    accounts: &Accounts<AccountInfo<'a>>,
+    fee_referral_account: &AccountInfo<'a>,
+    referral_fee: u64,
+) -> ProgramResult {
+    transfer_checked(
+        accounts.spl_token_program,
+        accounts.quote_vault,
+        accounts.quote_mint,
+        fee_referral_account,
+        accounts.market_signer,
+        referral_fee,
+        &[&[
+            &accounts.market.key.to_bytes(),
+            &[market_state.signer_nonce as u8],
+        ]],
+    )
+}
+
+/// Credits a referred trade to the referrer account of the referral account
+pub(crate) fn record_referral(
+    program_id: &Pubkey,
+    market: &AccountInfo,
+    fee_referral_account: &AccountInfo,
+    referrer: &AccountInfo,
+    matched_quote_qty: u64,
+    referral_fee: u64,
+) -> ProgramResult {
+    check_account_owner(referrer, program_id, DexError::InvalidStateAccountOwner)?;
+    let mut referrer = Referrer::get(referrer)?;
+    if &referrer.market != market.key || &referrer.token_account != fee_referral_account.key {
+        msg!("The referrer account doesn't match the market and the referral account");
+        return Err(DexError::InvalidReferrerAccount.into());
+    }
+    referrer.record_referral(matched_quote_qty, referral_fee);
     Ok(())
 }
 
-fn check_accounts(market_state: &DexState, accounts: &Accounts<AccountInfo>) -> ProgramResult {
+/// Returns true when an order at the given limit price would match against the opposite side of the book.
+fn would_cross(
+    accounts: &Accounts<AccountInfo>,
+    side: Side,
+    limit_price: u64,
+) -> Result<bool, ProgramError> {
+    let (book, tag) = match side {
+        Side::Bid => (accounts.asks, AccountTag::Asks),
+        Side::Ask => (accounts.bids, AccountTag::Bids),
+    };
+    let mut book_guard = book.data.borrow_mut();
+    let slab = Slab::<CallBackInfo>::from_buffer(&mut book_guard, tag)?;
+    let best_price = match side {
+        Side::Bid => slab.find_min(),
+        Side::Ask => slab.find_max(),
+    }
+    .map(|h| slab.leaf_nodes[h as usize].price());
+    Ok(match (side, best_price) {
+        (Side::Bid, Some(p)) => p <= limit_price,
+        (Side::Ask, Some(p)) => p >= limit_price,
+        (_, None) => false,
+    })
+}
+
+pub(crate) fn check_accounts(
+    program_id: &Pubkey,
+    market_state: &DexState,
+    accounts: &Accounts<AccountInfo>,
+) -> ProgramResult {
+    let market_signer = Pubkey::create_program_address(
+        &[
+            &accounts.market.key.to_bytes(),
+            &[market_state.signer_nonce as u8],
+        ],
+        program_id,
+    )?;
+    check_account_key(
+        accounts.market_signer,
+        &market_signer,
+        DexError::InvalidMarketSignerAccount,
+    )?;
     check_account_key(
         accounts.orderbook,
         &market_state.orderbook,
@@ -450,6 +803,43 @@ fn check_accounts(market_state: &DexState, accounts: &Accounts<AccountInfo>) ->
         &market_state.quote_vault,
         DexError::InvalidQuoteVaultAccount,
     )?;
+    check_account_key(
+        accounts.base_mint,
+        &market_state.base_mint,
+        DexError::InvalidBaseMintAccount,
+    )?;
+    check_account_key(
+        accounts.quote_mint,
+        &market_state.quote_mint,
+        DexError::InvalidQuoteMintAccount,
+    )?;
+    check_account_key(
+        accounts.spl_token_program,
+        &market_state.token_program_id(),
+        DexError::InvalidSplTokenProgram,
+    )?;
 
     Ok(())
 }
+
+/// Replaces a zero match limit with the default one, and caps it to the maximum match limit
+pub(crate) fn capped_match_limit(match_limit: u64) -> u64 {
+    if match_limit == 0 {
+        DEFAULT_MATCH_LIMIT
+    } else {
+        match_limit.min(MAX_MATCH_LIMIT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capped_match_limit() {
+        assert_eq!(capped_match_limit(0), DEFAULT_MATCH_LIMIT);
+        assert_eq!(capped_match_limit(3), 3);
+        assert_eq!(capped_match_limit(MAX_MATCH_LIMIT), MAX_MATCH_LIMIT);
+        assert_eq!(capped_match_limit(u64::MAX), MAX_MATCH_LIMIT);
+    }
+}
@@ -0,0 +1,258 @@
+//! Reduce the size of an existing order in place, keeping its priority in the orderbook.
+//!
+//! The unlocked amounts are credited to the free balance of the user account and returned as a
+//! [`CancelOrderSummary`](crate::processor::cancel_order::CancelOrderSummary). Reducing an order by its whole remaining
+//! size cancels it.
+use crate::{
+    error::DexError,
+    processor::cancel_order::{unlock_order_funds, CancelOrderSummary},
+    state::{CallBackInfo, DexState, NativeQty, ScaledQty, UserAccount},
+    utils::{check_account_key, check_account_owner, check_signer},
+};
+use asset_agnostic_orderbook::state::{
+    critbit::Slab, get_side_from_order_id, market_state::MarketState, AccountTag, Side,
+};
+use bonfida_utils::BorshSize;
+use bonfida_utils::InstructionsAccount;
+use borsh::BorshDeserialize;
+use borsh::BorshSerialize;
+use bytemuck::{try_from_bytes, Pod, Zeroable};
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::set_return_data,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+#[derive(Clone, Copy, Zeroable, Pod, BorshDeserialize, BorshSerialize, BorshSize)]
+#[repr(C)]
+/**
+The required arguments for a reduce_order instruction.
+*/
+pub struct Params {
+    /// The order_id of the order to reduce
+    pub order_id: u128,
+    /// The index in the user account of the order to reduce
+    pub order_index: u64,
+    /// The base quantity to remove from the order, in native units. It is rounded down to the market's base currency
+    /// multiplier, and a quantity larger than the remaining size of the order cancels it.
+    pub reduce_by: u64,
+}
+
+#[derive(InstructionsAccount)]
+pub struct Accounts<'a, T> {
+    /// The DEX market
+    pub market: &'a T,
+
+    /// The orderbook
+    #[cons(writable)]
+    pub orderbook: &'a T,
+
+    /// The AOB event queue
+    #[cons(writable)]
+    pub event_queue: &'a T,
+
+    /// The AOB bids shared memory
+    #[cons(writable)]
+    pub bids: &'a T,
+
+    /// The AOB asks shared memory
+    #[cons(writable)]
+    pub asks: &'a T,
+
+    /// The DEX user account
+    #[cons(writable)]
+    pub user: &'a T,
+
+    /// The user wallet, or the user account's delegate
+    #[cons(signer)]
+    pub user_owner: &'a T,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let a = Self {
+            market: next_account_info(accounts_iter)?,
+            orderbook: next_account_info(accounts_iter)?,
+            event_queue: next_account_info(accounts_iter)?,
+            bids: next_account_info(accounts_iter)?,
+            asks: next_account_info(accounts_iter)?,
+            user: next_account_info(accounts_iter)?,
+            user_owner: next_account_info(accounts_iter)?,
+        };
+        check_signer(a.user_owner).map_err(|e| {
+            msg!("The user account owner should be a signer for this transaction!");
+            e
+        })?;
+        check_account_owner(a.market, program_id, DexError::InvalidStateAccountOwner)?;
+        check_account_owner(a.user, program_id, DexError::InvalidStateAccountOwner)?;
+
+        Ok(a)
+    }
+
+    pub fn load_user_account(
+        &self,
+        user_account_data: &'a mut [u8],
+    ) -> Result<UserAccount<'a>, ProgramError> {
+        let user_account = UserAccount::from_buffer(user_account_data)?;
+        if !user_account
+            .header
+            .is_owner_or_delegate(self.user_owner.key)
+        {
+            msg!("Invalid user account owner or delegate provided!");
+            return Err(ProgramError::InvalidArgument);
+        }
+        if &user_account.header.market != self.market.key {
+            msg!("The provided user account doesn't match the current market");
+            return Err(ProgramError::InvalidArgument);
+        };
+        Ok(user_account)
+    }
+}
+
+pub(crate) fn process(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let Params {
+        order_id,
+        order_index,
+        reduce_by,
+    } = try_from_bytes(instruction_data).map_err(|_| ProgramError::InvalidInstructionData)?;
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let market_state = DexState::get(accounts.market)?;
+    let mut user_account_data = accounts.user.data.borrow_mut();
+    let mut user_account = accounts.load_user_account(&mut user_account_data)?;
+
+    check_accounts(&market_state, &accounts)?;
+
+    if *order_id != user_account.read_order(*order_index as usize)?.id {
+        msg!("Order id does not match with the order at the given index!");
+        return Err(ProgramError::InvalidArgument);
+    }
+    let ScaledQty(reduce_by) = market_state.scale_base_amount(NativeQty(*reduce_by));
+    if reduce_by == 0 {
+        msg!("The reduced quantity should be at least one base currency multiplier");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let side = get_side_from_order_id(*order_id);
+    let (base_qty, quote_qty) = match reduce_leaf(&market_state, &accounts, *order_id, reduce_by)? {
+        Some(unlocked) => unlocked,
+        None => {
+            let (base_qty, quote_qty) = cancel(program_id, &market_state, &accounts, *order_id)?;
+            user_account.remove_order(*order_index as usize)?;
+            (base_qty, quote_qty)
+        }
+    };
+    unlock_order_funds(&mut user_account, side, base_qty, quote_qty);
+
+    let summary = CancelOrderSummary {
+        total_base_qty: base_qty,
+        total_quote_qty: quote_qty,
+    };
+    set_return_data(&summary.try_to_vec()?);
+
+    Ok(())
+}
+
+/// Decreases the size of the order's leaf in the orderbook and returns the unlocked native quantities, or `None` when
+/// the order should be cancelled instead
+fn reduce_leaf(
+    market_state: &DexState,
+    accounts: &Accounts<AccountInfo>,
+    order_id: u128,
+    reduce_by: u64,
+) -> Result<Option<(u64, u64)>, ProgramError> {
+    let (book, tag) = match get_side_from_order_id(order_id) {
+        Side::Bid => (accounts.bids, AccountTag::Bids),
+        Side::Ask => (accounts.asks, AccountTag::Asks),
+    };
+    let mut book_guard = book.data.borrow_mut();
+    let mut slab = Slab::<CallBackInfo>::from_buffer(&mut book_guard, tag)?;
+    // The order was filled, it will be removed from the user account when its events are consumed
+    let handle = slab.find_by_key(order_id).ok_or(DexError::OrderNotFound)?;
+    let leaf = &mut slab.leaf_nodes[handle as usize];
+    if reduce_by >= leaf.base_quantity {
+        return Ok(None);
+    }
+
+    let NativeQty(base_qty_before) =
+        market_state.unscale_base_amount(ScaledQty(leaf.base_quantity))?;
+    let NativeQty(base_qty_after) =
+        market_state.unscale_base_amount(ScaledQty(leaf.base_quantity - reduce_by))?;
+    if base_qty_after < market_state.min_base_order_size {
+        msg!("The remaining base order size is too small.");
+        return Err(ProgramError::InvalidArgument);
+    }
+    // The quote quantity of a bid is locked by the order's posted base quantity at its price
+    let NativeQty(quote_qty_before) =
+        market_state.get_quote_from_base(NativeQty(base_qty_before), leaf.price())?;
+    let NativeQty(quote_qty_after) =
+        market_state.get_quote_from_base(NativeQty(base_qty_after), leaf.price())?;
+    leaf.base_quantity -= reduce_by;
+
+    Ok(Some((
+        base_qty_before - base_qty_after,
+        quote_qty_before - quote_qty_after,
+    )))
+}
+
+/// Cancels the order in the orderbook and returns its remaining native quantities
+fn cancel(
+    program_id: &Pubkey,
+    market_state: &DexState,
+    accounts: &Accounts<AccountInfo>,
+    order_id: u128,
+) -> Result<(u64, u64), ProgramError> {
+    let invoke_params = asset_agnostic_orderbook::instruction::cancel_order::Params { order_id };
+    let invoke_accounts = asset_agnostic_orderbook::instruction::cancel_order::Accounts {
+        market: accounts.orderbook,
+        event_queue: accounts.event_queue,
+        bids: accounts.bids,
+        asks: accounts.asks,
+    };
+    let order_summary =
+        asset_agnostic_orderbook::instruction::cancel_order::process::<CallBackInfo>(
+            program_id,
+            invoke_accounts,
+            invoke_params,
+        )
+        .map_err(DexError::from_aob_error)?;
+    let NativeQty(base_qty) =
+        market_state.unscale_base_amount(ScaledQty(order_summary.total_base_qty))?;
+    let NativeQty(quote_qty) =
+        market_state.unscale_quote_amount(ScaledQty(order_summary.total_quote_qty))?;
+
+    Ok((base_qty, quote_qty))
+}
+
+fn check_accounts(market_state: &DexState, accounts: &Accounts<AccountInfo>) -> ProgramResult {
+    check_account_key(
+        accounts.orderbook,
+        &market_state.orderbook,
+        DexError::InvalidOrderbookAccount,
+    )?;
+    let mut orderbook_guard = accounts.orderbook.data.borrow_mut();
+    let orderbook = MarketState::from_buffer(&mut orderbook_guard, AccountTag::Market)?;
+    check_account_key(
+        accounts.bids,
+        &orderbook.bids,
+        DexError::InvalidOrderbookAccount,
+    )?;
+    check_account_key(
+        accounts.asks,
+        &orderbook.asks,
+        DexError::InvalidOrderbookAccount,
+    )?;
+
+    Ok(())
+}
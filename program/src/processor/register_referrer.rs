@@ -0,0 +1,145 @@
+//! Register a quote token account as a referrer of a market, so that the referral volume and fees it receives are tracked
+//! on-chain.
+use bonfida_utils::BorshSize;
+use bonfida_utils::InstructionsAccount;
+use borsh::BorshDeserialize;
+use borsh::BorshSerialize;
+use bytemuck::{try_from_bytes_mut, Pod, Zeroable};
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::invoke_signed,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction::create_account,
+    system_program,
+    sysvar::Sysvar,
+};
+
+use crate::{
+    error::DexError,
+    state::{find_referrer, AccountTag, DexState, Referrer, REFERRER_LEN, REFERRER_SEED},
+    utils::{check_account_key, check_account_owner, check_signer, unpack_token_account},
+};
+
+#[derive(Clone, Copy, Zeroable, Pod, BorshDeserialize, BorshSerialize, BorshSize)]
+#[repr(C)]
+pub struct Params {}
+
+#[derive(InstructionsAccount)]
+pub struct Accounts<'a, T> {
+    /// The system program
+    pub system_program: &'a T,
+
+    /// The DEX market
+    pub market: &'a T,
+
+    /// The referrer account to create
+    #[cons(writable)]
+    pub referrer: &'a T,
+
+    /// The quote token account which receives the referral fees
+    pub token_account: &'a T,
+
+    /// The owner of the token account
+    #[cons(signer)]
+    pub referrer_owner: &'a T,
+
+    /// The fee payer
+    #[cons(writable, signer)]
+    pub fee_payer: &'a T,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let a = Self {
+            system_program: next_account_info(accounts_iter)?,
+            market: next_account_info(accounts_iter)?,
+            referrer: next_account_info(accounts_iter)?,
+            token_account: next_account_info(accounts_iter)?,
+            referrer_owner: next_account_info(accounts_iter)?,
+            fee_payer: next_account_info(accounts_iter)?,
+        };
+        check_signer(a.referrer_owner).map_err(|e| {
+            msg!("The owner of the token account should be a signer for this transaction!");
+            e
+        })?;
+        check_account_key(
+            a.system_program,
+            &system_program::ID,
+            DexError::InvalidSystemProgramAccount,
+        )?;
+        check_account_owner(a.market, program_id, DexError::InvalidStateAccountOwner)?;
+        check_account_owner(
+            a.referrer,
+            &system_program::ID,
+            DexError::InvalidStateAccountOwner,
+        )?;
+
+        Ok(a)
+    }
+}
+
+pub(crate) fn process(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let market_state = DexState::get(accounts.market)?;
+    let token_account = unpack_token_account(accounts.token_account)?;
+    if token_account.mint != market_state.quote_mint {
+        msg!("The referral account should hold the quote token of the market");
+        return Err(DexError::InvalidQuoteTokenAccountMint.into());
+    }
+    if &token_account.owner != accounts.referrer_owner.key {
+        msg!("The referral account should be owned by the signer");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let (referrer_key, referrer_nonce) =
+        find_referrer(program_id, accounts.market.key, accounts.token_account.key);
+    if &referrer_key != accounts.referrer.key {
+        msg!("Provided an invalid referrer account for the specified market and token account");
+        return Err(DexError::InvalidReferrerAccount.into());
+    }
+
+    let allocate_account = create_account(
+        accounts.fee_payer.key,
+        accounts.referrer.key,
+        Rent::get()?.minimum_balance(REFERRER_LEN),
+        REFERRER_LEN as u64,
+        program_id,
+    );
+
+    invoke_signed(
+        &allocate_account,
+        &[
+            accounts.system_program.clone(),
+            accounts.fee_payer.clone(),
+            accounts.referrer.clone(),
+        ],
+        &[&[
+            &accounts.market.key.to_bytes(),
+            REFERRER_SEED,
+            &accounts.token_account.key.to_bytes(),
+            &[referrer_nonce],
+        ]],
+    )?;
+
+    let mut referrer_data = accounts.referrer.data.borrow_mut();
+    let referrer: &mut Referrer =
+        try_from_bytes_mut(&mut referrer_data).map_err(|_| ProgramError::InvalidAccountData)?;
+    *referrer = Referrer {
+        tag: AccountTag::Referrer as u64,
+        market: *accounts.market.key,
+        token_account: *accounts.token_account.key,
+        owner: *accounts.referrer_owner.key,
+        ..Referrer::zeroed()
+    };
+
+    Ok(())
+}
@@ -0,0 +1,291 @@
+//! Execute a new order funded from the free balance of the user account.
+//!
+//! The order is matched and posted as a new_order would, but the user token account is only required when the free
+//! balance of the user account doesn't cover the order, in which case the remainder is transferred from it. Makers can
+//! then post their settled proceeds again without a round trip through their wallet.
+use crate::{
+    error::DexError,
+    processor::new_order::{
+        self, execute_order, transfer_to_vault, OrderRequest, OrderTransfer, OrderType,
+    },
+    state::{DexState, DuplicateClientIdPolicy, FeeTier, MarketStatus},
+    utils::{
+        check_account_key, check_account_owner, check_discount_token_account, check_signer,
+        check_token_account_mint,
+    },
+};
+use asset_agnostic_orderbook::state::{SelfTradeBehavior, Side};
+use bonfida_utils::InstructionsAccount;
+use bytemuck::try_from_bytes;
+use num_traits::FromPrimitive;
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    clock::Clock,
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_program,
+    sysvar::Sysvar,
+};
+
+/// The arguments of a repost instruction are the ones of a new_order instruction
+pub use crate::processor::new_order::Params;
+
+#[derive(InstructionsAccount)]
+pub struct Accounts<'a, T> {
+    /// The SPL token program
+    pub spl_token_program: &'a T,
+
+    /// The system program
+    pub system_program: &'a T,
+
+    /// The DEX market
+    #[cons(writable)]
+    pub market: &'a T,
+
+    /// The orderbook
+    #[cons(writable)]
+    pub orderbook: &'a T,
+
+    /// The AOB event queue
+    #[cons(writable)]
+    pub event_queue: &'a T,
+
+    /// The AOB bids shared memory
+    #[cons(writable)]
+    pub bids: &'a T,
+
+    /// The AOB asks shared memory
+    #[cons(writable)]
+    pub asks: &'a T,
+
+    /// The base token vault
+    #[cons(writable)]
+    pub base_vault: &'a T,
+
+    /// The quote token vault
+    #[cons(writable)]
+    pub quote_vault: &'a T,
+
+    /// The base token mint
+    pub base_mint: &'a T,
+
+    /// The quote token mint
+    pub quote_mint: &'a T,
+
+    /// The DEX market signer
+    pub market_signer: &'a T,
+
+    /// The DEX user account
+    #[cons(writable)]
+    pub user: &'a T,
+
+    /// The user wallet, or the user account's delegate
+    #[cons(writable, signer)]
+    pub user_owner: &'a T,
+
+    /// The optional SRM or MSRM discount token account (must be owned by the user wallet)
+    pub discount_token_account: Option<&'a T>,
+
+    /// The optional user source token account, required when the free balance doesn't cover the order
+    #[cons(writable)]
+    pub user_token_account: Option<&'a T>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+        has_discount_token_account: bool,
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let a = Self {
+            spl_token_program: next_account_info(accounts_iter)?,
+            system_program: next_account_info(accounts_iter)?,
+            market: next_account_info(accounts_iter)?,
+            orderbook: next_account_info(accounts_iter)?,
+            event_queue: next_account_info(accounts_iter)?,
+            bids: next_account_info(accounts_iter)?,
+            asks: next_account_info(accounts_iter)?,
+            base_vault: next_account_info(accounts_iter)?,
+            quote_vault: next_account_info(accounts_iter)?,
+            base_mint: next_account_info(accounts_iter)?,
+            quote_mint: next_account_info(accounts_iter)?,
+            market_signer: next_account_info(accounts_iter)?,
+            user: next_account_info(accounts_iter)?,
+            user_owner: next_account_info(accounts_iter)?,
+            discount_token_account: if has_discount_token_account {
+                next_account_info(accounts_iter).ok()
+            } else {
+                None
+            },
+            user_token_account: next_account_info(accounts_iter).ok(),
+        };
+
+        check_signer(a.user_owner).map_err(|e| {
+            msg!("The user account owner should be a signer for this transaction!");
+            e
+        })?;
+
+        check_account_key(
+            a.system_program,
+            &system_program::ID,
+            DexError::InvalidSystemProgramAccount,
+        )?;
+
+        if let Some(discount_account) = a.discount_token_account {
+            check_account_owner(
+                discount_account,
+                &spl_token::ID,
+                DexError::InvalidSplTokenProgram,
+            )?;
+        }
+        check_account_owner(a.user, program_id, DexError::InvalidStateAccountOwner)?;
+        check_account_owner(a.market, program_id, DexError::InvalidStateAccountOwner)?;
+
+        Ok(a)
+    }
+
+    /// The new_order accounts used to execute the order
+    fn order_accounts(&self) -> new_order::Accounts<'a, AccountInfo<'b>> {
+        new_order::Accounts {
+            spl_token_program: self.spl_token_program,
+            system_program: self.system_program,
+            market: self.market,
+            orderbook: self.orderbook,
+            event_queue: self.event_queue,
+            bids: self.bids,
+            asks: self.asks,
+            base_vault: self.base_vault,
+            quote_vault: self.quote_vault,
+            base_mint: self.base_mint,
+            quote_mint: self.quote_mint,
+            market_signer: self.market_signer,
+            user: self.user,
+            // The order is executed without any token transfer, the user account stands in for a missing token account
+            user_token_account: self.user_token_account.unwrap_or(self.user),
+            user_owner: self.user_owner,
+            discount_token_account: self.discount_token_account,
+            fee_referral_account: None,
+            referrer: None,
+        }
+    }
+}
+
+pub(crate) fn process(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let Params {
+        side,
+        limit_price,
+        max_base_qty,
+        max_quote_qty,
+        order_type,
+        self_trade_behavior,
+        match_limit,
+        has_discount_token_account,
+        client_order_id,
+        fok_fallback_ioc,
+        reduce_only,
+        duplicate_client_id_policy,
+        ..
+    } = try_from_bytes(instruction_data).map_err(|_| ProgramError::InvalidInstructionData)?;
+    #[cfg(any(target_arch = "aarch64", feature = "aarch64-test"))]
+    let client_order_id: &u128 = bytemuck::cast_ref(client_order_id);
+    let side: Side = FromPrimitive::from_u8(*side).ok_or(ProgramError::InvalidInstructionData)?;
+    let order_type: OrderType =
+        FromPrimitive::from_u8(*order_type).ok_or(ProgramError::InvalidInstructionData)?;
+    let self_trade_behavior: SelfTradeBehavior =
+        FromPrimitive::from_u8(*self_trade_behavior).ok_or(ProgramError::InvalidInstructionData)?;
+    let duplicate_client_id_policy: DuplicateClientIdPolicy =
+        FromPrimitive::from_u8(*duplicate_client_id_policy)
+            .ok_or(ProgramError::InvalidInstructionData)?;
+    let accounts = Accounts::parse(program_id, accounts, *has_discount_token_account != 0)?;
+
+    let mut market_state = DexState::get(accounts.market)?;
+
+    if market_state.status != MarketStatus::Active as u8 {
+        msg!("The market is not accepting new orders.");
+        return Err(DexError::MarketPaused.into());
+    }
+
+    let order_accounts = accounts.order_accounts();
+
+    let mut user_account_data = accounts.user.data.borrow_mut();
+    let mut user_account = order_accounts.load_user_account(&mut user_account_data)?;
+
+    new_order::check_accounts(program_id, &market_state, &order_accounts)?;
+    // The fee tier is the one of the user account owner, even when a delegate places the order
+    if let Some(a) = accounts.discount_token_account {
+        check_discount_token_account(a, &user_account.header.owner)?;
+    }
+    let fee_tier = FeeTier::get_for_taker(
+        &market_state,
+        accounts.discount_token_account,
+        &user_account.header.owner,
+        Some(&*user_account.header),
+        Clock::get()?.unix_timestamp,
+    )?;
+
+    let OrderTransfer {
+        qty_to_transfer, ..
+    } = execute_order(
+        program_id,
+        &order_accounts,
+        &mut market_state,
+        &mut user_account,
+        fee_tier,
+        OrderRequest {
+            client_order_id: *client_order_id,
+            side,
+            limit_price: *limit_price,
+            max_base_qty: *max_base_qty,
+            max_quote_qty: *max_quote_qty,
+            match_limit: *match_limit,
+            order_type,
+            self_trade_behavior,
+            fok_fallback_ioc: *fok_fallback_ioc != 0,
+            reduce_only: *reduce_only != 0,
+            duplicate_client_id_policy,
+        },
+    )?;
+
+    if qty_to_transfer != 0 {
+        let user_token_account = accounts.user_token_account.ok_or_else(|| {
+            msg!("The free balance doesn't cover the order, a user token account is required");
+            ProgramError::NotEnoughAccountKeys
+        })?;
+        let (transfer_destination, transfer_mint) = match side {
+            Side::Bid => {
+                check_token_account_mint(
+                    user_token_account,
+                    &market_state.quote_mint,
+                    DexError::InvalidQuoteTokenAccountMint,
+                )?;
+                (accounts.quote_vault, accounts.quote_mint)
+            }
+            Side::Ask => {
+                check_token_account_mint(
+                    user_token_account,
+                    &market_state.base_mint,
+                    DexError::InvalidBaseTokenAccountMint,
+                )?;
+                (accounts.base_vault, accounts.base_mint)
+            }
+        };
+        transfer_to_vault(
+            &order_accounts,
+            user_token_account,
+            transfer_destination,
+            transfer_mint,
+            qty_to_transfer,
+        )?;
+    }
+
+    market_state.last_order_slot = Clock::get()?.slot;
+
+    Ok(())
+}
@@ -0,0 +1,126 @@
+//! Grow a user account to hold a larger number of orders
+use bonfida_utils::BorshSize;
+use bonfida_utils::InstructionsAccount;
+use borsh::BorshDeserialize;
+use borsh::BorshSerialize;
+use bytemuck::{try_from_bytes, Pod, Zeroable};
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::invoke,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction::transfer,
+    system_program,
+    sysvar::Sysvar,
+};
+
+use crate::{
+    error::DexError,
+    state::UserAccount,
+    utils::{check_account_key, check_account_owner, check_signer},
+};
+
+#[derive(Clone, Copy, Zeroable, Pod, BorshDeserialize, BorshSerialize, BorshSize)]
+#[repr(C)]
+/**
+The required arguments for a resize_user_account instruction.
+*/
+pub struct Params {
+    /// The new maximum number of orders the user account may hold
+    pub new_max_orders: u64,
+}
+
+#[derive(InstructionsAccount)]
+pub struct Accounts<'a, T> {
+    /// The system program
+    pub system_program: &'a T,
+
+    /// The user account to resize
+    #[cons(writable)]
+    pub user: &'a T,
+
+    /// The owner of the user account
+    #[cons(signer)]
+    pub user_owner: &'a T,
+
+    /// The fee payer
+    #[cons(writable, signer)]
+    pub fee_payer: &'a T,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let a = Self {
+            system_program: next_account_info(accounts_iter)?,
+            user: next_account_info(accounts_iter)?,
+            user_owner: next_account_info(accounts_iter)?,
+            fee_payer: next_account_info(accounts_iter)?,
+        };
+        check_signer(a.user_owner).map_err(|e| {
+            msg!("The user account owner should be a signer for this transaction!");
+            e
+        })?;
+        check_account_key(
+            a.system_program,
+            &system_program::ID,
+            DexError::InvalidSystemProgramAccount,
+        )?;
+        check_account_owner(a.user, program_id, DexError::InvalidStateAccountOwner)?;
+
+        Ok(a)
+    }
+}
+
+pub(crate) fn process(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let Params { new_max_orders } =
+        try_from_bytes(instruction_data).map_err(|_| ProgramError::InvalidInstructionData)?;
+
+    {
+        let mut user_account_data = accounts.user.data.borrow_mut();
+        let user_account = UserAccount::from_buffer(&mut user_account_data)?;
+        if &user_account.header.owner != accounts.user_owner.key {
+            msg!("Invalid user account owner provided!");
+            return Err(ProgramError::InvalidArgument);
+        }
+    }
+
+    let new_space = UserAccount::compute_allocation_size(*new_max_orders as usize)
+        .ok_or(DexError::NumericalOverflow)?;
+
+    if new_space <= accounts.user.data_len() {
+        msg!("The user account can only be grown");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let lamports_needed = Rent::get()?
+        .minimum_balance(new_space)
+        .saturating_sub(accounts.user.lamports());
+    if lamports_needed != 0 {
+        invoke(
+            &transfer(accounts.fee_payer.key, accounts.user.key, lamports_needed),
+            &[
+                accounts.system_program.clone(),
+                accounts.fee_payer.clone(),
+                accounts.user.clone(),
+            ],
+        )?;
+    }
+
+    // The existing header and orders are preserved, the new order slots are zeroed
+    accounts.user.realloc(new_space, true)?;
+
+    Ok(())
+}
@@ -0,0 +1,99 @@
+//! Pause or resume trading on a market. This is an admin instruction.
+//!
+//! Paused markets reject new orders and swaps, but still allow users to cancel their orders and settle their funds.
+use {
+    bonfida_utils::{BorshSize, InstructionsAccount},
+    borsh::{BorshDeserialize, BorshSerialize},
+    bytemuck::{try_from_bytes, Pod, Zeroable},
+    num_traits::FromPrimitive,
+    solana_program::{
+        account_info::{next_account_info, AccountInfo},
+        entrypoint::ProgramResult,
+        msg,
+        program_error::ProgramError,
+        pubkey::Pubkey,
+    },
+};
+
+use crate::{
+    error::DexError,
+    state::{DexState, MarketStatus},
+    utils::{check_account_key, check_account_owner, check_signer},
+};
+
+#[derive(Copy, Clone, Zeroable, Pod, BorshDeserialize, BorshSerialize, BorshSize)]
+#[repr(C)]
+pub struct Params {
+    /// The new market status, either `Active` or `Paused`
+    pub status: u8,
+    pub _padding: [u8; 7],
+}
+
+#[derive(InstructionsAccount)]
+pub struct Accounts<'a, T> {
+    /// The DEX market
+    #[cons(writable)]
+    pub market: &'a T,
+
+    /// The market admin account
+    #[cons(signer)]
+    pub market_admin: &'a T,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
+    pub(crate) fn parse(
+        accounts: &'a [AccountInfo<'b>],
+        program_id: &Pubkey,
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let a = Self {
+            market: next_account_info(accounts_iter)?,
+            market_admin: next_account_info(accounts_iter)?,
+        };
+
+        // Check keys
+
+        // Check owners
+        check_account_owner(a.market, program_id, DexError::InvalidStateAccountOwner)?;
+
+        // Check signers
+        check_signer(a.market_admin).map_err(|e| {
+            msg!("The market admin should be a signer for this transaction!");
+            e
+        })?;
+
+        Ok(a)
+    }
+}
+
+pub(crate) fn process(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let accounts = Accounts::parse(accounts, program_id)?;
+    let Params {
+        status,
+        _padding: _,
+    } = try_from_bytes(instruction_data).map_err(|_| ProgramError::InvalidInstructionData)?;
+
+    let mut market_state = DexState::get(accounts.market)?;
+
+    check_account_key(
+        accounts.market_admin,
+        &market_state.admin,
+        DexError::InvalidMarketAdminAccount,
+    )?;
+
+    match FromPrimitive::from_u8(*status) {
+        Some(MarketStatus::Active) | Some(MarketStatus::Paused) => {}
+        _ => {
+            msg!("The market status can only be set to active or paused!");
+            return Err(ProgramError::InvalidArgument);
+        }
+    }
+
+    market_state.status = *status;
+
+    Ok(())
+}
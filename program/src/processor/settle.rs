@@ -2,25 +2,38 @@
 use crate::{
     error::DexError,
     state::{DexState, UserAccount},
-    utils::{check_account_key, check_account_owner, check_signer},
+    utils::{
+        check_account_key, check_account_owner, check_signer, check_token_account_mint,
+        transfer_checked, unpack_token_account,
+    },
 };
 use bonfida_utils::BorshSize;
 use bonfida_utils::InstructionsAccount;
 use borsh::BorshDeserialize;
 use borsh::BorshSerialize;
-use bytemuck::{Pod, Zeroable};
+use bytemuck::{try_from_bytes, Pod, Zeroable};
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
     entrypoint::ProgramResult,
     msg,
-    program::invoke_signed,
+    program::invoke,
     program_error::ProgramError,
     pubkey::Pubkey,
 };
 
 #[derive(Clone, Copy, BorshDeserialize, BorshSerialize, BorshSize, Pod, Zeroable)]
 #[repr(C)]
-pub struct Params {}
+pub struct Params {
+    /// The amount of base tokens to transfer, 0 to transfer all free base tokens
+    pub base_amount: u64,
+    /// The amount of quote tokens to transfer, 0 to transfer all free quote tokens
+    pub quote_amount: u64,
+    /// When set to 1, the wrapped SOL destination token account is closed to the user wallet once settled.
+    /// Should be 0 or 1.
+    pub unwrap_sol: u8,
+    /// To eliminate implicit padding
+    pub _padding: [u8; 7],
+}
 
 #[derive(InstructionsAccount)]
 pub struct Accounts<'a, T> {
@@ -28,6 +41,7 @@ pub struct Accounts<'a, T> {
     pub spl_token_program: &'a T,
 
     /// The DEX market
+    #[cons(writable)]
     pub market: &'a T,
 
     /// The base token vault
@@ -38,6 +52,12 @@ pub struct Accounts<'a, T> {
     #[cons(writable)]
     pub quote_vault: &'a T,
 
+    /// The base token mint
+    pub base_mint: &'a T,
+
+    /// The quote token mint
+    pub quote_mint: &'a T,
+
     /// The DEX market signer account
     pub market_signer: &'a T,
 
@@ -46,7 +66,7 @@ pub struct Accounts<'a, T> {
     pub user: &'a T,
 
     /// The DEX user account owner wallet
-    #[cons(signer)]
+    #[cons(writable, signer)]
     pub user_owner: &'a T,
 
     /// The destination base token account
@@ -69,6 +89,8 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
             market: next_account_info(accounts_iter)?,
             base_vault: next_account_info(accounts_iter)?,
             quote_vault: next_account_info(accounts_iter)?,
+            base_mint: next_account_info(accounts_iter)?,
+            quote_mint: next_account_info(accounts_iter)?,
             market_signer: next_account_info(accounts_iter)?,
             user: next_account_info(accounts_iter)?,
             user_owner: next_account_info(accounts_iter)?,
@@ -79,11 +101,6 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
             msg!("The user account owner should be a signer for this transaction!");
             e
         })?;
-        check_account_key(
-            a.spl_token_program,
-            &spl_token::ID,
-            DexError::InvalidSplTokenProgram,
-        )?;
         check_account_owner(a.market, program_id, DexError::InvalidStateAccountOwner)?;
         check_account_owner(a.user, program_id, DexError::InvalidStateAccountOwner)?;
 
@@ -107,68 +124,137 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
     }
 }
 
-pub(crate) fn process(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+pub(crate) fn process(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
     let accounts = Accounts::parse(program_id, accounts)?;
 
-    let market_state = DexState::get(accounts.market)?;
+    let Params {
+        base_amount,
+        quote_amount,
+        unwrap_sol,
+        ..
+    } = try_from_bytes(instruction_data).map_err(|_| ProgramError::InvalidInstructionData)?;
+
+    let mut market_state = DexState::get(accounts.market)?;
 
     let mut user_account_data = accounts.user.data.borrow_mut();
     let mut user_account = accounts.load_user_account(&mut user_account_data)?;
 
-    check_accounts(program_id, &market_state, &accounts).unwrap();
+    check_accounts(program_id, &market_state, &accounts)?;
 
-    let transfer_quote_instruction = spl_token::instruction::transfer(
-        &spl_token::ID,
-        &market_state.quote_vault,
-        accounts.destination_quote_account.key,
-        accounts.market_signer.key,
-        &[],
-        user_account.header.quote_token_free,
-    )?;
+    let base_amount = get_settled_amount(*base_amount, user_account.header.base_token_free)?;
+    let quote_amount = get_settled_amount(*quote_amount, user_account.header.quote_token_free)?;
 
-    invoke_signed(
-        &transfer_quote_instruction,
-        &[
-            accounts.spl_token_program.clone(),
-            accounts.quote_vault.clone(),
-            accounts.destination_quote_account.clone(),
-            accounts.market_signer.clone(),
-        ],
-        &[&[
-            &accounts.market.key.to_bytes(),
-            &[market_state.signer_nonce as u8],
-        ]],
+    transfer_from_vault(
+        &market_state,
+        accounts.spl_token_program,
+        accounts.market,
+        accounts.market_signer,
+        accounts.quote_vault,
+        accounts.quote_mint,
+        accounts.destination_quote_account,
+        quote_amount,
     )?;
-
-    let transfer_base_instruction = spl_token::instruction::transfer(
-        &spl_token::ID,
-        &market_state.base_vault,
-        accounts.destination_base_account.key,
-        accounts.market_signer.key,
-        &[],
-        user_account.header.base_token_free,
+    transfer_from_vault(
+        &market_state,
+        accounts.spl_token_program,
+        accounts.market,
+        accounts.market_signer,
+        accounts.base_vault,
+        accounts.base_mint,
+        accounts.destination_base_account,
+        base_amount,
     )?;
 
-    invoke_signed(
-        &transfer_base_instruction,
-        &[
-            accounts.spl_token_program.clone(),
-            accounts.base_vault.clone(),
-            accounts.destination_base_account.clone(),
-            accounts.market_signer.clone(),
-        ],
-        &[&[
-            &accounts.market.key.to_bytes(),
-            &[market_state.signer_nonce as u8],
-        ]],
-    )?;
+    let quote_balance_before = user_account.header.quote_balance();
+    user_account.header.quote_token_free -= quote_amount;
+    market_state.update_quote_backing(quote_balance_before, user_account.header.quote_balance())?;
+    user_account.header.base_token_free -= base_amount;
 
-    user_account.header.quote_token_free = 0;
-    user_account.header.base_token_free = 0;
+    if *unwrap_sol == 1 {
+        unwrap_native_destinations(&accounts)?;
+    }
+
+    Ok(())
+}
 
+/// Resolves the amount to settle out of a free balance, 0 meaning all of it
+fn get_settled_amount(requested_amount: u64, free_balance: u64) -> Result<u64, DexError> {
+    match requested_amount {
+        0 => Ok(free_balance),
+        a if a > free_balance => {
+            msg!(
+                "Cannot settle {} tokens out of a free balance of {}",
+                a,
+                free_balance
+            );
+            Err(DexError::InsufficientFreeBalance)
+        }
+        a => Ok(a),
+    }
+}
+
+/// Closes the wrapped SOL destination token accounts to the user wallet
+fn unwrap_native_destinations(accounts: &Accounts<AccountInfo>) -> ProgramResult {
+    let mut unwrapped = false;
+    for destination in [
+        accounts.destination_base_account,
+        accounts.destination_quote_account,
+    ] {
+        let mint = unpack_token_account(destination)?.mint;
+        if mint != spl_token::native_mint::ID && mint != spl_token_2022::native_mint::ID {
+            continue;
+        }
+        let close_instruction = spl_token_2022::instruction::close_account(
+            accounts.spl_token_program.key,
+            destination.key,
+            accounts.user_owner.key,
+            accounts.user_owner.key,
+            &[],
+        )?;
+        invoke(
+            &close_instruction,
+            &[
+                accounts.spl_token_program.clone(),
+                destination.clone(),
+                accounts.user_owner.clone(),
+            ],
+        )?;
+        unwrapped = true;
+    }
+    if !unwrapped {
+        msg!("Neither destination token account holds wrapped SOL");
+        return Err(DexError::NonNativeMint.into());
+    }
     Ok(())
 }
 
+/// Transfers tokens out of one of the market vaults, signing as the market signer
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn transfer_from_vault<'a>(
+    market_state: &DexState,
+    spl_token_program: &AccountInfo<'a>,
+    market: &AccountInfo<'a>,
+    market_signer: &AccountInfo<'a>,
+    vault: &AccountInfo<'a>,
+    mint: &AccountInfo<'a>,
+    destination: &AccountInfo<'a>,
+    amount: u64,
+) -> ProgramResult {
+    transfer_checked(
+        spl_token_program,
+        vault,
+        mint,
+        destination,
+        market_signer,
+        amount,
+        &[&[&market.key.to_bytes(), &[market_state.signer_nonce as u8]]],
+    )
+}
+
 fn check_accounts(
     program_id: &Pubkey,
     market_state: &DexState,
@@ -196,6 +282,32 @@ fn check_accounts(
         &market_state.quote_vault,
         DexError::InvalidQuoteVaultAccount,
     )?;
+    check_account_key(
+        accounts.base_mint,
+        &market_state.base_mint,
+        DexError::InvalidBaseMintAccount,
+    )?;
+    check_account_key(
+        accounts.quote_mint,
+        &market_state.quote_mint,
+        DexError::InvalidQuoteMintAccount,
+    )?;
+    check_account_key(
+        accounts.spl_token_program,
+        &market_state.token_program_id(),
+        DexError::InvalidSplTokenProgram,
+    )?;
+    // Swapped destinations would otherwise only fail within the token program
+    check_token_account_mint(
+        accounts.destination_base_account,
+        &market_state.base_mint,
+        DexError::InvalidBaseTokenAccountMint,
+    )?;
+    check_token_account_mint(
+        accounts.destination_quote_account,
+        &market_state.quote_mint,
+        DexError::InvalidQuoteTokenAccountMint,
+    )?;
 
     Ok(())
 }
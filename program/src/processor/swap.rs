@@ -1,10 +1,17 @@
 use crate::{
     error::DexError,
-    state::{CallBackInfo, DexState, FeeTier},
-    utils::{check_account_key, check_account_owner, check_signer},
+    processor::new_order::{
+        capped_match_limit, check_referral_account, event_queue_len, fill_events_quote_qty,
+        record_referral,
+    },
+    state::{CallBackInfo, DexState, FeeTier, MarketStatus, NativeQty},
+    utils::{
+        check_account_key, check_account_owner, check_discount_token_account, check_signer,
+        check_token_account_mint, fp32_div, transfer_checked, FP_32_ONE,
+    },
 };
-use asset_agnostic_orderbook::state::{SelfTradeBehavior, Side};
-use asset_agnostic_orderbook::{error::AoError, state::AccountTag};
+use asset_agnostic_orderbook::state::AccountTag;
+use asset_agnostic_orderbook::state::{critbit::Slab, SelfTradeBehavior, Side};
 use bonfida_utils::BorshSize;
 use bonfida_utils::InstructionsAccount;
 use borsh::BorshDeserialize;
@@ -13,14 +20,15 @@ use bytemuck::{try_from_bytes, Pod, Zeroable};
 use num_traits::FromPrimitive;
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
+    clock::Clock,
     entrypoint::ProgramResult,
     msg,
-    program::invoke,
-    program::invoke_signed,
-    program_error::{PrintProgramError, ProgramError},
+    program_error::ProgramError,
     pubkey::Pubkey,
     system_program,
+    sysvar::Sysvar,
 };
+use std::convert::TryFrom;
 
 use super::REFERRAL_MASK;
 
@@ -30,20 +38,30 @@ use super::REFERRAL_MASK;
 The required arguments for a new_order instruction.
 */
 pub struct Params {
-    /// For bids, the min output quantity. For asks, the exact input quantity.
+    /// For bids, the min output quantity (or the exact output quantity in exact output mode).
+    /// For asks, the exact input quantity (or the max input quantity in exact output mode).
     pub base_qty: u64,
-    /// For bids, the exact input quantity. For asks, the min output quantity.
+    /// For bids, the exact input quantity (or the max input quantity in exact output mode).
+    /// For asks, the min output quantity (or the exact output quantity in exact output mode).
     pub quote_qty: u64,
     /// The maximum number of orders to be matched against.
     ///
     /// Setting this number too high can sometimes lead to excessive resource consumption which can cause a failure.
+    /// It is capped to [`MAX_MATCH_LIMIT`](crate::processor::new_order::MAX_MATCH_LIMIT), and a zero value is replaced with
+    /// [`DEFAULT_MATCH_LIMIT`](crate::processor::new_order::DEFAULT_MATCH_LIMIT).
     pub match_limit: u64,
     /// The order's side (Bid or Ask)
     pub side: u8,
     /// Whether or not the optional discount token account was given
     pub has_discount_token_account: u8,
+    /// The maximum tolerated deviation of the average execution price from the best price on the book, in basis points.
+    ///
+    /// A value of 0 disables this check.
+    pub max_slippage_bps: u16,
+    /// Whether the output quantity is fixed and the input quantity is capped, instead of the opposite
+    pub exact_out: u8,
     /// To eliminate implicit padding
-    pub _padding: [u8; 6],
+    pub _padding: [u8; 3],
 }
 
 #[derive(InstructionsAccount)]
@@ -82,6 +100,12 @@ pub struct Accounts<'a, T> {
     #[cons(writable)]
     pub quote_vault: &'a T,
 
+    /// The base token mint
+    pub base_mint: &'a T,
+
+    /// The quote token mint
+    pub quote_mint: &'a T,
+
     /// The DEX market signer
     pub market_signer: &'a T,
 
@@ -103,6 +127,10 @@ pub struct Accounts<'a, T> {
     /// The optional referrer's token account which will receive a 20% cut of the fees
     #[cons(writable)]
     pub fee_referral_account: Option<&'a T>,
+
+    /// The optional referrer account tracking the referral volume and fees, which requires the referral account
+    #[cons(writable)]
+    pub referrer: Option<&'a T>,
 }
 
 impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
@@ -122,6 +150,8 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
             asks: next_account_info(accounts_iter)?,
             base_vault: next_account_info(accounts_iter)?,
             quote_vault: next_account_info(accounts_iter)?,
+            base_mint: next_account_info(accounts_iter)?,
+            quote_mint: next_account_info(accounts_iter)?,
             market_signer: next_account_info(accounts_iter)?,
             user_base_account: next_account_info(accounts_iter)?,
             user_quote_account: next_account_info(accounts_iter)?,
@@ -132,16 +162,12 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
                 None
             },
             fee_referral_account: next_account_info(accounts_iter).ok(),
+            referrer: next_account_info(accounts_iter).ok(),
         };
         check_signer(a.user_owner).map_err(|e| {
             msg!("The user account owner should be a signer for this transaction!");
             e
         })?;
-        check_account_key(
-            a.spl_token_program,
-            &spl_token::ID,
-            DexError::InvalidSplTokenProgram,
-        )?;
         check_account_key(
             a.system_program,
             &system_program::ID,
@@ -152,7 +178,8 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
                 discount_account,
                 &spl_token::ID,
                 DexError::InvalidSplTokenProgram,
-            )?
+            )?;
+            check_discount_token_account(discount_account, a.user_owner.key)?;
         }
         check_account_owner(a.market, program_id, DexError::InvalidStateAccountOwner)?;
 
@@ -171,11 +198,19 @@ pub(crate) fn process(
         mut quote_qty,
         match_limit,
         has_discount_token_account,
+        max_slippage_bps,
+        exact_out,
         _padding: _,
     } = try_from_bytes(instruction_data).map_err(|_| ProgramError::InvalidInstructionData)?;
+    let side: Side = FromPrimitive::from_u8(*side).ok_or(ProgramError::InvalidInstructionData)?;
     let accounts = Accounts::parse(program_id, accounts, *has_discount_token_account != 0)?;
 
-    let market_state = DexState::get(accounts.market)?;
+    let mut market_state = DexState::get(accounts.market)?;
+
+    if market_state.status != MarketStatus::Active as u8 {
+        msg!("The market is not accepting new orders.");
+        return Err(DexError::MarketPaused.into());
+    }
 
     // Check the order size
     if base_qty < &market_state.min_base_order_size {
@@ -183,19 +218,37 @@ pub(crate) fn process(
         return Err(ProgramError::InvalidArgument);
     }
 
-    check_accounts(program_id, &market_state, &accounts).unwrap();
-    let fee_tier = accounts
-        .discount_token_account
-        .map(|a| FeeTier::get(&market_state, a, accounts.user_owner.key))
-        .unwrap_or(Ok(FeeTier::Base))?;
+    check_accounts(program_id, &market_state, &accounts)?;
+    if let Some(a) = accounts.fee_referral_account {
+        check_referral_account(a, accounts.quote_vault, accounts.user_owner.key)?;
+    }
+    check_token_account_mint(
+        accounts.user_base_account,
+        &market_state.base_mint,
+        DexError::InvalidBaseTokenAccountMint,
+    )?;
+    check_token_account_mint(
+        accounts.user_quote_account,
+        &market_state.quote_mint,
+        DexError::InvalidQuoteTokenAccountMint,
+    )?;
+    let fee_tier = FeeTier::get_for_taker(
+        &market_state,
+        accounts.discount_token_account,
+        accounts.user_owner.key,
+        None,
+        Clock::get()?.unix_timestamp,
+    )?;
     let callback_info = CallBackInfo {
         user_account: Pubkey::default(),
         fee_tier: fee_tier as u8
             | ((accounts.fee_referral_account.is_some() as u8) * REFERRAL_MASK),
     };
-    if *side == Side::Bid as u8 {
-        // We make sure to leave enough quote quantity to pay for taker fees in the worst case
-        quote_qty = fee_tier.remove_taker_fee(quote_qty);
+    let exact_out = *exact_out != 0;
+    let max_quote_input = quote_qty;
+    if side == Side::Bid {
+        // We make sure to leave enough quote quantity to pay for taker fees and royalties in the worst case
+        quote_qty = fee_tier.remove_fees(&market_state, quote_qty);
     }
 
     let mut orderbook_guard = accounts.orderbook.data.borrow_mut();
@@ -204,24 +257,55 @@ pub(crate) fn process(
         AccountTag::Market,
     )?;
     let tick_size = orderbook.tick_size;
+    let (bids_key, asks_key) = (orderbook.bids, orderbook.asks);
     drop(orderbook_guard);
 
-    let (max_base_qty_scaled, max_quote_qty_scaled, limit_price) =
-        match FromPrimitive::from_u8(*side).unwrap() {
-            Side::Bid => (
-                u64::MAX,
-                market_state.scale_quote_amount(quote_qty),
-                u64::MAX - (u64::MAX % tick_size),
-            ),
-            Side::Ask => (market_state.scale_base_amount(*base_qty), u64::MAX, 0),
-        };
+    let reference_price = if *max_slippage_bps != 0 {
+        Some(get_best_price(&accounts, &bids_key, &asks_key, side)?)
+    } else {
+        None
+    };
+
+    let (max_base_qty_scaled, max_quote_qty_scaled, limit_price) = match (side, exact_out) {
+        (Side::Bid, false) => (
+            u64::MAX,
+            market_state.scale_quote_amount(NativeQty(quote_qty)).0,
+            u64::MAX - (u64::MAX % tick_size),
+        ),
+        (Side::Bid, true) => (
+            market_state.scale_base_amount(NativeQty(*base_qty)).0,
+            market_state.scale_quote_amount(NativeQty(quote_qty)).0,
+            u64::MAX - (u64::MAX % tick_size),
+        ),
+        (Side::Ask, false) => (
+            market_state.scale_base_amount(NativeQty(*base_qty)).0,
+            u64::MAX,
+            0,
+        ),
+        (Side::Ask, true) => {
+            // The matched quote quantity needs to cover the taker fees and royalties on top of the requested output
+            let royalties_rate = (market_state.royalties_bps << 32) / 10_000;
+            let gross_quote_qty = FP_32_ONE
+                .checked_sub(fee_tier.taker_rate(&market_state) + royalties_rate)
+                .and_then(|r| fp32_div(quote_qty, r))
+                .ok_or(DexError::NumericalOverflow)?;
+            (
+                market_state.scale_base_amount(NativeQty(*base_qty)).0,
+                market_state
+                    .scale_quote_amount(NativeQty(gross_quote_qty))
+                    .0
+                    + (gross_quote_qty % market_state.quote_currency_multiplier != 0) as u64,
+                0,
+            )
+        }
+    };
 
     let invoke_params = asset_agnostic_orderbook::instruction::new_order::Params {
         max_base_qty: max_base_qty_scaled,
         max_quote_qty: max_quote_qty_scaled,
         limit_price,
-        side: FromPrimitive::from_u8(*side).unwrap(),
-        match_limit: *match_limit,
+        side,
+        match_limit: capped_match_limit(*match_limit),
         callback_info,
         post_only: false,
         post_allowed: false,
@@ -235,158 +319,212 @@ pub(crate) fn process(
         asks: accounts.asks,
     };
 
+    let events_before = event_queue_len(accounts.event_queue)?;
     let mut order_summary = match asset_agnostic_orderbook::instruction::new_order::process(
         program_id,
         invoke_accounts,
         invoke_params,
     ) {
         Err(error) => {
-            error.print::<AoError>();
-            return Err(DexError::AOBError.into());
+            return Err(DexError::from_aob_error(error).into());
         }
         Ok(s) => s,
     };
 
-    market_state
-        .unscale_order_summary(&mut order_summary)
-        .unwrap();
+    market_state.unscale_order_summary(&mut order_summary)?;
+    // The makers are credited the quote of the fills of a bid, and debited the one of the fills of an ask
+    let filled_quote_qty =
+        fill_events_quote_qty(&market_state, accounts.event_queue, events_before)? as u128;
+    match side {
+        Side::Bid => market_state.update_quote_backing(0, filled_quote_qty)?,
+        Side::Ask => market_state.update_quote_backing(filled_quote_qty, 0)?,
+    }
 
-    let referral_fee = fee_tier.referral_fee(order_summary.total_quote_qty);
+    let matched_quote_qty = order_summary.total_quote_qty;
+    if matched_quote_qty < market_state.min_quote_order_size {
+        msg!("The quote order size is too small.");
+        return Err(ProgramError::InvalidArgument);
+    }
+    market_state.check_price_deviation(order_summary.total_base_qty, matched_quote_qty)?;
+    let referral_fee = fee_tier.referral_fee(&market_state, order_summary.total_quote_qty);
     let royalties_fees = order_summary
         .total_quote_qty
         .checked_mul(market_state.royalties_bps)
         .unwrap()
         / 10_000;
-    let (is_valid, base_transfer_qty, quote_transfer_qty) =
-        match FromPrimitive::from_u8(*side).unwrap() {
-            Side::Bid => {
-                // We update the order summary to properly handle the FOK order type
-
-                order_summary.total_quote_qty +=
-                    fee_tier.taker_fee(order_summary.total_quote_qty) + royalties_fees;
-
-                let is_valid = &order_summary.total_base_qty >= base_qty;
+    market_state.accumulated_royalties = market_state
+        .accumulated_royalties
+        .checked_add(royalties_fees)
+        .ok_or(DexError::NumericalOverflow)?;
+    let (is_valid, base_transfer_qty, quote_transfer_qty) = match side {
+        Side::Bid => {
+            // We update the order summary to properly handle the FOK order type
+
+            let taker_fee = fee_tier.taker_fee(&market_state, order_summary.total_quote_qty);
+            order_summary.total_quote_qty += taker_fee + royalties_fees;
+
+            let is_valid = &order_summary.total_base_qty >= base_qty
+                && (!exact_out || order_summary.total_quote_qty <= max_quote_input);
+
+            (
+                is_valid,
+                order_summary.total_base_qty,
+                order_summary.total_quote_qty,
+            )
+        }
+        Side::Ask => {
+            let taker_fee = fee_tier.taker_fee(&market_state, order_summary.total_quote_qty);
+            let quote_output = order_summary
+                .total_quote_qty
+                .checked_sub(taker_fee + royalties_fees)
+                .ok_or(DexError::NumericalOverflow)?;
+
+            let is_valid = if exact_out {
+                quote_output >= quote_qty
+            } else {
+                order_summary.total_quote_qty >= quote_qty
+            };
 
-                (
-                    is_valid,
-                    order_summary.total_base_qty,
-                    order_summary.total_quote_qty,
-                )
-            }
-            Side::Ask => {
-                let taker_fee = fee_tier.taker_fee(order_summary.total_quote_qty);
-
-                let is_valid = order_summary.total_quote_qty >= quote_qty;
-
-                (
-                    is_valid,
-                    order_summary.total_base_qty,
-                    order_summary
-                        .total_quote_qty
-                        .checked_sub(taker_fee + royalties_fees)
-                        .unwrap(),
-                )
-            }
-        };
+            (is_valid, order_summary.total_base_qty, quote_output)
+        }
+    };
 
     if !is_valid {
         msg!("Insufficient output amount");
         return Err(DexError::TransactionAborted.into());
     };
 
+    if let Some(reference_price) = reference_price {
+        let worst_price = match side {
+            Side::Bid => (reference_price as u128) * (10_000 + *max_slippage_bps as u128) / 10_000,
+            Side::Ask => {
+                (reference_price as u128) * 10_000u128.saturating_sub(*max_slippage_bps as u128)
+                    / 10_000
+            }
+        };
+        let worst_quote_qty = u64::try_from(worst_price)
+            .ok()
+            .and_then(|p| {
+                market_state
+                    .get_quote_from_base(NativeQty(order_summary.total_base_qty), p)
+                    .ok()
+            })
+            .map(|q| q.0)
+            .unwrap_or(u64::MAX);
+        let within_slippage = match side {
+            Side::Bid => matched_quote_qty <= worst_quote_qty,
+            Side::Ask => matched_quote_qty >= worst_quote_qty,
+        };
+        if !within_slippage {
+            msg!("The swap exceeds the maximum allowed slippage");
+            return Err(DexError::TransactionAborted.into());
+        }
+    }
+
     let base_transfer_params = (
         base_transfer_qty,
         accounts.user_base_account,
         accounts.base_vault,
+        accounts.base_mint,
     );
     let quote_transfer_params = (
         quote_transfer_qty,
         accounts.user_quote_account,
         accounts.quote_vault,
+        accounts.quote_mint,
     );
 
-    let (transfer_in_qty, transfer_in_from, transfer_in_to) =
-        match FromPrimitive::from_u8(*side).unwrap() {
-            Side::Bid => quote_transfer_params,
-            Side::Ask => base_transfer_params,
-        };
+    let (transfer_in_qty, transfer_in_from, transfer_in_to, transfer_in_mint) = match side {
+        Side::Bid => quote_transfer_params,
+        Side::Ask => base_transfer_params,
+    };
 
-    let transfer_in_instruction = spl_token::instruction::transfer(
-        accounts.spl_token_program.key,
-        transfer_in_from.key,
-        transfer_in_to.key,
-        accounts.user_owner.key,
-        &[],
+    transfer_checked(
+        accounts.spl_token_program,
+        transfer_in_from,
+        transfer_in_mint,
+        transfer_in_to,
+        accounts.user_owner,
         transfer_in_qty,
+        &[],
     )?;
 
-    invoke(
-        &transfer_in_instruction,
-        &[
-            accounts.spl_token_program.clone(),
-            transfer_in_from.clone(),
-            transfer_in_to.clone(),
-            accounts.user_owner.clone(),
-        ],
-    )?;
-
-    let (transfer_out_qty, transfer_out_to, transfer_out_from) =
-        match FromPrimitive::from_u8(*side).unwrap() {
-            Side::Bid => base_transfer_params,
-            Side::Ask => quote_transfer_params,
-        };
+    let (transfer_out_qty, transfer_out_to, transfer_out_from, transfer_out_mint) = match side {
+        Side::Bid => base_transfer_params,
+        Side::Ask => quote_transfer_params,
+    };
 
-    let transfer_out_instruction = spl_token::instruction::transfer(
-        accounts.spl_token_program.key,
-        transfer_out_from.key,
-        transfer_out_to.key,
-        accounts.market_signer.key,
-        &[],
+    transfer_checked(
+        accounts.spl_token_program,
+        transfer_out_from,
+        transfer_out_mint,
+        transfer_out_to,
+        accounts.market_signer,
         transfer_out_qty,
-    )?;
-
-    invoke_signed(
-        &transfer_out_instruction,
-        &[
-            accounts.spl_token_program.clone(),
-            transfer_out_from.clone(),
-            transfer_out_to.clone(),
-            accounts.market_signer.clone(),
-        ],
         &[&[
             &accounts.market.key.to_bytes(),
             &[market_state.signer_nonce as u8],
         ]],
     )?;
 
+    market_state.last_order_slot = Clock::get()?.slot;
+
     if let Some(fee_token_account) = accounts.fee_referral_account {
-        let referral_fee_transfer_instruction = spl_token::instruction::transfer(
-            accounts.spl_token_program.key,
-            accounts.quote_vault.key,
-            fee_token_account.key,
-            accounts.user_owner.key,
-            &[],
+        transfer_checked(
+            accounts.spl_token_program,
+            accounts.quote_vault,
+            accounts.quote_mint,
+            fee_token_account,
+            accounts.market_signer,
             referral_fee,
-        )?;
-
-        invoke_signed(
-            &referral_fee_transfer_instruction,
-            &[
-                accounts.spl_token_program.clone(),
-                accounts.quote_vault.clone(),
-                fee_token_account.clone(),
-                accounts.user_owner.clone(),
-            ],
             &[&[
                 &accounts.market.key.to_bytes(),
                 &[market_state.signer_nonce as u8],
             ]],
         )?;
+        if let Some(referrer) = accounts.referrer {
+            record_referral(
+                program_id,
+                accounts.market,
+                fee_token_account,
+                referrer,
+                matched_quote_qty,
+                referral_fee,
+            )?;
+        }
     }
 
     Ok(())
 }
 
+/// Returns the best price on the side of the book which the swap is taking liquidity from.
+fn get_best_price(
+    accounts: &Accounts<AccountInfo>,
+    bids_key: &Pubkey,
+    asks_key: &Pubkey,
+    taker_side: Side,
+) -> Result<u64, ProgramError> {
+    check_account_key(accounts.bids, bids_key, DexError::InvalidOrderbookAccount)?;
+    check_account_key(accounts.asks, asks_key, DexError::InvalidOrderbookAccount)?;
+    let (book, tag) = match taker_side {
+        Side::Bid => (accounts.asks, AccountTag::Asks),
+        Side::Ask => (accounts.bids, AccountTag::Bids),
+    };
+    let mut book_guard = book.data.borrow_mut();
+    let slab = Slab::<CallBackInfo>::from_buffer(&mut book_guard, tag)?;
+    let best_handle = match taker_side {
+        Side::Bid => slab.find_min(),
+        Side::Ask => slab.find_max(),
+    };
+    match best_handle {
+        Some(h) => Ok(slab.leaf_nodes[h as usize].price()),
+        None => {
+            msg!("The orderbook is empty, no reference price is available");
+            Err(DexError::TransactionAborted.into())
+        }
+    }
+}
+
 fn check_accounts(
     program_id: &Pubkey,
     market_state: &DexState,
@@ -419,6 +557,21 @@ fn check_accounts(
         &market_state.quote_vault,
         DexError::InvalidQuoteVaultAccount,
     )?;
+    check_account_key(
+        accounts.base_mint,
+        &market_state.base_mint,
+        DexError::InvalidBaseMintAccount,
+    )?;
+    check_account_key(
+        accounts.quote_mint,
+        &market_state.quote_mint,
+        DexError::InvalidQuoteMintAccount,
+    )?;
+    check_account_key(
+        accounts.spl_token_program,
+        &market_state.token_program_id(),
+        DexError::InvalidSplTokenProgram,
+    )?;
 
     Ok(())
 }
@@ -3,27 +3,30 @@ use crate::{
     error::DexError,
     processor::SWEEP_AUTHORITY,
     state::DexState,
-    utils::{check_account_key, check_account_owner, check_metadata_account},
+    utils::{
+        check_account_key, check_account_owner, check_token_account_owner, transfer_checked,
+        unpack_token_account,
+    },
 };
-use bonfida_utils::checks::check_token_account_owner;
 use bonfida_utils::BorshSize;
 use bonfida_utils::InstructionsAccount;
 use borsh::BorshDeserialize;
 use borsh::BorshSerialize;
-use bytemuck::{Pod, Zeroable};
-use mpl_token_metadata::state::{Metadata, TokenMetadataAccount};
+use bytemuck::{try_from_bytes, Pod, Zeroable};
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
     entrypoint::ProgramResult,
     msg,
-    program::invoke_signed,
     program_error::ProgramError,
     pubkey::Pubkey,
 };
 
 #[derive(Clone, Copy, BorshDeserialize, BorshSerialize, BorshSize, Pod, Zeroable)]
 #[repr(C)]
-pub struct Params {}
+pub struct Params {
+    /// The maximum amount of accumulated fees to sweep, 0 meaning all of them
+    pub amount: u64,
+}
 
 #[derive(InstructionsAccount)]
 pub struct Accounts<'a, T> {
@@ -38,19 +41,15 @@ pub struct Accounts<'a, T> {
     #[cons(writable)]
     pub quote_vault: &'a T,
 
+    /// The quote token mint
+    pub quote_mint: &'a T,
+
     /// The destination token account
     #[cons(writable)]
     pub destination_token_account: &'a T,
 
     /// The spl token program
     pub spl_token_program: &'a T,
-
-    /// The metadata account
-    pub token_metadata: &'a T,
-
-    /// The creator token account
-    #[cons(writable)]
-    pub creators_token_accounts: &'a [T],
 }
 
 impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
@@ -64,122 +63,83 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
             market: next_account_info(accounts_iter)?,
             market_signer: next_account_info(accounts_iter)?,
             quote_vault: next_account_info(accounts_iter)?,
+            quote_mint: next_account_info(accounts_iter)?,
             destination_token_account: next_account_info(accounts_iter)?,
             spl_token_program: next_account_info(accounts_iter)?,
-            token_metadata: next_account_info(accounts_iter)?,
-            creators_token_accounts: accounts_iter.as_slice(),
         };
 
-        check_account_key(
-            a.spl_token_program,
-            &spl_token::ID,
-            DexError::InvalidSplTokenProgram,
-        )?;
-
         check_account_owner(a.market, program_id, DexError::InvalidStateAccountOwner)?;
 
         Ok(a)
     }
 }
 
-pub(crate) fn process(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+pub(crate) fn process(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
     let accounts = Accounts::parse(program_id, accounts)?;
+    let Params { amount } =
+        try_from_bytes(instruction_data).map_err(|_| ProgramError::InvalidInstructionData)?;
 
     let mut market_state = DexState::get(accounts.market)?;
     check_accounts(program_id, &market_state, &accounts)?;
-    check_metadata_account(accounts.token_metadata, &market_state.base_mint)?;
-
-    let mut no_op = true;
-
-    if accounts.token_metadata.data_len() != 0 && market_state.accumulated_royalties != 0 {
-        no_op = false;
-        let metadata: Metadata = Metadata::from_account_info(accounts.token_metadata)?;
-        let mut share_sum = 0;
-        let mut royalties_sum = 0u64;
-        if let Some(creators) = metadata.data.creators {
-            for (idx, creator) in creators.into_iter().enumerate() {
-                share_sum += creator.share;
-                let token_destination = accounts.creators_token_accounts.get(idx).unwrap();
-                let amount = market_state
-                    .accumulated_royalties
-                    .checked_mul(creator.share as u64)
-                    .ok_or(DexError::NumericalOverflow)?
-                    / 100;
-
-                royalties_sum = royalties_sum
-                    .checked_add(amount)
-                    .ok_or(DexError::NumericalOverflow)?;
-
-                check_token_account_owner(token_destination, &creator.address)?;
-
-                let transfer_instruction = spl_token::instruction::transfer(
-                    &spl_token::ID,
-                    accounts.quote_vault.key,
-                    token_destination.key,
-                    accounts.market_signer.key,
-                    &[],
-                    amount,
-                )?;
-                invoke_signed(
-                    &transfer_instruction,
-                    &[
-                        accounts.spl_token_program.clone(),
-                        accounts.quote_vault.clone(),
-                        token_destination.clone(),
-                        accounts.market_signer.clone(),
-                    ],
-                    &[&[
-                        &accounts.market.key.to_bytes(),
-                        &[market_state.signer_nonce as u8],
-                    ]],
-                )?;
-            }
-
-            if share_sum != 100 {
-                msg!("Invalid metadata shares - received {}", share_sum);
-                return Err(ProgramError::InvalidAccountData);
-            }
-
-            market_state.accumulated_royalties = market_state
-                .accumulated_royalties
-                .checked_sub(royalties_sum)
-                .ok_or(DexError::NumericalOverflow)?;
-        }
-    }
 
-    if market_state.accumulated_fees != 0 {
-        no_op = false;
-        let transfer_instruction = spl_token::instruction::transfer(
-            &spl_token::ID,
-            accounts.quote_vault.key,
-            accounts.destination_token_account.key,
-            accounts.market_signer.key,
-            &[],
-            market_state.accumulated_fees,
-        )?;
-
-        invoke_signed(
-            &transfer_instruction,
-            &[
-                accounts.spl_token_program.clone(),
-                accounts.quote_vault.clone(),
-                accounts.destination_token_account.clone(),
-                accounts.market_signer.clone(),
-            ],
-            &[&[
-                &accounts.market.key.to_bytes(),
-                &[market_state.signer_nonce as u8],
-            ]],
-        )?;
-
-        market_state.accumulated_fees = 0;
-    }
+    check_vault_balance(&market_state, accounts.quote_vault)?;
+
+    let fees_to_sweep = if *amount == 0 {
+        market_state.accumulated_fees
+    } else {
+        market_state.accumulated_fees.min(*amount)
+    };
 
-    if no_op {
+    if fees_to_sweep == 0 {
         msg!("There are no fees to be extracted from this market!");
         return Err(DexError::NoOp.into());
     }
 
+    transfer_checked(
+        accounts.spl_token_program,
+        accounts.quote_vault,
+        accounts.quote_mint,
+        accounts.destination_token_account,
+        accounts.market_signer,
+        fees_to_sweep,
+        &[&[
+            &accounts.market.key.to_bytes(),
+            &[market_state.signer_nonce as u8],
+        ]],
+    )?;
+
+    market_state.accumulated_fees -= fees_to_sweep;
+
+    Ok(())
+}
+
+/// Checks that the quote vault holds enough tokens to cover all fees and royalties accounted for by the market, on top of
+/// the quote tokens held on behalf of the user accounts
+pub(crate) fn check_vault_balance(
+    market_state: &DexState,
+    quote_vault: &AccountInfo,
+) -> ProgramResult {
+    let vault_balance = unpack_token_account(quote_vault)?.amount;
+    let total_sweep = market_state
+        .accumulated_fees
+        .checked_add(market_state.accumulated_royalties)
+        .ok_or(DexError::NumericalOverflow)?;
+    let total_owed = total_sweep
+        .checked_add(market_state.total_quote_backing)
+        .ok_or(DexError::NumericalOverflow)?;
+    if total_owed > vault_balance {
+        msg!(
+            "The market accounts for {} quote tokens in fees and royalties and {} in user balances but the vault only holds {}",
+            total_sweep,
+            market_state.total_quote_backing,
+            vault_balance
+        );
+        return Err(DexError::InsufficientVaultBalance.into());
+    }
     Ok(())
 }
 
@@ -205,8 +165,24 @@ fn check_accounts(
         &market_state.quote_vault,
         DexError::InvalidQuoteVaultAccount,
     )?;
+    check_account_key(
+        accounts.quote_mint,
+        &market_state.quote_mint,
+        DexError::InvalidQuoteMintAccount,
+    )?;
+    check_account_key(
+        accounts.spl_token_program,
+        &market_state.token_program_id(),
+        DexError::InvalidSplTokenProgram,
+    )?;
 
-    check_token_account_owner(accounts.destination_token_account, &SWEEP_AUTHORITY)?;
+    // Markets created before the sweep authority was configurable fall back to the global sweep authority
+    let sweep_authority = if market_state.sweep_authority == Pubkey::default() {
+        &SWEEP_AUTHORITY
+    } else {
+        &market_state.sweep_authority
+    };
+    check_token_account_owner(accounts.destination_token_account, sweep_authority)?;
 
     Ok(())
 }
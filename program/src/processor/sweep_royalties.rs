@@ -0,0 +1,230 @@
+//! Distribute the accumulated royalties to the creators of the base token.
+use crate::{
+    error::DexError,
+    processor::sweep_fees::check_vault_balance,
+    state::DexState,
+    utils::{check_account_key, check_account_owner, check_token_account_owner, transfer_checked},
+};
+use bonfida_utils::BorshSize;
+use bonfida_utils::InstructionsAccount;
+use borsh::BorshDeserialize;
+use borsh::BorshSerialize;
+use bytemuck::{try_from_bytes, Pod, Zeroable};
+use mpl_token_metadata::state::MAX_CREATOR_LIMIT;
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+#[derive(Clone, Copy, BorshDeserialize, BorshSerialize, BorshSize, Pod, Zeroable)]
+#[repr(C)]
+pub struct Params {
+    /// Whether to skip the creators whose token account is missing, keeping what they are owed on the market for a
+    /// later sweep, instead of failing
+    pub skip_missing_creators: u8,
+}
+
+#[derive(InstructionsAccount)]
+pub struct Accounts<'a, T> {
+    /// The DEX market
+    #[cons(writable)]
+    pub market: &'a T,
+
+    /// The DEX market signer
+    pub market_signer: &'a T,
+
+    /// The market quote token vault
+    #[cons(writable)]
+    pub quote_vault: &'a T,
+
+    /// The quote token mint
+    pub quote_mint: &'a T,
+
+    /// The spl token program
+    pub spl_token_program: &'a T,
+
+    /// The creator token account
+    #[cons(writable)]
+    pub creators_token_accounts: &'a [T],
+}
+
+impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+
+        let a = Self {
+            market: next_account_info(accounts_iter)?,
+            market_signer: next_account_info(accounts_iter)?,
+            quote_vault: next_account_info(accounts_iter)?,
+            quote_mint: next_account_info(accounts_iter)?,
+            spl_token_program: next_account_info(accounts_iter)?,
+            creators_token_accounts: accounts_iter.as_slice(),
+        };
+
+        check_account_owner(a.market, program_id, DexError::InvalidStateAccountOwner)?;
+
+        Ok(a)
+    }
+}
+
+pub(crate) fn process(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+    let Params {
+        skip_missing_creators,
+    } = try_from_bytes(instruction_data).map_err(|_| ProgramError::InvalidInstructionData)?;
+
+    let mut market_state = DexState::get(accounts.market)?;
+    check_accounts(program_id, &market_state, &accounts)?;
+
+    check_vault_balance(&market_state, accounts.quote_vault)?;
+
+    if market_state.number_of_creators == 0 || market_state.accumulated_royalties == 0 {
+        msg!("There are no royalties to be extracted from this market!");
+        return Err(DexError::NoOp.into());
+    }
+
+    // The royalties which weren't attributed yet are split between the creators cached by create_market and
+    // update_royalties, on top of what the creators skipped by previous sweeps are owed
+    let number_of_creators = market_state.number_of_creators as usize;
+    let creators = market_state.creators;
+    let creator_shares = market_state.creator_shares;
+    let share_sum: u64 = creator_shares
+        .iter()
+        .take(number_of_creators)
+        .map(|s| *s as u64)
+        .sum();
+    if share_sum != 100 {
+        msg!("Invalid metadata shares - received {}", share_sum);
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let unpaid_sum = market_state
+        .unpaid_royalties
+        .iter()
+        .try_fold(0u64, |sum, unpaid| sum.checked_add(*unpaid))
+        .ok_or(DexError::NumericalOverflow)?;
+    let new_royalties = market_state
+        .accumulated_royalties
+        .checked_sub(unpaid_sum)
+        .ok_or(DexError::NumericalOverflow)?;
+    let mut amounts = [0u64; MAX_CREATOR_LIMIT];
+    let mut attributed = 0;
+    for (amount, share) in amounts
+        .iter_mut()
+        .zip(creator_shares.iter())
+        .take(number_of_creators)
+    {
+        *amount = new_royalties
+            .checked_mul(*share as u64)
+            .ok_or(DexError::NumericalOverflow)?
+            / 100;
+        attributed += *amount;
+    }
+    // The rounding dust goes to the creator with the highest share, so that the royalties can be fully swept
+    if let Some(idx) = (0..number_of_creators)
+        .rev()
+        .max_by_key(|idx| creator_shares[*idx])
+    {
+        amounts[idx] += new_royalties - attributed;
+    }
+
+    // The skipped creators keep their amount on the market, to be paid out by a later sweep
+    let mut royalties_sum = 0u64;
+    let mut payouts = Vec::with_capacity(number_of_creators);
+    for (idx, address) in creators.iter().take(number_of_creators).enumerate() {
+        let amount = market_state.unpaid_royalties[idx]
+            .checked_add(amounts[idx])
+            .ok_or(DexError::NumericalOverflow)?;
+        let token_destination = match accounts.creators_token_accounts.get(idx) {
+            Some(a) if a.data_len() != 0 => a,
+            _ if *skip_missing_creators != 0 => {
+                msg!(
+                    "Skipping creator {}: missing token account, {} royalties are left on the market",
+                    address,
+                    amount
+                );
+                market_state.unpaid_royalties[idx] = amount;
+                continue;
+            }
+            _ => {
+                msg!("Missing the token account of creator {}", address);
+                return Err(DexError::MissingCreatorTokenAccount.into());
+            }
+        };
+
+        check_token_account_owner(token_destination, address)?;
+
+        market_state.unpaid_royalties[idx] = 0;
+        royalties_sum = royalties_sum
+            .checked_add(amount)
+            .ok_or(DexError::NumericalOverflow)?;
+        payouts.push((token_destination, amount));
+    }
+
+    for (token_destination, amount) in payouts {
+        transfer_checked(
+            accounts.spl_token_program,
+            accounts.quote_vault,
+            accounts.quote_mint,
+            token_destination,
+            accounts.market_signer,
+            amount,
+            &[&[
+                &accounts.market.key.to_bytes(),
+                &[market_state.signer_nonce as u8],
+            ]],
+        )?;
+    }
+
+    market_state.accumulated_royalties = market_state
+        .accumulated_royalties
+        .checked_sub(royalties_sum)
+        .ok_or(DexError::NumericalOverflow)?;
+
+    Ok(())
+}
+
+fn check_accounts(
+    program_id: &Pubkey,
+    market_state: &DexState,
+    accounts: &Accounts<AccountInfo>,
+) -> ProgramResult {
+    let market_signer = Pubkey::create_program_address(
+        &[
+            &accounts.market.key.to_bytes(),
+            &[market_state.signer_nonce as u8],
+        ],
+        program_id,
+    )?;
+    check_account_key(
+        accounts.market_signer,
+        &market_signer,
+        DexError::InvalidMarketSignerAccount,
+    )?;
+    check_account_key(
+        accounts.quote_vault,
+        &market_state.quote_vault,
+        DexError::InvalidQuoteVaultAccount,
+    )?;
+    check_account_key(
+        accounts.quote_mint,
+        &market_state.quote_mint,
+        DexError::InvalidQuoteMintAccount,
+    )?;
+    check_account_key(
+        accounts.spl_token_program,
+        &market_state.token_program_id(),
+        DexError::InvalidSplTokenProgram,
+    )?;
+
+    Ok(())
+}
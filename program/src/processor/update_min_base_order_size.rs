@@ -0,0 +1,111 @@
+//! Update the market's minimum base order size. This is an admin instruction.
+use {
+    bonfida_utils::{BorshSize, InstructionsAccount},
+    borsh::{BorshDeserialize, BorshSerialize},
+    bytemuck::{try_from_bytes, Pod, Zeroable},
+    solana_program::{
+        account_info::{next_account_info, AccountInfo},
+        entrypoint::ProgramResult,
+        msg,
+        program_error::ProgramError,
+        pubkey::Pubkey,
+    },
+};
+
+use asset_agnostic_orderbook::state::{market_state::MarketState, AccountTag};
+
+use crate::{
+    error::DexError,
+    state::{DexState, NativeQty},
+    utils::{check_account_key, check_account_owner, check_signer},
+};
+
+#[derive(Copy, Clone, Zeroable, Pod, BorshDeserialize, BorshSerialize, BorshSize)]
+#[repr(C)]
+pub struct Params {
+    /// The new minimum allowed order size in base token amount
+    pub min_base_order_size: u64,
+}
+
+#[derive(InstructionsAccount)]
+pub struct Accounts<'a, T> {
+    /// The DEX market
+    #[cons(writable)]
+    pub market: &'a T,
+
+    /// The AOB market account
+    #[cons(writable)]
+    pub orderbook: &'a T,
+
+    /// The market admin account
+    #[cons(signer)]
+    pub market_admin: &'a T,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
+    pub(crate) fn parse(
+        accounts: &'a [AccountInfo<'b>],
+        program_id: &Pubkey,
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let a = Self {
+            market: next_account_info(accounts_iter)?,
+            orderbook: next_account_info(accounts_iter)?,
+            market_admin: next_account_info(accounts_iter)?,
+        };
+
+        // Check keys
+
+        // Check owners
+        check_account_owner(a.market, program_id, DexError::InvalidStateAccountOwner)?;
+        check_account_owner(a.orderbook, program_id, DexError::InvalidStateAccountOwner)?;
+
+        // Check signers
+        check_signer(a.market_admin).map_err(|e| {
+            msg!("The market admin should be a signer for this transaction!");
+            e
+        })?;
+
+        Ok(a)
+    }
+}
+
+pub(crate) fn process(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let accounts = Accounts::parse(accounts, program_id)?;
+    let Params {
+        min_base_order_size,
+    } = try_from_bytes(instruction_data).map_err(|_| ProgramError::InvalidInstructionData)?;
+
+    let mut market_state = DexState::get(accounts.market)?;
+
+    check_account_key(
+        accounts.orderbook,
+        &market_state.orderbook,
+        DexError::InvalidOrderbookAccount,
+    )?;
+    check_account_key(
+        accounts.market_admin,
+        &market_state.admin,
+        DexError::InvalidMarketAdminAccount,
+    )?;
+
+    if min_base_order_size == &0 || min_base_order_size % market_state.base_currency_multiplier != 0
+    {
+        msg!("The minimum base order size should be a nonzero multiple of the base currency multiplier!");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let mut orderbook_guard = accounts.orderbook.data.borrow_mut();
+    let aob_state = MarketState::from_buffer(&mut orderbook_guard, AccountTag::Market)?;
+
+    aob_state.min_base_order_size = market_state
+        .scale_base_amount(NativeQty(*min_base_order_size))
+        .0;
+    market_state.min_base_order_size = *min_base_order_size;
+
+    Ok(())
+}
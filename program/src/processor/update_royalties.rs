@@ -1,4 +1,4 @@
-//! Update market royalties.
+//! Update market royalties, and resync the cached creators with the base token metadata.
 use {
     bonfida_utils::{
         checks::{check_account_key, check_account_owner},
@@ -21,7 +21,7 @@ use asset_agnostic_orderbook::state::{event_queue::EventQueue, AccountTag};
 use crate::{
     error::DexError,
     state::{CallBackInfo, DexState},
-    utils::{check_metadata_account, verify_metadata},
+    utils::{check_metadata_account, check_signer, verify_metadata},
 };
 
 #[derive(Copy, Clone, Zeroable, Pod, BorshDeserialize, BorshSerialize, BorshSize)]
@@ -42,6 +42,10 @@ pub struct Accounts<'a, T> {
 
     /// The token metadata
     pub token_metadata: &'a T,
+
+    /// The market admin account
+    #[cons(signer)]
+    pub market_admin: &'a T,
 }
 
 impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
@@ -55,6 +59,7 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
             event_queue: next_account_info(accounts_iter)?,
             orderbook: next_account_info(accounts_iter)?,
             token_metadata: next_account_info(accounts_iter)?,
+            market_admin: next_account_info(accounts_iter)?,
         };
 
         // Check keys
@@ -65,6 +70,10 @@ impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
         check_account_owner(a.event_queue, program_id)?;
 
         // Check signers
+        check_signer(a.market_admin).map_err(|e| {
+            msg!("The market admin should be a signer for this transaction!");
+            e
+        })?;
 
         Ok(a)
     }
@@ -83,6 +92,11 @@ pub(crate) fn process(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramR
     check_metadata_account(accounts.token_metadata, &market_state.base_mint)?;
     check_account_key(accounts.orderbook, &market_state.orderbook)?;
 
+    if accounts.market_admin.key != &market_state.admin {
+        msg!("Invalid market admin account provided");
+        return Err(DexError::InvalidMarketAdminAccount.into());
+    }
+
     if &aob_state.event_queue != accounts.event_queue.key {
         return Err(DexError::EventQueueMismatch.into());
     }
@@ -98,9 +112,13 @@ pub(crate) fn process(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramR
     }
 
     let metadata: Metadata = Metadata::from_account_info(accounts.token_metadata)?;
-    verify_metadata(&metadata.data.creators.unwrap())?;
+    let creators = metadata.data.creators.unwrap();
+    verify_metadata(&creators)?;
 
     market_state.royalties_bps = metadata.data.seller_fee_basis_points as u64;
+    market_state.check_fee_schedule()?;
+    // The creators are resynced with the metadata, as sweep_royalties distributes to the cached ones
+    market_state.cache_creators(&creators)?;
 
     Ok(())
 }
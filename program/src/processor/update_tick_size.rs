@@ -0,0 +1,153 @@
+//! Update the market tick size. This is an admin instruction which requires the orderbook to be empty.
+use {
+    bonfida_utils::{BorshSize, InstructionsAccount},
+    borsh::{BorshDeserialize, BorshSerialize},
+    bytemuck::{try_from_bytes, Pod, Zeroable},
+    solana_program::{
+        account_info::{next_account_info, AccountInfo},
+        entrypoint::ProgramResult,
+        msg,
+        program_error::ProgramError,
+        pubkey::Pubkey,
+    },
+};
+
+use asset_agnostic_orderbook::state::{
+    critbit::Slab, event_queue::EventQueue, market_state::MarketState, AccountTag,
+};
+
+use crate::{
+    error::DexError,
+    state::{CallBackInfo, DexState},
+    utils::{check_account_key, check_account_owner, check_signer},
+};
+
+#[derive(Copy, Clone, Zeroable, Pod, BorshDeserialize, BorshSerialize, BorshSize)]
+#[repr(C)]
+pub struct Params {
+    /// The new tick size of the market, as FP32
+    pub tick_size: u64,
+}
+
+#[derive(InstructionsAccount)]
+pub struct Accounts<'a, T> {
+    /// The DEX market
+    pub market: &'a T,
+
+    /// The AOB market account
+    #[cons(writable)]
+    pub orderbook: &'a T,
+
+    /// The AOB event queue account
+    pub event_queue: &'a T,
+
+    /// The AOB bids account
+    pub bids: &'a T,
+
+    /// The AOB asks account
+    pub asks: &'a T,
+
+    /// The market admin account
+    #[cons(signer)]
+    pub market_admin: &'a T,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, AccountInfo<'b>> {
+    pub(crate) fn parse(
+        accounts: &'a [AccountInfo<'b>],
+        program_id: &Pubkey,
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let a = Self {
+            market: next_account_info(accounts_iter)?,
+            orderbook: next_account_info(accounts_iter)?,
+            event_queue: next_account_info(accounts_iter)?,
+            bids: next_account_info(accounts_iter)?,
+            asks: next_account_info(accounts_iter)?,
+            market_admin: next_account_info(accounts_iter)?,
+        };
+
+        // Check keys
+
+        // Check owners
+        check_account_owner(a.market, program_id, DexError::InvalidStateAccountOwner)?;
+        check_account_owner(a.orderbook, program_id, DexError::InvalidStateAccountOwner)?;
+
+        // Check signers
+        check_signer(a.market_admin).map_err(|e| {
+            msg!("The market admin should be a signer for this transaction!");
+            e
+        })?;
+
+        Ok(a)
+    }
+}
+
+pub(crate) fn process(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let accounts = Accounts::parse(accounts, program_id)?;
+    let Params { tick_size } =
+        try_from_bytes(instruction_data).map_err(|_| ProgramError::InvalidInstructionData)?;
+
+    if tick_size == &0 {
+        msg!("The ticksize should be nonzero!");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let market_state = DexState::get(accounts.market)?;
+
+    check_account_key(
+        accounts.orderbook,
+        &market_state.orderbook,
+        DexError::InvalidOrderbookAccount,
+    )?;
+    check_account_key(
+        accounts.market_admin,
+        &market_state.admin,
+        DexError::InvalidMarketAdminAccount,
+    )?;
+
+    let mut orderbook_guard = accounts.orderbook.data.borrow_mut();
+    let aob_state = MarketState::from_buffer(&mut orderbook_guard, AccountTag::Market)?;
+
+    if &aob_state.event_queue != accounts.event_queue.key {
+        return Err(DexError::EventQueueMismatch.into());
+    }
+    check_account_key(
+        accounts.bids,
+        &aob_state.bids,
+        DexError::InvalidOrderbookAccount,
+    )?;
+    check_account_key(
+        accounts.asks,
+        &aob_state.asks,
+        DexError::InvalidOrderbookAccount,
+    )?;
+
+    let mut event_queue_guard = accounts.event_queue.data.borrow_mut();
+    let event_queue =
+        EventQueue::<CallBackInfo>::from_buffer(&mut event_queue_guard, AccountTag::EventQueue)?;
+    if !event_queue.is_empty() {
+        msg!("The event queue needs to be empty");
+        return Err(DexError::EventQueueMustBeEmpty.into());
+    }
+
+    for (book, tag) in [
+        (accounts.bids, AccountTag::Bids),
+        (accounts.asks, AccountTag::Asks),
+    ] {
+        let mut book_guard = book.data.borrow_mut();
+        let slab = Slab::<CallBackInfo>::from_buffer(&mut book_guard, tag)?;
+        if slab.find_min().is_some() {
+            msg!("The orderbook needs to be empty");
+            return Err(DexError::OrderbookMustBeEmpty.into());
+        }
+    }
+
+    aob_state.tick_size = *tick_size;
+
+    Ok(())
+}
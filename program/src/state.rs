@@ -1,6 +1,7 @@
 use asset_agnostic_orderbook::state::{orderbook::CallbackInfo, OrderSummary};
 use borsh::{BorshDeserialize, BorshSerialize};
-use bytemuck::{try_cast_slice_mut, try_from_bytes_mut, Pod, Zeroable};
+use bytemuck::{bytes_of, try_cast_slice_mut, try_from_bytes, try_from_bytes_mut, Pod, Zeroable};
+use mpl_token_metadata::state::{Creator, MAX_CREATOR_LIMIT};
 use num_derive::{FromPrimitive, ToPrimitive};
 use solana_program::{
     account_info::AccountInfo, msg, program_error::ProgramError, program_pack::Pack, pubkey::Pubkey,
@@ -10,17 +11,25 @@ use std::{cell::RefMut, convert::TryInto, mem::size_of};
 use crate::{
     error::DexError,
     processor::{MSRM_MINT, REFERRAL_MASK, SRM_MINT},
-    utils::{fp32_div, fp32_mul, FP_32_ONE},
+    utils::{fp32_div, fp32_mul, fp32_mul_ceil, FP_32_ONE},
 };
 
+/// Validated decoding of the program's accounts, for off-chain consumers
+#[cfg(feature = "client")]
+pub mod decode;
+
 #[derive(Clone, Debug, PartialEq, Copy)]
 #[allow(missing_docs)]
 #[repr(u64)]
 pub enum AccountTag {
     Uninitialized,
     DexState,
-    UserAccount,
+    /// A user account under the legacy header layout, which the migrate_user_account instruction upgrades
+    LegacyUserAccount,
     Closed,
+    CandleHistory,
+    Referrer,
+    UserAccount,
 }
 
 #[derive(Clone, Copy, PartialEq, FromPrimitive, ToPrimitive)]
@@ -45,6 +54,32 @@ pub enum SelfTradeBehavior {
     AbortTransaction,
 }
 
+/// A token quantity expressed in the orderbook's scaled units.
+///
+/// Scaled quantities are obtained by dividing native quantities by the relevant currency multiplier.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct ScaledQty(pub u64);
+
+/// A token quantity expressed in native token units, as held in SPL token accounts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct NativeQty(pub u64);
+
+impl NativeQty {
+    /// Converts to scaled units, rounding down.
+    pub fn to_scaled(self, currency_multiplier: u64) -> ScaledQty {
+        ScaledQty(self.0 / currency_multiplier)
+    }
+}
+
+impl ScaledQty {
+    /// Converts to native units, returns `None` on overflow.
+    pub fn to_native(self, currency_multiplier: u64) -> Option<NativeQty> {
+        self.0.checked_mul(currency_multiplier).map(NativeQty)
+    }
+}
+
 /// The primary market state object
 #[derive(Copy, Clone, Pod, Zeroable)]
 #[repr(C)]
@@ -85,10 +120,83 @@ pub struct DexState {
     pub signer_nonce: u8,
     /// Fee type (e.g. default or stable)
     pub fee_type: u8,
+    /// The market's trading status (e.g. active or paused)
+    pub status: u8,
+    /// How the taker fee tiers are assigned (based on token holdings or on taker volume), or whether a single flat
+    /// taker rate applies to everyone
+    pub fee_model: u8,
     /// Padding
-    pub _padding: [u8; 6],
+    pub _padding: [u8; 4],
+    /// The slot at which the last order was placed on the market
+    pub last_order_slot: u64,
+    /// The minimum number of slots which must elapse after the last order placement before the market can be closed
+    pub close_grace_slots: u64,
+    /// The minimum SRM balances (in native units) required to access the Srm2 to Srm6 fee tiers, or the minimum
+    /// lifetime taker volumes (in native quote units) for volume-based markets
+    pub fee_tier_thresholds: [u64; 5],
+    /// The taker fee rates (as FP32) for each fee tier
+    pub fee_tier_taker_rates: [u64; 8],
+    /// The maker rebate rates (as FP32) for each fee tier, funded from the taker fees
+    pub fee_tier_maker_rebate_rates: [u64; 8],
+    /// The admin nominated by the current market admin, which has yet to accept the role
+    pub pending_admin: Pubkey,
+    /// The authority which must own the destination token account when sweeping fees
+    pub sweep_authority: Pubkey,
+    /// The token program which owns both market vaults (either SPL token or Token-2022)
+    pub token_program: Pubkey,
+    /// The market's minimum allowed order size in quote token amount, the check is disabled when zero
+    pub min_quote_order_size: u64,
+    /// The price (as a FP32 of native quote per native base) of the last fill between two different users
+    pub last_price: u64,
+    /// The sum of the last prices weighted by the number of seconds they were in effect, see [`DexState::cumulative_price_at`]
+    pub cumulative_price: u128,
+    /// The unix timestamp of the last price update, zero until the first fill
+    pub last_update_ts: i64,
+    /// The maximum deviation (in bps) of an order's average fill price from the last price, the check is disabled when zero
+    pub max_deviation_bps: u16,
+    /// The referrer's cut (in bps) of the taker fees, [`DEFAULT_REFERRAL_BPS`] when zero
+    pub referral_bps: u16,
+    /// The taker fee (in bps) paid by every taker on flat-fee markets, ignored by the other fee models
+    pub flat_taker_bps: u16,
+    /// To eliminate implicit padding
+    pub _padding2: [u8; 2],
+    /// The creators of the base token, cached from its metadata by create_market and update_royalties
+    pub creators: [Pubkey; MAX_CREATOR_LIMIT],
+    /// The royalties share (in percent) of each cached creator
+    pub creator_shares: [u8; MAX_CREATOR_LIMIT],
+    /// The number of cached creators
+    pub number_of_creators: u8,
+    /// The market's human-readable name, as UTF-8 padded with trailing zeroes
+    pub name: [u8; MARKET_NAME_LEN],
+    /// The market's ticker, as UTF-8 padded with trailing zeroes
+    pub ticker: [u8; MARKET_TICKER_LEN],
+    /// To eliminate implicit padding
+    pub _padding3: [u8; 2],
+    /// The unix timestamp until which takers pay no fees, see [`DexState::is_promo_active`]
+    pub promo_end_ts: i64,
+    /// The part of the accumulated royalties owed to each cached creator, which was left on the market because their
+    /// token account was missing when the royalties were swept
+    pub unpaid_royalties: [u64; MAX_CREATOR_LIMIT],
+    /// The quote tokens held in the quote vault on behalf of the user accounts, see [`DexState::update_quote_backing`]
+    pub total_quote_backing: u64,
+    /// To eliminate implicit padding
+    pub _padding4: [u8; 8],
+}
+
+/// The length in bytes of the market name field
+pub const MARKET_NAME_LEN: usize = 16;
+/// The length in bytes of the market ticker field
+pub const MARKET_TICKER_LEN: usize = 8;
+
+/// Decodes a fixed-size label padded with trailing zeroes, returning `None` if it isn't valid UTF-8
+pub fn decode_label(label: &[u8]) -> Option<&str> {
+    let len = label.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    std::str::from_utf8(&label[..len]).ok()
 }
 
+/// The referrer's cut (in bps) of the taker fees for markets which don't configure one
+pub const DEFAULT_REFERRAL_BPS: u16 = 2_000;
+
 /// Size in bytes of the dex state object
 pub const DEX_STATE_LEN: usize = size_of::<DexState>();
 
@@ -96,6 +204,10 @@ impl DexState {
     pub(crate) fn get<'a, 'b: 'a>(
         account_info: &'a AccountInfo<'b>,
     ) -> Result<RefMut<'a, Self>, ProgramError> {
+        if account_info.data_len() != DEX_STATE_LEN {
+            msg!("The market state doesn't use the current layout, migrate the market first");
+            return Err(ProgramError::InvalidAccountData);
+        }
         let a = Self::get_unchecked(account_info);
         if a.tag != AccountTag::DexState as u64 {
             return Err(ProgramError::InvalidAccountData);
@@ -110,39 +222,397 @@ impl DexState {
         a
     }
 
-    pub(crate) fn scale_quote_amount(&self, raw_quote_amount: u64) -> u64 {
-        raw_quote_amount / self.quote_currency_multiplier
+    /// Accounts for a change of the quote balance (free and locked) of a user account.
+    ///
+    /// The quote of a fill is accounted for when the order matches, so that the total also covers the fills whose events
+    /// were not consumed yet: it is credited to the makers of a bid and debited from the makers of an ask.
+    pub fn update_quote_backing(
+        &mut self,
+        balance_before: u128,
+        balance_after: u128,
+    ) -> Result<(), DexError> {
+        self.total_quote_backing = (self.total_quote_backing as u128 + balance_after)
+            .checked_sub(balance_before)
+            .and_then(|n| n.try_into().ok())
+            .ok_or(DexError::NumericalOverflow)?;
+        Ok(())
+    }
+
+    /// The token program which owns the market vaults. Markets created before Token-2022 support
+    /// have a zeroed `token_program` field and use the SPL token program.
+    pub fn token_program_id(&self) -> Pubkey {
+        if self.token_program == Pubkey::default() {
+            spl_token::ID
+        } else {
+            self.token_program
+        }
+    }
+
+    /// Converts a native quote quantity to the orderbook's scaled units, rounding down
+    pub fn scale_quote_amount(&self, raw_quote_amount: NativeQty) -> ScaledQty {
+        raw_quote_amount.to_scaled(self.quote_currency_multiplier)
+    }
+
+    /// Converts a native base quantity to the orderbook's scaled units, rounding down
+    pub fn scale_base_amount(&self, raw_base_amount: NativeQty) -> ScaledQty {
+        raw_base_amount.to_scaled(self.base_currency_multiplier)
+    }
+
+    /// Converts a scaled quote quantity to native units
+    pub fn unscale_quote_amount(
+        &self,
+        scaled_quote_amount: ScaledQty,
+    ) -> Result<NativeQty, DexError> {
+        scaled_quote_amount
+            .to_native(self.quote_currency_multiplier)
+            .ok_or(DexError::NumericalOverflow)
+    }
+
+    /// Converts a scaled base quantity to native units
+    pub fn unscale_base_amount(
+        &self,
+        scaled_base_amount: ScaledQty,
+    ) -> Result<NativeQty, DexError> {
+        scaled_base_amount
+            .to_native(self.base_currency_multiplier)
+            .ok_or(DexError::NumericalOverflow)
+    }
+
+    pub(crate) fn unscale_order_summary(
+        &self,
+        order_summary: &mut OrderSummary,
+    ) -> Result<(), DexError> {
+        order_summary.total_base_qty = self
+            .unscale_base_amount(ScaledQty(order_summary.total_base_qty))?
+            .0;
+        order_summary.total_base_qty_posted = self
+            .unscale_base_amount(ScaledQty(order_summary.total_base_qty_posted))?
+            .0;
+        order_summary.total_quote_qty = self
+            .unscale_quote_amount(ScaledQty(order_summary.total_quote_qty))?
+            .0;
+        Ok(())
+    }
+
+    /// Records the price of a fill, accumulating the previous price over the time elapsed since the last update.
+    ///
+    /// The first fill only seeds the last price and timestamp.
+    pub(crate) fn record_fill_price(&mut self, price: u64, timestamp: i64) {
+        if self.last_update_ts != 0 {
+            self.cumulative_price = self.cumulative_price_at(timestamp);
+        }
+        self.last_price = price;
+        self.last_update_ts = timestamp;
+    }
+
+    /// Caches the creators of the base token and their royalties shares, replacing the previous ones.
+    ///
+    /// The unpaid royalties follow the creators which are kept, a creator which is still owed royalties cannot be
+    /// removed.
+    pub(crate) fn cache_creators(&mut self, creators: &[Creator]) -> Result<(), ProgramError> {
+        if creators.len() > MAX_CREATOR_LIMIT {
+            msg!("Too many creators - received {}", creators.len());
+            return Err(ProgramError::InvalidArgument);
+        }
+        let mut unpaid_royalties = [0; MAX_CREATOR_LIMIT];
+        for (address, unpaid) in self
+            .creators
+            .iter()
+            .zip(self.unpaid_royalties.iter())
+            .take(self.number_of_creators as usize)
+            .filter(|(_, unpaid)| **unpaid != 0)
+        {
+            match creators.iter().position(|c| &c.address == address) {
+                Some(idx) => unpaid_royalties[idx] = *unpaid,
+                None => {
+                    msg!(
+                        "Creator {} is owed {} royalties, they need to be swept before it is removed",
+                        address,
+                        unpaid
+                    );
+                    return Err(DexError::UnpaidRoyalties.into());
+                }
+            }
+        }
+        self.creators = [Pubkey::default(); MAX_CREATOR_LIMIT];
+        self.creator_shares = [0; MAX_CREATOR_LIMIT];
+        for (idx, creator) in creators.iter().enumerate() {
+            self.creators[idx] = creator.address;
+            self.creator_shares[idx] = creator.share;
+        }
+        self.number_of_creators = creators.len() as u8;
+        self.unpaid_royalties = unpaid_royalties;
+        Ok(())
+    }
+
+    /// Sets the market's name and ticker, which must be valid UTF-8 once their trailing zeroes are removed
+    pub(crate) fn set_labels(
+        &mut self,
+        name: &[u8; MARKET_NAME_LEN],
+        ticker: &[u8; MARKET_TICKER_LEN],
+    ) -> Result<(), ProgramError> {
+        if decode_label(name).is_none() || decode_label(ticker).is_none() {
+            msg!("The market name and ticker should be valid UTF-8!");
+            return Err(ProgramError::InvalidArgument);
+        }
+        self.name = *name;
+        self.ticker = *ticker;
+        Ok(())
     }
 
-    pub(crate) fn scale_base_amount(&self, raw_base_amount: u64) -> u64 {
-        raw_base_amount / self.base_currency_multiplier
+    /// Checks that the fee tier thresholds are non-decreasing, and that every taker rate of the market, the flat one
+    /// included, stays below 100% once the royalties are added so that a taker ask always receives a quote amount.
+    ///
+    /// What the referrer leaves of each taker rate should also cover the highest maker rebate rate, as the maker of a
+    /// fill can be of any fee tier.
+    pub(crate) fn check_fee_schedule(&self) -> Result<(), ProgramError> {
+        if self.fee_tier_thresholds.windows(2).any(|w| w[0] > w[1]) {
+            msg!("The fee tier thresholds should be non-decreasing!");
+            return Err(ProgramError::InvalidArgument);
+        }
+        let royalties_rate = (self.royalties_bps as u128) << 32;
+        let exceeds_quote =
+            |rate: u64| (rate as u128) * 10_000 + royalties_rate >= (FP_32_ONE as u128) * 10_000;
+        if self
+            .fee_tier_taker_rates
+            .iter()
+            .any(|rate| exceeds_quote(*rate))
+            || self.flat_taker_bps as u64 + self.royalties_bps >= 10_000
+        {
+            msg!("The taker fees and royalties should stay below 100%!");
+            return Err(ProgramError::InvalidArgument);
+        }
+        let flat_taker_rate = [FeeTier::Base.taker_rate(self)];
+        let taker_rates = if self.fee_model == MarketFeeModel::Flat as u8 {
+            &flat_taker_rate[..]
+        } else {
+            &self.fee_tier_taker_rates[..]
+        };
+        let max_rebate_rate = self
+            .fee_tier_maker_rebate_rates
+            .iter()
+            .max()
+            .copied()
+            .unwrap_or(0);
+        if taker_rates
+            .iter()
+            .any(|rate| rate - self.referral_rate(*rate) < max_rebate_rate)
+        {
+            msg!("The taker fees should cover the maker rebates and referral fees!");
+            return Err(ProgramError::InvalidArgument);
+        }
+        Ok(())
     }
 
-    pub(crate) fn unscale_quote_amount(&self, scaled_quote_amount: u64) -> Option<u64> {
-        scaled_quote_amount.checked_mul(self.quote_currency_multiplier)
+    /// The referrer's cut of a taker rate, which never exceeds the taker rate itself
+    pub(crate) fn referral_rate(&self, taker_rate: u64) -> u64 {
+        let referral_bps = match self.referral_bps {
+            0 => DEFAULT_REFERRAL_BPS,
+            bps => bps.min(10_000),
+        };
+        taker_rate * referral_bps as u64 / 10_000
     }
 
-    pub(crate) fn unscale_base_amount(&self, scaled_base_amount: u64) -> Option<u64> {
-        scaled_base_amount.checked_mul(self.base_currency_multiplier)
+    /// The market's name, `None` if it isn't valid UTF-8
+    pub fn name(&self) -> Option<&str> {
+        decode_label(&self.name)
     }
 
-    pub(crate) fn unscale_order_summary(&self, order_summary: &mut OrderSummary) -> Option<()> {
-        order_summary.total_base_qty = self.unscale_base_amount(order_summary.total_base_qty)?;
-        order_summary.total_base_qty_posted =
-            self.unscale_base_amount(order_summary.total_base_qty_posted)?;
-        order_summary.total_quote_qty = self.unscale_quote_amount(order_summary.total_quote_qty)?;
-        Some(())
+    /// The market's ticker, `None` if it isn't valid UTF-8
+    pub fn ticker(&self) -> Option<&str> {
+        decode_label(&self.ticker)
     }
 
-    pub(crate) fn get_quote_from_base(
+    /// Checks that the average price of a matched quantity is within `max_deviation_bps` of the last price.
+    ///
+    /// The check is skipped when disabled, before the first fill, or when nothing was matched.
+    pub(crate) fn check_price_deviation(
         &self,
-        raw_base_amount: u64,
+        matched_base_qty: u64,
+        matched_quote_qty: u64,
+    ) -> Result<(), DexError> {
+        if self.max_deviation_bps == 0 || self.last_price == 0 || matched_base_qty == 0 {
+            return Ok(());
+        }
+        let price = fp32_div(matched_quote_qty, matched_base_qty).unwrap_or(u64::MAX);
+        let deviation = if price > self.last_price {
+            price - self.last_price
+        } else {
+            self.last_price - price
+        };
+        if (deviation as u128) * 10_000
+            > (self.last_price as u128) * (self.max_deviation_bps as u128)
+        {
+            msg!("The average fill price deviates too much from the last price");
+            return Err(DexError::PriceDeviationExceeded);
+        }
+        Ok(())
+    }
+
+    /// Whether takers pay no fees at the given timestamp. The promotional window ends at `promo_end_ts`, which is
+    /// excluded: a trade at exactly that timestamp pays the regular fees.
+    pub fn is_promo_active(&self, timestamp: i64) -> bool {
+        timestamp < self.promo_end_ts
+    }
+
+    /// The cumulative price extrapolated to the given timestamp, using the last price since the last update.
+    ///
+    /// The accumulator wraps around on overflow, which [`compute_twap`] accounts for.
+    pub fn cumulative_price_at(&self, timestamp: i64) -> u128 {
+        if self.last_update_ts == 0 {
+            return self.cumulative_price;
+        }
+        let elapsed = timestamp.saturating_sub(self.last_update_ts).max(0) as u128;
+        self.cumulative_price
+            .wrapping_add((self.last_price as u128).wrapping_mul(elapsed))
+    }
+
+    /// Computes the native quote quantity matching a native base quantity at a given orderbook price (as a FP32).
+    ///
+    /// The intermediate products are computed on 128 bits, so that only a result which does not fit in a `u64` overflows.
+    pub fn get_quote_from_base(
+        &self,
+        raw_base_amount: NativeQty,
         scaled_price_fp32: u64,
-    ) -> Option<u64> {
-        fp32_mul(raw_base_amount, scaled_price_fp32)
+    ) -> Result<NativeQty, DexError> {
+        fp32_mul(raw_base_amount.0, scaled_price_fp32)
             .and_then(|n| (n as u128).checked_mul(self.quote_currency_multiplier as u128))
             .and_then(|n| n.checked_div(self.base_currency_multiplier as u128))
             .and_then(|n| n.try_into().ok())
+            .map(NativeQty)
+            .ok_or(DexError::NumericalOverflow)
+    }
+}
+
+/// The layout of the dex state of markets created before the market status, fee tier schedule and later fields were
+/// introduced. Such markets can be upgraded to the current layout with the migrate_market_state instruction.
+#[derive(Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct LegacyDexState {
+    /// This u64 is used to verify and version the dex state
+    pub tag: u64,
+    /// The mint key of the base token
+    pub base_mint: Pubkey,
+    /// The mint key of the quote token
+    pub quote_mint: Pubkey,
+    /// The SPL token account holding the market's base tokens
+    pub base_vault: Pubkey,
+    /// The SPL token account holding the market's quote tokens
+    pub quote_vault: Pubkey,
+    /// The asset agnostic orderbook address
+    pub orderbook: Pubkey,
+    /// The market admin which can recuperate all transaction fees
+    pub admin: Pubkey,
+    /// The market's creation timestamp on the Solana runtime clock.
+    pub creation_timestamp: i64,
+    /// The market's total historical volume in base token
+    pub base_volume: u64,
+    /// The market's total historical volume in quote token
+    pub quote_volume: u64,
+    /// The market's fees which are available for extraction by the market admin
+    pub accumulated_fees: u64,
+    /// The market's minimum allowed order size in base token amount
+    pub min_base_order_size: u64,
+    /// Royalties bps
+    pub royalties_bps: u64,
+    /// Accumulated royalties fees
+    pub accumulated_royalties: u64,
+    /// The base currency multiplier
+    pub base_currency_multiplier: u64,
+    /// The quote currency multiplier
+    pub quote_currency_multiplier: u64,
+    /// The signer nonce is necessary for the market to perform as a signing entity
+    pub signer_nonce: u8,
+    /// Fee type (e.g. default or stable)
+    pub fee_type: u8,
+    /// Padding
+    pub _padding: [u8; 6],
+}
+
+/// Size in bytes of the legacy dex state object
+pub const LEGACY_DEX_STATE_LEN: usize = size_of::<LegacyDexState>();
+
+impl DexState {
+    /// Upgrades a legacy dex state to the current layout.
+    ///
+    /// The new fields take the values which preserve the market's behavior: the market is active, uses the default fee
+    /// tier schedule, the legacy sweep authority and the SPL token program. The creators are cached on the next
+    /// update_royalties.
+    pub fn from_legacy(legacy: &LegacyDexState) -> Self {
+        Self {
+            tag: legacy.tag,
+            base_mint: legacy.base_mint,
+            quote_mint: legacy.quote_mint,
+            base_vault: legacy.base_vault,
+            quote_vault: legacy.quote_vault,
+            orderbook: legacy.orderbook,
+            admin: legacy.admin,
+            creation_timestamp: legacy.creation_timestamp,
+            base_volume: legacy.base_volume,
+            quote_volume: legacy.quote_volume,
+            accumulated_fees: legacy.accumulated_fees,
+            min_base_order_size: legacy.min_base_order_size,
+            royalties_bps: legacy.royalties_bps,
+            accumulated_royalties: legacy.accumulated_royalties,
+            base_currency_multiplier: legacy.base_currency_multiplier,
+            quote_currency_multiplier: legacy.quote_currency_multiplier,
+            signer_nonce: legacy.signer_nonce,
+            fee_type: legacy.fee_type,
+            status: MarketStatus::Active as u8,
+            fee_model: MarketFeeModel::Holdings as u8,
+            fee_tier_thresholds: DEFAULT_FEE_TIER_THRESHOLDS,
+            fee_tier_taker_rates: DEFAULT_FEE_TIER_TAKER_RATES,
+            fee_tier_maker_rebate_rates: DEFAULT_FEE_TIER_MAKER_REBATE_RATES,
+            ..Self::zeroed()
+        }
+    }
+}
+
+/// The layout information of a market, returned by the get_market_info instruction
+#[derive(Clone, Copy, Debug, PartialEq, BorshDeserialize, BorshSerialize)]
+pub struct MarketInfo {
+    /// The major version of the program
+    pub version_major: u16,
+    /// The minor version of the program
+    pub version_minor: u16,
+    /// The patch version of the program
+    pub version_patch: u16,
+    /// The size in bytes of the dex state expected by the program
+    pub state_len: u64,
+    /// The size in bytes of the market account, which differs from `state_len` for markets with another layout
+    pub account_len: u64,
+    /// The tag of the market account
+    pub tag: u64,
+    /// The market's fee type, zero when the account doesn't hold a dex state of the expected layout
+    pub fee_type: u8,
+    /// The market's status, zero when the account doesn't hold a dex state of the expected layout
+    pub status: u8,
+}
+
+impl MarketInfo {
+    /// Reads the layout information of a market from its account data
+    pub fn from_market_data(data: &[u8]) -> Self {
+        let tag = data
+            .get(..8)
+            .map(|t| u64::from_le_bytes(t.try_into().unwrap()))
+            .unwrap_or_default();
+        let (fee_type, status) =
+            if tag == AccountTag::DexState as u64 && data.len() >= DEX_STATE_LEN {
+                let dex_state = try_from_bytes::<DexState>(&data[..DEX_STATE_LEN]).unwrap();
+                (dex_state.fee_type, dex_state.status)
+            } else {
+                (0, 0)
+            };
+        Self {
+            version_major: env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap(),
+            version_minor: env!("CARGO_PKG_VERSION_MINOR").parse().unwrap(),
+            version_patch: env!("CARGO_PKG_VERSION_PATCH").parse().unwrap(),
+            state_len: DEX_STATE_LEN as u64,
+            account_len: data.len() as u64,
+            tag,
+            fee_type,
+            status,
+        }
     }
 }
 
@@ -180,6 +650,10 @@ pub struct UserAccountHeader {
     _padding: u32,
     /// The user account's number of active orders.
     pub number_of_orders: u32,
+    /// An optional authority which can place and cancel orders on behalf of the owner, but cannot settle funds
+    pub delegate: Pubkey,
+    /// The account which paid the rent of the user account, refunded by default when it is closed
+    pub rent_payer: Pubkey,
 }
 
 /// Represents and order in the user account. The client id offers an alias which can be used off-chain to map custom ids to an actual order id.
@@ -188,7 +662,8 @@ pub struct UserAccountHeader {
 pub struct Order {
     /// The raw order id
     pub id: u128,
-    /// The client-defined order id. Care should be taken off-chain to only create new orders with new client_ids.
+    /// The client-defined order id, unique among the active orders of the user account unless the order was placed
+    /// with [`DuplicateClientIdPolicy::Allow`]
     pub client_id: u128,
 }
 
@@ -197,6 +672,20 @@ impl Order {
     pub const LEN: usize = std::mem::size_of::<Self>();
 }
 
+/// Describes how a new order is handled when an active order of the user account already uses its client id.
+///
+/// A zero client id is never considered as a duplicate.
+#[derive(Clone, Copy, Debug, PartialEq, FromPrimitive)]
+#[repr(u8)]
+pub enum DuplicateClientIdPolicy {
+    /// The new order is rejected with [`DexError::DuplicateClientId`]
+    Reject,
+    /// The client id is moved to the new order, the existing order being left with a zero client id
+    Overwrite,
+    /// Several active orders can share the same client id
+    Allow,
+}
+
 #[allow(missing_docs)]
 pub struct UserAccount<'a> {
     pub header: &'a mut UserAccountHeader,
@@ -204,10 +693,79 @@ pub struct UserAccount<'a> {
 }
 
 /// Size in bytes of the user account header object
-pub const USER_ACCOUNT_HEADER_LEN: usize = 152;
+pub const USER_ACCOUNT_HEADER_LEN: usize = 216;
+
+/// The layout of the header of user accounts created before the delegate and rent payer were introduced. Such accounts
+/// are tagged with [`AccountTag::LegacyUserAccount`] and can be upgraded with the migrate_user_account instruction.
+#[derive(Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct LegacyUserAccountHeader {
+    /// This byte is used to verify and version the dex state
+    pub tag: u64,
+    /// The user account's assocatied DEX market
+    pub market: Pubkey,
+    /// The user account owner's wallet
+    pub owner: Pubkey,
+    /// The amount of base token available for settlement
+    pub base_token_free: u64,
+    /// The amount of base token currently locked in the orderbook
+    pub base_token_locked: u64,
+    /// The amount of quote token available for settlement
+    pub quote_token_free: u64,
+    /// The amount of quote token currently locked in the orderbook
+    pub quote_token_locked: u64,
+    /// The all time quantity of rebates accumulated by this user account.
+    pub accumulated_rebates: u64,
+    /// The accumulated maker quote volume of the user.
+    pub accumulated_maker_quote_volume: u64,
+    /// The accumulated maker quote volume of the user.
+    pub accumulated_maker_base_volume: u64,
+    /// The accumulated taker quote volume of the user.
+    pub accumulated_taker_quote_volume: u64,
+    /// The accumulated taker quote volume of the user.
+    pub accumulated_taker_base_volume: u64,
+    /// Padding
+    pub _padding: u32,
+    /// The user account's number of active orders.
+    pub number_of_orders: u32,
+}
+
+/// Size in bytes of the legacy user account header object
+pub const LEGACY_USER_ACCOUNT_HEADER_LEN: usize = size_of::<LegacyUserAccountHeader>();
+
+/// Derives the address of the user account of an owner on a given market, along with its bump seed.
+///
+/// The user account is seeded by the market key, then the owner key. This is the address which
+/// the initialize_account instruction expects.
+///
+/// ```
+/// use dex_v4::state::find_user_account;
+/// use solana_program::pubkey::Pubkey;
+///
+/// let market = Pubkey::new_unique();
+/// let owner = Pubkey::new_unique();
+/// let (user_account, bump) = find_user_account(&dex_v4::ID, &market, &owner);
+/// assert_eq!(
+///     Pubkey::create_program_address(
+///         &[&market.to_bytes(), &owner.to_bytes(), &[bump]],
+///         &dex_v4::ID
+///     ),
+///     Ok(user_account)
+/// );
+/// // The seeds are not interchangeable
+/// assert_ne!(find_user_account(&dex_v4::ID, &owner, &market).0, user_account);
+/// ```
+pub fn find_user_account(program_id: &Pubkey, market: &Pubkey, owner: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[&market.to_bytes(), &owner.to_bytes()], program_id)
+}
 
 impl UserAccountHeader {
-    pub(crate) fn new(market: &Pubkey, owner: &Pubkey) -> Self {
+    /// The quote tokens held by the market on behalf of the user account, free and locked
+    pub fn quote_balance(&self) -> u128 {
+        self.quote_token_free as u128 + self.quote_token_locked as u128
+    }
+
+    pub(crate) fn new(market: &Pubkey, owner: &Pubkey, rent_payer: &Pubkey) -> Self {
         Self {
             tag: AccountTag::UserAccount as u64,
             market: *market,
@@ -223,8 +781,39 @@ impl UserAccountHeader {
             accumulated_maker_base_volume: 0,
             accumulated_taker_quote_volume: 0,
             accumulated_taker_base_volume: 0,
+            delegate: Pubkey::default(),
+            rent_payer: *rent_payer,
         }
     }
+
+    /// Upgrades a legacy user account header to the current layout.
+    ///
+    /// The account has no delegate, and its rent payer is unknown so it is left zeroed.
+    pub fn from_legacy(legacy: &LegacyUserAccountHeader) -> Self {
+        Self {
+            tag: AccountTag::UserAccount as u64,
+            market: legacy.market,
+            owner: legacy.owner,
+            base_token_free: legacy.base_token_free,
+            base_token_locked: legacy.base_token_locked,
+            quote_token_free: legacy.quote_token_free,
+            quote_token_locked: legacy.quote_token_locked,
+            number_of_orders: legacy.number_of_orders,
+            accumulated_rebates: legacy.accumulated_rebates,
+            _padding: 0,
+            accumulated_maker_quote_volume: legacy.accumulated_maker_quote_volume,
+            accumulated_maker_base_volume: legacy.accumulated_maker_base_volume,
+            accumulated_taker_quote_volume: legacy.accumulated_taker_quote_volume,
+            accumulated_taker_base_volume: legacy.accumulated_taker_base_volume,
+            delegate: Pubkey::default(),
+            rent_payer: Pubkey::default(),
+        }
+    }
+
+    /// Checks whether the given key is allowed to place and cancel orders for this user account
+    pub(crate) fn is_owner_or_delegate(&self, key: &Pubkey) -> bool {
+        &self.owner == key || (self.delegate != Pubkey::default() && &self.delegate == key)
+    }
 }
 
 impl<'a> UserAccount<'a> {
@@ -245,9 +834,38 @@ impl<'a> UserAccount<'a> {
 
         Ok(Self { header, orders })
     }
+
+    /// Computes the size in bytes of a user account holding up to `max_orders` orders.
+    ///
+    /// This is the space allocated by the initialize_account and resize_user_account instructions.
+    ///
+    /// ```
+    /// use dex_v4::state::{Order, UserAccount, USER_ACCOUNT_HEADER_LEN};
+    ///
+    /// assert_eq!(
+    ///     UserAccount::compute_allocation_size(10),
+    ///     Some(USER_ACCOUNT_HEADER_LEN + 10 * Order::LEN)
+    /// );
+    /// assert_eq!(UserAccount::compute_allocation_size(usize::MAX), None);
+    /// ```
+    pub fn compute_allocation_size(max_orders: usize) -> Option<usize> {
+        max_orders
+            .checked_mul(Order::LEN)
+            .and_then(|n| n.checked_add(USER_ACCOUNT_HEADER_LEN))
+    }
 }
 
 impl<'a> UserAccount<'a> {
+    /// The orders currently held by the user account
+    pub fn orders(&self) -> &[Order] {
+        &self.orders[..self.header.number_of_orders as usize]
+    }
+
+    /// Iterates over the orders currently held by the user account, along with their index
+    pub fn iter_orders(&self) -> impl Iterator<Item = (usize, Order)> + '_ {
+        self.orders().iter().copied().enumerate()
+    }
+
     #[allow(missing_docs)]
     pub fn read_order(&self, order_index: usize) -> Result<Order, DexError> {
         if order_index >= self.header.number_of_orders as usize {
@@ -268,8 +886,29 @@ impl<'a> UserAccount<'a> {
         Ok(())
     }
 
-    #[allow(missing_docs)]
-    pub fn add_order(&mut self, order: Order) -> Result<(), DexError> {
+    /// Adds an order to the user account, handling an active order with the same client id according to the policy
+    pub fn add_order(
+        &mut self,
+        order: Order,
+        duplicate_client_id_policy: DuplicateClientIdPolicy,
+    ) -> Result<(), DexError> {
+        if order.client_id != 0 && duplicate_client_id_policy != DuplicateClientIdPolicy::Allow {
+            let number_of_orders = self.header.number_of_orders as usize;
+            if let Some(duplicate) = self.orders[..number_of_orders]
+                .iter_mut()
+                .find(|o| o.client_id == order.client_id)
+            {
+                if duplicate_client_id_policy == DuplicateClientIdPolicy::Reject {
+                    msg!(
+                        "The client order id {} is already used by order {}",
+                        order.client_id,
+                        duplicate.id
+                    );
+                    return Err(DexError::DuplicateClientId);
+                }
+                duplicate.client_id = 0;
+            }
+        }
         let slot = self
             .orders
             .get_mut(self.header.number_of_orders as usize)
@@ -282,9 +921,7 @@ impl<'a> UserAccount<'a> {
     #[allow(missing_docs)]
     pub fn find_order_index(&self, order_id: u128) -> Result<usize, DexError> {
         let res = self
-            .orders
-            .iter()
-            .enumerate()
+            .iter_orders()
             .find(|(_, b)| b.id == order_id)
             .ok_or(DexError::OrderNotFound)?
             .0;
@@ -297,9 +934,7 @@ impl<'a> UserAccount<'a> {
         client_order_id: u128,
     ) -> Result<(u64, u128), DexError> {
         let res = self
-            .orders
-            .iter()
-            .enumerate()
+            .iter_orders()
             .find(|(_, b)| b.client_id == client_order_id)
             .map(|(idx, b)| (idx as u64, b.id))
             .ok_or(DexError::OrderNotFound)?;
@@ -307,13 +942,141 @@ impl<'a> UserAccount<'a> {
     }
 }
 
+/// The number of finalized candles held by a candle history
+pub const MAX_CANDLES: usize = 64;
+
+/// The seed which follows the market key in the derivation of its candle history address
+pub const CANDLE_HISTORY_SEED: &[u8] = b"candles";
+
+/// Size in bytes of the candle history object
+pub const CANDLE_HISTORY_LEN: usize = size_of::<CandleHistory>();
+
+/// The prices and volumes of the fills of a market over a time interval
+#[derive(Copy, Clone, Pod, Zeroable, Debug, PartialEq)]
+#[repr(C)]
+pub struct Candle {
+    /// The unix timestamp at which the candle's interval starts
+    pub start_ts: i64,
+    /// The price of the first fill (as a FP32 of native quote per native base)
+    pub open: u64,
+    /// The highest fill price (as a FP32 of native quote per native base)
+    pub high: u64,
+    /// The lowest fill price (as a FP32 of native quote per native base)
+    pub low: u64,
+    /// The price of the last fill (as a FP32 of native quote per native base)
+    pub close: u64,
+    /// The filled base quantity, in native units
+    pub base_volume: u64,
+    /// The filled quote quantity, in native units
+    pub quote_volume: u64,
+    /// The number of fills, the candle is empty when zero
+    pub number_of_fills: u64,
+}
+
+/// The candles of a market, updated by consume_events when the account is given.
+///
+/// The finalized candles are kept in a ring buffer, the oldest ones being overwritten.
+#[derive(Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct CandleHistory {
+    /// This u64 is used to verify and version the candle history
+    pub tag: u64,
+    /// The market whose fills are recorded
+    pub market: Pubkey,
+    /// The length in seconds of a candle's interval
+    pub interval: i64,
+    /// The index in `candles` where the next finalized candle is written
+    pub head: u64,
+    /// The number of finalized candles, up to [`MAX_CANDLES`]
+    pub count: u64,
+    /// The candle of the current interval, which is finalized by the first fill of a later interval
+    pub current: Candle,
+    /// The finalized candles
+    pub candles: [Candle; MAX_CANDLES],
+}
+
+/// Derives the address of the candle history of a market, along with its bump seed.
+pub fn find_candle_history(program_id: &Pubkey, market: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[&market.to_bytes(), CANDLE_HISTORY_SEED], program_id)
+}
+
+impl CandleHistory {
+    pub(crate) fn get<'a, 'b: 'a>(
+        account_info: &'a AccountInfo<'b>,
+    ) -> Result<RefMut<'a, Self>, ProgramError> {
+        if account_info.data_len() < CANDLE_HISTORY_LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let a = RefMut::map(account_info.data.borrow_mut(), |s| {
+            try_from_bytes_mut::<Self>(&mut s[0..CANDLE_HISTORY_LEN]).unwrap()
+        });
+        if a.tag != AccountTag::CandleHistory as u64 {
+            return Err(ProgramError::InvalidAccountData);
+        };
+        Ok(a)
+    }
+
+    /// Records a fill in the candle of its interval.
+    ///
+    /// The first fill of a later interval finalizes the current candle before opening a new one.
+    pub fn record_candle(&mut self, price: u64, base_qty: u64, quote_qty: u64, timestamp: i64) {
+        let start_ts = timestamp - timestamp.rem_euclid(self.interval);
+        if self.current.number_of_fills != 0 && start_ts > self.current.start_ts {
+            self.candles[self.head as usize] = self.current;
+            self.head = (self.head + 1) % MAX_CANDLES as u64;
+            self.count = (self.count + 1).min(MAX_CANDLES as u64);
+            self.current = Candle::zeroed();
+        }
+        let current = &mut self.current;
+        if current.number_of_fills == 0 {
+            *current = Candle {
+                start_ts,
+                open: price,
+                high: price,
+                low: price,
+                close: price,
+                ..Candle::zeroed()
+            };
+        }
+        current.high = current.high.max(price);
+        current.low = current.low.min(price);
+        current.close = price;
+        current.base_volume = current.base_volume.saturating_add(base_qty);
+        current.quote_volume = current.quote_volume.saturating_add(quote_qty);
+        current.number_of_fills += 1;
+    }
+
+    /// Returns the finalized candles, from the oldest to the most recent
+    pub fn finalized_candles(&self) -> impl Iterator<Item = &Candle> {
+        let len = MAX_CANDLES as u64;
+        let start = (self.head + len - self.count) % len;
+        (0..self.count).map(move |i| &self.candles[((start + i) % len) as usize])
+    }
+}
+
 #[doc(hidden)]
-#[derive(BorshDeserialize, BorshSerialize, Debug, Clone, Copy)]
+#[derive(BorshDeserialize, BorshSerialize, Debug, Clone, Copy, PartialEq, FromPrimitive)]
 pub enum MarketFeeType {
     Default,
     Stable,
 }
 
+#[doc(hidden)]
+#[derive(BorshDeserialize, BorshSerialize, Debug, Clone, Copy, PartialEq, FromPrimitive)]
+pub enum MarketFeeModel {
+    Holdings,
+    Volume,
+    Flat,
+}
+
+#[doc(hidden)]
+#[derive(BorshDeserialize, BorshSerialize, Debug, Clone, Copy, PartialEq, FromPrimitive)]
+pub enum MarketStatus {
+    Active,
+    Paused,
+    Closed,
+}
+
 #[doc(hidden)]
 #[derive(BorshDeserialize, BorshSerialize, Debug, Clone, Copy)]
 pub enum FeeTier {
@@ -325,8 +1088,42 @@ pub enum FeeTier {
     Srm6,
     MSrm,
     Stable,
+    Promo,
 }
 
+/// The default minimum SRM balances (in native units) required to access the Srm2 to Srm6 fee tiers
+pub const DEFAULT_FEE_TIER_THRESHOLDS: [u64; 5] = [
+    100_000_000,
+    1_000_000_000,
+    10_000_000_000,
+    100_000_000_000,
+    1_000_000_000_000,
+];
+
+/// The default taker fee rates (as FP32) for each fee tier
+pub const DEFAULT_FEE_TIER_TAKER_RATES: [u64; 8] = [
+    (40 << 32) / 100_000,
+    (39 << 32) / 100_000,
+    (38 << 32) / 100_000,
+    (36 << 32) / 100_000,
+    (34 << 32) / 100_000,
+    (32 << 32) / 100_000,
+    (30 << 32) / 100_000,
+    (10 << 32) / 100_000,
+];
+
+/// The default maker rebate rates (as FP32) for each fee tier
+pub const DEFAULT_FEE_TIER_MAKER_REBATE_RATES: [u64; 8] = [
+    (3 << 32) / 100_000,
+    (3 << 32) / 100_000,
+    (3 << 32) / 100_000,
+    (3 << 32) / 100_000,
+    (3 << 32) / 100_000,
+    (3 << 32) / 100_000,
+    (3 << 32) / 100_000,
+    (1 << 32) / 100_000,
+];
+
 #[doc(hidden)]
 impl FeeTier {
     pub fn from_srm_and_msrm_balances(
@@ -334,19 +1131,30 @@ impl FeeTier {
         srm_held: u64,
         msrm_held: u64,
     ) -> FeeTier {
-        let one_srm = 1_000_000;
-
         if dex_state.fee_type == MarketFeeType::Stable as u8 {
             return FeeTier::Stable;
         }
 
+        let thresholds = &dex_state.fee_tier_thresholds;
         match () {
             () if msrm_held >= 1 => FeeTier::MSrm,
-            () if srm_held >= one_srm * 1_000_000 => FeeTier::Srm6,
-            () if srm_held >= one_srm * 100_000 => FeeTier::Srm5,
-            () if srm_held >= one_srm * 10_000 => FeeTier::Srm4,
-            () if srm_held >= one_srm * 1_000 => FeeTier::Srm3,
-            () if srm_held >= one_srm * 100 => FeeTier::Srm2,
+            () if srm_held >= thresholds[4] => FeeTier::Srm6,
+            () if srm_held >= thresholds[3] => FeeTier::Srm5,
+            () if srm_held >= thresholds[2] => FeeTier::Srm4,
+            () if srm_held >= thresholds[1] => FeeTier::Srm3,
+            () if srm_held >= thresholds[0] => FeeTier::Srm2,
+            () => FeeTier::Base,
+        }
+    }
+
+    /// Returns the fee tier reached by a lifetime taker volume (in native quote units)
+    pub fn from_volume(thresholds: &[u64; 5], volume: u64) -> FeeTier {
+        match () {
+            () if volume >= thresholds[4] => FeeTier::Srm6,
+            () if volume >= thresholds[3] => FeeTier::Srm5,
+            () if volume >= thresholds[2] => FeeTier::Srm4,
+            () if volume >= thresholds[1] => FeeTier::Srm3,
+            () if volume >= thresholds[0] => FeeTier::Srm2,
             () => FeeTier::Base,
         }
     }
@@ -360,6 +1168,9 @@ impl FeeTier {
             3 => FeeTier::Srm4,
             4 => FeeTier::Srm5,
             5 => FeeTier::Srm6,
+            6 => FeeTier::MSrm,
+            7 => FeeTier::Stable,
+            8 => FeeTier::Promo,
             _ => unreachable!(),
         };
         (fee_tier, is_referred)
@@ -371,62 +1182,141 @@ impl FeeTier {
         expected_owner: &Pubkey,
     ) -> Result<Self, ProgramError> {
         let parsed_token_account = spl_token::state::Account::unpack(&account.data.borrow())?;
-        if &parsed_token_account.owner != expected_owner {
-            msg!("The discount token account must share its owner with the user account.");
-            return Err(ProgramError::InvalidArgument);
-        }
         let (srm_held, msrm_held) = match parsed_token_account.mint {
             a if a == MSRM_MINT => (0, parsed_token_account.amount),
             a if a == SRM_MINT => (parsed_token_account.amount, 0),
             _ => {
                 msg!("Invalid mint for discount token acccount.");
-                return Err(ProgramError::InvalidArgument);
+                return Err(DexError::InvalidDiscountTokenMint.into());
             }
         };
+        if &parsed_token_account.owner != expected_owner {
+            msg!("The discount token account must share its owner with the user account.");
+            return Err(DexError::InvalidDiscountTokenOwner.into());
+        }
         Ok(Self::from_srm_and_msrm_balances(
             dex_state, srm_held, msrm_held,
         ))
     }
 
-    pub fn taker_rate(self) -> u64 {
-        match self {
-            FeeTier::Base => (40 << 32) / 100_000,
-            FeeTier::Srm2 => (39 << 32) / 100_000,
-            FeeTier::Srm3 => (38 << 32) / 100_000,
-            FeeTier::Srm4 => (36 << 32) / 100_000,
-            FeeTier::Srm5 => (34 << 32) / 100_000,
-            FeeTier::Srm6 => (32 << 32) / 100_000,
-            FeeTier::MSrm => (30 << 32) / 100_000,
-            FeeTier::Stable => (10 << 32) / 100_000,
+    /// Determines the fee tier of a taker according to the market's fee model, stable markets always use the stable tier.
+    ///
+    /// Volume-based markets ignore the discount token account and read the lifetime taker volume of the user
+    /// account instead, takers without a user account (i.e. swaps) get the base tier. Flat-fee markets ignore both and
+    /// always use the base tier, whose rate is overridden by the market's flat taker rate.
+    ///
+    /// During the market's promotional window, all takers get the fee-free promo tier.
+    pub fn get_for_taker(
+        dex_state: &DexState,
+        discount_token_account: Option<&AccountInfo>,
+        expected_owner: &Pubkey,
+        user_account: Option<&UserAccountHeader>,
+        timestamp: i64,
+    ) -> Result<Self, ProgramError> {
+        if dex_state.is_promo_active(timestamp) {
+            return Ok(FeeTier::Promo);
+        }
+        if dex_state.fee_model == MarketFeeModel::Flat as u8 {
+            return Ok(FeeTier::Base);
         }
+        if dex_state.fee_type == MarketFeeType::Stable as u8 {
+            return Ok(FeeTier::Stable);
+        }
+        if dex_state.fee_model == MarketFeeModel::Volume as u8 {
+            let volume = user_account
+                .map(|u| u.accumulated_taker_quote_volume)
+                .unwrap_or(0);
+            return Ok(Self::from_volume(&dex_state.fee_tier_thresholds, volume));
+        }
+        discount_token_account
+            .map(|a| Self::get(dex_state, a, expected_owner))
+            .unwrap_or(Ok(FeeTier::Base))
+    }
+
+    pub fn taker_rate(self, dex_state: &DexState) -> u64 {
+        if let FeeTier::Promo = self {
+            return 0;
+        }
+        if dex_state.fee_model == MarketFeeModel::Flat as u8 {
+            return ((dex_state.flat_taker_bps as u64) << 32) / 10_000;
+        }
+        dex_state.fee_tier_taker_rates[self as usize]
     }
 
-    pub fn maker_rate(self) -> u64 {
-        0
+    pub fn maker_rebate_rate(self, dex_state: &DexState) -> u64 {
+        match self {
+            // Orders posted during the promotional window get the base rebate when they are filled
+            FeeTier::Promo => dex_state.fee_tier_maker_rebate_rates[FeeTier::Base as usize],
+            _ => dex_state.fee_tier_maker_rebate_rates[self as usize],
+        }
     }
 
-    pub fn maker_rebate(self, _quote_qty: u64) -> u64 {
-        0
+    pub fn maker_rebate(self, dex_state: &DexState, quote_qty: u64) -> u64 {
+        let rate = self.maker_rebate_rate(dex_state);
+        fp32_mul(quote_qty, rate).unwrap()
     }
 
-    pub fn remove_taker_fee(self, quote_qty: u64) -> u64 {
-        let rate = self.taker_rate();
-        fp32_div(quote_qty, FP_32_ONE + rate).unwrap()
+    /// Returns the taker fee charged on a quote quantity.
+    ///
+    /// The fee is rounded up, so that the program never collects less than the advertised rate.
+    pub fn taker_fee(self, dex_state: &DexState, quote_qty: u64) -> u64 {
+        let rate = self.taker_rate(dex_state);
+        fp32_mul_ceil(quote_qty, rate).unwrap()
     }
 
-    pub fn taker_fee(self, quote_qty: u64) -> u64 {
-        let rate = self.taker_rate();
+    /// Returns the taker fee on a quote quantity, rounded down.
+    ///
+    /// This is used to account for the fees of each fill of an order, so that their sum never exceeds the
+    /// taker fee which was charged for the whole order.
+    pub fn taker_fee_floor(self, dex_state: &DexState, quote_qty: u64) -> u64 {
+        let rate = self.taker_rate(dex_state);
         fp32_mul(quote_qty, rate).unwrap()
     }
 
-    pub fn referral_rate(self) -> u64 {
-        let taker_rate = self.taker_rate();
-        let min_maker_rebate = Self::Base.maker_rate();
-        taker_rate.saturating_sub(min_maker_rebate) / 5
+    /// Returns the royalties owed on a matched quote quantity
+    pub fn royalties_fee(dex_state: &DexState, quote_qty: u64) -> u64 {
+        quote_qty.checked_mul(dex_state.royalties_bps).unwrap() / 10_000
+    }
+
+    /// Returns the gross quote quantity paid by a taker bid for a matched quote quantity, including the taker fee and
+    /// royalties.
+    ///
+    /// This is the amount new_order charges, `remove_fees` is its inverse.
+    pub fn quote_with_fees(self, dex_state: &DexState, matched_quote_qty: u64) -> u64 {
+        matched_quote_qty
+            + self.taker_fee(dex_state, matched_quote_qty)
+            + Self::royalties_fee(dex_state, matched_quote_qty)
+    }
+
+    /// Returns the largest matched quote quantity whose gross amount (as computed by `quote_with_fees`) fits in
+    /// `quote_qty`.
+    ///
+    /// This is how new_order and swap cap the quote quantity a bid can match.
+    pub fn remove_fees(self, dex_state: &DexState, quote_qty: u64) -> u64 {
+        let royalties_rate = (dex_state.royalties_bps << 32) / 10_000;
+        let rate = self.taker_rate(dex_state) + royalties_rate;
+        let mut matched_quote_qty = fp32_div(quote_qty, FP_32_ONE + rate).unwrap();
+        // The fees are rounded separately, which can shift the result by a few units
+        while matched_quote_qty > 0
+            && self.quote_with_fees(dex_state, matched_quote_qty) > quote_qty
+        {
+            matched_quote_qty -= 1;
+        }
+        while matched_quote_qty < quote_qty
+            && self.quote_with_fees(dex_state, matched_quote_qty + 1) <= quote_qty
+        {
+            matched_quote_qty += 1;
+        }
+        matched_quote_qty
+    }
+
+    /// Returns the referrer's cut of the taker rate, which never exceeds the taker rate itself.
+    pub fn referral_rate(self, dex_state: &DexState) -> u64 {
+        dex_state.referral_rate(self.taker_rate(dex_state))
     }
 
-    pub fn referral_fee(self, quote_qty: u64) -> u64 {
-        let rate = self.referral_rate();
+    pub fn referral_fee(self, dex_state: &DexState, quote_qty: u64) -> u64 {
+        let rate = self.referral_rate(dex_state);
         fp32_mul(quote_qty, rate).unwrap()
     }
 }
@@ -440,6 +1330,18 @@ pub struct CallBackInfo {
     pub fee_tier: u8,
 }
 
+impl CallBackInfo {
+    /// Reads callback information in place from its binary representation, which is identical to its Borsh encoding
+    pub fn from_bytes(data: &[u8]) -> Result<&Self, ProgramError> {
+        try_from_bytes(data).map_err(|_| ProgramError::InvalidAccountData)
+    }
+
+    /// Returns the binary representation of the callback information, which is identical to its Borsh encoding
+    pub fn to_bytes(&self) -> &[u8] {
+        bytes_of(self)
+    }
+}
+
 impl CallbackInfo for CallBackInfo {
     type CallbackId = Pubkey;
 
@@ -447,3 +1349,845 @@ impl CallbackInfo for CallBackInfo {
         &self.user_account
     }
 }
+
+/// The current version of the [`FillLog`] layout
+pub const FILL_LOG_VERSION: u8 = 1;
+
+/// Size in bytes of a [`FillLog`]
+pub const FILL_LOG_LEN: usize = size_of::<FillLog>();
+
+#[derive(Debug, Clone, Copy, Zeroable, Pod, PartialEq)]
+#[repr(C)]
+/// A fill processed by consume_events, logged as the single field of a `sol_log_data` call.
+///
+/// The layout of a given version is stable, all integers are little endian:
+///
+/// | Offset | Size | Field          |
+/// | ------------------------------ |
+/// | 0      | 1    | `version`      |
+/// | 1      | 1    | `taker_side`   |
+/// | 2      | 6    | `_padding`     |
+/// | 8      | 32   | `maker`        |
+/// | 40     | 32   | `taker`        |
+/// | 72     | 8    | `price`        |
+/// | 80     | 8    | `base_size`    |
+/// | 88     | 8    | `quote_size`   |
+/// | 96     | 8    | `taker_fee`    |
+/// | 104    | 8    | `maker_rebate` |
+pub struct FillLog {
+    /// The layout version, see [`FILL_LOG_VERSION`]
+    pub version: u8,
+    /// The side of the taker order (Bid or Ask)
+    pub taker_side: u8,
+    /// To eliminate implicit padding
+    pub _padding: [u8; 6],
+    /// The maker's user account
+    pub maker: Pubkey,
+    /// The taker's user account, zeroed for swaps
+    pub taker: Pubkey,
+    /// The fill price (as FP32), which is the maker order's limit price
+    pub price: u64,
+    /// The filled base quantity, in native units
+    pub base_size: u64,
+    /// The filled quote quantity, in native units
+    pub quote_size: u64,
+    /// The taker fee accounted for this fill, in native quote units
+    pub taker_fee: u64,
+    /// The rebate credited to the maker for this fill, in native quote units
+    pub maker_rebate: u64,
+}
+
+/// The seed which follows the market key in the derivation of a referrer address
+pub const REFERRER_SEED: &[u8] = b"referrer";
+
+/// Size in bytes of the referrer object
+pub const REFERRER_LEN: usize = size_of::<Referrer>();
+
+/// The referral statistics of a referrer on a market, created by register_referrer.
+///
+/// new_order and swap credit it when it is given after the referrer's token account.
+#[derive(Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct Referrer {
+    /// This u64 is used to verify and version the referrer
+    pub tag: u64,
+    /// The market whose referrals are tracked
+    pub market: Pubkey,
+    /// The quote token account which receives the referral fees
+    pub token_account: Pubkey,
+    /// The wallet which owns the token account
+    pub owner: Pubkey,
+    /// The matched quote volume of the referred takers, in native units
+    pub total_referred_volume: u64,
+    /// The referral fees received, in native quote units
+    pub total_fees_earned: u64,
+}
+
+/// Derives the address of the referrer of a market's quote token account, along with its bump seed.
+pub fn find_referrer(program_id: &Pubkey, market: &Pubkey, token_account: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[&market.to_bytes(), REFERRER_SEED, &token_account.to_bytes()],
+        program_id,
+    )
+}
+
+impl Referrer {
+    pub(crate) fn get<'a, 'b: 'a>(
+        account_info: &'a AccountInfo<'b>,
+    ) -> Result<RefMut<'a, Self>, ProgramError> {
+        if account_info.data_len() < REFERRER_LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let a = RefMut::map(account_info.data.borrow_mut(), |s| {
+            try_from_bytes_mut::<Self>(&mut s[0..REFERRER_LEN]).unwrap()
+        });
+        if a.tag != AccountTag::Referrer as u64 {
+            return Err(ProgramError::InvalidAccountData);
+        };
+        Ok(a)
+    }
+
+    /// Records a referred trade of the given matched quote quantity
+    pub fn record_referral(&mut self, quote_qty: u64, referral_fee: u64) {
+        self.total_referred_volume = self.total_referred_volume.saturating_add(quote_qty);
+        self.total_fees_earned = self.total_fees_earned.saturating_add(referral_fee);
+    }
+}
+
+/// Computes the time-weighted average price (as a FP32) between two `(cumulative_price, timestamp)` snapshots of a market,
+/// as given by [`DexState::cumulative_price_at`].
+///
+/// Returns `None` when the window is empty or the average doesn't fit in a `u64`.
+pub fn compute_twap(start: (u128, i64), end: (u128, i64)) -> Option<u64> {
+    let elapsed = end.1.checked_sub(start.1).filter(|e| *e > 0)? as u128;
+    (end.0.wrapping_sub(start.0) / elapsed).try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_qty_conversions() {
+        assert_eq!(NativeQty(12_345).to_scaled(100), ScaledQty(123));
+        assert_eq!(NativeQty(99).to_scaled(100), ScaledQty(0));
+        assert_eq!(ScaledQty(123).to_native(100), Some(NativeQty(12_300)));
+        assert_eq!(ScaledQty(u64::MAX).to_native(2), None);
+        assert_eq!(
+            ScaledQty(42).to_native(10_000).map(|n| n.to_scaled(10_000)),
+            Some(ScaledQty(42))
+        );
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut dex_state = DexState::zeroed();
+        assert_eq!(dex_state.name(), Some(""));
+
+        let mut name = [0; MARKET_NAME_LEN];
+        name[..7].copy_from_slice("SOL/USD".as_bytes());
+        let mut ticker = [0; MARKET_TICKER_LEN];
+        ticker[..3].copy_from_slice("SOL".as_bytes());
+        dex_state.set_labels(&name, &ticker).unwrap();
+        assert_eq!(dex_state.name(), Some("SOL/USD"));
+        assert_eq!(dex_state.ticker(), Some("SOL"));
+
+        // Multi-byte characters are kept whole, and invalid UTF-8 is rejected without modifying the labels
+        let mut name = [0; MARKET_NAME_LEN];
+        name[..6].copy_from_slice(&"€/USD".as_bytes()[..6]);
+        assert_eq!(decode_label(&name), Some("€/US"));
+        name[1] = 0xff;
+        assert!(dex_state.set_labels(&name, &ticker).is_err());
+        assert_eq!(dex_state.name(), Some("SOL/USD"));
+    }
+
+    #[test]
+    fn test_scaling_with_large_multipliers() {
+        let mut dex_state = DexState::zeroed();
+        dex_state.base_currency_multiplier = 1_000_000_000;
+        dex_state.quote_currency_multiplier = 1_000_000_000;
+
+        let base = dex_state.scale_base_amount(NativeQty(123_456_789_000_000_000));
+        assert_eq!(base, ScaledQty(123_456_789));
+        assert_eq!(
+            dex_state.unscale_base_amount(base).unwrap(),
+            NativeQty(123_456_789_000_000_000)
+        );
+        assert!(matches!(
+            dex_state.unscale_base_amount(ScaledQty(u64::MAX / 1_000)),
+            Err(DexError::NumericalOverflow)
+        ));
+        assert!(matches!(
+            dex_state.unscale_quote_amount(ScaledQty(20_000_000_000)),
+            Err(DexError::NumericalOverflow)
+        ));
+
+        // The quote quantity of 10^12 base units at a price of 1 goes through a 10^21 intermediate product
+        assert_eq!(
+            dex_state
+                .get_quote_from_base(NativeQty(1_000_000_000_000), FP_32_ONE)
+                .unwrap(),
+            NativeQty(1_000_000_000_000)
+        );
+        dex_state.base_currency_multiplier = 1;
+        assert!(matches!(
+            dex_state.get_quote_from_base(NativeQty(1_000_000_000_000), FP_32_ONE),
+            Err(DexError::NumericalOverflow)
+        ));
+    }
+
+    #[test]
+    fn test_taker_fee_rounding() {
+        let mut dex_state = DexState::zeroed();
+        dex_state.fee_tier_taker_rates = DEFAULT_FEE_TIER_TAKER_RATES;
+        let rate = FeeTier::Base.taker_rate(&dex_state);
+        for quote_qty in [1, 2, 999, 1_000_000, u32::MAX as u64, 123_456_789_012] {
+            let exact_fee = (quote_qty as u128 * rate as u128) as f64 / FP_32_ONE as f64;
+            let fee = FeeTier::Base.taker_fee(&dex_state, quote_qty);
+            let fee_floor = FeeTier::Base.taker_fee_floor(&dex_state, quote_qty);
+            assert!(fee as f64 >= exact_fee);
+            assert!(fee_floor as f64 <= exact_fee);
+            assert!(fee - fee_floor <= 1);
+        }
+        // The fees of the fills of an order never exceed the fee charged for the whole order
+        let fills = [1, 7, 333, 12_345];
+        let accounted: u64 = fills
+            .iter()
+            .map(|q| FeeTier::Base.taker_fee_floor(&dex_state, *q))
+            .sum();
+        assert!(accounted <= FeeTier::Base.taker_fee(&dex_state, fills.iter().sum()));
+    }
+
+    #[test]
+    fn test_quote_with_fees() {
+        let mut dex_state = DexState::zeroed();
+        dex_state.fee_tier_taker_rates = DEFAULT_FEE_TIER_TAKER_RATES;
+        for royalties_bps in [0, 25, 1_000] {
+            dex_state.royalties_bps = royalties_bps;
+            for fee_tier in [FeeTier::Base, FeeTier::MSrm, FeeTier::Stable] {
+                for matched_quote_qty in [0, 1, 999, 1_000_000, 123_456_789_012] {
+                    let gross_quote_qty = fee_tier.quote_with_fees(&dex_state, matched_quote_qty);
+                    assert!(gross_quote_qty >= matched_quote_qty);
+                    let recovered = fee_tier.remove_fees(&dex_state, gross_quote_qty);
+                    assert!(
+                        recovered.max(matched_quote_qty) - recovered.min(matched_quote_qty) <= 1
+                    );
+                    assert!(fee_tier.quote_with_fees(&dex_state, recovered) <= gross_quote_qty);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_record_candle() {
+        let mut history = CandleHistory::zeroed();
+        history.interval = 60;
+
+        // Two fills in the first interval
+        history.record_candle(10, 1, 10, 120);
+        history.record_candle(12, 2, 24, 179);
+        assert_eq!(history.finalized_candles().count(), 0);
+        assert_eq!(history.current.start_ts, 120);
+        assert_eq!(history.current.number_of_fills, 2);
+
+        // A fill in the next interval finalizes the first candle
+        history.record_candle(8, 1, 8, 180);
+        // And a fill two intervals later finalizes the second one
+        history.record_candle(9, 1, 9, 300);
+        let candles = history.finalized_candles().copied().collect::<Vec<_>>();
+        assert_eq!(
+            candles,
+            vec![
+                Candle {
+                    start_ts: 120,
+                    open: 10,
+                    high: 12,
+                    low: 10,
+                    close: 12,
+                    base_volume: 3,
+                    quote_volume: 34,
+                    number_of_fills: 2,
+                },
+                Candle {
+                    start_ts: 180,
+                    open: 8,
+                    high: 8,
+                    low: 8,
+                    close: 8,
+                    base_volume: 1,
+                    quote_volume: 8,
+                    number_of_fills: 1,
+                }
+            ]
+        );
+        assert_eq!(history.current.start_ts, 300);
+        assert_eq!(history.current.open, 9);
+
+        // The oldest candles are overwritten once the ring buffer is full
+        for i in 0..MAX_CANDLES as i64 {
+            history.record_candle(100 + i as u64, 1, 1, 360 + 60 * i);
+        }
+        assert_eq!(history.finalized_candles().count(), MAX_CANDLES);
+        let oldest = history.finalized_candles().next().unwrap();
+        assert_eq!(oldest.start_ts, 300);
+        let latest = history.finalized_candles().last().unwrap();
+        assert_eq!(latest.open, 100 + MAX_CANDLES as u64 - 2);
+    }
+
+    #[test]
+    fn test_market_info() {
+        let mut dex_state = DexState::zeroed();
+        dex_state.tag = AccountTag::DexState as u64;
+        dex_state.fee_type = MarketFeeType::Stable as u8;
+        dex_state.status = MarketStatus::Paused as u8;
+
+        let info = MarketInfo::from_market_data(bytemuck::bytes_of(&dex_state));
+        let decoded = MarketInfo::try_from_slice(&info.try_to_vec().unwrap()).unwrap();
+        assert_eq!(decoded, info);
+        assert_eq!(
+            format!(
+                "{}.{}.{}",
+                decoded.version_major, decoded.version_minor, decoded.version_patch
+            ),
+            env!("CARGO_PKG_VERSION")
+        );
+        assert_eq!(decoded.state_len, DEX_STATE_LEN as u64);
+        assert_eq!(decoded.account_len, DEX_STATE_LEN as u64);
+        assert_eq!(decoded.tag, AccountTag::DexState as u64);
+        assert_eq!(decoded.fee_type, MarketFeeType::Stable as u8);
+        assert_eq!(decoded.status, MarketStatus::Paused as u8);
+
+        // A market with another layout only exposes its tag and length
+        let info = MarketInfo::from_market_data(&bytemuck::bytes_of(&dex_state)[..400]);
+        assert_eq!(info.account_len, 400);
+        assert_eq!(info.tag, AccountTag::DexState as u64);
+        assert_eq!(info.fee_type, 0);
+        assert_eq!(info.status, 0);
+    }
+
+    #[test]
+    fn test_migrate_legacy_dex_state() {
+        assert_eq!(LEGACY_DEX_STATE_LEN, 280);
+
+        // A buffer holding a market created under the legacy layout
+        let mut legacy_data = [0u64; LEGACY_DEX_STATE_LEN / 8];
+        {
+            let legacy =
+                try_from_bytes_mut::<LegacyDexState>(bytemuck::cast_slice_mut(&mut legacy_data))
+                    .unwrap();
+            legacy.tag = AccountTag::DexState as u64;
+            legacy.base_mint = Pubkey::new_unique();
+            legacy.admin = Pubkey::new_unique();
+            legacy.quote_volume = 1_234;
+            legacy.accumulated_fees = 56;
+            legacy.royalties_bps = 250;
+            legacy.quote_currency_multiplier = 10;
+            legacy.signer_nonce = 254;
+            legacy.fee_type = MarketFeeType::Stable as u8;
+        }
+        let legacy = try_from_bytes::<LegacyDexState>(bytemuck::cast_slice(&legacy_data)).unwrap();
+
+        let dex_state = DexState::from_legacy(legacy);
+        // The fields shared with the legacy layout are preserved at the same offsets
+        assert_eq!(
+            &bytemuck::bytes_of(&dex_state)[..LEGACY_DEX_STATE_LEN],
+            bytemuck::cast_slice::<u64, u8>(&legacy_data)
+        );
+        assert_eq!(dex_state.admin, legacy.admin);
+        assert_eq!(dex_state.royalties_bps, 250);
+        assert_eq!(dex_state.fee_type, MarketFeeType::Stable as u8);
+        assert_eq!(dex_state.status, MarketStatus::Active as u8);
+        assert_eq!(dex_state.fee_tier_thresholds, DEFAULT_FEE_TIER_THRESHOLDS);
+        assert_eq!(dex_state.fee_tier_taker_rates, DEFAULT_FEE_TIER_TAKER_RATES);
+        assert_eq!(dex_state.token_program_id(), spl_token::ID);
+        assert_eq!(dex_state.number_of_creators, 0);
+    }
+
+    #[test]
+    fn test_migrate_legacy_user_account_header() {
+        assert_eq!(LEGACY_USER_ACCOUNT_HEADER_LEN, 152);
+        assert_eq!(USER_ACCOUNT_HEADER_LEN, size_of::<UserAccountHeader>());
+
+        let mut legacy = LegacyUserAccountHeader::zeroed();
+        legacy.tag = AccountTag::LegacyUserAccount as u64;
+        legacy.owner = Pubkey::new_unique();
+        legacy.quote_token_locked = 1_000;
+        legacy.accumulated_taker_base_volume = 42;
+        legacy.number_of_orders = 3;
+
+        let header = UserAccountHeader::from_legacy(&legacy);
+        assert_eq!(header.tag, AccountTag::UserAccount as u64);
+        // The fields shared with the legacy layout are preserved at the same offsets
+        assert_eq!(
+            &bytemuck::bytes_of(&header)[8..LEGACY_USER_ACCOUNT_HEADER_LEN],
+            &bytemuck::bytes_of(&legacy)[8..]
+        );
+        assert_eq!(header.delegate, Pubkey::default());
+        assert_eq!(header.rent_payer, Pubkey::default());
+    }
+
+    #[test]
+    fn test_volume_fee_tiers() {
+        let mut dex_state = DexState::zeroed();
+        dex_state.fee_tier_taker_rates = DEFAULT_FEE_TIER_TAKER_RATES;
+        dex_state.fee_tier_thresholds = [1_000, 10_000, 100_000, 1_000_000, 10_000_000];
+        assert_eq!(
+            FeeTier::from_volume(&dex_state.fee_tier_thresholds, 999) as u8,
+            FeeTier::Base as u8
+        );
+        assert_eq!(
+            FeeTier::from_volume(&dex_state.fee_tier_thresholds, 1_000) as u8,
+            FeeTier::Srm2 as u8
+        );
+        assert_eq!(
+            FeeTier::from_volume(&dex_state.fee_tier_thresholds, u64::MAX) as u8,
+            FeeTier::Srm6 as u8
+        );
+
+        let owner = Pubkey::new_unique();
+        let new_user = UserAccountHeader::new(&Pubkey::new_unique(), &owner, &owner);
+        let mut high_volume_user = new_user;
+        high_volume_user.accumulated_taker_quote_volume = 50_000_000;
+
+        // Holdings-based markets ignore the volume
+        for user in [&new_user, &high_volume_user] {
+            let fee_tier = FeeTier::get_for_taker(&dex_state, None, &owner, Some(user), 0).unwrap();
+            assert_eq!(fee_tier as u8, FeeTier::Base as u8);
+        }
+
+        dex_state.fee_model = MarketFeeModel::Volume as u8;
+        let new_user_tier =
+            FeeTier::get_for_taker(&dex_state, None, &owner, Some(&new_user), 0).unwrap();
+        let high_volume_tier =
+            FeeTier::get_for_taker(&dex_state, None, &owner, Some(&high_volume_user), 0).unwrap();
+        assert_eq!(new_user_tier as u8, FeeTier::Base as u8);
+        assert_eq!(high_volume_tier as u8, FeeTier::Srm6 as u8);
+        assert!(high_volume_tier.taker_rate(&dex_state) < new_user_tier.taker_rate(&dex_state));
+        // Swaps have no user account
+        let swap_tier = FeeTier::get_for_taker(&dex_state, None, &owner, None, 0).unwrap();
+        assert_eq!(swap_tier as u8, FeeTier::Base as u8);
+    }
+
+    #[test]
+    fn test_flat_fee_model() {
+        let mut dex_state = DexState::zeroed();
+        dex_state.fee_tier_taker_rates = DEFAULT_FEE_TIER_TAKER_RATES;
+        dex_state.fee_tier_thresholds = DEFAULT_FEE_TIER_THRESHOLDS;
+
+        let owner = Pubkey::new_unique();
+        let mut high_volume_user = UserAccountHeader::new(&Pubkey::new_unique(), &owner, &owner);
+        high_volume_user.accumulated_taker_quote_volume = u64::MAX;
+        let discount_key = Pubkey::new_unique();
+        let mut discount_lamports = 0;
+        let mut discount_data = vec![0; spl_token::state::Account::LEN];
+        spl_token::state::Account::pack(
+            spl_token::state::Account {
+                mint: MSRM_MINT,
+                owner,
+                amount: 1,
+                state: spl_token::state::AccountState::Initialized,
+                ..Default::default()
+            },
+            &mut discount_data,
+        )
+        .unwrap();
+        let discount_account = AccountInfo::new(
+            &discount_key,
+            false,
+            false,
+            &mut discount_lamports,
+            &mut discount_data,
+            &spl_token::ID,
+            false,
+            0,
+        );
+
+        // Holding MSRM grants a discount on holdings-based markets
+        let msrm_tier =
+            FeeTier::get_for_taker(&dex_state, Some(&discount_account), &owner, None, 0).unwrap();
+        assert_eq!(msrm_tier as u8, FeeTier::MSrm as u8);
+
+        dex_state.fee_model = MarketFeeModel::Flat as u8;
+        dex_state.flat_taker_bps = 25;
+        let takers = [
+            (None, None),
+            (Some(&discount_account), None),
+            (None, Some(&high_volume_user)),
+            (Some(&discount_account), Some(&high_volume_user)),
+        ];
+        for fee_type in [MarketFeeType::Default, MarketFeeType::Stable] {
+            dex_state.fee_type = fee_type as u8;
+            for (discount_token_account, user_account) in takers {
+                let fee_tier = FeeTier::get_for_taker(
+                    &dex_state,
+                    discount_token_account,
+                    &owner,
+                    user_account,
+                    0,
+                )
+                .unwrap();
+                assert_eq!(fee_tier as u8, FeeTier::Base as u8);
+                assert_eq!(fee_tier.taker_fee(&dex_state, 1_000_000), 2_500);
+            }
+        }
+        // The tier table is ignored even for tiers encoded in existing orders
+        for tag in 0..8 {
+            let (fee_tier, _) = FeeTier::from_u8(tag);
+            assert_eq!(fee_tier.taker_fee(&dex_state, 1_000_000), 2_500);
+        }
+    }
+
+    #[test]
+    fn test_promo_window() {
+        let mut dex_state = DexState::zeroed();
+        dex_state.fee_tier_taker_rates = DEFAULT_FEE_TIER_TAKER_RATES;
+        dex_state.fee_tier_maker_rebate_rates = DEFAULT_FEE_TIER_MAKER_REBATE_RATES;
+        dex_state.royalties_bps = 100;
+        dex_state.promo_end_ts = 1_000;
+        let owner = Pubkey::new_unique();
+        let quote_qty = 1_000_000;
+
+        // The window ends at promo_end_ts, which is excluded
+        let promo_tier = FeeTier::get_for_taker(&dex_state, None, &owner, None, 999).unwrap();
+        assert_eq!(promo_tier as u8, FeeTier::Promo as u8);
+        for timestamp in [1_000, 1_001] {
+            let fee_tier =
+                FeeTier::get_for_taker(&dex_state, None, &owner, None, timestamp).unwrap();
+            assert_eq!(fee_tier as u8, FeeTier::Base as u8);
+        }
+
+        // Takers pay no fees and no referral cut, the royalties still apply
+        assert_eq!(promo_tier.taker_fee(&dex_state, quote_qty), 0);
+        assert_eq!(promo_tier.referral_fee(&dex_state, quote_qty), 0);
+        assert_eq!(
+            promo_tier.quote_with_fees(&dex_state, quote_qty),
+            quote_qty + FeeTier::royalties_fee(&dex_state, quote_qty)
+        );
+        assert_eq!(
+            promo_tier.remove_fees(
+                &dex_state,
+                promo_tier.quote_with_fees(&dex_state, quote_qty)
+            ),
+            quote_qty
+        );
+
+        // The promo tier survives the round trip through the callback info, and its orders earn the base maker rebate
+        let (fee_tier, is_referred) = FeeTier::from_u8(FeeTier::Promo as u8 | REFERRAL_MASK);
+        assert_eq!(fee_tier as u8, FeeTier::Promo as u8);
+        assert!(is_referred);
+        assert_eq!(
+            fee_tier.maker_rebate(&dex_state, quote_qty),
+            FeeTier::Base.maker_rebate(&dex_state, quote_qty)
+        );
+        assert_ne!(fee_tier.maker_rebate(&dex_state, quote_qty), 0);
+    }
+
+    #[test]
+    fn test_referral_fee() {
+        let mut dex_state = DexState::zeroed();
+        dex_state.fee_tier_taker_rates = DEFAULT_FEE_TIER_TAKER_RATES;
+        let quote_qty = 1_000_000_000;
+        let taker_fee = FeeTier::Base.taker_fee_floor(&dex_state, quote_qty);
+
+        // A fifth of the taker fees by default
+        let referral_fee = FeeTier::Base.referral_fee(&dex_state, quote_qty);
+        assert!(referral_fee.max(taker_fee / 5) - referral_fee.min(taker_fee / 5) <= 1);
+
+        dex_state.referral_bps = 5_000;
+        let referral_fee = FeeTier::Base.referral_fee(&dex_state, quote_qty);
+        assert!(referral_fee.max(taker_fee / 2) - referral_fee.min(taker_fee / 2) <= 1);
+
+        // The referral fee never exceeds the taker fee it is paid from
+        for referral_bps in [10_000, u16::MAX] {
+            dex_state.referral_bps = referral_bps;
+            for fee_tier in [FeeTier::Base, FeeTier::MSrm, FeeTier::Stable] {
+                for quote_qty in [0, 1, 999, 1_000_000, 123_456_789_012] {
+                    assert!(
+                        fee_tier.referral_fee(&dex_state, quote_qty)
+                            <= fee_tier.taker_fee_floor(&dex_state, quote_qty)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_user_account_orders() {
+        // The buffer is backed by u64s to keep the header aligned
+        let mut buffer = vec![0u64; UserAccount::compute_allocation_size(4).unwrap() / 8];
+        let mut user_account =
+            UserAccount::from_buffer_unchecked(bytemuck::cast_slice_mut(&mut buffer)).unwrap();
+        assert!(user_account.orders().is_empty());
+        for i in 0..3 {
+            user_account
+                .add_order(
+                    Order {
+                        id: i,
+                        client_id: 100 + i,
+                    },
+                    DuplicateClientIdPolicy::Reject,
+                )
+                .unwrap();
+        }
+        assert_eq!(
+            user_account
+                .orders()
+                .iter()
+                .map(|o| o.id)
+                .collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        user_account.remove_order(0).unwrap();
+        assert_eq!(
+            user_account
+                .iter_orders()
+                .map(|(i, o)| (i, o.id, o.client_id))
+                .collect::<Vec<_>>(),
+            vec![(0, 2, 102), (1, 1, 101)]
+        );
+        // Removed orders are no longer found, even though they are still in the buffer
+        user_account.remove_order(1).unwrap();
+        assert_eq!(user_account.orders().len(), 1);
+        assert!(user_account.find_order_index(1).is_err());
+    }
+
+    #[test]
+    fn test_duplicate_client_id() {
+        // The buffer is backed by u64s to keep the header aligned
+        let mut buffer = vec![0u64; UserAccount::compute_allocation_size(8).unwrap() / 8];
+        let mut user_account =
+            UserAccount::from_buffer_unchecked(bytemuck::cast_slice_mut(&mut buffer)).unwrap();
+        let order = |id, client_id| Order { id, client_id };
+        user_account
+            .add_order(order(0, 42), DuplicateClientIdPolicy::Reject)
+            .unwrap();
+        assert!(matches!(
+            user_account.add_order(order(1, 42), DuplicateClientIdPolicy::Reject),
+            Err(DexError::DuplicateClientId)
+        ));
+        assert_eq!(user_account.orders().len(), 1);
+
+        // A zero client id is never a duplicate
+        for id in [2, 3] {
+            user_account
+                .add_order(order(id, 0), DuplicateClientIdPolicy::Reject)
+                .unwrap();
+        }
+
+        // The client id is moved to the new order
+        user_account
+            .add_order(order(4, 42), DuplicateClientIdPolicy::Overwrite)
+            .unwrap();
+        assert_eq!(
+            user_account
+                .find_order_id_and_index_by_client_id(42)
+                .unwrap(),
+            (3, 4)
+        );
+        assert_eq!(user_account.orders()[0].client_id, 0);
+
+        user_account
+            .add_order(order(5, 42), DuplicateClientIdPolicy::Allow)
+            .unwrap();
+        assert_eq!(
+            user_account
+                .orders()
+                .iter()
+                .filter(|o| o.client_id == 42)
+                .count(),
+            2
+        );
+
+        // Orders which were removed are not considered
+        user_account.remove_order(4).unwrap();
+        user_account.remove_order(3).unwrap();
+        user_account
+            .add_order(order(6, 42), DuplicateClientIdPolicy::Reject)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_fill_log_layout() {
+        let log = FillLog {
+            version: FILL_LOG_VERSION,
+            taker_side: Side::Ask as u8,
+            _padding: [0; 6],
+            maker: Pubkey::new_unique(),
+            taker: Pubkey::new_unique(),
+            price: 1,
+            base_size: 2,
+            quote_size: 3,
+            taker_fee: 4,
+            maker_rebate: 5,
+        };
+        let bytes = bytemuck::bytes_of(&log);
+        assert_eq!(FILL_LOG_LEN, 112);
+        assert_eq!(bytes[0], FILL_LOG_VERSION);
+        assert_eq!(bytes[1], Side::Ask as u8);
+        assert_eq!(&bytes[8..40], log.maker.as_ref());
+        assert_eq!(&bytes[40..72], log.taker.as_ref());
+        for (i, n) in (1u64..=5).enumerate() {
+            assert_eq!(bytes[72 + 8 * i..80 + 8 * i], n.to_le_bytes());
+        }
+        assert_eq!(bytemuck::from_bytes::<FillLog>(bytes), &log);
+    }
+
+    #[test]
+    fn test_price_accumulator() {
+        let mut market_state = DexState::zeroed();
+
+        // The first fill only seeds the price
+        market_state.record_fill_price(10 << 32, 1_000);
+        assert_eq!(market_state.last_price, 10 << 32);
+        assert_eq!(market_state.last_update_ts, 1_000);
+        assert_eq!(market_state.cumulative_price, 0);
+        let start = (market_state.cumulative_price_at(1_000), 1_000);
+
+        // Each following fill accumulates the previous price over the elapsed time
+        market_state.record_fill_price(20 << 32, 1_010);
+        assert_eq!(market_state.cumulative_price, 100 << 32);
+        market_state.record_fill_price(40 << 32, 1_010);
+        assert_eq!(market_state.cumulative_price, 100 << 32);
+        market_state.record_fill_price(30 << 32, 1_020);
+        assert_eq!(market_state.cumulative_price, 500 << 32);
+        assert_eq!(market_state.last_price, 30 << 32);
+        assert_eq!(market_state.last_update_ts, 1_020);
+
+        // The last price is extrapolated up to the end of the window
+        let end = (market_state.cumulative_price_at(1_040), 1_040);
+        assert_eq!(end.0, 1_100 << 32);
+        assert_eq!(compute_twap(start, end), Some((1_100 << 32) / 40));
+        assert_eq!(compute_twap(end, end), None);
+        assert_eq!(compute_twap(end, start), None);
+
+        // A wrapped accumulator still yields the right average
+        assert_eq!(
+            compute_twap((u128::MAX, 0), (10 * (5 << 32) - 1, 10)),
+            Some(5 << 32)
+        );
+    }
+
+    #[test]
+    fn test_price_deviation() {
+        let mut market_state = DexState::zeroed();
+        market_state.last_price = 100 << 32;
+
+        // Disabled by default
+        assert!(market_state.check_price_deviation(1, 1_000).is_ok());
+
+        market_state.max_deviation_bps = 100;
+        // Just inside the band on both sides
+        assert!(market_state.check_price_deviation(100, 10_100).is_ok());
+        assert!(market_state.check_price_deviation(100, 9_900).is_ok());
+        // Just outside the band on both sides
+        assert!(matches!(
+            market_state.check_price_deviation(1_000, 101_001),
+            Err(DexError::PriceDeviationExceeded)
+        ));
+        assert!(matches!(
+            market_state.check_price_deviation(1_000, 98_999),
+            Err(DexError::PriceDeviationExceeded)
+        ));
+        // Nothing was matched
+        assert!(market_state.check_price_deviation(0, 0).is_ok());
+
+        // No reference price before the first fill
+        market_state.last_price = 0;
+        assert!(market_state.check_price_deviation(1, 1_000).is_ok());
+    }
+
+    #[test]
+    fn test_check_fee_schedule() {
+        let mut market_state = DexState::zeroed();
+        market_state.fee_tier_thresholds = DEFAULT_FEE_TIER_THRESHOLDS;
+        market_state.fee_tier_taker_rates = DEFAULT_FEE_TIER_TAKER_RATES;
+        market_state.fee_tier_maker_rebate_rates = DEFAULT_FEE_TIER_MAKER_REBATE_RATES;
+        market_state.royalties_bps = 500;
+        assert!(market_state.check_fee_schedule().is_ok());
+
+        // Equal thresholds are allowed, decreasing ones are not
+        market_state.fee_tier_thresholds[1] = market_state.fee_tier_thresholds[0];
+        assert!(market_state.check_fee_schedule().is_ok());
+        market_state.fee_tier_thresholds[1] -= 1;
+        assert!(market_state.check_fee_schedule().is_err());
+        market_state.fee_tier_thresholds = DEFAULT_FEE_TIER_THRESHOLDS;
+
+        // A taker rate and the royalties should add up to less than 100%
+        market_state.fee_tier_taker_rates[3] = (9_499 << 32) / 10_000;
+        assert!(market_state.check_fee_schedule().is_ok());
+        market_state.fee_tier_taker_rates[3] = (9_500 << 32) / 10_000 + 1;
+        assert!(market_state.check_fee_schedule().is_err());
+        market_state.fee_tier_taker_rates[3] = FP_32_ONE;
+        market_state.royalties_bps = 0;
+        assert!(market_state.check_fee_schedule().is_err());
+        market_state.fee_tier_taker_rates = DEFAULT_FEE_TIER_TAKER_RATES;
+
+        // So should the flat taker rate
+        market_state.royalties_bps = 100;
+        market_state.flat_taker_bps = 9_899;
+        assert!(market_state.check_fee_schedule().is_ok());
+        market_state.flat_taker_bps = 9_900;
+        assert!(market_state.check_fee_schedule().is_err());
+        market_state.royalties_bps = 0;
+        market_state.flat_taker_bps = 10_000;
+        assert!(market_state.check_fee_schedule().is_err());
+        market_state.flat_taker_bps = 0;
+
+        // What the referrer leaves of every taker rate should cover the highest maker rebate rate
+        market_state.referral_bps = 5_000;
+        market_state.fee_tier_taker_rates[7] = 2 * market_state.fee_tier_maker_rebate_rates[0];
+        assert!(market_state.check_fee_schedule().is_ok());
+        market_state.fee_tier_taker_rates[7] -= 1;
+        assert!(market_state.check_fee_schedule().is_err());
+        market_state.fee_tier_taker_rates = DEFAULT_FEE_TIER_TAKER_RATES;
+        market_state.fee_tier_maker_rebate_rates[7] = market_state.fee_tier_taker_rates[7];
+        assert!(market_state.check_fee_schedule().is_err());
+        market_state.fee_tier_maker_rebate_rates = DEFAULT_FEE_TIER_MAKER_REBATE_RATES;
+
+        // Only the flat taker rate is charged on flat-fee markets
+        market_state.fee_model = MarketFeeModel::Flat as u8;
+        market_state.fee_tier_taker_rates = [0; 8];
+        market_state.flat_taker_bps = 1;
+        market_state.referral_bps = 7_000;
+        assert!(market_state.check_fee_schedule().is_ok());
+        market_state.referral_bps = 7_001;
+        assert!(market_state.check_fee_schedule().is_err());
+    }
+
+    #[test]
+    fn test_cache_creators() {
+        let creator = |address, share| Creator {
+            address,
+            verified: false,
+            share,
+        };
+        let (a, b, c) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let mut market_state = DexState::zeroed();
+        market_state
+            .cache_creators(&[creator(a, 50), creator(b, 50)])
+            .unwrap();
+        market_state.unpaid_royalties[1] = 42;
+
+        // The unpaid royalties follow their creator
+        market_state
+            .cache_creators(&[creator(c, 20), creator(b, 40), creator(a, 40)])
+            .unwrap();
+        assert_eq!(market_state.number_of_creators, 3);
+        assert_eq!(market_state.creators[..3], [c, b, a]);
+        assert_eq!(market_state.unpaid_royalties, [0, 42, 0, 0, 0]);
+
+        // A creator which is still owed royalties cannot be removed
+        assert!(market_state
+            .cache_creators(&[creator(a, 60), creator(c, 40)])
+            .is_err());
+        market_state.unpaid_royalties[1] = 0;
+        market_state
+            .cache_creators(&[creator(a, 60), creator(c, 40)])
+            .unwrap();
+        assert_eq!(market_state.creators[..2], [a, c]);
+    }
+}
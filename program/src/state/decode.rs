@@ -0,0 +1,130 @@
+//! Validated decoding of the program's accounts, for off-chain consumers
+use bytemuck::{try_cast_slice_mut, try_from_bytes, try_from_bytes_mut};
+use solana_program::program_error::ProgramError;
+
+use super::{
+    AccountTag, CallBackInfo, DexState, Order, UserAccount, UserAccountHeader, DEX_STATE_LEN,
+    USER_ACCOUNT_HEADER_LEN,
+};
+
+/// Decodes the data of a DEX market account, checking its length and tag
+pub fn decode_dex_state(data: &[u8]) -> Result<&DexState, ProgramError> {
+    let dex_state: &DexState = data
+        .get(..DEX_STATE_LEN)
+        .and_then(|d| try_from_bytes(d).ok())
+        .ok_or(ProgramError::InvalidAccountData)?;
+    if dex_state.tag != AccountTag::DexState as u64 {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(dex_state)
+}
+
+/// Decodes the data of a user account, checking its length and tag
+pub fn decode_user_account(data: &mut [u8]) -> Result<UserAccount, ProgramError> {
+    if data.len() < USER_ACCOUNT_HEADER_LEN {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let (hd, tl) = data.split_at_mut(USER_ACCOUNT_HEADER_LEN);
+    let header: &mut UserAccountHeader =
+        try_from_bytes_mut(hd).map_err(|_| ProgramError::InvalidAccountData)?;
+    if header.tag != AccountTag::UserAccount as u64 {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let orders: &mut [Order] =
+        try_cast_slice_mut(tl).map_err(|_| ProgramError::InvalidAccountData)?;
+    if orders.len() < header.number_of_orders as usize {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(UserAccount { header, orders })
+}
+
+/// Decodes the callback information attached to an orderbook order or event, checking its length
+pub fn decode_callback_info(data: &[u8]) -> Result<CallBackInfo, ProgramError> {
+    CallBackInfo::from_bytes(data).map(|callback_info| *callback_info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::DuplicateClientIdPolicy;
+    use crate::CALLBACK_INFO_LEN;
+    use borsh::BorshSerialize;
+    use bytemuck::Zeroable;
+    use solana_program::pubkey::Pubkey;
+
+    #[test]
+    fn test_decode_dex_state() {
+        let mut dex_state = DexState::zeroed();
+        dex_state.tag = AccountTag::DexState as u64;
+        dex_state.base_mint = Pubkey::new_unique();
+        dex_state.min_base_order_size = 42;
+        let decoded = decode_dex_state(bytemuck::bytes_of(&dex_state)).unwrap();
+        assert_eq!(decoded.base_mint, dex_state.base_mint);
+        assert_eq!(decoded.min_base_order_size, 42);
+
+        let data = bytemuck::bytes_of(&dex_state);
+        assert!(decode_dex_state(&data[..DEX_STATE_LEN - 1]).is_err());
+        dex_state.tag = AccountTag::Closed as u64;
+        assert!(decode_dex_state(bytemuck::bytes_of(&dex_state)).is_err());
+    }
+
+    #[test]
+    fn test_decode_user_account() {
+        let market = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        // The buffer is backed by u64s to keep the header aligned
+        let mut buffer = vec![0u64; UserAccount::compute_allocation_size(2).unwrap() / 8];
+        let data: &mut [u8] = bytemuck::cast_slice_mut(&mut buffer);
+        assert!(decode_user_account(data).is_err());
+        *try_from_bytes_mut::<UserAccountHeader>(&mut data[..USER_ACCOUNT_HEADER_LEN]).unwrap() =
+            UserAccountHeader::new(&market, &owner, &owner);
+        decode_user_account(data)
+            .unwrap()
+            .add_order(
+                Order {
+                    id: 1,
+                    client_id: 2,
+                },
+                DuplicateClientIdPolicy::Reject,
+            )
+            .unwrap();
+
+        let user_account = decode_user_account(data).unwrap();
+        assert_eq!(user_account.header.market, market);
+        assert_eq!(user_account.header.owner, owner);
+        assert_eq!(user_account.orders().len(), 1);
+        assert_eq!(user_account.orders()[0].client_id, 2);
+
+        assert!(decode_user_account(&mut data[..USER_ACCOUNT_HEADER_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn test_decode_callback_info() {
+        let callback_info = CallBackInfo {
+            user_account: Pubkey::new_unique(),
+            fee_tier: 3,
+        };
+        let data = callback_info.try_to_vec().unwrap();
+        assert_eq!(decode_callback_info(&data).unwrap(), callback_info);
+        assert!(decode_callback_info(&data[1..]).is_err());
+    }
+
+    #[test]
+    fn test_callback_info_encoding() {
+        let callback_info = CallBackInfo {
+            user_account: Pubkey::new_unique(),
+            fee_tier: 255,
+        };
+        let borsh_data = callback_info.try_to_vec().unwrap();
+        assert_eq!(borsh_data.len(), CALLBACK_INFO_LEN as usize);
+        assert_eq!(callback_info.to_bytes(), &borsh_data[..]);
+        assert_eq!(
+            CallBackInfo::from_bytes(&borsh_data).unwrap(),
+            &callback_info
+        );
+
+        let mut padded = borsh_data;
+        padded.push(0);
+        assert!(CallBackInfo::from_bytes(&padded).is_err());
+    }
+}
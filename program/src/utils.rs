@@ -1,11 +1,18 @@
-use crate::error::DexError;
+use crate::{
+    error::DexError,
+    processor::{MSRM_MINT, SRM_MINT},
+};
 use mpl_token_metadata::{
     pda::find_metadata_account,
     state::{Creator, Metadata, TokenMetadataAccount},
 };
 use solana_program::{
-    account_info::AccountInfo, entrypoint::ProgramResult, msg, program_error::ProgramError,
-    pubkey::Pubkey,
+    account_info::AccountInfo, entrypoint::ProgramResult, msg, program::invoke_signed,
+    program_error::ProgramError, pubkey::Pubkey,
+};
+use spl_token_2022::{
+    extension::StateWithExtensions,
+    state::{Account, Mint},
 };
 
 // Safety verification functions
@@ -31,6 +38,95 @@ pub fn check_account_owner(
     Ok(())
 }
 
+pub fn check_token_account_mint(
+    account: &AccountInfo,
+    mint: &Pubkey,
+    error: DexError,
+) -> Result<(), DexError> {
+    match unpack_token_account(account) {
+        Ok(a) if &a.mint == mint => Ok(()),
+        _ => Err(error),
+    }
+}
+
+/// Returns true if the given key is either the SPL token program or Token-2022
+pub fn is_token_program(key: &Pubkey) -> bool {
+    key == &spl_token::ID || key == &spl_token_2022::ID
+}
+
+/// Unpacks the base state of a token account owned by the SPL token program or Token-2022,
+/// ignoring any Token-2022 extension
+pub fn unpack_token_account(account: &AccountInfo) -> Result<Account, ProgramError> {
+    if !is_token_program(account.owner) {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    Ok(StateWithExtensions::<Account>::unpack(&account.data.borrow())?.base)
+}
+
+/// Checks that a token account of either token program is owned by the given wallet
+pub fn check_token_account_owner(account: &AccountInfo, owner: &Pubkey) -> ProgramResult {
+    if &unpack_token_account(account)?.owner != owner {
+        msg!("Invalid token account owner");
+        return Err(ProgramError::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// Checks that a discount token account holds SRM or MSRM and is owned by the given wallet, so that it can be used to
+/// determine a fee tier
+pub fn check_discount_token_account(account: &AccountInfo, owner: &Pubkey) -> ProgramResult {
+    let token_account = unpack_token_account(account)?;
+    if token_account.mint != SRM_MINT && token_account.mint != MSRM_MINT {
+        msg!(
+            "The discount token account should hold SRM or MSRM, found mint {}",
+            token_account.mint
+        );
+        return Err(DexError::InvalidDiscountTokenMint.into());
+    }
+    if &token_account.owner != owner {
+        msg!("The discount token account should be owned by the user wallet");
+        return Err(DexError::InvalidDiscountTokenOwner.into());
+    }
+    Ok(())
+}
+
+/// Transfers tokens with a transfer_checked instruction, which works with both the SPL token program and Token-2022
+pub fn transfer_checked<'a>(
+    token_program: &AccountInfo<'a>,
+    source: &AccountInfo<'a>,
+    mint: &AccountInfo<'a>,
+    destination: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let decimals = StateWithExtensions::<Mint>::unpack(&mint.data.borrow())?
+        .base
+        .decimals;
+    let instruction = spl_token_2022::instruction::transfer_checked(
+        token_program.key,
+        source.key,
+        mint.key,
+        destination.key,
+        authority.key,
+        &[],
+        amount,
+        decimals,
+    )?;
+
+    invoke_signed(
+        &instruction,
+        &[
+            token_program.clone(),
+            source.clone(),
+            mint.clone(),
+            destination.clone(),
+            authority.clone(),
+        ],
+        signer_seeds,
+    )
+}
+
 pub fn check_signer(account: &AccountInfo) -> ProgramResult {
     if !(account.is_signer) {
         return Err(ProgramError::MissingRequiredSignature);
@@ -54,6 +150,14 @@ pub(crate) fn fp32_mul(a: u64, b_fp32: u64) -> Option<u64> {
         .and_then(|e| safe_downcast(e >> 32))
 }
 
+/// a is fp0, b is fp32 and result is a*b fp0, rounded up
+pub(crate) fn fp32_mul_ceil(a: u64, b_fp32: u64) -> Option<u64> {
+    (a as u128)
+        .checked_mul(b_fp32 as u128)
+        .and_then(|e| e.checked_add(FP_32_ONE as u128 - 1))
+        .and_then(|e| safe_downcast(e >> 32))
+}
+
 fn safe_downcast(n: u128) -> Option<u64> {
     static BOUND: u128 = u64::MAX as u128;
     if n > BOUND {
@@ -102,3 +206,27 @@ pub fn verify_metadata(creators: &[Creator]) -> ProgramResult {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fp32_mul_rounding() {
+        // Exact products are not affected by the rounding direction
+        assert_eq!(fp32_mul(3, 2 * FP_32_ONE), Some(6));
+        assert_eq!(fp32_mul_ceil(3, 2 * FP_32_ONE), Some(6));
+        assert_eq!(fp32_mul_ceil(0, FP_32_ONE + 1), Some(0));
+        // The smallest fractional part
+        assert_eq!(fp32_mul(1, 1), Some(0));
+        assert_eq!(fp32_mul_ceil(1, 1), Some(1));
+        // The largest fractional part
+        assert_eq!(fp32_mul(1, FP_32_ONE - 1), Some(0));
+        assert_eq!(fp32_mul_ceil(1, FP_32_ONE - 1), Some(1));
+        assert_eq!(fp32_mul(5, FP_32_ONE / 2), Some(2));
+        assert_eq!(fp32_mul_ceil(5, FP_32_ONE / 2), Some(3));
+        // Overflows
+        assert_eq!(fp32_mul_ceil(u64::MAX, FP_32_ONE), Some(u64::MAX));
+        assert_eq!(fp32_mul_ceil(u64::MAX, FP_32_ONE + 1), None);
+    }
+}
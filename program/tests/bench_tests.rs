@@ -0,0 +1,117 @@
+#![cfg(feature = "bench")]
+
+pub mod common;
+
+use crate::common::performance_test_utils::aob_dex_new_order;
+use crate::common::performance_test_utils::create_aob_dex;
+use crate::common::utils::sign_send_instructions;
+use asset_agnostic_orderbook::state::Side;
+use dex_v4::instruction_auto::consume_events;
+use dex_v4::instruction_auto::swap;
+use solana_program::pubkey::Pubkey;
+use solana_program::system_program;
+use solana_program_test::{processor, ProgramTest};
+use solana_sdk::signature::Signer;
+
+// The compute unit logs only show up in the program logs, this checks that the profiled instructions still succeed
+#[tokio::test]
+async fn test_compute_unit_logging() {
+    let program_test = ProgramTest::new(
+        "dex_v4",
+        dex_v4::ID,
+        processor!(dex_v4::entrypoint::process_instruction),
+    );
+    let (aob_dex_test_ctx, mut pgr_test_ctx) = create_aob_dex(program_test).await;
+    let price = 1 << 32;
+
+    // A resting bid which is partially filled by an ask
+    aob_dex_new_order(
+        &mut pgr_test_ctx,
+        &aob_dex_test_ctx,
+        Side::Bid,
+        price,
+        2_000,
+        u64::MAX,
+        0,
+    )
+    .await;
+    aob_dex_new_order(
+        &mut pgr_test_ctx,
+        &aob_dex_test_ctx,
+        Side::Ask,
+        price,
+        1_000,
+        u64::MAX,
+        1,
+    )
+    .await;
+
+    // A swap fills the remainder of the bid
+    let (market_signer, _) =
+        Pubkey::find_program_address(&[&aob_dex_test_ctx.dex_market_key.to_bytes()], &dex_v4::ID);
+    let swap_instruction = swap(
+        dex_v4::ID,
+        swap::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &aob_dex_test_ctx.dex_market_key,
+            orderbook: &aob_dex_test_ctx.dex_market.orderbook,
+            event_queue: &aob_dex_test_ctx.aob_market.event_queue,
+            bids: &aob_dex_test_ctx.aob_market.bids,
+            asks: &aob_dex_test_ctx.aob_market.asks,
+            base_vault: &aob_dex_test_ctx.dex_market.base_vault,
+            quote_vault: &aob_dex_test_ctx.dex_market.quote_vault,
+            base_mint: &aob_dex_test_ctx.dex_market.base_mint,
+            quote_mint: &aob_dex_test_ctx.dex_market.quote_mint,
+            market_signer: &market_signer,
+            user_base_account: &aob_dex_test_ctx.user_bases[2],
+            user_quote_account: &aob_dex_test_ctx.user_quotes[2],
+            user_owner: &aob_dex_test_ctx.user_owners[2].pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        swap::Params {
+            side: Side::Ask as u8,
+            base_qty: 1_000,
+            quote_qty: 0,
+            match_limit: 10,
+            has_discount_token_account: 0,
+            max_slippage_bps: 0,
+            exact_out: 0,
+            _padding: [0; 3],
+        },
+    );
+    sign_send_instructions(
+        &mut pgr_test_ctx,
+        vec![swap_instruction],
+        vec![&aob_dex_test_ctx.user_owners[2]],
+    )
+    .await
+    .unwrap();
+
+    // The crank looks the user accounts up with a binary search
+    let mut user_accounts = aob_dex_test_ctx.user_account_keys[..2].to_vec();
+    user_accounts.sort();
+    let consume_events_instruction = consume_events(
+        dex_v4::ID,
+        consume_events::Accounts {
+            market: &aob_dex_test_ctx.dex_market_key,
+            orderbook: &aob_dex_test_ctx.dex_market.orderbook,
+            event_queue: &aob_dex_test_ctx.aob_market.event_queue,
+            reward_target: &pgr_test_ctx.payer.pubkey(),
+            candle_history: None,
+            user_accounts: &user_accounts,
+        },
+        consume_events::Params {
+            max_iterations: 10,
+            no_op_err: 1,
+            has_candle_history: 0,
+            skip_missing: 0,
+            _padding: [0; 7],
+        },
+    );
+    sign_send_instructions(&mut pgr_test_ctx, vec![consume_events_instruction], vec![])
+        .await
+        .unwrap();
+}
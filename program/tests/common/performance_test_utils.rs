@@ -111,6 +111,8 @@ pub async fn create_aob_dex(
         dex_v4::instruction_auto::create_market::Accounts {
             base_vault: &base_vault,
             quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
             market: &market_account.pubkey(),
             orderbook: &aaob_accounts.market,
             market_admin: &market_admin.pubkey(),
@@ -125,6 +127,20 @@ pub async fn create_aob_dex(
             tick_size: 1,
             base_currency_multiplier: 1,
             quote_currency_multiplier: 1,
+            close_grace_slots: 0,
+            fee_tier_thresholds: [0; 5],
+            fee_tier_taker_rates: [0; 8],
+            fee_tier_maker_rebate_rates: [0; 8],
+            sweep_authority: Pubkey::default(),
+            min_quote_order_size: 0,
+            max_deviation_bps: 0,
+            referral_bps: 0,
+            fee_model: 0,
+            fee_type: 0,
+            name: [0; 16],
+            ticker: [0; 8],
+            flat_taker_bps: 0,
+            promo_end_ts: 0,
         },
     );
     sign_send_instructions(&mut pgr_test_ctx, vec![create_market_instruction], vec![])
@@ -406,6 +422,14 @@ pub async fn aob_dex_new_order(
     user_account_index: usize,
 ) {
     // New Order on AOB DEX
+    let market_signer = Pubkey::create_program_address(
+        &[
+            &dex_test_ctx.dex_market_key.to_bytes(),
+            &[dex_test_ctx.dex_market.signer_nonce as u8],
+        ],
+        &dex_v4::ID,
+    )
+    .unwrap();
     let new_order_instruction = new_order(
         dex_v4::ID,
         new_order::Accounts {
@@ -418,6 +442,9 @@ pub async fn aob_dex_new_order(
             asks: &dex_test_ctx.aob_market.asks,
             base_vault: &dex_test_ctx.dex_market.base_vault,
             quote_vault: &dex_test_ctx.dex_market.quote_vault,
+            base_mint: &dex_test_ctx.dex_market.base_mint,
+            quote_mint: &dex_test_ctx.dex_market.quote_mint,
+            market_signer: &market_signer,
             user: &dex_test_ctx.user_account_keys[user_account_index],
             user_token_account: &match side {
                 asset_agnostic_orderbook::state::Side::Ask => {
@@ -430,6 +457,7 @@ pub async fn aob_dex_new_order(
             user_owner: &dex_test_ctx.user_owners[user_account_index].pubkey(),
             discount_token_account: None,
             fee_referral_account: None,
+            referrer: None,
         },
         new_order::Params {
             side: side as u8,
@@ -445,7 +473,10 @@ pub async fn aob_dex_new_order(
             #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
             client_order_id: bytemuck::cast(0u128),
             has_discount_token_account: false as u8,
-            _padding: 0,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
         },
     );
     sign_send_instructions(
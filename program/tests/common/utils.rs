@@ -1,7 +1,13 @@
 use asset_agnostic_orderbook::state::event_queue::EventQueue;
 use asset_agnostic_orderbook::state::market_state::MarketState;
-use dex_v4::state::CallBackInfo;
-use solana_program::instruction::Instruction;
+use asset_agnostic_orderbook::state::AccountTag;
+use dex_v4::state::{
+    CallBackInfo, DexState, UserAccountHeader, DEX_STATE_LEN, USER_ACCOUNT_HEADER_LEN,
+};
+use solana_program::account_info::AccountInfo;
+use solana_program::entrypoint::ProgramResult;
+use solana_program::instruction::{AccountMeta, Instruction};
+use solana_program::program::invoke_signed;
 use solana_program::program_pack::Pack;
 use solana_program::pubkey::Pubkey;
 use solana_program::system_instruction::create_account;
@@ -12,6 +18,7 @@ use solana_sdk::transport::TransportError;
 use solana_sdk::{signature::Keypair, transaction::Transaction};
 use spl_associated_token_account::{create_associated_token_account, get_associated_token_address};
 use spl_token::state::Mint;
+use std::convert::TryInto;
 use std::str::FromStr;
 
 pub async fn sign_send_instructions(
@@ -29,6 +36,111 @@ pub async fn sign_send_instructions(
     ctx.banks_client.process_transaction(transaction).await
 }
 
+/// Sends the instructions and returns the data set by the last instruction of the given program, if any
+pub async fn sign_send_instructions_with_return_data(
+    ctx: &mut ProgramTestContext,
+    instructions: Vec<Instruction>,
+    signers: Vec<&Keypair>,
+    program_id: &Pubkey,
+) -> Result<Option<Vec<u8>>, BanksClientError> {
+    let mut transaction = Transaction::new_with_payer(&instructions, Some(&ctx.payer.pubkey()));
+    let mut payer_signers = vec![&ctx.payer];
+    for s in signers {
+        payer_signers.push(s);
+    }
+    transaction.partial_sign(&payer_signers, ctx.last_blockhash);
+    let res = ctx
+        .banks_client
+        .process_transaction_with_metadata(transaction)
+        .await?;
+    res.result.map_err(BanksClientError::TransactionError)?;
+    // The runtime logs the return data as "Program return: <program_id> <base64 data>"
+    let prefix = format!("Program return: {} ", program_id);
+    Ok(res
+        .metadata
+        .into_iter()
+        .flat_map(|m| m.log_messages)
+        .filter_map(|l| l.strip_prefix(&prefix).map(|d| base64::decode(d).unwrap()))
+        .last())
+}
+
+/// Sends the instructions and returns the decoded fields of each `sol_log_data` call, in order
+pub async fn sign_send_instructions_with_data_logs(
+    ctx: &mut ProgramTestContext,
+    instructions: Vec<Instruction>,
+    signers: Vec<&Keypair>,
+) -> Result<Vec<Vec<Vec<u8>>>, BanksClientError> {
+    let mut transaction = Transaction::new_with_payer(&instructions, Some(&ctx.payer.pubkey()));
+    let mut payer_signers = vec![&ctx.payer];
+    for s in signers {
+        payer_signers.push(s);
+    }
+    transaction.partial_sign(&payer_signers, ctx.last_blockhash);
+    let res = ctx
+        .banks_client
+        .process_transaction_with_metadata(transaction)
+        .await?;
+    res.result.map_err(BanksClientError::TransactionError)?;
+    // The runtime logs the data as "Program data: <base64 field> <base64 field> ..."
+    Ok(res
+        .metadata
+        .into_iter()
+        .flat_map(|m| m.log_messages)
+        .filter_map(|l| {
+            l.strip_prefix("Program data: ")
+                .map(|d| d.split(' ').map(|f| base64::decode(f).unwrap()).collect())
+        })
+        .collect())
+}
+
+/// The seeds of the PDA signing the instructions forwarded by [`process_forward_instruction`]
+pub const FORWARDING_PDA_SEEDS: &[u8] = b"forwarding_pda";
+
+/// A helper program which forwards an instruction, signing it as its PDA with `invoke_signed`.
+///
+/// The first account is the program of the forwarded instruction, followed by its accounts.
+pub fn process_forward_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let (pda, bump) = Pubkey::find_program_address(&[FORWARDING_PDA_SEEDS], program_id);
+    let (forwarded_program, forwarded_accounts) = accounts.split_first().unwrap();
+    let instruction = Instruction {
+        program_id: *forwarded_program.key,
+        accounts: forwarded_accounts
+            .iter()
+            .map(|a| AccountMeta {
+                pubkey: *a.key,
+                is_signer: a.is_signer || a.key == &pda,
+                is_writable: a.is_writable,
+            })
+            .collect(),
+        data: instruction_data.to_vec(),
+    };
+    invoke_signed(&instruction, accounts, &[&[FORWARDING_PDA_SEEDS, &[bump]]])
+}
+
+/// Wraps an instruction signed by the PDA of the forwarding program into a call to that program
+pub fn forward_instruction(
+    forwarding_program_id: &Pubkey,
+    instruction: Instruction,
+) -> Instruction {
+    let (pda, _) = Pubkey::find_program_address(&[FORWARDING_PDA_SEEDS], forwarding_program_id);
+    let mut accounts = vec![AccountMeta::new_readonly(instruction.program_id, false)];
+    accounts.extend(instruction.accounts.into_iter().map(|mut a| {
+        if a.pubkey == pda {
+            a.is_signer = false;
+        }
+        a
+    }));
+    Instruction {
+        program_id: *forwarding_program_id,
+        accounts,
+        data: instruction.data,
+    }
+}
+
 pub async fn create_associated_token(
     prg_test_ctx: &mut ProgramTestContext,
     mint: &Pubkey,
@@ -50,6 +162,23 @@ pub fn mint_bootstrap(
     decimals: u8,
     program_test: &mut ProgramTest,
     mint_authority: &Pubkey,
+) -> MintInfo {
+    mint_bootstrap_with_program(
+        address,
+        decimals,
+        program_test,
+        mint_authority,
+        &spl_token::ID,
+    )
+}
+
+/// Adds a mint owned by the given token program, which can be the SPL token program or Token-2022
+pub fn mint_bootstrap_with_program(
+    address: Option<&str>,
+    decimals: u8,
+    program_test: &mut ProgramTest,
+    mint_authority: &Pubkey,
+    token_program: &Pubkey,
 ) -> MintInfo {
     let address = address
         .map(|s| Pubkey::from_str(s).unwrap())
@@ -68,7 +197,7 @@ pub fn mint_bootstrap(
         Account {
             lamports: u32::MAX.into(),
             data: data.into(),
-            owner: spl_token::ID,
+            owner: *token_program,
             executable: false,
             ..Account::default()
         },
@@ -76,6 +205,53 @@ pub fn mint_bootstrap(
     (address, mint_info)
 }
 
+/// Creates a Token-2022 token account without any extension
+pub async fn create_token_2022_account(
+    prg_test_ctx: &mut ProgramTestContext,
+    mint: &Pubkey,
+    owner: &Pubkey,
+) -> Result<Pubkey, BanksClientError> {
+    let account = Keypair::new();
+    let rent = prg_test_ctx.banks_client.get_rent().await.unwrap();
+    let instructions = vec![
+        create_account(
+            &prg_test_ctx.payer.pubkey(),
+            &account.pubkey(),
+            rent.minimum_balance(spl_token_2022::state::Account::LEN),
+            spl_token_2022::state::Account::LEN as u64,
+            &spl_token_2022::ID,
+        ),
+        spl_token_2022::instruction::initialize_account(
+            &spl_token_2022::ID,
+            &account.pubkey(),
+            mint,
+            owner,
+        )
+        .unwrap(),
+    ];
+
+    sign_send_instructions(prg_test_ctx, instructions, vec![&account])
+        .await
+        .map(|()| account.pubkey())
+}
+
+/// The type of the Token-2022 transfer hook mint extension, which is missing from the spl-token-2022 version in use
+pub const TRANSFER_HOOK_EXTENSION_TYPE: u16 = 14;
+
+/// Appends a zeroed extension of the given type to a Token-2022 mint account without extensions
+pub fn add_mint_extension(mint: &mut Account, extension_type: u16, extension_len: usize) {
+    mint.data.resize(spl_token_2022::state::Account::LEN, 0);
+    mint.data
+        .push(spl_token_2022::extension::AccountType::Mint as u8);
+    mint.data.extend_from_slice(&extension_type.to_le_bytes());
+    mint.data
+        .extend_from_slice(&(extension_len as u16).to_le_bytes());
+    mint.data.resize(mint.data.len() + extension_len, 0);
+}
+
+/// The number of events which fit in the event queues of the test markets
+pub const EVENT_QUEUE_CAPACITY: usize = 10;
+
 pub struct AOBAccounts {
     pub event_queue: Pubkey,
     pub market: Pubkey,
@@ -111,7 +287,7 @@ pub async fn create_aob_market_and_accounts(
 
     // Create event queue account
     let event_queue_account = Keypair::new();
-    let evq_space = EventQueue::<CallBackInfo>::compute_allocation_size(10);
+    let evq_space = EventQueue::<CallBackInfo>::compute_allocation_size(EVENT_QUEUE_CAPACITY);
     let create_event_queue_account_instruction = create_account(
         &prg_test_ctx.payer.pubkey(),
         &event_queue_account.pubkey(),
@@ -172,3 +348,66 @@ pub async fn create_aob_market_and_accounts(
         bids: bids_account.pubkey(),
     }
 }
+
+pub async fn get_market_state(ctx: &mut ProgramTestContext, market: &Pubkey) -> DexState {
+    let data = ctx
+        .banks_client
+        .get_account(*market)
+        .await
+        .unwrap()
+        .unwrap()
+        .data;
+    *bytemuck::from_bytes(&data[..DEX_STATE_LEN])
+}
+
+/// Pads a string with trailing zeroes into a market name or ticker
+pub fn market_label<const N: usize>(label: &str) -> [u8; N] {
+    let mut res = [0; N];
+    res[..label.len()].copy_from_slice(label.as_bytes());
+    res
+}
+
+pub async fn get_token_balance(ctx: &mut ProgramTestContext, token_account: &Pubkey) -> u64 {
+    let data = ctx
+        .banks_client
+        .get_account(*token_account)
+        .await
+        .unwrap()
+        .unwrap()
+        .data;
+    spl_token::state::Account::unpack(&data).unwrap().amount
+}
+
+/// Returns the head index and the number of pending events of an event queue
+pub async fn get_event_queue_position(
+    ctx: &mut ProgramTestContext,
+    event_queue: &Pubkey,
+) -> (u64, u64) {
+    let mut data = ctx
+        .banks_client
+        .get_account(*event_queue)
+        .await
+        .unwrap()
+        .unwrap()
+        .data;
+    // The event queue header follows the 8 bytes account tag and starts with the head index
+    let head = u64::from_le_bytes(data[8..16].try_into().unwrap());
+    let count = EventQueue::<CallBackInfo>::from_buffer(&mut data, AccountTag::EventQueue)
+        .unwrap()
+        .len();
+    (head, count)
+}
+
+pub async fn get_user_account_header(
+    ctx: &mut ProgramTestContext,
+    user_account: &Pubkey,
+) -> UserAccountHeader {
+    let data = ctx
+        .banks_client
+        .get_account(*user_account)
+        .await
+        .unwrap()
+        .unwrap()
+        .data;
+    *bytemuck::from_bytes(&data[..USER_ACCOUNT_HEADER_LEN])
+}
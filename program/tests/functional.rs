@@ -1,22 +1,73 @@
+use asset_agnostic_orderbook::state::event_queue::EventQueue;
+use asset_agnostic_orderbook::state::event_queue::EventRef;
+use asset_agnostic_orderbook::state::event_queue::FillEventRef;
+use asset_agnostic_orderbook::state::event_queue::OutEventRef;
 use asset_agnostic_orderbook::state::market_state::MarketState;
 use asset_agnostic_orderbook::state::AccountTag;
+use borsh::BorshDeserialize;
 use bytemuck::try_from_bytes_mut;
+use dex_v4::error::DexError;
+use dex_v4::instruction_auto::accept_admin;
+use dex_v4::instruction_auto::admin_cancel_orders;
+use dex_v4::instruction_auto::batch_new_order;
 use dex_v4::instruction_auto::cancel_order;
+use dex_v4::instruction_auto::close_account;
+use dex_v4::instruction_auto::close_market;
 use dex_v4::instruction_auto::consume_events;
+use dex_v4::instruction_auto::consume_events_and_settle;
 use dex_v4::instruction_auto::create_market;
+use dex_v4::instruction_auto::force_close_market;
+use dex_v4::instruction_auto::get_best_prices;
+use dex_v4::instruction_auto::get_market_info;
 use dex_v4::instruction_auto::initialize_account;
+use dex_v4::instruction_auto::initialize_candle_history;
+use dex_v4::instruction_auto::migrate_market_state;
+use dex_v4::instruction_auto::migrate_user_account;
 use dex_v4::instruction_auto::new_order;
+use dex_v4::instruction_auto::nominate_admin;
+use dex_v4::instruction_auto::reduce_order;
+use dex_v4::instruction_auto::register_referrer;
+use dex_v4::instruction_auto::repost;
+use dex_v4::instruction_auto::resize_user_account;
+use dex_v4::instruction_auto::set_delegate;
+use dex_v4::instruction_auto::set_market_metadata;
+use dex_v4::instruction_auto::set_market_status;
 use dex_v4::instruction_auto::settle;
 use dex_v4::instruction_auto::swap;
 use dex_v4::instruction_auto::sweep_fees;
+use dex_v4::instruction_auto::sweep_royalties;
+use dex_v4::state::find_candle_history;
+use dex_v4::state::find_referrer;
+use dex_v4::state::CallBackInfo;
+use dex_v4::state::CandleHistory;
+use dex_v4::state::DexState;
+use dex_v4::state::FeeTier;
+use dex_v4::state::FillLog;
+use dex_v4::state::LegacyUserAccountHeader;
+use dex_v4::state::MarketFeeType;
+use dex_v4::state::MarketInfo;
+use dex_v4::state::MarketStatus;
+use dex_v4::state::Order;
+use dex_v4::state::Referrer;
+use dex_v4::state::UserAccount;
 use dex_v4::state::UserAccountHeader;
+use dex_v4::state::CANDLE_HISTORY_LEN;
+use dex_v4::state::DEFAULT_FEE_TIER_MAKER_REBATE_RATES;
+use dex_v4::state::DEFAULT_FEE_TIER_TAKER_RATES;
 use dex_v4::state::DEX_STATE_LEN;
+use dex_v4::state::FILL_LOG_VERSION;
+use dex_v4::state::LEGACY_DEX_STATE_LEN;
+use dex_v4::state::LEGACY_USER_ACCOUNT_HEADER_LEN;
+use dex_v4::state::REFERRER_LEN;
 use dex_v4::state::USER_ACCOUNT_HEADER_LEN;
 use mpl_token_metadata::pda::find_metadata_account;
+use num_traits::FromPrimitive;
 use solana_program::account_info::AccountInfo;
+use solana_program::clock::Clock;
 use solana_program::entrypoint::ProgramResult;
 use solana_program::msg;
 use solana_program::program_error::PrintProgramError;
+use solana_program::program_pack::Pack;
 use solana_program::pubkey::Pubkey;
 use solana_program::system_instruction::create_account;
 use solana_program::system_program;
@@ -25,14 +76,38 @@ use solana_program_test::ProgramTest;
 use solana_sdk::signature::Keypair;
 use solana_sdk::signature::Signer;
 use spl_token::instruction::mint_to;
+use spl_token_2022::extension::ExtensionType;
 use std::convert::TryInto;
 pub mod common;
+use crate::common::utils::add_mint_extension;
 use crate::common::utils::create_associated_token;
+use crate::common::utils::create_token_2022_account;
+use crate::common::utils::forward_instruction;
+use crate::common::utils::get_event_queue_position;
+use crate::common::utils::get_market_state;
+use crate::common::utils::get_token_balance;
+use crate::common::utils::get_user_account_header;
+use crate::common::utils::market_label;
 use crate::common::utils::mint_bootstrap;
-use crate::common::utils::{create_aob_market_and_accounts, sign_send_instructions};
+use crate::common::utils::mint_bootstrap_with_program;
+use crate::common::utils::process_forward_instruction;
+use crate::common::utils::EVENT_QUEUE_CAPACITY;
+use crate::common::utils::FORWARDING_PDA_SEEDS;
+use crate::common::utils::TRANSFER_HOOK_EXTENSION_TYPE;
+use crate::common::utils::{
+    create_aob_market_and_accounts, sign_send_instructions, sign_send_instructions_with_data_logs,
+    sign_send_instructions_with_return_data,
+};
+use dex_v4::instruction_auto::update_min_base_order_size;
+use dex_v4::instruction_auto::update_promo_end_ts;
 use dex_v4::instruction_auto::update_royalties;
+use dex_v4::instruction_auto::update_tick_size;
+use dex_v4::instruction_auto::DexInstruction;
 use mpl_token_metadata::state::Creator;
+use mpl_token_metadata::state::DataV2;
+use solana_program::instruction::InstructionError;
 use solana_program::pubkey;
+use solana_sdk::transaction::TransactionError;
 
 #[tokio::test]
 async fn test_dex() {
@@ -47,6 +122,17 @@ async fn test_dex() {
     );
 
     program_test.add_program("mpl_token_metadata", mpl_token_metadata::ID, None);
+    let forwarding_program_id = Pubkey::new_unique();
+    program_test.add_program(
+        "forward_instruction",
+        forwarding_program_id,
+        processor!(process_forward_instruction),
+    );
+    program_test.add_program(
+        "spl_token_2022",
+        spl_token_2022::ID,
+        processor!(spl_token_2022::processor::Processor::process),
+    );
     let user_account_owner = Keypair::new();
 
     // Create the market mints
@@ -54,6 +140,26 @@ async fn test_dex() {
     let (base_mint_key, _) = mint_bootstrap(None, 0, &mut program_test, &base_mint_auth.pubkey());
     let quote_mint_auth = Keypair::new();
     let (quote_mint_key, _) = mint_bootstrap(None, 6, &mut program_test, &quote_mint_auth.pubkey());
+    let (token_2022_base_mint, _) = mint_bootstrap_with_program(
+        None,
+        0,
+        &mut program_test,
+        &base_mint_auth.pubkey(),
+        &spl_token_2022::ID,
+    );
+    let (token_2022_quote_mint, _) = mint_bootstrap_with_program(
+        None,
+        6,
+        &mut program_test,
+        &quote_mint_auth.pubkey(),
+        &spl_token_2022::ID,
+    );
+    let (srm_mint, _) = mint_bootstrap(
+        Some("SRMuApVNdxXokk5GT7XD5cUUgXMBCoAz2LHeuAoKWRt"),
+        6,
+        &mut program_test,
+        &Pubkey::new_unique(),
+    );
 
     // Create test context
     let mut prg_test_ctx = program_test.start_with_context().await;
@@ -142,6 +248,8 @@ async fn test_dex() {
         dex_v4::instruction_auto::create_market::Accounts {
             base_vault: &base_vault,
             quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
             market: &market_account.pubkey(),
             orderbook: &aaob_accounts.market,
             market_admin: &market_admin.pubkey(),
@@ -156,6 +264,20 @@ async fn test_dex() {
             tick_size: 42949672,
             base_currency_multiplier: 1,
             quote_currency_multiplier: 10000,
+            close_grace_slots: 1_000,
+            fee_tier_thresholds: [0; 5],
+            fee_tier_taker_rates: [0; 8],
+            fee_tier_maker_rebate_rates: [0; 8],
+            sweep_authority,
+            min_quote_order_size: 0,
+            max_deviation_bps: 0,
+            referral_bps: 5_000,
+            fee_model: 0,
+            fee_type: 0,
+            name: market_label("BASE/QUOTE"),
+            ticker: market_label("BASE"),
+            flat_taker_bps: 0,
+            promo_end_ts: 0,
         },
     );
     sign_send_instructions(&mut prg_test_ctx, vec![create_market_instruction], vec![])
@@ -287,6 +409,154 @@ async fn test_dex() {
             .await
             .unwrap();
 
+    // Create a second market with its own sweep authority
+    let other_market_account = Keypair::new();
+    let create_market_account_instruction = create_account(
+        &prg_test_ctx.payer.pubkey(),
+        &other_market_account.pubkey(),
+        market_rent,
+        DEX_STATE_LEN as u64,
+        &dex_program_id,
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![create_market_account_instruction],
+        vec![&other_market_account],
+    )
+    .await
+    .unwrap();
+    let (other_market_signer, other_signer_nonce) = Pubkey::find_program_address(
+        &[&other_market_account.pubkey().to_bytes()],
+        &dex_program_id,
+    );
+    let other_aaob_accounts =
+        create_aob_market_and_accounts(&mut prg_test_ctx, dex_program_id).await;
+    let other_base_vault =
+        create_associated_token(&mut prg_test_ctx, &base_mint_key, &other_market_signer)
+            .await
+            .unwrap();
+    let other_quote_vault =
+        create_associated_token(&mut prg_test_ctx, &quote_mint_key, &other_market_signer)
+            .await
+            .unwrap();
+    let other_sweep_authority = Keypair::new().pubkey();
+    let create_market_instruction = create_market(
+        dex_program_id,
+        dex_v4::instruction_auto::create_market::Accounts {
+            base_vault: &other_base_vault,
+            quote_vault: &other_quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market: &other_market_account.pubkey(),
+            orderbook: &other_aaob_accounts.market,
+            market_admin: &market_admin.pubkey(),
+            event_queue: &other_aaob_accounts.event_queue,
+            asks: &other_aaob_accounts.asks,
+            bids: &other_aaob_accounts.bids,
+            token_metadata: &find_metadata_account(&base_mint_key).0,
+        },
+        create_market::Params {
+            signer_nonce: other_signer_nonce as u64,
+            min_base_order_size: 1,
+            tick_size: 42949672,
+            base_currency_multiplier: 1,
+            quote_currency_multiplier: 10000,
+            close_grace_slots: 0,
+            fee_tier_thresholds: [0; 5],
+            fee_tier_taker_rates: [0; 8],
+            fee_tier_maker_rebate_rates: [0; 8],
+            sweep_authority: other_sweep_authority,
+            min_quote_order_size: 0,
+            max_deviation_bps: 0,
+            referral_bps: 0,
+            fee_model: 0,
+            fee_type: 0,
+            name: [0; 16],
+            ticker: [0; 8],
+            flat_taker_bps: 0,
+            promo_end_ts: 0,
+        },
+    );
+    sign_send_instructions(&mut prg_test_ctx, vec![create_market_instruction], vec![])
+        .await
+        .unwrap();
+    let other_sweep_fees_ata =
+        create_associated_token(&mut prg_test_ctx, &quote_mint_key, &other_sweep_authority)
+            .await
+            .unwrap();
+
+    let market_state = get_market_state(&mut prg_test_ctx, &market_account.pubkey()).await;
+    assert_eq!(market_state.sweep_authority, sweep_authority);
+    let other_market_state =
+        get_market_state(&mut prg_test_ctx, &other_market_account.pubkey()).await;
+    assert_eq!(other_market_state.sweep_authority, other_sweep_authority);
+    // The name and ticker given at creation are stored in the market state
+    assert_eq!(market_state.name(), Some("BASE/QUOTE"));
+    assert_eq!(market_state.ticker(), Some("BASE"));
+    assert_eq!(other_market_state.name(), Some(""));
+
+    // Fees of the second market cannot be swept to the first market's sweep authority
+    let ix = sweep_fees(
+        dex_program_id,
+        sweep_fees::Accounts {
+            market: &other_market_account.pubkey(),
+            market_signer: &other_market_signer,
+            quote_vault: &other_quote_vault,
+            quote_mint: &quote_mint_key,
+            destination_token_account: &sweep_fees_ata,
+            spl_token_program: &spl_token::ID,
+        },
+        sweep_fees::Params { amount: 0 },
+    );
+    sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![])
+        .await
+        .unwrap_err();
+
+    // The destination account checks pass with the second market's own sweep authority,
+    // there are no fees to sweep yet
+    let ix = sweep_fees(
+        dex_program_id,
+        sweep_fees::Accounts {
+            market: &other_market_account.pubkey(),
+            market_signer: &other_market_signer,
+            quote_vault: &other_quote_vault,
+            quote_mint: &quote_mint_key,
+            destination_token_account: &other_sweep_fees_ata,
+            spl_token_program: &spl_token::ID,
+        },
+        sweep_fees::Params { amount: 0 },
+    );
+    let err = sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(DexError::NoOp as u32))
+    );
+
+    // Update the tick size while the orderbook is still empty
+    let update_tick_size_instruction = update_tick_size(
+        dex_program_id,
+        update_tick_size::Accounts {
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_accounts.event_queue,
+            bids: &aaob_accounts.bids,
+            asks: &aaob_accounts.asks,
+            market_admin: &market_admin.pubkey(),
+        },
+        update_tick_size::Params {
+            tick_size: 2 * 42949672,
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![update_tick_size_instruction],
+        vec![&market_admin],
+    )
+    .await
+    .unwrap();
+
     let mut aaob_market_state_data = prg_test_ctx
         .banks_client
         .get_account(aaob_accounts.market)
@@ -295,7 +565,166 @@ async fn test_dex() {
         .unwrap();
     let aaob_market_state =
         MarketState::from_buffer(&mut aaob_market_state_data.data, AccountTag::Market).unwrap();
-        
+    assert_eq!(aaob_market_state.tick_size, 2 * 42949672);
+
+    // Raise the minimum base order size
+    let update_min_base_order_size_instruction = update_min_base_order_size(
+        dex_program_id,
+        update_min_base_order_size::Accounts {
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            market_admin: &market_admin.pubkey(),
+        },
+        update_min_base_order_size::Params {
+            min_base_order_size: 500,
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![update_min_base_order_size_instruction],
+        vec![&market_admin],
+    )
+    .await
+    .unwrap();
+
+    // Orders below the minimum base order size should be rejected
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            bids: &aaob_market_state.bids,
+            asks: &aaob_market_state.asks,
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user: &user_account,
+            user_token_account: &user_base_token_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id: 0,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(0u128),
+            side: asset_agnostic_orderbook::state::Side::Ask as u8,
+            limit_price: 9 * aaob_market_state.tick_size,
+            max_base_qty: 100,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::Limit as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(0, InstructionError::InvalidArgument)
+    );
+
+    // Orders below the minimum quote order size should be rejected on both sides
+    let mut market_account_data = prg_test_ctx
+        .banks_client
+        .get_account(market_account.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    let market_state: &mut DexState =
+        try_from_bytes_mut(&mut market_account_data.data[..DEX_STATE_LEN]).unwrap();
+    market_state.min_quote_order_size = 10_000_000;
+    prg_test_ctx.set_account(
+        &market_account.pubkey(),
+        &market_account_data.clone().into(),
+    );
+    for &(side, user_token_account) in &[
+        (
+            asset_agnostic_orderbook::state::Side::Bid,
+            user_quote_token_account,
+        ),
+        (
+            asset_agnostic_orderbook::state::Side::Ask,
+            user_base_token_account,
+        ),
+    ] {
+        let new_order_instruction = new_order(
+            dex_program_id,
+            new_order::Accounts {
+                spl_token_program: &spl_token::ID,
+                system_program: &system_program::ID,
+                market: &market_account.pubkey(),
+                orderbook: &aaob_accounts.market,
+                event_queue: &aaob_market_state.event_queue,
+                bids: &aaob_market_state.bids,
+                asks: &aaob_market_state.asks,
+                base_vault: &base_vault,
+                quote_vault: &quote_vault,
+                base_mint: &base_mint_key,
+                quote_mint: &quote_mint_key,
+                market_signer: &market_signer,
+                user: &user_account,
+                user_token_account: &user_token_account,
+                user_owner: &user_account_owner.pubkey(),
+                discount_token_account: None,
+                fee_referral_account: None,
+                referrer: None,
+            },
+            new_order::Params {
+                #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+                client_order_id: 0,
+                #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+                client_order_id: bytemuck::cast(0u128),
+                side: side as u8,
+                limit_price: 9 * aaob_market_state.tick_size,
+                max_base_qty: 1_000,
+                max_quote_qty: u64::MAX,
+                order_type: new_order::OrderType::Limit as u8,
+                self_trade_behavior:
+                    asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake as u8,
+                match_limit: 10,
+                has_discount_token_account: false as u8,
+                fok_fallback_ioc: 0,
+                reduce_only: 0,
+                duplicate_client_id_policy: 0,
+                _padding: [0; 1],
+            },
+        );
+        let err = sign_send_instructions(
+            &mut prg_test_ctx,
+            vec![new_order_instruction],
+            vec![&user_account_owner],
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.unwrap(),
+            TransactionError::InstructionError(0, InstructionError::InvalidArgument)
+        );
+    }
+    let market_state: &mut DexState =
+        try_from_bytes_mut(&mut market_account_data.data[..DEX_STATE_LEN]).unwrap();
+    market_state.min_quote_order_size = 0;
+    prg_test_ctx.set_account(&market_account.pubkey(), &market_account_data.into());
+
     // New Order, to be cancelled
     let new_order_instruction = new_order(
         dex_program_id,
@@ -309,11 +738,15 @@ async fn test_dex() {
             asks: &aaob_market_state.asks,
             base_vault: &base_vault,
             quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
             user: &user_account,
             user_token_account: &user_base_token_account,
             user_owner: &user_account_owner.pubkey(),
             discount_token_account: None,
             fee_referral_account: None,
+            referrer: None,
         },
         new_order::Params {
             #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
@@ -322,14 +755,17 @@ async fn test_dex() {
             client_order_id: bytemuck::cast(0u128),
             side: asset_agnostic_orderbook::state::Side::Ask as u8,
             limit_price: 9 * aaob_market_state.tick_size,
-            max_base_qty: 1,
+            max_base_qty: 1_000,
             max_quote_qty: u64::MAX,
             order_type: new_order::OrderType::Limit as u8,
             self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
                 as u8,
             match_limit: 10,
             has_discount_token_account: false as u8,
-            _padding: 0,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
         },
     );
     sign_send_instructions(
@@ -394,11 +830,15 @@ async fn test_dex() {
             asks: &aaob_market_state.asks,
             base_vault: &base_vault,
             quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
             user: &user_account,
-            user_token_account: &user_base_token_account,
+            user_token_account: &user_quote_token_account,
             user_owner: &user_account_owner.pubkey(),
             discount_token_account: None,
             fee_referral_account: None,
+            referrer: None,
         },
         new_order::Params {
             #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
@@ -407,14 +847,17 @@ async fn test_dex() {
             client_order_id: bytemuck::cast(0u128),
             side: asset_agnostic_orderbook::state::Side::Bid as u8,
             limit_price: 11 * aaob_market_state.tick_size,
-            max_base_qty: 1,
+            max_base_qty: 1_000,
             max_quote_qty: u64::MAX,
             order_type: new_order::OrderType::Limit as u8,
             self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
                 as u8,
             match_limit: 10,
             has_discount_token_account: false as u8,
-            _padding: 0,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
         },
     );
     sign_send_instructions(
@@ -425,6 +868,15 @@ async fn test_dex() {
     .await
     .unwrap();
 
+    // The taker royalties should be accounted for in the market state
+    let market_state = get_market_state(&mut prg_test_ctx, &market_account.pubkey()).await;
+    let matched_quote_qty =
+        (((1_000u128 * (9 * aaob_market_state.tick_size) as u128) >> 32) as u64) * 10_000;
+    assert_eq!(
+        market_state.accumulated_royalties,
+        matched_quote_qty * market_state.royalties_bps / 10_000
+    );
+
     // New Order, matching, takes 100 units @ 1000 price
     // let new_order_instruction = new_order(
     //     dex_program_id,
@@ -438,6 +890,8 @@ async fn test_dex() {
     //         asks: &aaob_market_state.asks,
     //         base_vault: &base_vault,
     //         quote_vault: &quote_vault,
+    //         base_mint: &base_mint_key,
+    //         quote_mint: &quote_mint_key,
     //         user: &user_account,
     //         user_token_account: &user_quote_token_account,
     //         user_owner: &user_account_owner.pubkey(),
@@ -458,7 +912,10 @@ async fn test_dex() {
     //             as u8,
     //         match_limit: 10,
     //         has_discount_token_account: false as u8,
-    //         _padding: 0,
+    //         fok_fallback_ioc: 0,
+    //         reduce_only: 0,
+    //         duplicate_client_id_policy: 0,
+    //         _padding: [0; 1],
     //     },
     // );
     // sign_send_instructions(
@@ -490,135 +947,6400 @@ async fn test_dex() {
     //     .await
     //     .unwrap();
 
-    // Settle
-    let settle_instruction = settle(
+    // New Order, to be cancelled while the market is paused
+    let new_order_instruction = new_order(
         dex_program_id,
-        settle::Accounts {
+        new_order::Accounts {
             spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
             market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            bids: &aaob_market_state.bids,
+            asks: &aaob_market_state.asks,
             base_vault: &base_vault,
             quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
             market_signer: &market_signer,
             user: &user_account,
+            user_token_account: &user_base_token_account,
             user_owner: &user_account_owner.pubkey(),
-            destination_base_account: &user_base_token_account,
-            destination_quote_account: &user_quote_token_account,
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id: 5,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(5u128),
+            side: asset_agnostic_orderbook::state::Side::Ask as u8,
+            limit_price: 100 * aaob_market_state.tick_size,
+            max_base_qty: 1_000,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::Limit as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
         },
-        settle::Params {},
     );
     sign_send_instructions(
         &mut prg_test_ctx,
-        vec![settle_instruction],
+        vec![new_order_instruction],
         vec![&user_account_owner],
     )
     .await
     .unwrap();
 
-    // Swap, matching, takes 10 units @ 1000 price
-    // let new_order_instruction = swap(
-    //     dex_program_id,
-    //     swap::Accounts {
-    //         spl_token_program: &spl_token::ID,
-    //         system_program: &system_program::ID,
-    //         market: &market_account.pubkey(),
-    //         orderbook: &aaob_accounts.market,
-    //         event_queue: &aaob_market_state.event_queue,
-    //         bids: &aaob_market_state.bids,
-    //         asks: &aaob_market_state.asks,
-    //         base_vault: &base_vault,
-    //         quote_vault: &quote_vault,
-    //         market_signer: &market_signer,
-    //         user_base_account: &user_base_token_account,
-    //         user_quote_account: &user_quote_token_account,
-    //         user_owner: &user_account_owner.pubkey(),
-    //         discount_token_account: None,
-    //         fee_referral_account: None,
-    //     },
-    //     swap::Params {
-    //         side: asset_agnostic_orderbook::state::Side::Bid as u8,
-    //         base_qty: 10_000,
-    //         quote_qty: 100000,
-    //         match_limit: 10,
-    //         has_discount_token_account: 0,
-    //         _padding: [0; 6],
-    //     },
-    // );
-    // sign_send_instructions(
-    //     &mut prg_test_ctx,
-    //     vec![new_order_instruction],
-    //     vec![&user_account_owner],
-    // )
-    // .await
-    // .unwrap();
-
-    // Sweep fees
-    let ix = sweep_fees(
+    // Pause the market
+    let set_market_status_instruction = set_market_status(
         dex_program_id,
-        sweep_fees::Accounts {
+        set_market_status::Accounts {
             market: &market_account.pubkey(),
-            market_signer: &market_signer,
-            quote_vault: &quote_vault,
-            destination_token_account: &sweep_fees_ata,
-            spl_token_program: &spl_token::ID,
-            token_metadata: &find_metadata_account(&base_mint_key).0,
-            creators_token_accounts: &[user_quote_token_account, base_mint_auth_token_account],
+            market_admin: &market_admin.pubkey(),
+        },
+        set_market_status::Params {
+            status: MarketStatus::Paused as u8,
+            _padding: [0; 7],
         },
-        sweep_fees::Params {},
     );
-    sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![])
-        .await
-        .unwrap();
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![set_market_status_instruction],
+        vec![&market_admin],
+    )
+    .await
+    .unwrap();
 
-    // Consume Events
-    let consume_events_instruction = consume_events(
+    // Paused markets should reject new orders
+    let new_order_instruction = new_order(
         dex_program_id,
-        consume_events::Accounts {
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
             market: &market_account.pubkey(),
             orderbook: &aaob_accounts.market,
             event_queue: &aaob_market_state.event_queue,
-            reward_target: &reward_target.pubkey(),
-            user_accounts: &[user_account],
-        },
-        consume_events::Params {
-            max_iterations: 11,
-            no_op_err: 1,
+            bids: &aaob_market_state.bids,
+            asks: &aaob_market_state.asks,
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user: &user_account,
+            user_token_account: &user_quote_token_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
         },
-    );
-    sign_send_instructions(&mut prg_test_ctx, vec![consume_events_instruction], vec![])
-        .await
-        .unwrap();
-
-    // Change royalties_bps
-    let ix = update_royalties(
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id: 6,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(6u128),
+            side: asset_agnostic_orderbook::state::Side::Bid as u8,
+            limit_price: 8 * aaob_market_state.tick_size,
+            max_base_qty: 1_000,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::Limit as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::MarketPaused as u32)
+        )
+    );
+
+    // Paused markets should still allow cancelling orders
+    let user_account_header_before_cancel =
+        get_user_account_header(&mut prg_test_ctx, &user_account).await;
+    let cancel_order_instruction = cancel_order(
         dex_program_id,
-        update_royalties::Accounts {
+        cancel_order::Accounts {
             market: &market_account.pubkey(),
-            event_queue: &aaob_accounts.event_queue,
-            token_metadata: &find_metadata_account(&base_mint_key).0,
             orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            bids: &aaob_market_state.bids,
+            asks: &aaob_market_state.asks,
+            user: &user_account,
+            user_owner: &user_account_owner.pubkey(),
+        },
+        cancel_order::Params {
+            order_index: 0,
+            order_id: 5,
+            is_client_id: true,
+            _padding: [0u8; 7],
         },
-        update_royalties::Params {},
     );
-    sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![])
-        .await
-        .unwrap();
+    let return_data = sign_send_instructions_with_return_data(
+        &mut prg_test_ctx,
+        vec![cancel_order_instruction],
+        vec![&user_account_owner],
+        &dex_program_id,
+    )
+    .await
+    .unwrap()
+    .unwrap();
 
-    // consume_events should not error when no_op_err == 0
-    let consume_events_instruction = consume_events(
+    // The unlocked amounts are returned to the client
+    let cancel_order_summary =
+        cancel_order::CancelOrderSummary::try_from_slice(&return_data).unwrap();
+    let user_account_header = get_user_account_header(&mut prg_test_ctx, &user_account).await;
+    assert_eq!(cancel_order_summary.total_base_qty, 1_000);
+    assert_eq!(
+        user_account_header.base_token_free - user_account_header_before_cancel.base_token_free,
+        cancel_order_summary.total_base_qty
+    );
+
+    // Settling to swapped destination token accounts should be rejected
+    let settle_instruction = settle(
         dex_program_id,
-        consume_events::Accounts {
+        settle::Accounts {
+            spl_token_program: &spl_token::ID,
+            market: &market_account.pubkey(),
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user: &user_account,
+            user_owner: &user_account_owner.pubkey(),
+            destination_base_account: &user_quote_token_account,
+            destination_quote_account: &user_base_token_account,
+        },
+        settle::Params {
+            base_amount: 0,
+            quote_amount: 0,
+            unwrap_sol: 0,
+            _padding: [0; 7],
+        },
+    );
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![settle_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::InvalidBaseTokenAccountMint as u32)
+        )
+    );
+
+    // Paused markets should still allow settling funds
+    let settle_instruction = settle(
+        dex_program_id,
+        settle::Accounts {
+            spl_token_program: &spl_token::ID,
+            market: &market_account.pubkey(),
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user: &user_account,
+            user_owner: &user_account_owner.pubkey(),
+            destination_base_account: &user_base_token_account,
+            destination_quote_account: &user_quote_token_account,
+        },
+        settle::Params {
+            base_amount: 0,
+            quote_amount: 0,
+            unwrap_sol: 0,
+            _padding: [0; 7],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![settle_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+
+    // Resume the market
+    let set_market_status_instruction = set_market_status(
+        dex_program_id,
+        set_market_status::Accounts {
+            market: &market_account.pubkey(),
+            market_admin: &market_admin.pubkey(),
+        },
+        set_market_status::Params {
+            status: MarketStatus::Active as u8,
+            _padding: [0; 7],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![set_market_status_instruction],
+        vec![&market_admin],
+    )
+    .await
+    .unwrap();
+
+    // New Order, to be matched by a swap
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
             market: &market_account.pubkey(),
             orderbook: &aaob_accounts.market,
             event_queue: &aaob_market_state.event_queue,
-            reward_target: &reward_target.pubkey(),
-            user_accounts: &[user_account],
+            bids: &aaob_market_state.bids,
+            asks: &aaob_market_state.asks,
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user: &user_account,
+            user_token_account: &user_base_token_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
         },
-        consume_events::Params {
-            max_iterations: 10,
-            no_op_err: 0,
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id: 1,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(1u128),
+            side: asset_agnostic_orderbook::state::Side::Ask as u8,
+            limit_price: 9 * aaob_market_state.tick_size,
+            max_base_qty: 1_000,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::Limit as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
         },
     );
-    sign_send_instructions(&mut prg_test_ctx, vec![consume_events_instruction], vec![])
-        .await
-        .unwrap();
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+
+    // Token accounts which don't match the order side should be refused
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            bids: &aaob_market_state.bids,
+            asks: &aaob_market_state.asks,
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user: &user_account,
+            user_token_account: &user_base_token_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id: 7,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(7u128),
+            side: asset_agnostic_orderbook::state::Side::Bid as u8,
+            limit_price: 9 * aaob_market_state.tick_size,
+            max_base_qty: 1_000,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::Limit as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::InvalidQuoteTokenAccountMint as u32)
+        )
+    );
+
+    // The mints given for the transfer_checked instructions must be the market's
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            bids: &aaob_market_state.bids,
+            asks: &aaob_market_state.asks,
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &quote_mint_key,
+            quote_mint: &base_mint_key,
+            market_signer: &market_signer,
+            user: &user_account,
+            user_token_account: &user_base_token_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id: 7,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(7u128),
+            side: asset_agnostic_orderbook::state::Side::Ask as u8,
+            limit_price: 9 * aaob_market_state.tick_size,
+            max_base_qty: 1_000,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::Limit as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::InvalidBaseMintAccount as u32)
+        )
+    );
+    let swap_instruction = swap(
+        dex_program_id,
+        swap::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            bids: &aaob_market_state.bids,
+            asks: &aaob_market_state.asks,
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user_base_account: &user_quote_token_account,
+            user_quote_account: &user_base_token_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        swap::Params {
+            side: asset_agnostic_orderbook::state::Side::Bid as u8,
+            base_qty: 1_000,
+            quote_qty: 2_000_000,
+            match_limit: 10,
+            has_discount_token_account: 0,
+            max_slippage_bps: 0,
+            exact_out: 0,
+            _padding: [0; 3],
+        },
+    );
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![swap_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::InvalidBaseTokenAccountMint as u32)
+        )
+    );
+
+    // The quote vault and the taker's own token accounts can't be used as referral accounts
+    for fee_referral_account in [quote_vault, user_quote_token_account] {
+        let new_order_instruction = new_order(
+            dex_program_id,
+            new_order::Accounts {
+                spl_token_program: &spl_token::ID,
+                system_program: &system_program::ID,
+                market: &market_account.pubkey(),
+                orderbook: &aaob_accounts.market,
+                event_queue: &aaob_market_state.event_queue,
+                bids: &aaob_market_state.bids,
+                asks: &aaob_market_state.asks,
+                base_vault: &base_vault,
+                quote_vault: &quote_vault,
+                base_mint: &base_mint_key,
+                quote_mint: &quote_mint_key,
+                market_signer: &market_signer,
+                user: &user_account,
+                user_token_account: &user_base_token_account,
+                user_owner: &user_account_owner.pubkey(),
+                discount_token_account: None,
+                fee_referral_account: Some(&fee_referral_account),
+                referrer: None,
+            },
+            new_order::Params {
+                #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+                client_order_id: 0,
+                #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+                client_order_id: bytemuck::cast(0u128),
+                side: asset_agnostic_orderbook::state::Side::Ask as u8,
+                limit_price: 1_000 * aaob_market_state.tick_size,
+                max_base_qty: 1_000,
+                max_quote_qty: u64::MAX,
+                order_type: new_order::OrderType::Limit as u8,
+                self_trade_behavior:
+                    asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake as u8,
+                match_limit: 10,
+                has_discount_token_account: false as u8,
+                fok_fallback_ioc: 0,
+                reduce_only: 0,
+                duplicate_client_id_policy: 0,
+                _padding: [0; 1],
+            },
+        );
+        let swap_instruction = swap(
+            dex_program_id,
+            swap::Accounts {
+                spl_token_program: &spl_token::ID,
+                system_program: &system_program::ID,
+                market: &market_account.pubkey(),
+                orderbook: &aaob_accounts.market,
+                event_queue: &aaob_market_state.event_queue,
+                bids: &aaob_market_state.bids,
+                asks: &aaob_market_state.asks,
+                base_vault: &base_vault,
+                quote_vault: &quote_vault,
+                base_mint: &base_mint_key,
+                quote_mint: &quote_mint_key,
+                market_signer: &market_signer,
+                user_base_account: &user_base_token_account,
+                user_quote_account: &user_quote_token_account,
+                user_owner: &user_account_owner.pubkey(),
+                discount_token_account: None,
+                fee_referral_account: Some(&fee_referral_account),
+                referrer: None,
+            },
+            swap::Params {
+                side: asset_agnostic_orderbook::state::Side::Bid as u8,
+                base_qty: 1_000,
+                quote_qty: 2_000_000,
+                match_limit: 10,
+                has_discount_token_account: 0,
+                max_slippage_bps: 0,
+                exact_out: 0,
+                _padding: [0; 3],
+            },
+        );
+        for instruction in [new_order_instruction, swap_instruction] {
+            let err = sign_send_instructions(
+                &mut prg_test_ctx,
+                vec![instruction],
+                vec![&user_account_owner],
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(
+                err.unwrap(),
+                TransactionError::InstructionError(
+                    0,
+                    InstructionError::Custom(DexError::InvalidReferralAccount as u32)
+                )
+            );
+        }
+    }
+
+    // Discount token accounts are checked up front, they must hold SRM or MSRM and belong to the user wallet
+    let other_owner_srm_account =
+        create_associated_token(&mut prg_test_ctx, &srm_mint, &Pubkey::new_unique())
+            .await
+            .unwrap();
+    for (discount_token_account, error) in [
+        (user_quote_token_account, DexError::InvalidDiscountTokenMint),
+        (other_owner_srm_account, DexError::InvalidDiscountTokenOwner),
+    ] {
+        let new_order_instruction = new_order(
+            dex_program_id,
+            new_order::Accounts {
+                spl_token_program: &spl_token::ID,
+                system_program: &system_program::ID,
+                market: &market_account.pubkey(),
+                orderbook: &aaob_accounts.market,
+                event_queue: &aaob_market_state.event_queue,
+                bids: &aaob_market_state.bids,
+                asks: &aaob_market_state.asks,
+                base_vault: &base_vault,
+                quote_vault: &quote_vault,
+                base_mint: &base_mint_key,
+                quote_mint: &quote_mint_key,
+                market_signer: &market_signer,
+                user: &user_account,
+                user_token_account: &user_base_token_account,
+                user_owner: &user_account_owner.pubkey(),
+                discount_token_account: Some(&discount_token_account),
+                fee_referral_account: None,
+                referrer: None,
+            },
+            new_order::Params {
+                #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+                client_order_id: 0,
+                #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+                client_order_id: bytemuck::cast(0u128),
+                side: asset_agnostic_orderbook::state::Side::Ask as u8,
+                limit_price: 1_000 * aaob_market_state.tick_size,
+                max_base_qty: 1_000,
+                max_quote_qty: u64::MAX,
+                order_type: new_order::OrderType::Limit as u8,
+                self_trade_behavior:
+                    asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake as u8,
+                match_limit: 10,
+                has_discount_token_account: true as u8,
+                fok_fallback_ioc: 0,
+                reduce_only: 0,
+                duplicate_client_id_policy: 0,
+                _padding: [0; 1],
+            },
+        );
+        let swap_instruction = swap(
+            dex_program_id,
+            swap::Accounts {
+                spl_token_program: &spl_token::ID,
+                system_program: &system_program::ID,
+                market: &market_account.pubkey(),
+                orderbook: &aaob_accounts.market,
+                event_queue: &aaob_market_state.event_queue,
+                bids: &aaob_market_state.bids,
+                asks: &aaob_market_state.asks,
+                base_vault: &base_vault,
+                quote_vault: &quote_vault,
+                base_mint: &base_mint_key,
+                quote_mint: &quote_mint_key,
+                market_signer: &market_signer,
+                user_base_account: &user_base_token_account,
+                user_quote_account: &user_quote_token_account,
+                user_owner: &user_account_owner.pubkey(),
+                discount_token_account: Some(&discount_token_account),
+                fee_referral_account: None,
+                referrer: None,
+            },
+            swap::Params {
+                side: asset_agnostic_orderbook::state::Side::Bid as u8,
+                base_qty: 1_000,
+                quote_qty: 2_000_000,
+                match_limit: 10,
+                has_discount_token_account: 1,
+                max_slippage_bps: 0,
+                exact_out: 0,
+                _padding: [0; 3],
+            },
+        );
+        for instruction in [new_order_instruction, swap_instruction] {
+            let err = sign_send_instructions(
+                &mut prg_test_ctx,
+                vec![instruction],
+                vec![&user_account_owner],
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(
+                err.unwrap(),
+                TransactionError::InstructionError(0, InstructionError::Custom(error as u32))
+            );
+        }
+    }
+
+    // Out of range enum values should be rejected without panicking
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            bids: &aaob_market_state.bids,
+            asks: &aaob_market_state.asks,
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user: &user_account,
+            user_token_account: &user_quote_token_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id: 7,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(7u128),
+            side: asset_agnostic_orderbook::state::Side::Bid as u8,
+            limit_price: 9 * aaob_market_state.tick_size,
+            max_base_qty: 1_000,
+            max_quote_qty: u64::MAX,
+            order_type: 42,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(0, InstructionError::InvalidInstructionData)
+    );
+    let swap_instruction = swap(
+        dex_program_id,
+        swap::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            bids: &aaob_market_state.bids,
+            asks: &aaob_market_state.asks,
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user_base_account: &user_base_token_account,
+            user_quote_account: &user_quote_token_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        swap::Params {
+            side: 42,
+            base_qty: 1_000,
+            quote_qty: 2_000_000,
+            match_limit: 10,
+            has_discount_token_account: 0,
+            max_slippage_bps: 0,
+            exact_out: 0,
+            _padding: [0; 3],
+        },
+    );
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![swap_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(0, InstructionError::InvalidInstructionData)
+    );
+
+    // Swaps whose average price is more than 1% away from the last price should be rejected
+    let swap_price = (((matched_quote_qty as u128) << 32) / 1_000) as u64;
+    let mut market_account_data = prg_test_ctx
+        .banks_client
+        .get_account(market_account.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    let market_state: &mut DexState =
+        try_from_bytes_mut(&mut market_account_data.data[..DEX_STATE_LEN]).unwrap();
+    market_state.max_deviation_bps = 100;
+    market_state.last_price = swap_price * 100 / 101 - 1;
+    prg_test_ctx.set_account(
+        &market_account.pubkey(),
+        &market_account_data.clone().into(),
+    );
+    let swap_instruction = swap(
+        dex_program_id,
+        swap::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            bids: &aaob_market_state.bids,
+            asks: &aaob_market_state.asks,
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user_base_account: &user_base_token_account,
+            user_quote_account: &user_quote_token_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        swap::Params {
+            side: asset_agnostic_orderbook::state::Side::Bid as u8,
+            base_qty: 1_000,
+            quote_qty: 2_000_000,
+            match_limit: 10,
+            has_discount_token_account: 0,
+            max_slippage_bps: 0,
+            exact_out: 0,
+            _padding: [0; 3],
+        },
+    );
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![swap_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::PriceDeviationExceeded as u32)
+        )
+    );
+
+    // Swap, matching, takes 1000 units @ 9 ticks, a zero match limit falls back to the default.
+    // Its average price is just inside the band, and the referrer gets the market's custom cut of the taker fee.
+    let market_state: &mut DexState =
+        try_from_bytes_mut(&mut market_account_data.data[..DEX_STATE_LEN]).unwrap();
+    market_state.last_price = (swap_price * 100 + 100) / 101;
+    prg_test_ctx.set_account(&market_account.pubkey(), &market_account_data.into());
+    let new_order_instruction = swap(
+        dex_program_id,
+        swap::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            bids: &aaob_market_state.bids,
+            asks: &aaob_market_state.asks,
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user_base_account: &user_base_token_account,
+            user_quote_account: &user_quote_token_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: Some(&base_mint_auth_token_account),
+            referrer: None,
+        },
+        swap::Params {
+            side: asset_agnostic_orderbook::state::Side::Bid as u8,
+            base_qty: 1_000,
+            quote_qty: 2_000_000,
+            match_limit: 0,
+            has_discount_token_account: 0,
+            max_slippage_bps: 0,
+            exact_out: 0,
+            _padding: [0; 3],
+        },
+    );
+    let referrer_balance_before =
+        get_token_balance(&mut prg_test_ctx, &base_mint_auth_token_account).await;
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+    let mut market_account_data = prg_test_ctx
+        .banks_client
+        .get_account(market_account.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    let market_state: &mut DexState =
+        try_from_bytes_mut(&mut market_account_data.data[..DEX_STATE_LEN]).unwrap();
+    market_state.max_deviation_bps = 0;
+    market_state.last_price = 0;
+    prg_test_ctx.set_account(&market_account.pubkey(), &market_account_data.into());
+    let market_state = get_market_state(&mut prg_test_ctx, &market_account.pubkey()).await;
+    assert_eq!(market_state.referral_bps, 5_000);
+    let swap_referral_fee = FeeTier::Base.referral_fee(&market_state, matched_quote_qty);
+    assert!(
+        swap_referral_fee > FeeTier::Base.taker_fee_floor(&market_state, matched_quote_qty) / 5
+    );
+    assert_eq!(
+        get_token_balance(&mut prg_test_ctx, &base_mint_auth_token_account).await
+            - referrer_balance_before,
+        swap_referral_fee
+    );
+
+    // The swap royalties should be accounted for in the market state
+    let market_state = get_market_state(&mut prg_test_ctx, &market_account.pubkey()).await;
+    assert_eq!(
+        market_state.accumulated_royalties,
+        2 * matched_quote_qty * market_state.royalties_bps / 10_000
+    );
+
+    // A FillOrKill order which can only be partially filled should abort,
+    // unless the IOC fallback is enabled
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            bids: &aaob_market_state.bids,
+            asks: &aaob_market_state.asks,
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user: &user_account,
+            user_token_account: &user_base_token_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id: 2,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(2u128),
+            side: asset_agnostic_orderbook::state::Side::Ask as u8,
+            limit_price: 9 * aaob_market_state.tick_size,
+            max_base_qty: 1_000,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::Limit as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+
+    for fok_fallback_ioc in [0, 1] {
+        let new_order_instruction = new_order(
+            dex_program_id,
+            new_order::Accounts {
+                spl_token_program: &spl_token::ID,
+                system_program: &system_program::ID,
+                market: &market_account.pubkey(),
+                orderbook: &aaob_accounts.market,
+                event_queue: &aaob_market_state.event_queue,
+                bids: &aaob_market_state.bids,
+                asks: &aaob_market_state.asks,
+                base_vault: &base_vault,
+                quote_vault: &quote_vault,
+                base_mint: &base_mint_key,
+                quote_mint: &quote_mint_key,
+                market_signer: &market_signer,
+                user: &user_account,
+                user_token_account: &user_quote_token_account,
+                user_owner: &user_account_owner.pubkey(),
+                discount_token_account: None,
+                fee_referral_account: None,
+                referrer: None,
+            },
+            new_order::Params {
+                #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+                client_order_id: 0,
+                #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+                client_order_id: bytemuck::cast(0u128),
+                side: asset_agnostic_orderbook::state::Side::Bid as u8,
+                limit_price: 11 * aaob_market_state.tick_size,
+                max_base_qty: 2_000,
+                max_quote_qty: u64::MAX,
+                order_type: new_order::OrderType::FillOrKill as u8,
+                self_trade_behavior:
+                    asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake as u8,
+                match_limit: 10,
+                has_discount_token_account: false as u8,
+                fok_fallback_ioc,
+                reduce_only: 0,
+                duplicate_client_id_policy: 0,
+                _padding: [0; 1],
+            },
+        );
+        let res = sign_send_instructions(
+            &mut prg_test_ctx,
+            vec![new_order_instruction],
+            vec![&user_account_owner],
+        )
+        .await;
+        if fok_fallback_ioc == 0 {
+            assert_eq!(
+                res.unwrap_err().unwrap(),
+                TransactionError::InstructionError(
+                    0,
+                    InstructionError::Custom(DexError::TransactionAborted as u32)
+                )
+            );
+        } else {
+            res.unwrap();
+        }
+    }
+    let user_account_header = get_user_account_header(&mut prg_test_ctx, &user_account).await;
+    assert_eq!(user_account_header.base_token_free, 1_000);
+
+    // New Order, resting bid to be matched by an ask taker, an absurd match limit is capped
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            bids: &aaob_market_state.bids,
+            asks: &aaob_market_state.asks,
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user: &user_account,
+            user_token_account: &user_quote_token_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id: 3,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(3u128),
+            side: asset_agnostic_orderbook::state::Side::Bid as u8,
+            limit_price: 8 * aaob_market_state.tick_size,
+            max_base_qty: 1_000,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::Limit as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: u64::MAX,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+
+    // New Order, ask taker matching the resting bid, a zero match limit falls back to the default
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            bids: &aaob_market_state.bids,
+            asks: &aaob_market_state.asks,
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user: &user_account,
+            user_token_account: &user_base_token_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id: 4,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(4u128),
+            side: asset_agnostic_orderbook::state::Side::Ask as u8,
+            limit_price: 8 * aaob_market_state.tick_size,
+            max_base_qty: 1_000,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::ImmediateOrCancel as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 0,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+
+    // Sweep royalties
+    let ix = sweep_royalties(
+        dex_program_id,
+        sweep_royalties::Accounts {
+            market: &market_account.pubkey(),
+            market_signer: &market_signer,
+            quote_vault: &quote_vault,
+            quote_mint: &quote_mint_key,
+            spl_token_program: &spl_token::ID,
+            creators_token_accounts: &[user_quote_token_account, base_mint_auth_token_account],
+        },
+        sweep_royalties::Params {
+            skip_missing_creators: 0,
+        },
+    );
+    sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![])
+        .await
+        .unwrap();
+
+    // All royalties should have been distributed to the creators
+    let market_state = get_market_state(&mut prg_test_ctx, &market_account.pubkey()).await;
+    assert_eq!(market_state.accumulated_royalties, 0);
+
+    let user_account_header_before_crank =
+        get_user_account_header(&mut prg_test_ctx, &user_account).await;
+
+    // Consume Events, the queue head should advance by the number of consumed events
+    let (head_before, count_before) =
+        get_event_queue_position(&mut prg_test_ctx, &aaob_market_state.event_queue).await;
+    let consume_events_instruction = consume_events(
+        dex_program_id,
+        consume_events::Accounts {
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            reward_target: &reward_target.pubkey(),
+            candle_history: None,
+            user_accounts: &[user_account],
+        },
+        consume_events::Params {
+            max_iterations: 2,
+            no_op_err: 1,
+            has_candle_history: 0,
+            skip_missing: 0,
+            _padding: [0; 7],
+        },
+    );
+    let mut data_logs = sign_send_instructions_with_data_logs(
+        &mut prg_test_ctx,
+        vec![consume_events_instruction],
+        vec![],
+    )
+    .await
+    .unwrap();
+    let (head, count) =
+        get_event_queue_position(&mut prg_test_ctx, &aaob_market_state.event_queue).await;
+    assert_eq!(head, (head_before + 2) % EVENT_QUEUE_CAPACITY as u64);
+    assert_eq!(count, count_before - 2);
+
+    let consume_events_instruction = consume_events(
+        dex_program_id,
+        consume_events::Accounts {
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            reward_target: &reward_target.pubkey(),
+            candle_history: None,
+            user_accounts: &[user_account],
+        },
+        consume_events::Params {
+            max_iterations: 11,
+            no_op_err: 1,
+            has_candle_history: 0,
+            skip_missing: 0,
+            _padding: [0; 7],
+        },
+    );
+    data_logs.extend(
+        sign_send_instructions_with_data_logs(
+            &mut prg_test_ctx,
+            vec![consume_events_instruction],
+            vec![],
+        )
+        .await
+        .unwrap(),
+    );
+    let (head, count) =
+        get_event_queue_position(&mut prg_test_ctx, &aaob_market_state.event_queue).await;
+    assert_eq!(
+        head,
+        (head_before + count_before) % EVENT_QUEUE_CAPACITY as u64
+    );
+    assert_eq!(count, 0);
+    let fill_logs = data_logs
+        .iter()
+        .map(|fields| {
+            assert_eq!(fields.len(), 1);
+            bytemuck::pod_read_unaligned::<FillLog>(&fields[0])
+        })
+        .collect::<Vec<_>>();
+
+    // Makers should be credited the quote of the bid taker fills, as well as all maker rebates,
+    // including the ones for ask taker fills
+    let user_account_header = get_user_account_header(&mut prg_test_ctx, &user_account).await;
+    assert_eq!(
+        user_account_header.quote_token_free - user_account_header_before_crank.quote_token_free,
+        3 * matched_quote_qty + user_account_header.accumulated_rebates
+            - user_account_header_before_crank.accumulated_rebates
+    );
+
+    // Makers should earn rebates, which are deducted from the accumulated fees along with the referral fees
+    let ask_taker_quote_qty =
+        (((1_000u128 * (8 * aaob_market_state.tick_size) as u128) >> 32) as u64) * 10_000;
+    let fill_quote_qtys = [
+        matched_quote_qty,
+        matched_quote_qty,
+        matched_quote_qty,
+        ask_taker_quote_qty,
+    ];
+    let market_state = get_market_state(&mut prg_test_ctx, &market_account.pubkey()).await;
+    let fill_fees = fill_quote_qtys
+        .iter()
+        .enumerate()
+        .map(|(i, q)| {
+            let taker_fee = FeeTier::Base.taker_fee_floor(&market_state, *q);
+            let referral_fee = if i == 0 { swap_referral_fee } else { 0 };
+            let maker_rebate = FeeTier::Base.maker_rebate(&market_state, *q);
+            (taker_fee - maker_rebate - referral_fee, maker_rebate)
+        })
+        .collect::<Vec<_>>();
+    let expected_rebates: u64 = fill_fees.iter().map(|(_, r)| r).sum();
+    let expected_fees: u64 = fill_fees.iter().map(|(f, _)| f).sum();
+    assert_ne!(expected_rebates, 0);
+    assert_eq!(
+        user_account_header.accumulated_rebates
+            - user_account_header_before_crank.accumulated_rebates,
+        expected_rebates
+    );
+    assert_eq!(market_state.accumulated_fees, expected_fees);
+
+    // A fill log is emitted for each fill event
+    let mut logged_quote_sizes = fill_logs.iter().map(|l| l.quote_size).collect::<Vec<_>>();
+    let mut expected_quote_sizes = fill_quote_qtys.to_vec();
+    logged_quote_sizes.sort_unstable();
+    expected_quote_sizes.sort_unstable();
+    assert_eq!(logged_quote_sizes, expected_quote_sizes);
+    let ask_fill_log = fill_logs
+        .iter()
+        .find(|l| l.taker_side == asset_agnostic_orderbook::state::Side::Ask as u8)
+        .unwrap();
+    assert_eq!(
+        ask_fill_log,
+        &FillLog {
+            version: FILL_LOG_VERSION,
+            taker_side: asset_agnostic_orderbook::state::Side::Ask as u8,
+            _padding: [0; 6],
+            maker: user_account,
+            taker: ask_fill_log.taker,
+            price: 8 * aaob_market_state.tick_size,
+            base_size: 1_000,
+            quote_size: ask_taker_quote_qty,
+            taker_fee: FeeTier::Base.taker_fee_floor(&market_state, ask_taker_quote_qty),
+            maker_rebate: FeeTier::Base.maker_rebate(&market_state, ask_taker_quote_qty),
+        }
+    );
+
+    // Sweep part of the fees, leaving the remainder on the market
+    let partial_sweep_amount = expected_fees / 2;
+    let sweep_balance_before = get_token_balance(&mut prg_test_ctx, &sweep_fees_ata).await;
+    let ix = sweep_fees(
+        dex_program_id,
+        sweep_fees::Accounts {
+            market: &market_account.pubkey(),
+            market_signer: &market_signer,
+            quote_vault: &quote_vault,
+            quote_mint: &quote_mint_key,
+            destination_token_account: &sweep_fees_ata,
+            spl_token_program: &spl_token::ID,
+        },
+        sweep_fees::Params {
+            amount: partial_sweep_amount,
+        },
+    );
+    sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![])
+        .await
+        .unwrap();
+
+    let market_state = get_market_state(&mut prg_test_ctx, &market_account.pubkey()).await;
+    assert_eq!(
+        market_state.accumulated_fees,
+        expected_fees - partial_sweep_amount
+    );
+    assert_eq!(
+        get_token_balance(&mut prg_test_ctx, &sweep_fees_ata).await - sweep_balance_before,
+        partial_sweep_amount
+    );
+
+    // Change royalties_bps
+    // Only the market admin can update the royalties
+    let ix = update_royalties(
+        dex_program_id,
+        update_royalties::Accounts {
+            market: &market_account.pubkey(),
+            event_queue: &aaob_accounts.event_queue,
+            token_metadata: &find_metadata_account(&base_mint_key).0,
+            orderbook: &aaob_accounts.market,
+            market_admin: &user_account_owner.pubkey(),
+        },
+        update_royalties::Params {},
+    );
+    let err = sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![&user_account_owner])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::InvalidMarketAdminAccount as u32)
+        )
+    );
+
+    let ix = update_royalties(
+        dex_program_id,
+        update_royalties::Accounts {
+            market: &market_account.pubkey(),
+            event_queue: &aaob_accounts.event_queue,
+            token_metadata: &find_metadata_account(&base_mint_key).0,
+            orderbook: &aaob_accounts.market,
+            market_admin: &market_admin.pubkey(),
+        },
+        update_royalties::Params {},
+    );
+    sign_send_instructions(&mut prg_test_ctx, vec![ix.clone()], vec![&market_admin])
+        .await
+        .unwrap();
+
+    // The creators are cached in the market state
+    let market_state = get_market_state(&mut prg_test_ctx, &market_account.pubkey()).await;
+    assert_eq!(market_state.number_of_creators, 2);
+    assert_eq!(
+        market_state.creators[..2],
+        [user_account_owner.pubkey(), base_mint_auth.pubkey()]
+    );
+    assert_eq!(market_state.creator_shares[..2], [50, 50]);
+
+    // The royalties injected by the sweep tests below are minted to the quote vault, so that they don't eat into the
+    // user balances
+    let ix = mint_to(
+        &spl_token::ID,
+        &quote_mint_key,
+        &quote_vault,
+        &quote_mint_auth.pubkey(),
+        &[],
+        3_001,
+    )
+    .unwrap();
+    sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![&quote_mint_auth])
+        .await
+        .unwrap();
+
+    // Once the metadata creators are updated and resynced, the royalties are swept to the new split
+    let mut market_account_data = prg_test_ctx
+        .banks_client
+        .get_account(market_account.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    let market_state: &mut DexState =
+        try_from_bytes_mut(&mut market_account_data.data[..DEX_STATE_LEN]).unwrap();
+    market_state.accumulated_royalties = 1_000;
+    prg_test_ctx.set_account(&market_account.pubkey(), &market_account_data.into());
+    let update_metadata_instruction = mpl_token_metadata::instruction::update_metadata_accounts_v2(
+        mpl_token_metadata::ID,
+        metadata_account_key,
+        base_mint_auth.pubkey(),
+        None,
+        Some(DataV2 {
+            name: "".to_string(),
+            symbol: "".to_string(),
+            uri: "".to_string(),
+            seller_fee_basis_points: 5_000,
+            creators: Some(vec![
+                Creator {
+                    address: user_account_owner.pubkey(),
+                    verified: true,
+                    share: 20,
+                },
+                Creator {
+                    address: base_mint_auth.pubkey(),
+                    verified: false,
+                    share: 80,
+                },
+            ]),
+            collection: None,
+            uses: None,
+        }),
+        None,
+        None,
+    );
+    let sweep_royalties_instruction = sweep_royalties(
+        dex_program_id,
+        sweep_royalties::Accounts {
+            market: &market_account.pubkey(),
+            market_signer: &market_signer,
+            quote_vault: &quote_vault,
+            quote_mint: &quote_mint_key,
+            spl_token_program: &spl_token::ID,
+            creators_token_accounts: &[user_quote_token_account, base_mint_auth_token_account],
+        },
+        sweep_royalties::Params {
+            skip_missing_creators: 0,
+        },
+    );
+    let user_quote_balance_before =
+        get_token_balance(&mut prg_test_ctx, &user_quote_token_account).await;
+    let base_mint_auth_balance_before =
+        get_token_balance(&mut prg_test_ctx, &base_mint_auth_token_account).await;
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![update_metadata_instruction, ix, sweep_royalties_instruction],
+        vec![&base_mint_auth, &market_admin],
+    )
+    .await
+    .unwrap();
+    let market_state = get_market_state(&mut prg_test_ctx, &market_account.pubkey()).await;
+    assert_eq!(market_state.creator_shares[..2], [20, 80]);
+    assert_eq!(market_state.accumulated_royalties, 0);
+    assert_eq!(
+        get_token_balance(&mut prg_test_ctx, &user_quote_token_account).await
+            - user_quote_balance_before,
+        200
+    );
+    assert_eq!(
+        get_token_balance(&mut prg_test_ctx, &base_mint_auth_token_account).await
+            - base_mint_auth_balance_before,
+        800
+    );
+
+    // A missing creator token account fails the sweep, unless the missing creators are skipped,
+    // in which case what they are owed stays on the market
+    let mut market_account_data = prg_test_ctx
+        .banks_client
+        .get_account(market_account.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    let market_state: &mut DexState =
+        try_from_bytes_mut(&mut market_account_data.data[..DEX_STATE_LEN]).unwrap();
+    market_state.accumulated_royalties = 1_000;
+    prg_test_ctx.set_account(&market_account.pubkey(), &market_account_data.into());
+    let user_quote_balance_before =
+        get_token_balance(&mut prg_test_ctx, &user_quote_token_account).await;
+    for skip_missing_creators in [0, 1] {
+        let ix = sweep_royalties(
+            dex_program_id,
+            sweep_royalties::Accounts {
+                market: &market_account.pubkey(),
+                market_signer: &market_signer,
+                quote_vault: &quote_vault,
+                quote_mint: &quote_mint_key,
+                spl_token_program: &spl_token::ID,
+                creators_token_accounts: &[user_quote_token_account],
+            },
+            sweep_royalties::Params {
+                skip_missing_creators,
+            },
+        );
+        let res = sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![]).await;
+        if skip_missing_creators == 0 {
+            assert_eq!(
+                res.unwrap_err().unwrap(),
+                TransactionError::InstructionError(
+                    0,
+                    InstructionError::Custom(DexError::MissingCreatorTokenAccount as u32)
+                )
+            );
+        } else {
+            res.unwrap();
+        }
+    }
+    let market_state = get_market_state(&mut prg_test_ctx, &market_account.pubkey()).await;
+    assert_eq!(market_state.accumulated_royalties, 800);
+    assert_eq!(market_state.unpaid_royalties[..2], [0, 800]);
+    assert_eq!(
+        get_token_balance(&mut prg_test_ctx, &user_quote_token_account).await
+            - user_quote_balance_before,
+        200
+    );
+
+    // The skipped creator is paid what it is owed by a later sweep, the other creators getting nothing more
+    let base_mint_auth_balance_before =
+        get_token_balance(&mut prg_test_ctx, &base_mint_auth_token_account).await;
+    let ix = sweep_royalties(
+        dex_program_id,
+        sweep_royalties::Accounts {
+            market: &market_account.pubkey(),
+            market_signer: &market_signer,
+            quote_vault: &quote_vault,
+            quote_mint: &quote_mint_key,
+            spl_token_program: &spl_token::ID,
+            creators_token_accounts: &[user_quote_token_account, base_mint_auth_token_account],
+        },
+        sweep_royalties::Params {
+            skip_missing_creators: 1,
+        },
+    );
+    sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![])
+        .await
+        .unwrap();
+    let market_state = get_market_state(&mut prg_test_ctx, &market_account.pubkey()).await;
+    assert_eq!(market_state.accumulated_royalties, 0);
+    assert_eq!(market_state.unpaid_royalties[..2], [0, 0]);
+    assert_eq!(
+        get_token_balance(&mut prg_test_ctx, &user_quote_token_account).await
+            - user_quote_balance_before,
+        200
+    );
+    assert_eq!(
+        get_token_balance(&mut prg_test_ctx, &base_mint_auth_token_account).await
+            - base_mint_auth_balance_before,
+        800
+    );
+
+    // The rounding dust of an uneven split goes to the creator with the highest share
+    let third_creator = Keypair::new();
+    let third_creator_token_account =
+        create_associated_token(&mut prg_test_ctx, &quote_mint_key, &third_creator.pubkey())
+            .await
+            .unwrap();
+    let mut market_account_data = prg_test_ctx
+        .banks_client
+        .get_account(market_account.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    let market_state: &mut DexState =
+        try_from_bytes_mut(&mut market_account_data.data[..DEX_STATE_LEN]).unwrap();
+    market_state.accumulated_royalties = 1_001;
+    prg_test_ctx.set_account(&market_account.pubkey(), &market_account_data.into());
+    let update_metadata_instruction = mpl_token_metadata::instruction::update_metadata_accounts_v2(
+        mpl_token_metadata::ID,
+        metadata_account_key,
+        base_mint_auth.pubkey(),
+        None,
+        Some(DataV2 {
+            name: "".to_string(),
+            symbol: "".to_string(),
+            uri: "".to_string(),
+            seller_fee_basis_points: 5_000,
+            creators: Some(vec![
+                Creator {
+                    address: user_account_owner.pubkey(),
+                    verified: true,
+                    share: 33,
+                },
+                Creator {
+                    address: base_mint_auth.pubkey(),
+                    verified: false,
+                    share: 33,
+                },
+                Creator {
+                    address: third_creator.pubkey(),
+                    verified: false,
+                    share: 34,
+                },
+            ]),
+            collection: None,
+            uses: None,
+        }),
+        None,
+        None,
+    );
+    let update_royalties_instruction = update_royalties(
+        dex_program_id,
+        update_royalties::Accounts {
+            market: &market_account.pubkey(),
+            event_queue: &aaob_accounts.event_queue,
+            token_metadata: &find_metadata_account(&base_mint_key).0,
+            orderbook: &aaob_accounts.market,
+            market_admin: &market_admin.pubkey(),
+        },
+        update_royalties::Params {},
+    );
+    let sweep_royalties_instruction = sweep_royalties(
+        dex_program_id,
+        sweep_royalties::Accounts {
+            market: &market_account.pubkey(),
+            market_signer: &market_signer,
+            quote_vault: &quote_vault,
+            quote_mint: &quote_mint_key,
+            spl_token_program: &spl_token::ID,
+            creators_token_accounts: &[
+                user_quote_token_account,
+                base_mint_auth_token_account,
+                third_creator_token_account,
+            ],
+        },
+        sweep_royalties::Params {
+            skip_missing_creators: 0,
+        },
+    );
+    let user_quote_balance_before =
+        get_token_balance(&mut prg_test_ctx, &user_quote_token_account).await;
+    let base_mint_auth_balance_before =
+        get_token_balance(&mut prg_test_ctx, &base_mint_auth_token_account).await;
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![
+            update_metadata_instruction,
+            update_royalties_instruction,
+            sweep_royalties_instruction,
+        ],
+        vec![&base_mint_auth, &market_admin],
+    )
+    .await
+    .unwrap();
+    let market_state = get_market_state(&mut prg_test_ctx, &market_account.pubkey()).await;
+    assert_eq!(market_state.creator_shares[..3], [33, 33, 34]);
+    assert_eq!(market_state.accumulated_royalties, 0);
+    assert_eq!(
+        get_token_balance(&mut prg_test_ctx, &user_quote_token_account).await
+            - user_quote_balance_before,
+        330
+    );
+    assert_eq!(
+        get_token_balance(&mut prg_test_ctx, &base_mint_auth_token_account).await
+            - base_mint_auth_balance_before,
+        330
+    );
+    assert_eq!(
+        get_token_balance(&mut prg_test_ctx, &third_creator_token_account).await,
+        341
+    );
+
+    // A taker bid on a market with royalties is never charged more than its max_quote_qty
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            bids: &aaob_market_state.bids,
+            asks: &aaob_market_state.asks,
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user: &user_account,
+            user_token_account: &user_base_token_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id: 8,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(8u128),
+            side: asset_agnostic_orderbook::state::Side::Ask as u8,
+            limit_price: 9 * aaob_market_state.tick_size,
+            max_base_qty: 1_000,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::Limit as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+    let max_quote_qty =
+        (((1_000u128 * (9 * aaob_market_state.tick_size) as u128) >> 32) as u64) * 10_000 / 2;
+    let user_account_header_before_bid =
+        get_user_account_header(&mut prg_test_ctx, &user_account).await;
+    let user_quote_balance_before =
+        get_token_balance(&mut prg_test_ctx, &user_quote_token_account).await;
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            bids: &aaob_market_state.bids,
+            asks: &aaob_market_state.asks,
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user: &user_account,
+            user_token_account: &user_quote_token_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id: 9,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(9u128),
+            side: asset_agnostic_orderbook::state::Side::Bid as u8,
+            limit_price: 9 * aaob_market_state.tick_size,
+            max_base_qty: 1_000,
+            max_quote_qty: max_quote_qty,
+            order_type: new_order::OrderType::ImmediateOrCancel as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+    let user_account_header = get_user_account_header(&mut prg_test_ctx, &user_account).await;
+    let charged_quote_qty = user_quote_balance_before
+        - get_token_balance(&mut prg_test_ctx, &user_quote_token_account).await
+        + user_account_header_before_bid.quote_token_free
+        - user_account_header.quote_token_free;
+    assert!(charged_quote_qty > 0);
+    assert!(charged_quote_qty <= max_quote_qty);
+    let cancel_order_instruction = cancel_order(
+        dex_program_id,
+        cancel_order::Accounts {
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            bids: &aaob_market_state.bids,
+            asks: &aaob_market_state.asks,
+            user: &user_account,
+            user_owner: &user_account_owner.pubkey(),
+        },
+        cancel_order::Params {
+            order_index: 0,
+            order_id: 8,
+            is_client_id: true,
+            _padding: [0u8; 7],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![cancel_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+
+    // consume_events should not error when no_op_err == 0
+    let consume_events_instruction = consume_events(
+        dex_program_id,
+        consume_events::Accounts {
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            reward_target: &reward_target.pubkey(),
+            candle_history: None,
+            user_accounts: &[user_account],
+        },
+        consume_events::Params {
+            max_iterations: 10,
+            no_op_err: 0,
+            has_candle_history: 0,
+            skip_missing: 0,
+            _padding: [0; 7],
+        },
+    );
+    sign_send_instructions(&mut prg_test_ctx, vec![consume_events_instruction], vec![])
+        .await
+        .unwrap();
+
+    // Closing the market within the grace window after the last order should fail
+    let close_market_instruction = close_market(
+        dex_program_id,
+        dex_v4::instruction_auto::close_market::Accounts {
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            market_admin: &market_admin.pubkey(),
+            event_queue: &aaob_accounts.event_queue,
+            asks: &aaob_accounts.asks,
+            bids: &aaob_accounts.bids,
+            target_lamports_account: &Pubkey::new_unique(),
+            market_signer: &market_signer,
+            spl_token_program: &spl_token::ID,
+        },
+        close_market::Params {},
+    );
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![close_market_instruction],
+        vec![&market_admin],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::CloseGracePeriodNotElapsed as u32)
+        )
+    );
+
+    // Once all events are consumed, the market accounts for the quote balances of its user accounts
+    let (_, pending_events) =
+        get_event_queue_position(&mut prg_test_ctx, &aaob_market_state.event_queue).await;
+    assert_eq!(pending_events, 0);
+    let market_state = get_market_state(&mut prg_test_ctx, &market_account.pubkey()).await;
+    let user_account_header = get_user_account_header(&mut prg_test_ctx, &user_account).await;
+    assert!(market_state.total_quote_backing > 0);
+    assert_eq!(
+        market_state.total_quote_backing,
+        user_account_header.quote_token_free + user_account_header.quote_token_locked
+    );
+
+    // Sweeping fees which would be taken out of the user balances should be refused, even when the vault covers them
+    let vault_balance = get_token_balance(&mut prg_test_ctx, &quote_vault).await;
+    let vault_surplus = vault_balance
+        - market_state.accumulated_fees
+        - market_state.accumulated_royalties
+        - market_state.total_quote_backing;
+    assert!(vault_surplus + 1 < market_state.total_quote_backing);
+    let mut market_account_data = prg_test_ctx
+        .banks_client
+        .get_account(market_account.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    let market_state: &mut DexState =
+        try_from_bytes_mut(&mut market_account_data.data[..DEX_STATE_LEN]).unwrap();
+    let accumulated_fees = market_state.accumulated_fees;
+    market_state.accumulated_fees += vault_surplus + 1;
+    assert!(market_state.accumulated_fees + market_state.accumulated_royalties <= vault_balance);
+    prg_test_ctx.set_account(
+        &market_account.pubkey(),
+        &market_account_data.clone().into(),
+    );
+    let ix = sweep_fees(
+        dex_program_id,
+        sweep_fees::Accounts {
+            market: &market_account.pubkey(),
+            market_signer: &market_signer,
+            quote_vault: &quote_vault,
+            quote_mint: &quote_mint_key,
+            destination_token_account: &sweep_fees_ata,
+            spl_token_program: &spl_token::ID,
+        },
+        sweep_fees::Params { amount: 1 },
+    );
+    let err = sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::InsufficientVaultBalance as u32)
+        )
+    );
+    let market_state: &mut DexState =
+        try_from_bytes_mut(&mut market_account_data.data[..DEX_STATE_LEN]).unwrap();
+    market_state.accumulated_fees = accumulated_fees;
+    prg_test_ctx.set_account(&market_account.pubkey(), &market_account_data.into());
+
+    // Sweeping fees which exceed the quote vault balance should be refused
+    let mut market_account_data = prg_test_ctx
+        .banks_client
+        .get_account(market_account.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    let market_state: &mut DexState =
+        try_from_bytes_mut(&mut market_account_data.data[..DEX_STATE_LEN]).unwrap();
+    market_state.accumulated_fees = u32::MAX as u64;
+    prg_test_ctx.set_account(&market_account.pubkey(), &market_account_data.into());
+    let ix = sweep_fees(
+        dex_program_id,
+        sweep_fees::Accounts {
+            market: &market_account.pubkey(),
+            market_signer: &market_signer,
+            quote_vault: &quote_vault,
+            quote_mint: &quote_mint_key,
+            destination_token_account: &sweep_fees_ata,
+            spl_token_program: &spl_token::ID,
+        },
+        sweep_fees::Params { amount: 0 },
+    );
+    let err = sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::InsufficientVaultBalance as u32)
+        )
+    );
+
+    // Nominate a new market admin
+    let new_market_admin = Keypair::new();
+    let ix = nominate_admin(
+        dex_program_id,
+        nominate_admin::Accounts {
+            market: &market_account.pubkey(),
+            market_admin: &market_admin.pubkey(),
+            new_admin: &new_market_admin.pubkey(),
+        },
+        nominate_admin::Params {},
+    );
+    sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![&market_admin])
+        .await
+        .unwrap();
+
+    // Only the nominated admin can accept the role
+    let ix = accept_admin(
+        dex_program_id,
+        accept_admin::Accounts {
+            market: &market_account.pubkey(),
+            new_admin: &user_account_owner.pubkey(),
+        },
+        accept_admin::Params {},
+    );
+    let err = sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![&user_account_owner])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::InvalidMarketAdminAccount as u32)
+        )
+    );
+
+    let ix = accept_admin(
+        dex_program_id,
+        accept_admin::Accounts {
+            market: &market_account.pubkey(),
+            new_admin: &new_market_admin.pubkey(),
+        },
+        accept_admin::Params {},
+    );
+    sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![&new_market_admin])
+        .await
+        .unwrap();
+
+    let market_state = get_market_state(&mut prg_test_ctx, &market_account.pubkey()).await;
+    assert_eq!(market_state.admin, new_market_admin.pubkey());
+    assert_eq!(market_state.pending_admin, Pubkey::default());
+
+    // Closing a market with a non-empty base vault should fail
+    let mut base_vault_account = prg_test_ctx
+        .banks_client
+        .get_account(other_base_vault)
+        .await
+        .unwrap()
+        .unwrap();
+    let mut base_vault_data = spl_token::state::Account::unpack(&base_vault_account.data).unwrap();
+    base_vault_data.amount = 1;
+    spl_token::state::Account::pack(base_vault_data, &mut base_vault_account.data).unwrap();
+    prg_test_ctx.set_account(&other_base_vault, &base_vault_account.clone().into());
+    let close_market_instruction = close_market(
+        dex_program_id,
+        dex_v4::instruction_auto::close_market::Accounts {
+            base_vault: &other_base_vault,
+            quote_vault: &other_quote_vault,
+            market: &other_market_account.pubkey(),
+            orderbook: &other_aaob_accounts.market,
+            market_admin: &market_admin.pubkey(),
+            event_queue: &other_aaob_accounts.event_queue,
+            asks: &other_aaob_accounts.asks,
+            bids: &other_aaob_accounts.bids,
+            target_lamports_account: &Pubkey::new_unique(),
+            market_signer: &other_market_signer,
+            spl_token_program: &spl_token::ID,
+        },
+        close_market::Params {},
+    );
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![close_market_instruction],
+        vec![&market_admin],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::MarketStillActive as u32)
+        )
+    );
+
+    base_vault_data.amount = 0;
+    spl_token::state::Account::pack(base_vault_data, &mut base_vault_account.data).unwrap();
+    prg_test_ctx.set_account(&other_base_vault, &base_vault_account.into());
+
+    // Closing a market with undistributed royalties should fail until they are swept
+    let royalties = 100;
+    let ix = spl_token::instruction::transfer(
+        &spl_token::ID,
+        &user_quote_token_account,
+        &other_quote_vault,
+        &user_account_owner.pubkey(),
+        &[],
+        royalties,
+    )
+    .unwrap();
+    sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![&user_account_owner])
+        .await
+        .unwrap();
+    let mut other_market_account_data = prg_test_ctx
+        .banks_client
+        .get_account(other_market_account.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    let other_market_state: &mut DexState =
+        try_from_bytes_mut(&mut other_market_account_data.data[..DEX_STATE_LEN]).unwrap();
+    other_market_state.accumulated_royalties = royalties;
+    prg_test_ctx.set_account(
+        &other_market_account.pubkey(),
+        &other_market_account_data.into(),
+    );
+    let close_market_instruction = close_market(
+        dex_program_id,
+        dex_v4::instruction_auto::close_market::Accounts {
+            base_vault: &other_base_vault,
+            quote_vault: &other_quote_vault,
+            market: &other_market_account.pubkey(),
+            orderbook: &other_aaob_accounts.market,
+            market_admin: &market_admin.pubkey(),
+            event_queue: &other_aaob_accounts.event_queue,
+            asks: &other_aaob_accounts.asks,
+            bids: &other_aaob_accounts.bids,
+            target_lamports_account: &Pubkey::new_unique(),
+            market_signer: &other_market_signer,
+            spl_token_program: &spl_token::ID,
+        },
+        close_market::Params {},
+    );
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![close_market_instruction],
+        vec![&market_admin],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::MarketStillActive as u32)
+        )
+    );
+
+    let ix = sweep_royalties(
+        dex_program_id,
+        sweep_royalties::Accounts {
+            market: &other_market_account.pubkey(),
+            market_signer: &other_market_signer,
+            quote_vault: &other_quote_vault,
+            quote_mint: &quote_mint_key,
+            spl_token_program: &spl_token::ID,
+            creators_token_accounts: &[user_quote_token_account, base_mint_auth_token_account],
+        },
+        sweep_royalties::Params {
+            skip_missing_creators: 0,
+        },
+    );
+    sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![])
+        .await
+        .unwrap();
+    let other_market_state =
+        get_market_state(&mut prg_test_ctx, &other_market_account.pubkey()).await;
+    assert_eq!(other_market_state.accumulated_royalties, 0);
+
+    // Closing a market with empty vaults and no pending royalties should succeed
+    let close_market_instruction = close_market(
+        dex_program_id,
+        dex_v4::instruction_auto::close_market::Accounts {
+            base_vault: &other_base_vault,
+            quote_vault: &other_quote_vault,
+            market: &other_market_account.pubkey(),
+            orderbook: &other_aaob_accounts.market,
+            market_admin: &market_admin.pubkey(),
+            event_queue: &other_aaob_accounts.event_queue,
+            asks: &other_aaob_accounts.asks,
+            bids: &other_aaob_accounts.bids,
+            target_lamports_account: &Pubkey::new_unique(),
+            market_signer: &other_market_signer,
+            spl_token_program: &spl_token::ID,
+        },
+        close_market::Params {},
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![close_market_instruction],
+        vec![&market_admin],
+    )
+    .await
+    .unwrap();
+
+    // The market admin can force close a market with residual fees and royalties, which are swept to its own quote
+    // token account, as long as the vaults hold no user funds
+    let emergency_market_account = Keypair::new();
+    let create_market_account_instruction = create_account(
+        &prg_test_ctx.payer.pubkey(),
+        &emergency_market_account.pubkey(),
+        market_rent,
+        DEX_STATE_LEN as u64,
+        &dex_program_id,
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![create_market_account_instruction],
+        vec![&emergency_market_account],
+    )
+    .await
+    .unwrap();
+    let (emergency_market_signer, emergency_signer_nonce) = Pubkey::find_program_address(
+        &[&emergency_market_account.pubkey().to_bytes()],
+        &dex_program_id,
+    );
+    let emergency_aaob_accounts =
+        create_aob_market_and_accounts(&mut prg_test_ctx, dex_program_id).await;
+    let emergency_base_vault =
+        create_associated_token(&mut prg_test_ctx, &base_mint_key, &emergency_market_signer)
+            .await
+            .unwrap();
+    let emergency_quote_vault =
+        create_associated_token(&mut prg_test_ctx, &quote_mint_key, &emergency_market_signer)
+            .await
+            .unwrap();
+    let create_market_instruction = create_market(
+        dex_program_id,
+        dex_v4::instruction_auto::create_market::Accounts {
+            base_vault: &emergency_base_vault,
+            quote_vault: &emergency_quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market: &emergency_market_account.pubkey(),
+            orderbook: &emergency_aaob_accounts.market,
+            market_admin: &market_admin.pubkey(),
+            event_queue: &emergency_aaob_accounts.event_queue,
+            asks: &emergency_aaob_accounts.asks,
+            bids: &emergency_aaob_accounts.bids,
+            token_metadata: &find_metadata_account(&base_mint_key).0,
+        },
+        create_market::Params {
+            signer_nonce: emergency_signer_nonce as u64,
+            min_base_order_size: 1,
+            tick_size: 42949672,
+            base_currency_multiplier: 1,
+            quote_currency_multiplier: 10000,
+            close_grace_slots: 0,
+            fee_tier_thresholds: [0; 5],
+            fee_tier_taker_rates: [0; 8],
+            fee_tier_maker_rebate_rates: [0; 8],
+            sweep_authority: Pubkey::default(),
+            min_quote_order_size: 0,
+            max_deviation_bps: 0,
+            referral_bps: 0,
+            fee_model: 0,
+            fee_type: 0,
+            name: [0; 16],
+            ticker: [0; 8],
+            flat_taker_bps: 0,
+            promo_end_ts: 0,
+        },
+    );
+    sign_send_instructions(&mut prg_test_ctx, vec![create_market_instruction], vec![])
+        .await
+        .unwrap();
+    let admin_quote_account =
+        create_associated_token(&mut prg_test_ctx, &quote_mint_key, &market_admin.pubkey())
+            .await
+            .unwrap();
+
+    // The vault holds the residual fees and royalties, along with a single unit of user funds
+    let (residual_fees, residual_royalties) = (300, 200);
+    let ix = spl_token::instruction::transfer(
+        &spl_token::ID,
+        &user_quote_token_account,
+        &emergency_quote_vault,
+        &user_account_owner.pubkey(),
+        &[],
+        residual_fees + residual_royalties + 1,
+    )
+    .unwrap();
+    sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![&user_account_owner])
+        .await
+        .unwrap();
+    let mut emergency_market_account_data = prg_test_ctx
+        .banks_client
+        .get_account(emergency_market_account.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    let emergency_market_state: &mut DexState =
+        try_from_bytes_mut(&mut emergency_market_account_data.data[..DEX_STATE_LEN]).unwrap();
+    emergency_market_state.accumulated_fees = residual_fees;
+    emergency_market_state.accumulated_royalties = residual_royalties;
+    prg_test_ctx.set_account(
+        &emergency_market_account.pubkey(),
+        &emergency_market_account_data.into(),
+    );
+
+    let emergency_force_close_market = |market_admin: &Pubkey, target_lamports_account: &Pubkey| {
+        force_close_market(
+            dex_program_id,
+            force_close_market::Accounts {
+                market: &emergency_market_account.pubkey(),
+                base_vault: &emergency_base_vault,
+                quote_vault: &emergency_quote_vault,
+                quote_mint: &quote_mint_key,
+                orderbook: &emergency_aaob_accounts.market,
+                event_queue: &emergency_aaob_accounts.event_queue,
+                bids: &emergency_aaob_accounts.bids,
+                asks: &emergency_aaob_accounts.asks,
+                market_admin,
+                admin_quote_account: &admin_quote_account,
+                target_lamports_account,
+                market_signer: &emergency_market_signer,
+                spl_token_program: &spl_token::ID,
+            },
+            force_close_market::Params {},
+        )
+    };
+    // Only the market admin can force close the market
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![emergency_force_close_market(
+            &user_account_owner.pubkey(),
+            &Pubkey::new_unique(),
+        )],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::InvalidMarketAdminAccount as u32)
+        )
+    );
+    // The user funds left in the quote vault once the fees and royalties are swept prevent the closing
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![emergency_force_close_market(
+            &market_admin.pubkey(),
+            &Pubkey::new_unique(),
+        )],
+        vec![&market_admin],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::MarketStillActive as u32)
+        )
+    );
+
+    let mut quote_vault_account = prg_test_ctx
+        .banks_client
+        .get_account(emergency_quote_vault)
+        .await
+        .unwrap()
+        .unwrap();
+    let mut quote_vault_data =
+        spl_token::state::Account::unpack(&quote_vault_account.data).unwrap();
+    quote_vault_data.amount = residual_fees + residual_royalties;
+    spl_token::state::Account::pack(quote_vault_data, &mut quote_vault_account.data).unwrap();
+    prg_test_ctx.set_account(&emergency_quote_vault, &quote_vault_account.into());
+    let target_lamports_account = Pubkey::new_unique();
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![emergency_force_close_market(
+            &market_admin.pubkey(),
+            &target_lamports_account,
+        )],
+        vec![&market_admin],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        get_token_balance(&mut prg_test_ctx, &admin_quote_account).await,
+        residual_fees + residual_royalties
+    );
+    assert!(prg_test_ctx
+        .banks_client
+        .get_account(emergency_market_account.pubkey())
+        .await
+        .unwrap()
+        .is_none());
+    assert!(
+        prg_test_ctx
+            .banks_client
+            .get_balance(target_lamports_account)
+            .await
+            .unwrap()
+            >= market_rent
+    );
+
+    // Fill the user account to capacity
+    let user_account_header = get_user_account_header(&mut prg_test_ctx, &user_account).await;
+    let mut client_order_id = 100u128;
+    for _ in user_account_header.number_of_orders..10 {
+        let new_order_instruction = new_order(
+            dex_program_id,
+            new_order::Accounts {
+                spl_token_program: &spl_token::ID,
+                system_program: &system_program::ID,
+                market: &market_account.pubkey(),
+                orderbook: &aaob_accounts.market,
+                event_queue: &aaob_market_state.event_queue,
+                bids: &aaob_market_state.bids,
+                asks: &aaob_market_state.asks,
+                base_vault: &base_vault,
+                quote_vault: &quote_vault,
+                base_mint: &base_mint_key,
+                quote_mint: &quote_mint_key,
+                market_signer: &market_signer,
+                user: &user_account,
+                user_token_account: &user_base_token_account,
+                user_owner: &user_account_owner.pubkey(),
+                discount_token_account: None,
+                fee_referral_account: None,
+                referrer: None,
+            },
+            new_order::Params {
+                #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+                client_order_id: client_order_id,
+                #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+                client_order_id: bytemuck::cast(client_order_id),
+                side: asset_agnostic_orderbook::state::Side::Ask as u8,
+                limit_price: 1_000 * aaob_market_state.tick_size,
+                max_base_qty: 1_000,
+                max_quote_qty: u64::MAX,
+                order_type: new_order::OrderType::PostOnly as u8,
+                self_trade_behavior:
+                    asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake as u8,
+                match_limit: 10,
+                has_discount_token_account: false as u8,
+                fok_fallback_ioc: 0,
+                reduce_only: 0,
+                duplicate_client_id_policy: 0,
+                _padding: [0; 1],
+            },
+        );
+        sign_send_instructions(
+            &mut prg_test_ctx,
+            vec![new_order_instruction],
+            vec![&user_account_owner],
+        )
+        .await
+        .unwrap();
+        client_order_id += 1;
+    }
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            bids: &aaob_market_state.bids,
+            asks: &aaob_market_state.asks,
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user: &user_account,
+            user_token_account: &user_base_token_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id: client_order_id,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(client_order_id),
+            side: asset_agnostic_orderbook::state::Side::Ask as u8,
+            limit_price: 1_000 * aaob_market_state.tick_size,
+            max_base_qty: 1_000,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::PostOnly as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::UserAccountFull as u32)
+        )
+    );
+
+    // Grow the user account, the existing orders should be preserved
+    let user_account_data_before = prg_test_ctx
+        .banks_client
+        .get_account(user_account)
+        .await
+        .unwrap()
+        .unwrap()
+        .data;
+    let ix = resize_user_account(
+        dex_program_id,
+        resize_user_account::Accounts {
+            system_program: &system_program::ID,
+            user: &user_account,
+            user_owner: &user_account_owner.pubkey(),
+            fee_payer: &prg_test_ctx.payer.pubkey(),
+        },
+        resize_user_account::Params { new_max_orders: 20 },
+    );
+    sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![&user_account_owner])
+        .await
+        .unwrap();
+    let user_account_data = prg_test_ctx
+        .banks_client
+        .get_account(user_account)
+        .await
+        .unwrap()
+        .unwrap()
+        .data;
+    assert_eq!(
+        user_account_data.len(),
+        UserAccount::compute_allocation_size(20).unwrap()
+    );
+    assert_eq!(
+        &user_account_data[..user_account_data_before.len()],
+        &user_account_data_before[..]
+    );
+
+    // The resized user account can hold more orders
+    client_order_id += 1;
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            bids: &aaob_market_state.bids,
+            asks: &aaob_market_state.asks,
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user: &user_account,
+            user_token_account: &user_base_token_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id: client_order_id,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(client_order_id),
+            side: asset_agnostic_orderbook::state::Side::Ask as u8,
+            limit_price: 1_000 * aaob_market_state.tick_size,
+            max_base_qty: 1_000,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::PostOnly as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+    let user_account_header = get_user_account_header(&mut prg_test_ctx, &user_account).await;
+    assert_eq!(user_account_header.number_of_orders, 11);
+
+    // Set a delegate for the user account
+    let delegate = Keypair::new();
+    let ix = set_delegate(
+        dex_program_id,
+        set_delegate::Accounts {
+            user: &user_account,
+            user_owner: &user_account_owner.pubkey(),
+        },
+        set_delegate::Params {
+            delegate: delegate.pubkey(),
+        },
+    );
+    sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![&user_account_owner])
+        .await
+        .unwrap();
+    let delegate_base_token_account =
+        create_associated_token(&mut prg_test_ctx, &base_mint_key, &delegate.pubkey())
+            .await
+            .unwrap();
+    let ix = mint_to(
+        &spl_token::ID,
+        &base_mint_key,
+        &delegate_base_token_account,
+        &base_mint_auth.pubkey(),
+        &[],
+        1_000,
+    )
+    .unwrap();
+    sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![&base_mint_auth])
+        .await
+        .unwrap();
+
+    // The delegate can place orders on behalf of the owner
+    client_order_id += 1;
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            bids: &aaob_market_state.bids,
+            asks: &aaob_market_state.asks,
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user: &user_account,
+            user_token_account: &delegate_base_token_account,
+            user_owner: &delegate.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(client_order_id),
+            side: asset_agnostic_orderbook::state::Side::Ask as u8,
+            limit_price: 1_000 * aaob_market_state.tick_size,
+            max_base_qty: 1_000,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::PostOnly as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&delegate],
+    )
+    .await
+    .unwrap();
+    let user_account_header = get_user_account_header(&mut prg_test_ctx, &user_account).await;
+    assert_eq!(user_account_header.number_of_orders, 12);
+
+    // The delegate cannot settle the owner's funds
+    let settle_instruction = settle(
+        dex_program_id,
+        settle::Accounts {
+            spl_token_program: &spl_token::ID,
+            market: &market_account.pubkey(),
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user: &user_account,
+            user_owner: &delegate.pubkey(),
+            destination_base_account: &delegate_base_token_account,
+            destination_quote_account: &delegate_base_token_account,
+        },
+        settle::Params {
+            base_amount: 0,
+            quote_amount: 0,
+            unwrap_sol: 0,
+            _padding: [0; 7],
+        },
+    );
+    let err = sign_send_instructions(&mut prg_test_ctx, vec![settle_instruction], vec![&delegate])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(0, InstructionError::InvalidArgument)
+    );
+
+    // A post only bid above the best ask should be refused with a dedicated error
+    client_order_id += 1;
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            bids: &aaob_market_state.bids,
+            asks: &aaob_market_state.asks,
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user: &user_account,
+            user_token_account: &user_quote_token_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(client_order_id),
+            side: asset_agnostic_orderbook::state::Side::Bid as u8,
+            limit_price: 2_000 * aaob_market_state.tick_size,
+            max_base_qty: 500,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::PostOnly as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::PostOnlyWouldCross as u32)
+        )
+    );
+
+    // Flush the event queue
+    let consume_events_instruction = consume_events(
+        dex_program_id,
+        consume_events::Accounts {
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            reward_target: &reward_target.pubkey(),
+            candle_history: None,
+            user_accounts: &[user_account],
+        },
+        consume_events::Params {
+            max_iterations: 10,
+            no_op_err: 0,
+            has_candle_history: 0,
+            skip_missing: 0,
+            _padding: [0; 7],
+        },
+    );
+    sign_send_instructions(&mut prg_test_ctx, vec![consume_events_instruction], vec![])
+        .await
+        .unwrap();
+
+    // A resting bid, matched by an ask taker
+    for (side, order_type, user_token_account) in [
+        (
+            asset_agnostic_orderbook::state::Side::Bid,
+            new_order::OrderType::PostOnly,
+            user_quote_token_account,
+        ),
+        (
+            asset_agnostic_orderbook::state::Side::Ask,
+            new_order::OrderType::ImmediateOrCancel,
+            user_base_token_account,
+        ),
+    ] {
+        client_order_id += 1;
+        let new_order_instruction = new_order(
+            dex_program_id,
+            new_order::Accounts {
+                spl_token_program: &spl_token::ID,
+                system_program: &system_program::ID,
+                market: &market_account.pubkey(),
+                orderbook: &aaob_accounts.market,
+                event_queue: &aaob_market_state.event_queue,
+                bids: &aaob_market_state.bids,
+                asks: &aaob_market_state.asks,
+                base_vault: &base_vault,
+                quote_vault: &quote_vault,
+                base_mint: &base_mint_key,
+                quote_mint: &quote_mint_key,
+                market_signer: &market_signer,
+                user: &user_account,
+                user_token_account: &user_token_account,
+                user_owner: &user_account_owner.pubkey(),
+                discount_token_account: None,
+                fee_referral_account: None,
+                referrer: None,
+            },
+            new_order::Params {
+                #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+                client_order_id,
+                #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+                client_order_id: bytemuck::cast(client_order_id),
+                side: side as u8,
+                limit_price: aaob_market_state.tick_size,
+                max_base_qty: 500,
+                max_quote_qty: u64::MAX,
+                order_type: order_type as u8,
+                self_trade_behavior:
+                    asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake as u8,
+                match_limit: 10,
+                has_discount_token_account: false as u8,
+                fok_fallback_ioc: 0,
+                reduce_only: 0,
+                duplicate_client_id_policy: 0,
+                _padding: [0; 1],
+            },
+        );
+        sign_send_instructions(
+            &mut prg_test_ctx,
+            vec![new_order_instruction],
+            vec![&user_account_owner],
+        )
+        .await
+        .unwrap();
+    }
+
+    let user_account_header_before_crank =
+        get_user_account_header(&mut prg_test_ctx, &user_account).await;
+    let base_balance_before = get_token_balance(&mut prg_test_ctx, &user_base_token_account).await;
+    let quote_balance_before =
+        get_token_balance(&mut prg_test_ctx, &user_quote_token_account).await;
+    let delegate_balance_before =
+        get_token_balance(&mut prg_test_ctx, &delegate_base_token_account).await;
+
+    // Consume the fill and settle the maker in a single transaction,
+    // the user is skipped when its destination token accounts don't belong to its owner
+    let ix = consume_events_and_settle(
+        dex_program_id,
+        consume_events_and_settle::Accounts {
+            spl_token_program: &spl_token::ID,
+            market: &market_account.pubkey(),
+            orderbook: &aaob_accounts.market,
+            event_queue: &aaob_market_state.event_queue,
+            reward_target: &reward_target.pubkey(),
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user_accounts: &[
+                user_account,
+                user_account,
+                delegate_base_token_account,
+                delegate_base_token_account,
+                user_account,
+                user_base_token_account,
+                user_quote_token_account,
+            ],
+        },
+        consume_events_and_settle::Params {
+            max_iterations: 10,
+            no_op_err: 1,
+            number_of_settled_users: 2,
+        },
+    );
+    sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![])
+        .await
+        .unwrap();
+
+    // The maker directly receives the base of its filled bid, along with all of its free funds
+    let user_account_header = get_user_account_header(&mut prg_test_ctx, &user_account).await;
+    assert_eq!(user_account_header.base_token_free, 0);
+    assert_eq!(user_account_header.quote_token_free, 0);
+    assert_eq!(
+        get_token_balance(&mut prg_test_ctx, &user_base_token_account).await - base_balance_before,
+        user_account_header_before_crank.base_token_free + 500
+    );
+    assert_eq!(
+        get_token_balance(&mut prg_test_ctx, &user_quote_token_account).await
+            - quote_balance_before,
+        user_account_header_before_crank.quote_token_free + user_account_header.accumulated_rebates
+            - user_account_header_before_crank.accumulated_rebates
+    );
+    assert_eq!(
+        get_token_balance(&mut prg_test_ctx, &delegate_base_token_account).await,
+        delegate_balance_before
+    );
+
+    // A market quoted in wrapped SOL
+    let wsol_market_account = Keypair::new();
+    let create_market_account_instruction = create_account(
+        &prg_test_ctx.payer.pubkey(),
+        &wsol_market_account.pubkey(),
+        market_rent,
+        DEX_STATE_LEN as u64,
+        &dex_program_id,
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![create_market_account_instruction],
+        vec![&wsol_market_account],
+    )
+    .await
+    .unwrap();
+    let (wsol_market_signer, wsol_signer_nonce) =
+        Pubkey::find_program_address(&[&wsol_market_account.pubkey().to_bytes()], &dex_program_id);
+    let wsol_aaob_accounts =
+        create_aob_market_and_accounts(&mut prg_test_ctx, dex_program_id).await;
+    let wsol_base_vault =
+        create_associated_token(&mut prg_test_ctx, &base_mint_key, &wsol_market_signer)
+            .await
+            .unwrap();
+    let wsol_quote_vault = create_associated_token(
+        &mut prg_test_ctx,
+        &spl_token::native_mint::ID,
+        &wsol_market_signer,
+    )
+    .await
+    .unwrap();
+
+    // The metadata account must be derived from the base mint
+    #[cfg(not(feature = "disable-mpl-checks"))]
+    {
+        let wrong_metadata_instruction = create_market(
+            dex_program_id,
+            dex_v4::instruction_auto::create_market::Accounts {
+                base_vault: &wsol_base_vault,
+                quote_vault: &wsol_quote_vault,
+                base_mint: &base_mint_key,
+                quote_mint: &spl_token::native_mint::ID,
+                market: &wsol_market_account.pubkey(),
+                orderbook: &wsol_aaob_accounts.market,
+                market_admin: &market_admin.pubkey(),
+                event_queue: &wsol_aaob_accounts.event_queue,
+                asks: &wsol_aaob_accounts.asks,
+                bids: &wsol_aaob_accounts.bids,
+                token_metadata: &find_metadata_account(&spl_token::native_mint::ID).0,
+            },
+            create_market::Params {
+                signer_nonce: wsol_signer_nonce as u64,
+                min_base_order_size: 1,
+                tick_size: 42949672,
+                base_currency_multiplier: 1,
+                quote_currency_multiplier: 10000,
+                close_grace_slots: 0,
+                fee_tier_thresholds: [0; 5],
+                fee_tier_taker_rates: [0; 8],
+                fee_tier_maker_rebate_rates: [0; 8],
+                sweep_authority: Pubkey::default(),
+                min_quote_order_size: 0,
+                max_deviation_bps: 0,
+                referral_bps: 0,
+                fee_model: 0,
+                fee_type: 0,
+                name: [0; 16],
+                ticker: [0; 8],
+                flat_taker_bps: 0,
+                promo_end_ts: 0,
+            },
+        );
+        let err =
+            sign_send_instructions(&mut prg_test_ctx, vec![wrong_metadata_instruction], vec![])
+                .await
+                .unwrap_err()
+                .unwrap();
+        assert_eq!(
+            err,
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(DexError::InvalidMetadataKey as u32)
+            )
+        );
+        if let TransactionError::InstructionError(_, InstructionError::Custom(code)) = err {
+            assert!(matches!(
+                DexError::from_u32(code),
+                Some(DexError::InvalidMetadataKey)
+            ));
+        }
+    }
+    let create_market_instruction = create_market(
+        dex_program_id,
+        dex_v4::instruction_auto::create_market::Accounts {
+            base_vault: &wsol_base_vault,
+            quote_vault: &wsol_quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &spl_token::native_mint::ID,
+            market: &wsol_market_account.pubkey(),
+            orderbook: &wsol_aaob_accounts.market,
+            market_admin: &market_admin.pubkey(),
+            event_queue: &wsol_aaob_accounts.event_queue,
+            asks: &wsol_aaob_accounts.asks,
+            bids: &wsol_aaob_accounts.bids,
+            token_metadata: &find_metadata_account(&base_mint_key).0,
+        },
+        create_market::Params {
+            signer_nonce: wsol_signer_nonce as u64,
+            min_base_order_size: 1,
+            tick_size: 42949672,
+            base_currency_multiplier: 1,
+            quote_currency_multiplier: 10000,
+            close_grace_slots: 0,
+            fee_tier_thresholds: [0; 5],
+            fee_tier_taker_rates: [0; 8],
+            fee_tier_maker_rebate_rates: [0; 8],
+            sweep_authority: Pubkey::default(),
+            min_quote_order_size: 0,
+            max_deviation_bps: 0,
+            referral_bps: 0,
+            fee_model: 0,
+            fee_type: 0,
+            name: [0; 16],
+            ticker: [0; 8],
+            flat_taker_bps: 0,
+            promo_end_ts: 0,
+        },
+    );
+    sign_send_instructions(&mut prg_test_ctx, vec![create_market_instruction], vec![])
+        .await
+        .unwrap();
+    let (wsol_user_account, _) = Pubkey::find_program_address(
+        &[
+            &wsol_market_account.pubkey().to_bytes(),
+            &user_account_owner.pubkey().to_bytes(),
+        ],
+        &dex_program_id,
+    );
+    let create_user_account_instruction = initialize_account(
+        dex_program_id,
+        initialize_account::Accounts {
+            system_program: &system_program::ID,
+            user: &wsol_user_account,
+            user_owner: &user_account_owner.pubkey(),
+            fee_payer: &prg_test_ctx.payer.pubkey(),
+        },
+        initialize_account::Params {
+            market: wsol_market_account.pubkey(),
+            max_orders: 10,
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![create_user_account_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+
+    // Credit the user with free wrapped SOL, held by the quote vault
+    let settled_lamports = 1_000_000;
+    let ixs = vec![
+        solana_program::system_instruction::transfer(
+            &prg_test_ctx.payer.pubkey(),
+            &wsol_quote_vault,
+            settled_lamports,
+        ),
+        spl_token::instruction::sync_native(&spl_token::ID, &wsol_quote_vault).unwrap(),
+    ];
+    sign_send_instructions(&mut prg_test_ctx, ixs, vec![])
+        .await
+        .unwrap();
+    let mut wsol_user_account_data = prg_test_ctx
+        .banks_client
+        .get_account(wsol_user_account)
+        .await
+        .unwrap()
+        .unwrap();
+    let wsol_user_account_header: &mut UserAccountHeader =
+        try_from_bytes_mut(&mut wsol_user_account_data.data[..USER_ACCOUNT_HEADER_LEN]).unwrap();
+    wsol_user_account_header.quote_token_free = settled_lamports;
+    prg_test_ctx.set_account(&wsol_user_account, &wsol_user_account_data.into());
+    let mut wsol_market_account_data = prg_test_ctx
+        .banks_client
+        .get_account(wsol_market_account.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    let wsol_market_state: &mut DexState =
+        try_from_bytes_mut(&mut wsol_market_account_data.data[..DEX_STATE_LEN]).unwrap();
+    wsol_market_state.total_quote_backing += settled_lamports;
+    prg_test_ctx.set_account(
+        &wsol_market_account.pubkey(),
+        &wsol_market_account_data.into(),
+    );
+
+    // Unwrapping requires a wrapped SOL destination
+    let settle_instruction = settle(
+        dex_program_id,
+        settle::Accounts {
+            spl_token_program: &spl_token::ID,
+            market: &market_account.pubkey(),
+            base_vault: &base_vault,
+            quote_vault: &quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &quote_mint_key,
+            market_signer: &market_signer,
+            user: &user_account,
+            user_owner: &user_account_owner.pubkey(),
+            destination_base_account: &user_base_token_account,
+            destination_quote_account: &user_quote_token_account,
+        },
+        settle::Params {
+            base_amount: 0,
+            quote_amount: 0,
+            unwrap_sol: 1,
+            _padding: [0; 7],
+        },
+    );
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![settle_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::NonNativeMint as u32)
+        )
+    );
+
+    // Settle to a temporary wrapped SOL account, which is closed to the owner's wallet
+    let temporary_wsol_account = create_associated_token(
+        &mut prg_test_ctx,
+        &spl_token::native_mint::ID,
+        &user_account_owner.pubkey(),
+    )
+    .await
+    .unwrap();
+    let temporary_wsol_rent = prg_test_ctx
+        .banks_client
+        .get_account(temporary_wsol_account)
+        .await
+        .unwrap()
+        .unwrap()
+        .lamports;
+
+    // Partial settles cannot exceed the free balance
+    let partial_settle_instruction = |quote_amount| {
+        settle(
+            dex_program_id,
+            settle::Accounts {
+                spl_token_program: &spl_token::ID,
+                market: &wsol_market_account.pubkey(),
+                base_vault: &wsol_base_vault,
+                quote_vault: &wsol_quote_vault,
+                base_mint: &base_mint_key,
+                quote_mint: &spl_token::native_mint::ID,
+                market_signer: &wsol_market_signer,
+                user: &wsol_user_account,
+                user_owner: &user_account_owner.pubkey(),
+                destination_base_account: &user_base_token_account,
+                destination_quote_account: &temporary_wsol_account,
+            },
+            settle::Params {
+                base_amount: 0,
+                quote_amount,
+                unwrap_sol: 0,
+                _padding: [0; 7],
+            },
+        )
+    };
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![partial_settle_instruction(settled_lamports + 1)],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::InsufficientFreeBalance as u32)
+        )
+    );
+
+    // A partial settle leaves the rest of the free balance in the user account
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![partial_settle_instruction(settled_lamports / 4)],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+    let wsol_user_account_header =
+        get_user_account_header(&mut prg_test_ctx, &wsol_user_account).await;
+    assert_eq!(
+        wsol_user_account_header.quote_token_free,
+        settled_lamports - settled_lamports / 4
+    );
+    assert_eq!(
+        get_token_balance(&mut prg_test_ctx, &temporary_wsol_account).await,
+        settled_lamports / 4
+    );
+
+    let owner_lamports_before = prg_test_ctx
+        .banks_client
+        .get_balance(user_account_owner.pubkey())
+        .await
+        .unwrap();
+    let settle_instruction = settle(
+        dex_program_id,
+        settle::Accounts {
+            spl_token_program: &spl_token::ID,
+            market: &wsol_market_account.pubkey(),
+            base_vault: &wsol_base_vault,
+            quote_vault: &wsol_quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &spl_token::native_mint::ID,
+            market_signer: &wsol_market_signer,
+            user: &wsol_user_account,
+            user_owner: &user_account_owner.pubkey(),
+            destination_base_account: &user_base_token_account,
+            destination_quote_account: &temporary_wsol_account,
+        },
+        settle::Params {
+            base_amount: 0,
+            quote_amount: 0,
+            unwrap_sol: 1,
+            _padding: [0; 7],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![settle_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+    let owner_lamports = prg_test_ctx
+        .banks_client
+        .get_balance(user_account_owner.pubkey())
+        .await
+        .unwrap();
+    assert_eq!(
+        owner_lamports - owner_lamports_before,
+        settled_lamports + temporary_wsol_rent
+    );
+    assert!(prg_test_ctx
+        .banks_client
+        .get_account(temporary_wsol_account)
+        .await
+        .unwrap()
+        .is_none());
+    let wsol_user_account_header =
+        get_user_account_header(&mut prg_test_ctx, &wsol_user_account).await;
+    assert_eq!(wsol_user_account_header.quote_token_free, 0);
+
+    // Post a ladder of asks in a single instruction
+    let tick_size = 42949672;
+    let mut ladder = [batch_new_order::BatchOrder {
+        limit_price: 0,
+        max_base_qty: 0,
+        side: asset_agnostic_orderbook::state::Side::Ask as u8,
+        _padding: [0; 7],
+    }; batch_new_order::MAX_BATCH_ORDERS];
+    for (i, order) in ladder.iter_mut().take(5).enumerate() {
+        order.limit_price = (1000 + 100 * i as u64) * tick_size;
+        order.max_base_qty = 1000;
+    }
+    let base_balance_before = get_token_balance(&mut prg_test_ctx, &user_base_token_account).await;
+    let batch_new_order_instruction = batch_new_order(
+        dex_program_id,
+        batch_new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &wsol_market_account.pubkey(),
+            orderbook: &wsol_aaob_accounts.market,
+            event_queue: &wsol_aaob_accounts.event_queue,
+            bids: &wsol_aaob_accounts.bids,
+            asks: &wsol_aaob_accounts.asks,
+            base_vault: &wsol_base_vault,
+            quote_vault: &wsol_quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &spl_token::native_mint::ID,
+            market_signer: &wsol_market_signer,
+            user: &wsol_user_account,
+            user_base_account: &user_base_token_account,
+            user_quote_account: &user_quote_token_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        batch_new_order::Params {
+            orders: ladder,
+            match_limit: 10,
+            number_of_orders: 5,
+            post_only: 1,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            has_discount_token_account: 0,
+            _padding: [0; 4],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![batch_new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        base_balance_before - get_token_balance(&mut prg_test_ctx, &user_base_token_account).await,
+        5000
+    );
+    let mut wsol_user_account_data = prg_test_ctx
+        .banks_client
+        .get_account(wsol_user_account)
+        .await
+        .unwrap()
+        .unwrap()
+        .data;
+    let wsol_user_account_state = UserAccount::from_buffer(&mut wsol_user_account_data).unwrap();
+    assert_eq!(wsol_user_account_state.header.number_of_orders, 5);
+    assert_eq!(wsol_user_account_state.header.base_token_locked, 5000);
+    for (i, order) in ladder.iter().take(5).enumerate() {
+        let posted_order = wsol_user_account_state.read_order(i).unwrap();
+        assert_eq!((posted_order.id >> 64) as u64, order.limit_price);
+    }
+
+    // A reduce only ask is trimmed to the base balance of the user account
+    client_order_id += 1;
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &wsol_market_account.pubkey(),
+            orderbook: &wsol_aaob_accounts.market,
+            event_queue: &wsol_aaob_accounts.event_queue,
+            bids: &wsol_aaob_accounts.bids,
+            asks: &wsol_aaob_accounts.asks,
+            base_vault: &wsol_base_vault,
+            quote_vault: &wsol_quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &spl_token::native_mint::ID,
+            market_signer: &wsol_market_signer,
+            user: &wsol_user_account,
+            user_token_account: &user_base_token_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(client_order_id),
+            side: asset_agnostic_orderbook::state::Side::Ask as u8,
+            limit_price: 2_000 * tick_size,
+            max_base_qty: 8_000,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::PostOnly as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 1,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    let base_balance_before = get_token_balance(&mut prg_test_ctx, &user_base_token_account).await;
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        base_balance_before - get_token_balance(&mut prg_test_ctx, &user_base_token_account).await,
+        5_000
+    );
+    let wsol_user_account_header =
+        get_user_account_header(&mut prg_test_ctx, &wsol_user_account).await;
+    assert_eq!(wsol_user_account_header.number_of_orders, 6);
+    assert_eq!(wsol_user_account_header.base_token_locked, 10_000);
+
+    // A bid matching the user's own asks with the AbortTransaction behavior reverts the order
+    let wsol_token_account = Keypair::new();
+    let ixs = vec![
+        create_account(
+            &prg_test_ctx.payer.pubkey(),
+            &wsol_token_account.pubkey(),
+            rent.minimum_balance(spl_token::state::Account::LEN),
+            spl_token::state::Account::LEN as u64,
+            &spl_token::ID,
+        ),
+        spl_token::instruction::initialize_account(
+            &spl_token::ID,
+            &wsol_token_account.pubkey(),
+            &spl_token::native_mint::ID,
+            &user_account_owner.pubkey(),
+        )
+        .unwrap(),
+    ];
+    sign_send_instructions(&mut prg_test_ctx, ixs, vec![&wsol_token_account])
+        .await
+        .unwrap();
+    client_order_id += 1;
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &wsol_market_account.pubkey(),
+            orderbook: &wsol_aaob_accounts.market,
+            event_queue: &wsol_aaob_accounts.event_queue,
+            bids: &wsol_aaob_accounts.bids,
+            asks: &wsol_aaob_accounts.asks,
+            base_vault: &wsol_base_vault,
+            quote_vault: &wsol_quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &spl_token::native_mint::ID,
+            market_signer: &wsol_market_signer,
+            user: &wsol_user_account,
+            user_token_account: &wsol_token_account.pubkey(),
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(client_order_id),
+            side: asset_agnostic_orderbook::state::Side::Bid as u8,
+            limit_price: 1_500 * tick_size,
+            max_base_qty: 1_000,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::Limit as u8,
+            self_trade_behavior:
+                asset_agnostic_orderbook::state::SelfTradeBehavior::AbortTransaction as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    let wsol_user_account_data_before = prg_test_ctx
+        .banks_client
+        .get_account(wsol_user_account)
+        .await
+        .unwrap()
+        .unwrap()
+        .data;
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::TransactionAborted as u32)
+        )
+    );
+    let wsol_user_account_data_after = prg_test_ctx
+        .banks_client
+        .get_account(wsol_user_account)
+        .await
+        .unwrap()
+        .unwrap()
+        .data;
+    assert_eq!(wsol_user_account_data_before, wsol_user_account_data_after);
+    assert_eq!(
+        get_token_balance(&mut prg_test_ctx, &user_base_token_account).await,
+        base_balance_before - 5_000
+    );
+
+    // A program can place orders for a user account owned by its PDA, signing with invoke_signed
+    let (forwarding_pda, _) =
+        Pubkey::find_program_address(&[FORWARDING_PDA_SEEDS], &forwarding_program_id);
+    let (pda_user_account, _) = Pubkey::find_program_address(
+        &[
+            &wsol_market_account.pubkey().to_bytes(),
+            &forwarding_pda.to_bytes(),
+        ],
+        &dex_program_id,
+    );
+    let create_user_account_instruction = initialize_account(
+        dex_program_id,
+        initialize_account::Accounts {
+            system_program: &system_program::ID,
+            user: &pda_user_account,
+            user_owner: &forwarding_pda,
+            fee_payer: &prg_test_ctx.payer.pubkey(),
+        },
+        initialize_account::Params {
+            market: wsol_market_account.pubkey(),
+            max_orders: 10,
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![forward_instruction(
+            &forwarding_program_id,
+            create_user_account_instruction,
+        )],
+        vec![],
+    )
+    .await
+    .unwrap();
+    let pda_base_token_account =
+        create_associated_token(&mut prg_test_ctx, &base_mint_key, &forwarding_pda)
+            .await
+            .unwrap();
+    let mint_to_instruction = mint_to(
+        &spl_token::ID,
+        &base_mint_key,
+        &pda_base_token_account,
+        &base_mint_auth.pubkey(),
+        &[],
+        1_000,
+    )
+    .unwrap();
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![mint_to_instruction],
+        vec![&base_mint_auth],
+    )
+    .await
+    .unwrap();
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &wsol_market_account.pubkey(),
+            orderbook: &wsol_aaob_accounts.market,
+            event_queue: &wsol_aaob_accounts.event_queue,
+            bids: &wsol_aaob_accounts.bids,
+            asks: &wsol_aaob_accounts.asks,
+            base_vault: &wsol_base_vault,
+            quote_vault: &wsol_quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &spl_token::native_mint::ID,
+            market_signer: &wsol_market_signer,
+            user: &pda_user_account,
+            user_token_account: &pda_base_token_account,
+            user_owner: &forwarding_pda,
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id: 1,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(1u128),
+            side: asset_agnostic_orderbook::state::Side::Ask as u8,
+            limit_price: 10_000 * tick_size,
+            max_base_qty: 1_000,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::PostOnly as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+
+    // The PDA can't sign the transaction itself
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction.clone()],
+        vec![],
+    )
+    .await;
+    assert!(err.is_err());
+
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![forward_instruction(
+            &forwarding_program_id,
+            new_order_instruction,
+        )],
+        vec![],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        get_token_balance(&mut prg_test_ctx, &pda_base_token_account).await,
+        0
+    );
+    let pda_user_account_header =
+        get_user_account_header(&mut prg_test_ctx, &pda_user_account).await;
+    assert_eq!(pda_user_account_header.owner, forwarding_pda);
+    assert_eq!(pda_user_account_header.number_of_orders, 1);
+    assert_eq!(pda_user_account_header.base_token_locked, 1_000);
+
+    // Events referencing a missing user account stop the crank, unless they are skipped
+    let mint_to_instruction = mint_to(
+        &spl_token::ID,
+        &base_mint_key,
+        &pda_base_token_account,
+        &base_mint_auth.pubkey(),
+        &[],
+        1,
+    )
+    .unwrap();
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![mint_to_instruction],
+        vec![&base_mint_auth],
+    )
+    .await
+    .unwrap();
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &wsol_market_account.pubkey(),
+            orderbook: &wsol_aaob_accounts.market,
+            event_queue: &wsol_aaob_accounts.event_queue,
+            bids: &wsol_aaob_accounts.bids,
+            asks: &wsol_aaob_accounts.asks,
+            base_vault: &wsol_base_vault,
+            quote_vault: &wsol_quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &spl_token::native_mint::ID,
+            market_signer: &wsol_market_signer,
+            user: &pda_user_account,
+            user_token_account: &pda_base_token_account,
+            user_owner: &forwarding_pda,
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id: 2,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(2u128),
+            side: asset_agnostic_orderbook::state::Side::Ask as u8,
+            limit_price: 900 * tick_size,
+            max_base_qty: 1,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::PostOnly as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![forward_instruction(
+            &forwarding_program_id,
+            new_order_instruction,
+        )],
+        vec![],
+    )
+    .await
+    .unwrap();
+    let ixs = vec![
+        solana_program::system_instruction::transfer(
+            &prg_test_ctx.payer.pubkey(),
+            &wsol_token_account.pubkey(),
+            1_000_000,
+        ),
+        spl_token::instruction::sync_native(&spl_token::ID, &wsol_token_account.pubkey()).unwrap(),
+    ];
+    sign_send_instructions(&mut prg_test_ctx, ixs, vec![])
+        .await
+        .unwrap();
+    // The bid takes the PDA's ask, then the cheapest ask of the ladder
+    client_order_id += 1;
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &wsol_market_account.pubkey(),
+            orderbook: &wsol_aaob_accounts.market,
+            event_queue: &wsol_aaob_accounts.event_queue,
+            bids: &wsol_aaob_accounts.bids,
+            asks: &wsol_aaob_accounts.asks,
+            base_vault: &wsol_base_vault,
+            quote_vault: &wsol_quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &spl_token::native_mint::ID,
+            market_signer: &wsol_market_signer,
+            user: &wsol_user_account,
+            user_token_account: &wsol_token_account.pubkey(),
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(client_order_id),
+            side: asset_agnostic_orderbook::state::Side::Bid as u8,
+            limit_price: 1_000 * tick_size,
+            max_base_qty: 2,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::ImmediateOrCancel as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+    let (head_before, count_before) =
+        get_event_queue_position(&mut prg_test_ctx, &wsol_aaob_accounts.event_queue).await;
+    assert_eq!(count_before, 2);
+    let wsol_consume_events = |user_accounts: &[Pubkey], skip_missing: u8| {
+        consume_events(
+            dex_program_id,
+            consume_events::Accounts {
+                market: &wsol_market_account.pubkey(),
+                orderbook: &wsol_aaob_accounts.market,
+                event_queue: &wsol_aaob_accounts.event_queue,
+                reward_target: &reward_target.pubkey(),
+                candle_history: None,
+                user_accounts,
+            },
+            consume_events::Params {
+                max_iterations: 2,
+                no_op_err: 1,
+                has_candle_history: 0,
+                skip_missing,
+                _padding: [0; 7],
+            },
+        )
+    };
+    // The PDA's fill is at the head of the queue, so nothing is consumed by default
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![wsol_consume_events(&[wsol_user_account], 0)],
+        vec![],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(DexError::NoOp as u32))
+    );
+    // User accounts of another market are rejected
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![wsol_consume_events(&[user_account], 1)],
+        vec![],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::InvalidUserAccountMarket as u32)
+        )
+    );
+    // The skipped fill is moved to the back of the queue, the following one being consumed.
+    // The number of consumed events is returned.
+    let wsol_user_account_header_before =
+        get_user_account_header(&mut prg_test_ctx, &wsol_user_account).await;
+    let return_data = sign_send_instructions_with_return_data(
+        &mut prg_test_ctx,
+        vec![wsol_consume_events(&[wsol_user_account], 1)],
+        vec![],
+        &dex_program_id,
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(u64::try_from_slice(&return_data).unwrap(), 1);
+    let (head, count) =
+        get_event_queue_position(&mut prg_test_ctx, &wsol_aaob_accounts.event_queue).await;
+    assert_eq!(head, (head_before + 2) % EVENT_QUEUE_CAPACITY as u64);
+    assert_eq!(count, 1);
+    let wsol_user_account_header =
+        get_user_account_header(&mut prg_test_ctx, &wsol_user_account).await;
+    assert_eq!(
+        wsol_user_account_header.base_token_locked,
+        wsol_user_account_header_before.base_token_locked - 1
+    );
+    let pda_user_account_header =
+        get_user_account_header(&mut prg_test_ctx, &pda_user_account).await;
+    assert_eq!(pda_user_account_header.base_token_locked, 1_001);
+    // A crank which only skips events still moves them to the back of the queue, and is not a no-op
+    let consume_events_instruction = consume_events(
+        dex_program_id,
+        consume_events::Accounts {
+            market: &wsol_market_account.pubkey(),
+            orderbook: &wsol_aaob_accounts.market,
+            event_queue: &wsol_aaob_accounts.event_queue,
+            reward_target: &reward_target.pubkey(),
+            candle_history: None,
+            user_accounts: &[wsol_user_account],
+        },
+        consume_events::Params {
+            max_iterations: 1,
+            no_op_err: 1,
+            has_candle_history: 0,
+            skip_missing: 1,
+            _padding: [0; 7],
+        },
+    );
+    let return_data = sign_send_instructions_with_return_data(
+        &mut prg_test_ctx,
+        vec![consume_events_instruction],
+        vec![],
+        &dex_program_id,
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(u64::try_from_slice(&return_data).unwrap(), 0);
+    let (head, count) =
+        get_event_queue_position(&mut prg_test_ctx, &wsol_aaob_accounts.event_queue).await;
+    assert_eq!(head, (head_before + 3) % EVENT_QUEUE_CAPACITY as u64);
+    assert_eq!(count, 1);
+    // A later crank with the right account consumes the skipped fill
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![wsol_consume_events(&[pda_user_account], 0)],
+        vec![],
+    )
+    .await
+    .unwrap();
+    let (_, count) =
+        get_event_queue_position(&mut prg_test_ctx, &wsol_aaob_accounts.event_queue).await;
+    assert_eq!(count, 0);
+    let pda_user_account_header =
+        get_user_account_header(&mut prg_test_ctx, &pda_user_account).await;
+    assert_eq!(pda_user_account_header.base_token_locked, 1_000);
+    assert_eq!(pda_user_account_header.number_of_orders, 1);
+
+    // The user accounts given to the crank don't have to be sorted
+    let mint_to_instruction = mint_to(
+        &spl_token::ID,
+        &base_mint_key,
+        &pda_base_token_account,
+        &base_mint_auth.pubkey(),
+        &[],
+        2,
+    )
+    .unwrap();
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![mint_to_instruction],
+        vec![&base_mint_auth],
+    )
+    .await
+    .unwrap();
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &wsol_market_account.pubkey(),
+            orderbook: &wsol_aaob_accounts.market,
+            event_queue: &wsol_aaob_accounts.event_queue,
+            bids: &wsol_aaob_accounts.bids,
+            asks: &wsol_aaob_accounts.asks,
+            base_vault: &wsol_base_vault,
+            quote_vault: &wsol_quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &spl_token::native_mint::ID,
+            market_signer: &wsol_market_signer,
+            user: &pda_user_account,
+            user_token_account: &pda_base_token_account,
+            user_owner: &forwarding_pda,
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id: 0,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(0u128),
+            side: asset_agnostic_orderbook::state::Side::Ask as u8,
+            limit_price: 900 * tick_size,
+            max_base_qty: 2,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::PostOnly as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![forward_instruction(
+            &forwarding_program_id,
+            new_order_instruction,
+        )],
+        vec![],
+    )
+    .await
+    .unwrap();
+    let ixs = vec![
+        solana_program::system_instruction::transfer(
+            &prg_test_ctx.payer.pubkey(),
+            &wsol_token_account.pubkey(),
+            1_000_001,
+        ),
+        spl_token::instruction::sync_native(&spl_token::ID, &wsol_token_account.pubkey()).unwrap(),
+    ];
+    sign_send_instructions(&mut prg_test_ctx, ixs, vec![])
+        .await
+        .unwrap();
+    // The bid takes the PDA's ask, then the cheapest ask of the ladder, pushing fills for both makers
+    client_order_id += 1;
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &wsol_market_account.pubkey(),
+            orderbook: &wsol_aaob_accounts.market,
+            event_queue: &wsol_aaob_accounts.event_queue,
+            bids: &wsol_aaob_accounts.bids,
+            asks: &wsol_aaob_accounts.asks,
+            base_vault: &wsol_base_vault,
+            quote_vault: &wsol_quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &spl_token::native_mint::ID,
+            market_signer: &wsol_market_signer,
+            user: &wsol_user_account,
+            user_token_account: &wsol_token_account.pubkey(),
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(client_order_id),
+            side: asset_agnostic_orderbook::state::Side::Bid as u8,
+            limit_price: 1_000 * tick_size,
+            max_base_qty: 3,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::ImmediateOrCancel as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+    let mut user_accounts = [pda_user_account, wsol_user_account];
+    user_accounts.sort_unstable();
+    user_accounts.reverse();
+    let return_data = sign_send_instructions_with_return_data(
+        &mut prg_test_ctx,
+        vec![wsol_consume_events(&user_accounts, 0)],
+        vec![],
+        &dex_program_id,
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(u64::try_from_slice(&return_data).unwrap(), 2);
+    let (_, count) =
+        get_event_queue_position(&mut prg_test_ctx, &wsol_aaob_accounts.event_queue).await;
+    assert_eq!(count, 0);
+
+    // A bid out event whose unlocked quote quantity overflows doesn't block the events behind it. The self trading
+    // ask cancels the resting bid, pushing an out event, then a fill is pushed behind it.
+    let ixs = vec![
+        solana_program::system_instruction::transfer(
+            &prg_test_ctx.payer.pubkey(),
+            &wsol_token_account.pubkey(),
+            3_000_000,
+        ),
+        spl_token::instruction::sync_native(&spl_token::ID, &wsol_token_account.pubkey()).unwrap(),
+    ];
+    sign_send_instructions(&mut prg_test_ctx, ixs, vec![])
+        .await
+        .unwrap();
+    client_order_id += 1;
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &wsol_market_account.pubkey(),
+            orderbook: &wsol_aaob_accounts.market,
+            event_queue: &wsol_aaob_accounts.event_queue,
+            bids: &wsol_aaob_accounts.bids,
+            asks: &wsol_aaob_accounts.asks,
+            base_vault: &wsol_base_vault,
+            quote_vault: &wsol_quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &spl_token::native_mint::ID,
+            market_signer: &wsol_market_signer,
+            user: &wsol_user_account,
+            user_token_account: &wsol_token_account.pubkey(),
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(client_order_id),
+            side: asset_agnostic_orderbook::state::Side::Bid as u8,
+            limit_price: 100 * tick_size,
+            max_base_qty: 100,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::Limit as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+    client_order_id += 1;
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &wsol_market_account.pubkey(),
+            orderbook: &wsol_aaob_accounts.market,
+            event_queue: &wsol_aaob_accounts.event_queue,
+            bids: &wsol_aaob_accounts.bids,
+            asks: &wsol_aaob_accounts.asks,
+            base_vault: &wsol_base_vault,
+            quote_vault: &wsol_quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &spl_token::native_mint::ID,
+            market_signer: &wsol_market_signer,
+            user: &wsol_user_account,
+            user_token_account: &user_base_token_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(client_order_id),
+            side: asset_agnostic_orderbook::state::Side::Ask as u8,
+            limit_price: 100 * tick_size,
+            max_base_qty: 100,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::Limit as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::CancelProvide
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+    let ask_client_order_id = client_order_id;
+    client_order_id += 1;
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &wsol_market_account.pubkey(),
+            orderbook: &wsol_aaob_accounts.market,
+            event_queue: &wsol_aaob_accounts.event_queue,
+            bids: &wsol_aaob_accounts.bids,
+            asks: &wsol_aaob_accounts.asks,
+            base_vault: &wsol_base_vault,
+            quote_vault: &wsol_quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &spl_token::native_mint::ID,
+            market_signer: &wsol_market_signer,
+            user: &wsol_user_account,
+            user_token_account: &wsol_token_account.pubkey(),
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(client_order_id),
+            side: asset_agnostic_orderbook::state::Side::Bid as u8,
+            limit_price: 100 * tick_size,
+            max_base_qty: 1,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::ImmediateOrCancel as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+    let cancel_order_instruction = cancel_order(
+        dex_program_id,
+        cancel_order::Accounts {
+            market: &wsol_market_account.pubkey(),
+            orderbook: &wsol_aaob_accounts.market,
+            event_queue: &wsol_aaob_accounts.event_queue,
+            bids: &wsol_aaob_accounts.bids,
+            asks: &wsol_aaob_accounts.asks,
+            user: &wsol_user_account,
+            user_owner: &user_account_owner.pubkey(),
+        },
+        cancel_order::Params {
+            order_index: 0,
+            order_id: ask_client_order_id,
+            is_client_id: true,
+            _padding: [0u8; 7],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![cancel_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+    // The base size of the out event is inflated so that its quote quantity overflows
+    let mut event_queue_account = prg_test_ctx
+        .banks_client
+        .get_account(wsol_aaob_accounts.event_queue)
+        .await
+        .unwrap()
+        .unwrap();
+    {
+        let mut event_queue = EventQueue::<CallBackInfo>::from_buffer(
+            &mut event_queue_account.data,
+            AccountTag::EventQueue,
+        )
+        .unwrap();
+        assert_eq!(event_queue.len(), 2);
+        let (mut out_event, out_callback_info) = match event_queue.iter().next().unwrap() {
+            EventRef::Out(OutEventRef {
+                event,
+                callback_info,
+            }) => (*event, *callback_info),
+            _ => panic!("The head of the queue should be an out event"),
+        };
+        let (fill_event, maker_callback_info, taker_callback_info) =
+            match event_queue.iter().nth(1).unwrap() {
+                EventRef::Fill(FillEventRef {
+                    event,
+                    maker_callback_info,
+                    taker_callback_info,
+                }) => (*event, *maker_callback_info, *taker_callback_info),
+                _ => panic!("The out event should be followed by a fill event"),
+            };
+        assert_eq!(
+            out_event.side,
+            asset_agnostic_orderbook::state::Side::Bid as u8
+        );
+        out_event.base_size = u64::MAX;
+        event_queue.pop_n(2);
+        assert!(event_queue
+            .push_back(out_event, Some(&out_callback_info), None)
+            .is_ok());
+        assert!(event_queue
+            .push_back(
+                fill_event,
+                Some(&maker_callback_info),
+                Some(&taker_callback_info)
+            )
+            .is_ok());
+    }
+    prg_test_ctx.set_account(&wsol_aaob_accounts.event_queue, &event_queue_account.into());
+    let wsol_user_account_header_before =
+        get_user_account_header(&mut prg_test_ctx, &wsol_user_account).await;
+    assert!(wsol_user_account_header_before.quote_token_locked > 0);
+    let (head_before, _) =
+        get_event_queue_position(&mut prg_test_ctx, &wsol_aaob_accounts.event_queue).await;
+    // The locked quote is unlocked instead, and the fill behind the out event is consumed in the same crank
+    let return_data = sign_send_instructions_with_return_data(
+        &mut prg_test_ctx,
+        vec![wsol_consume_events(
+            &[wsol_user_account, pda_user_account],
+            1,
+        )],
+        vec![],
+        &dex_program_id,
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(u64::try_from_slice(&return_data).unwrap(), 2);
+    let (head, count) =
+        get_event_queue_position(&mut prg_test_ctx, &wsol_aaob_accounts.event_queue).await;
+    assert_eq!(head, (head_before + 2) % EVENT_QUEUE_CAPACITY as u64);
+    assert_eq!(count, 0);
+    let wsol_user_account_header =
+        get_user_account_header(&mut prg_test_ctx, &wsol_user_account).await;
+    assert_eq!(wsol_user_account_header.quote_token_locked, 0);
+    assert!(
+        wsol_user_account_header.quote_token_free
+            >= wsol_user_account_header_before.quote_token_free
+                + wsol_user_account_header_before.quote_token_locked
+    );
+    assert_eq!(
+        wsol_user_account_header.number_of_orders,
+        wsol_user_account_header_before.number_of_orders - 1
+    );
+
+    // A market whose vaults are owned by Token-2022
+    let token_2022_market_account = Keypair::new();
+    let create_market_account_instruction = create_account(
+        &prg_test_ctx.payer.pubkey(),
+        &token_2022_market_account.pubkey(),
+        market_rent,
+        DEX_STATE_LEN as u64,
+        &dex_program_id,
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![create_market_account_instruction],
+        vec![&token_2022_market_account],
+    )
+    .await
+    .unwrap();
+    let (token_2022_market_signer, token_2022_signer_nonce) = Pubkey::find_program_address(
+        &[&token_2022_market_account.pubkey().to_bytes()],
+        &dex_program_id,
+    );
+    let token_2022_aaob_accounts =
+        create_aob_market_and_accounts(&mut prg_test_ctx, dex_program_id).await;
+    let token_2022_base_vault = create_token_2022_account(
+        &mut prg_test_ctx,
+        &token_2022_base_mint,
+        &token_2022_market_signer,
+    )
+    .await
+    .unwrap();
+    let token_2022_quote_vault = create_token_2022_account(
+        &mut prg_test_ctx,
+        &token_2022_quote_mint,
+        &token_2022_market_signer,
+    )
+    .await
+    .unwrap();
+    let token_2022_metadata = find_metadata_account(&token_2022_base_mint).0;
+    let token_2022_create_market = |quote_vault: &Pubkey, close_grace_slots: u64| {
+        create_market(
+            dex_program_id,
+            create_market::Accounts {
+                base_vault: &token_2022_base_vault,
+                quote_vault,
+                base_mint: &token_2022_base_mint,
+                quote_mint: &token_2022_quote_mint,
+                market: &token_2022_market_account.pubkey(),
+                orderbook: &token_2022_aaob_accounts.market,
+                market_admin: &market_admin.pubkey(),
+                event_queue: &token_2022_aaob_accounts.event_queue,
+                asks: &token_2022_aaob_accounts.asks,
+                bids: &token_2022_aaob_accounts.bids,
+                token_metadata: &token_2022_metadata,
+            },
+            create_market::Params {
+                signer_nonce: token_2022_signer_nonce as u64,
+                min_base_order_size: 1,
+                tick_size,
+                base_currency_multiplier: 1,
+                quote_currency_multiplier: 10000,
+                close_grace_slots,
+                fee_tier_thresholds: [0; 5],
+                fee_tier_taker_rates: [0; 8],
+                fee_tier_maker_rebate_rates: [0; 8],
+                sweep_authority: Pubkey::default(),
+                min_quote_order_size: 0,
+                max_deviation_bps: 0,
+                referral_bps: 0,
+                fee_model: 0,
+                fee_type: MarketFeeType::Stable as u8,
+                name: [0; 16],
+                ticker: [0; 8],
+                flat_taker_bps: 0,
+                promo_end_ts: 0,
+            },
+        )
+    };
+
+    // Both vaults must be owned by the same token program
+    let legacy_quote_vault = create_associated_token(
+        &mut prg_test_ctx,
+        &quote_mint_key,
+        &token_2022_market_signer,
+    )
+    .await
+    .unwrap();
+    let create_market_instruction = token_2022_create_market(&legacy_quote_vault, 0);
+    let err = sign_send_instructions(&mut prg_test_ctx, vec![create_market_instruction], vec![])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::InvalidStateAccountOwner as u32)
+        )
+    );
+
+    // Mints with extensions which could alter the transferred amounts are rejected
+    let token_2022_quote_mint_account = prg_test_ctx
+        .banks_client
+        .get_account(token_2022_quote_mint)
+        .await
+        .unwrap()
+        .unwrap();
+    let mut transfer_fee_mint_account = token_2022_quote_mint_account.clone();
+    add_mint_extension(
+        &mut transfer_fee_mint_account,
+        ExtensionType::TransferFeeConfig as u16,
+        ExtensionType::TransferFeeConfig.get_type_len(),
+    );
+    prg_test_ctx.set_account(&token_2022_quote_mint, &transfer_fee_mint_account.into());
+    let create_market_instruction = token_2022_create_market(&token_2022_quote_vault, 1);
+    let err = sign_send_instructions(&mut prg_test_ctx, vec![create_market_instruction], vec![])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::UnsupportedMintExtension as u32)
+        )
+    );
+    prg_test_ctx.set_account(
+        &token_2022_quote_mint,
+        &token_2022_quote_mint_account.clone().into(),
+    );
+
+    // Transfer hook mints are rejected as their extension is unknown to the program
+    let mut transfer_hook_mint_account = token_2022_quote_mint_account.clone();
+    add_mint_extension(
+        &mut transfer_hook_mint_account,
+        TRANSFER_HOOK_EXTENSION_TYPE,
+        64,
+    );
+    prg_test_ctx.set_account(&token_2022_quote_mint, &transfer_hook_mint_account.into());
+    let create_market_instruction = token_2022_create_market(&token_2022_quote_vault, 2);
+    let err = sign_send_instructions(&mut prg_test_ctx, vec![create_market_instruction], vec![])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::UnsupportedMintExtension as u32)
+        )
+    );
+    prg_test_ctx.set_account(
+        &token_2022_quote_mint,
+        &token_2022_quote_mint_account.into(),
+    );
+
+    let create_market_instruction = token_2022_create_market(&token_2022_quote_vault, 0);
+    sign_send_instructions(&mut prg_test_ctx, vec![create_market_instruction], vec![])
+        .await
+        .unwrap();
+    let token_2022_market_state =
+        get_market_state(&mut prg_test_ctx, &token_2022_market_account.pubkey()).await;
+    assert_eq!(token_2022_market_state.token_program, spl_token_2022::ID);
+    assert_eq!(
+        token_2022_market_state.fee_type,
+        MarketFeeType::Stable as u8
+    );
+
+    let (token_2022_user_account, _) = Pubkey::find_program_address(
+        &[
+            &token_2022_market_account.pubkey().to_bytes(),
+            &user_account_owner.pubkey().to_bytes(),
+        ],
+        &dex_program_id,
+    );
+    let create_user_account_instruction = initialize_account(
+        dex_program_id,
+        initialize_account::Accounts {
+            system_program: &system_program::ID,
+            user: &token_2022_user_account,
+            user_owner: &user_account_owner.pubkey(),
+            fee_payer: &prg_test_ctx.payer.pubkey(),
+        },
+        initialize_account::Params {
+            market: token_2022_market_account.pubkey(),
+            max_orders: 10,
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![create_user_account_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+    let token_2022_user_base_account = create_token_2022_account(
+        &mut prg_test_ctx,
+        &token_2022_base_mint,
+        &user_account_owner.pubkey(),
+    )
+    .await
+    .unwrap();
+    let token_2022_user_quote_account = create_token_2022_account(
+        &mut prg_test_ctx,
+        &token_2022_quote_mint,
+        &user_account_owner.pubkey(),
+    )
+    .await
+    .unwrap();
+    let ixs = vec![
+        spl_token_2022::instruction::mint_to(
+            &spl_token_2022::ID,
+            &token_2022_base_mint,
+            &token_2022_user_base_account,
+            &base_mint_auth.pubkey(),
+            &[],
+            10_000,
+        )
+        .unwrap(),
+        spl_token_2022::instruction::mint_to(
+            &spl_token_2022::ID,
+            &token_2022_quote_mint,
+            &token_2022_user_quote_account,
+            &quote_mint_auth.pubkey(),
+            &[],
+            100_000_000,
+        )
+        .unwrap(),
+    ];
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        ixs,
+        vec![&base_mint_auth, &quote_mint_auth],
+    )
+    .await
+    .unwrap();
+
+    // Orders are funded with a transfer_checked of the market's token program
+    let token_2022_ask = |spl_token_program: &Pubkey| {
+        new_order(
+            dex_program_id,
+            new_order::Accounts {
+                spl_token_program,
+                system_program: &system_program::ID,
+                market: &token_2022_market_account.pubkey(),
+                orderbook: &token_2022_aaob_accounts.market,
+                event_queue: &token_2022_aaob_accounts.event_queue,
+                bids: &token_2022_aaob_accounts.bids,
+                asks: &token_2022_aaob_accounts.asks,
+                base_vault: &token_2022_base_vault,
+                quote_vault: &token_2022_quote_vault,
+                base_mint: &token_2022_base_mint,
+                quote_mint: &token_2022_quote_mint,
+                market_signer: &token_2022_market_signer,
+                user: &token_2022_user_account,
+                user_token_account: &token_2022_user_base_account,
+                user_owner: &user_account_owner.pubkey(),
+                discount_token_account: None,
+                fee_referral_account: None,
+                referrer: None,
+            },
+            new_order::Params {
+                #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+                client_order_id: 0,
+                #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+                client_order_id: [0; 2],
+                side: asset_agnostic_orderbook::state::Side::Ask as u8,
+                limit_price: 1_000 * tick_size,
+                max_base_qty: 1_000,
+                max_quote_qty: u64::MAX,
+                order_type: new_order::OrderType::PostOnly as u8,
+                self_trade_behavior:
+                    asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake as u8,
+                match_limit: 10,
+                has_discount_token_account: false as u8,
+                fok_fallback_ioc: 0,
+                reduce_only: 0,
+                duplicate_client_id_policy: 0,
+                _padding: [0; 1],
+            },
+        )
+    };
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![token_2022_ask(&spl_token::ID)],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::InvalidSplTokenProgram as u32)
+        )
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![token_2022_ask(&spl_token_2022::ID)],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        get_token_balance(&mut prg_test_ctx, &token_2022_base_vault).await,
+        1_000
+    );
+
+    // Swaps transfer in and out of the Token-2022 vaults, the taker paying the stable pair fee rate
+    let quote_balance_before =
+        get_token_balance(&mut prg_test_ctx, &token_2022_user_quote_account).await;
+    let swap_instruction = swap(
+        dex_program_id,
+        swap::Accounts {
+            spl_token_program: &spl_token_2022::ID,
+            system_program: &system_program::ID,
+            market: &token_2022_market_account.pubkey(),
+            orderbook: &token_2022_aaob_accounts.market,
+            event_queue: &token_2022_aaob_accounts.event_queue,
+            bids: &token_2022_aaob_accounts.bids,
+            asks: &token_2022_aaob_accounts.asks,
+            base_vault: &token_2022_base_vault,
+            quote_vault: &token_2022_quote_vault,
+            base_mint: &token_2022_base_mint,
+            quote_mint: &token_2022_quote_mint,
+            market_signer: &token_2022_market_signer,
+            user_base_account: &token_2022_user_base_account,
+            user_quote_account: &token_2022_user_quote_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        swap::Params {
+            side: asset_agnostic_orderbook::state::Side::Bid as u8,
+            base_qty: 500,
+            quote_qty: 100_000_000,
+            match_limit: 10,
+            has_discount_token_account: 0,
+            max_slippage_bps: 0,
+            exact_out: 0,
+            _padding: [0; 3],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![swap_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        get_token_balance(&mut prg_test_ctx, &token_2022_base_vault).await,
+        500
+    );
+    assert_eq!(
+        get_token_balance(&mut prg_test_ctx, &token_2022_user_base_account).await,
+        9_500
+    );
+    let swap_quote_qty = (((500u128 * (1_000 * tick_size) as u128) >> 32) as u64) * 10_000;
+    let stable_taker_fee = FeeTier::Stable.taker_fee(&token_2022_market_state, swap_quote_qty);
+    assert!(stable_taker_fee < FeeTier::Base.taker_fee(&token_2022_market_state, swap_quote_qty));
+    assert_eq!(
+        quote_balance_before
+            - get_token_balance(&mut prg_test_ctx, &token_2022_user_quote_account).await,
+        swap_quote_qty + stable_taker_fee
+    );
+
+    // The maker's proceeds are settled out of the Token-2022 quote vault
+    let quote_balance_before =
+        get_token_balance(&mut prg_test_ctx, &token_2022_user_quote_account).await;
+    let ix = consume_events_and_settle(
+        dex_program_id,
+        consume_events_and_settle::Accounts {
+            spl_token_program: &spl_token_2022::ID,
+            market: &token_2022_market_account.pubkey(),
+            orderbook: &token_2022_aaob_accounts.market,
+            event_queue: &token_2022_aaob_accounts.event_queue,
+            reward_target: &reward_target.pubkey(),
+            base_vault: &token_2022_base_vault,
+            quote_vault: &token_2022_quote_vault,
+            base_mint: &token_2022_base_mint,
+            quote_mint: &token_2022_quote_mint,
+            market_signer: &token_2022_market_signer,
+            user_accounts: &[
+                token_2022_user_account,
+                token_2022_user_account,
+                token_2022_user_base_account,
+                token_2022_user_quote_account,
+            ],
+        },
+        consume_events_and_settle::Params {
+            max_iterations: 10,
+            no_op_err: 1,
+            number_of_settled_users: 1,
+        },
+    );
+    sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![])
+        .await
+        .unwrap();
+    let token_2022_user_account_header =
+        get_user_account_header(&mut prg_test_ctx, &token_2022_user_account).await;
+    assert_eq!(token_2022_user_account_header.quote_token_free, 0);
+    assert_eq!(token_2022_user_account_header.base_token_locked, 500);
+    assert!(
+        get_token_balance(&mut prg_test_ctx, &token_2022_user_quote_account).await
+            > quote_balance_before
+    );
+
+    // Fills are recorded in the candle history when it is given to consume_events
+    let (candle_history_key, _) =
+        find_candle_history(&dex_program_id, &token_2022_market_account.pubkey());
+    let initialize_candle_history_instruction = initialize_candle_history(
+        dex_program_id,
+        initialize_candle_history::Accounts {
+            system_program: &system_program::ID,
+            market: &token_2022_market_account.pubkey(),
+            candle_history: &candle_history_key,
+            market_admin: &market_admin.pubkey(),
+            fee_payer: &prg_test_ctx.payer.pubkey(),
+        },
+        initialize_candle_history::Params { interval: 60 },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![initialize_candle_history_instruction],
+        vec![&market_admin],
+    )
+    .await
+    .unwrap();
+
+    let mut clock = prg_test_ctx
+        .banks_client
+        .get_sysvar::<Clock>()
+        .await
+        .unwrap();
+    clock.unix_timestamp += 60 - clock.unix_timestamp.rem_euclid(60);
+    let first_candle_ts = clock.unix_timestamp;
+    for base_qty in [100, 101, 102] {
+        prg_test_ctx.set_sysvar(&clock);
+        let swap_instruction = swap(
+            dex_program_id,
+            swap::Accounts {
+                spl_token_program: &spl_token_2022::ID,
+                system_program: &system_program::ID,
+                market: &token_2022_market_account.pubkey(),
+                orderbook: &token_2022_aaob_accounts.market,
+                event_queue: &token_2022_aaob_accounts.event_queue,
+                bids: &token_2022_aaob_accounts.bids,
+                asks: &token_2022_aaob_accounts.asks,
+                base_vault: &token_2022_base_vault,
+                quote_vault: &token_2022_quote_vault,
+                base_mint: &token_2022_base_mint,
+                quote_mint: &token_2022_quote_mint,
+                market_signer: &token_2022_market_signer,
+                user_base_account: &token_2022_user_base_account,
+                user_quote_account: &token_2022_user_quote_account,
+                user_owner: &user_account_owner.pubkey(),
+                discount_token_account: None,
+                fee_referral_account: None,
+                referrer: None,
+            },
+            swap::Params {
+                side: asset_agnostic_orderbook::state::Side::Bid as u8,
+                base_qty,
+                quote_qty: 100_000_000,
+                match_limit: 10,
+                has_discount_token_account: 0,
+                max_slippage_bps: 0,
+                exact_out: 0,
+                _padding: [0; 3],
+            },
+        );
+        let consume_events_instruction = consume_events(
+            dex_program_id,
+            consume_events::Accounts {
+                market: &token_2022_market_account.pubkey(),
+                orderbook: &token_2022_aaob_accounts.market,
+                event_queue: &token_2022_aaob_accounts.event_queue,
+                reward_target: &reward_target.pubkey(),
+                candle_history: Some(&candle_history_key),
+                user_accounts: &[token_2022_user_account],
+            },
+            consume_events::Params {
+                max_iterations: 10,
+                no_op_err: 1,
+                has_candle_history: 1,
+                skip_missing: 0,
+                _padding: [0; 7],
+            },
+        );
+        sign_send_instructions(
+            &mut prg_test_ctx,
+            vec![swap_instruction, consume_events_instruction],
+            vec![&user_account_owner],
+        )
+        .await
+        .unwrap();
+        clock.unix_timestamp += 60;
+    }
+
+    // The third fill opens a new candle, finalizing the second one
+    let candle_history_data = prg_test_ctx
+        .banks_client
+        .get_account(candle_history_key)
+        .await
+        .unwrap()
+        .unwrap()
+        .data;
+    let candle_history =
+        bytemuck::try_from_bytes::<CandleHistory>(&candle_history_data[..CANDLE_HISTORY_LEN])
+            .unwrap();
+    assert_eq!(candle_history.market, token_2022_market_account.pubkey());
+    assert_eq!(candle_history.count, 2);
+    let fill_quote_qty =
+        |base_qty: u64| (((base_qty as u128 * (1_000 * tick_size) as u128) >> 32) as u64) * 10_000;
+    let candles = candle_history
+        .finalized_candles()
+        .chain(std::iter::once(&candle_history.current))
+        .collect::<Vec<_>>();
+    for (i, (candle, base_qty)) in candles.into_iter().zip([100, 101, 102]).enumerate() {
+        assert_eq!(candle.start_ts, first_candle_ts + 60 * i as i64);
+        assert_eq!(candle.number_of_fills, 1);
+        assert_eq!(candle.base_volume, base_qty);
+        assert_eq!(candle.quote_volume, fill_quote_qty(base_qty));
+        assert_eq!(candle.open, candle.close);
+        assert_eq!(candle.high, candle.low);
+        assert_eq!(candle.open, candle.high);
+    }
+
+    // The market info can be queried without modifying the market
+    let market_data_before = prg_test_ctx
+        .banks_client
+        .get_account(token_2022_market_account.pubkey())
+        .await
+        .unwrap()
+        .unwrap()
+        .data;
+    let get_market_info_instruction = get_market_info(
+        dex_program_id,
+        get_market_info::Accounts {
+            market: &token_2022_market_account.pubkey(),
+        },
+        get_market_info::Params {},
+    );
+    let return_data = sign_send_instructions_with_return_data(
+        &mut prg_test_ctx,
+        vec![get_market_info_instruction],
+        vec![],
+        &dex_program_id,
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    let market_info = MarketInfo::try_from_slice(&return_data).unwrap();
+    assert_eq!(
+        format!(
+            "{}.{}.{}",
+            market_info.version_major, market_info.version_minor, market_info.version_patch
+        ),
+        env!("CARGO_PKG_VERSION")
+    );
+    assert_eq!(market_info.state_len, DEX_STATE_LEN as u64);
+    assert_eq!(market_info.account_len, DEX_STATE_LEN as u64);
+    assert_eq!(market_info.tag, dex_v4::state::AccountTag::DexState as u64);
+    assert_eq!(market_info.fee_type, MarketFeeType::Stable as u8);
+    assert_eq!(market_info.status, MarketStatus::Active as u8);
+    assert_eq!(
+        prg_test_ctx
+            .banks_client
+            .get_account(token_2022_market_account.pubkey())
+            .await
+            .unwrap()
+            .unwrap()
+            .data,
+        market_data_before
+    );
+    let get_market_info_instruction = get_market_info(
+        dex_program_id,
+        get_market_info::Accounts {
+            market: &token_2022_base_vault,
+        },
+        get_market_info::Params {},
+    );
+    let err = sign_send_instructions(&mut prg_test_ctx, vec![get_market_info_instruction], vec![])
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::InvalidStateAccountOwner as u32)
+        )
+    );
+
+    // A market created under the legacy layout is upgraded in place by its admin
+    let legacy_market_key = Pubkey::new_unique();
+    let mut legacy_market_data = prg_test_ctx
+        .banks_client
+        .get_account(token_2022_market_account.pubkey())
+        .await
+        .unwrap()
+        .unwrap();
+    legacy_market_data.data.truncate(LEGACY_DEX_STATE_LEN);
+    legacy_market_data.lamports = rent.minimum_balance(LEGACY_DEX_STATE_LEN);
+    prg_test_ctx.set_account(&legacy_market_key, &legacy_market_data.clone().into());
+    let payer = prg_test_ctx.payer.pubkey();
+    let migrate_market_state_instruction = |market_admin: &Pubkey| {
+        migrate_market_state(
+            dex_program_id,
+            migrate_market_state::Accounts {
+                system_program: &system_program::ID,
+                market: &legacy_market_key,
+                quote_vault: &token_2022_quote_vault,
+                market_admin,
+                fee_payer: &payer,
+            },
+            migrate_market_state::Params {},
+        )
+    };
+    // The other instructions reject the legacy layout until the market is migrated
+    let nominate_admin_instruction = nominate_admin(
+        dex_program_id,
+        nominate_admin::Accounts {
+            market: &legacy_market_key,
+            market_admin: &market_admin.pubkey(),
+            new_admin: &user_account_owner.pubkey(),
+        },
+        nominate_admin::Params {},
+    );
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![nominate_admin_instruction],
+        vec![&market_admin],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(0, InstructionError::InvalidAccountData)
+    );
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![migrate_market_state_instruction(
+            &user_account_owner.pubkey(),
+        )],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::InvalidMarketAdminAccount as u32)
+        )
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![migrate_market_state_instruction(&market_admin.pubkey())],
+        vec![&market_admin],
+    )
+    .await
+    .unwrap();
+    let mut migrated_market_data = prg_test_ctx
+        .banks_client
+        .get_account(legacy_market_key)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(migrated_market_data.data.len(), DEX_STATE_LEN);
+    assert_eq!(
+        migrated_market_data.data[..LEGACY_DEX_STATE_LEN],
+        legacy_market_data.data[..]
+    );
+    assert!(migrated_market_data.lamports >= rent.minimum_balance(DEX_STATE_LEN));
+    let migrated_market_state: &mut DexState =
+        try_from_bytes_mut(&mut migrated_market_data.data[..]).unwrap();
+    assert_eq!(migrated_market_state.base_mint, token_2022_base_mint);
+    assert_eq!(migrated_market_state.admin, market_admin.pubkey());
+    assert_eq!(migrated_market_state.fee_type, MarketFeeType::Stable as u8);
+    assert_eq!(migrated_market_state.status, MarketStatus::Active as u8);
+    assert_eq!(
+        migrated_market_state.fee_tier_taker_rates,
+        DEFAULT_FEE_TIER_TAKER_RATES
+    );
+    assert_eq!(
+        migrated_market_state.fee_tier_maker_rebate_rates,
+        DEFAULT_FEE_TIER_MAKER_REBATE_RATES
+    );
+    // The quote vault balance which isn't accounted for as fees or royalties is held on behalf of the user accounts
+    assert_eq!(
+        migrated_market_state.total_quote_backing,
+        get_token_balance(&mut prg_test_ctx, &token_2022_quote_vault).await
+            - migrated_market_state.accumulated_fees
+            - migrated_market_state.accumulated_royalties
+    );
+
+    // Migrating a market which uses the current layout is a no-op
+    let market_data_before = migrated_market_data.data.clone();
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![
+            migrate_market_state_instruction(&market_admin.pubkey()),
+            get_market_info(
+                dex_program_id,
+                get_market_info::Accounts {
+                    market: &legacy_market_key,
+                },
+                get_market_info::Params {},
+            ),
+        ],
+        vec![&market_admin],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        prg_test_ctx
+            .banks_client
+            .get_account(legacy_market_key)
+            .await
+            .unwrap()
+            .unwrap()
+            .data,
+        market_data_before
+    );
+
+    // The top of the book is returned in native units, an empty side being zeroed
+    let get_best_prices_instruction = get_best_prices(
+        dex_program_id,
+        get_best_prices::Accounts {
+            market: &token_2022_market_account.pubkey(),
+            orderbook: &token_2022_aaob_accounts.market,
+            bids: &token_2022_aaob_accounts.bids,
+            asks: &token_2022_aaob_accounts.asks,
+        },
+        get_best_prices::Params {},
+    );
+    let return_data = sign_send_instructions_with_return_data(
+        &mut prg_test_ctx,
+        vec![get_best_prices_instruction.clone()],
+        vec![],
+        &dex_program_id,
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    // The remainder of the ask after the swaps above
+    let best_ask_size = 1_000 - 500 - 100 - 101 - 102;
+    assert_eq!(
+        get_best_prices::BestPrices::try_from_slice(&return_data).unwrap(),
+        get_best_prices::BestPrices {
+            best_bid_price: 0,
+            best_bid_size: 0,
+            best_ask_price: 1_000 * tick_size * 10_000,
+            best_ask_size,
+        }
+    );
+    let bid_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token_2022::ID,
+            system_program: &system_program::ID,
+            market: &token_2022_market_account.pubkey(),
+            orderbook: &token_2022_aaob_accounts.market,
+            event_queue: &token_2022_aaob_accounts.event_queue,
+            bids: &token_2022_aaob_accounts.bids,
+            asks: &token_2022_aaob_accounts.asks,
+            base_vault: &token_2022_base_vault,
+            quote_vault: &token_2022_quote_vault,
+            base_mint: &token_2022_base_mint,
+            quote_mint: &token_2022_quote_mint,
+            market_signer: &token_2022_market_signer,
+            user: &token_2022_user_account,
+            user_token_account: &token_2022_user_quote_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id: 0,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: [0; 2],
+            side: asset_agnostic_orderbook::state::Side::Bid as u8,
+            limit_price: 900 * tick_size,
+            max_base_qty: 50,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::PostOnly as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    let return_data = sign_send_instructions_with_return_data(
+        &mut prg_test_ctx,
+        vec![bid_instruction, get_best_prices_instruction.clone()],
+        vec![&user_account_owner],
+        &dex_program_id,
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(
+        get_best_prices::BestPrices::try_from_slice(&return_data).unwrap(),
+        get_best_prices::BestPrices {
+            best_bid_price: 900 * tick_size * 10_000,
+            best_bid_size: 50,
+            best_ask_price: 1_000 * tick_size * 10_000,
+            best_ask_size,
+        }
+    );
+
+    // Orderbook errors are surfaced as dedicated variants, starting with a full event queue
+    let token_2022_swap = |base_qty: u64| {
+        swap(
+            dex_program_id,
+            swap::Accounts {
+                spl_token_program: &spl_token_2022::ID,
+                system_program: &system_program::ID,
+                market: &token_2022_market_account.pubkey(),
+                orderbook: &token_2022_aaob_accounts.market,
+                event_queue: &token_2022_aaob_accounts.event_queue,
+                bids: &token_2022_aaob_accounts.bids,
+                asks: &token_2022_aaob_accounts.asks,
+                base_vault: &token_2022_base_vault,
+                quote_vault: &token_2022_quote_vault,
+                base_mint: &token_2022_base_mint,
+                quote_mint: &token_2022_quote_mint,
+                market_signer: &token_2022_market_signer,
+                user_base_account: &token_2022_user_base_account,
+                user_quote_account: &token_2022_user_quote_account,
+                user_owner: &user_account_owner.pubkey(),
+                discount_token_account: None,
+                fee_referral_account: None,
+                referrer: None,
+            },
+            swap::Params {
+                side: asset_agnostic_orderbook::state::Side::Bid as u8,
+                base_qty,
+                quote_qty: 100_000_000,
+                match_limit: 10,
+                has_discount_token_account: 0,
+                max_slippage_bps: 0,
+                exact_out: 0,
+                _padding: [0; 3],
+            },
+        )
+    };
+    let token_2022_consume_events = |max_iterations: u64| {
+        consume_events(
+            dex_program_id,
+            consume_events::Accounts {
+                market: &token_2022_market_account.pubkey(),
+                orderbook: &token_2022_aaob_accounts.market,
+                event_queue: &token_2022_aaob_accounts.event_queue,
+                reward_target: &reward_target.pubkey(),
+                candle_history: None,
+                user_accounts: &[token_2022_user_account],
+            },
+            consume_events::Params {
+                max_iterations,
+                no_op_err: 1,
+                has_candle_history: 0,
+                skip_missing: 0,
+                _padding: [0; 7],
+            },
+        )
+    };
+    // Each swap partially fills the ask, pushing a single fill event
+    for base_qty in 1..=EVENT_QUEUE_CAPACITY as u64 {
+        sign_send_instructions(
+            &mut prg_test_ctx,
+            vec![token_2022_swap(base_qty)],
+            vec![&user_account_owner],
+        )
+        .await
+        .unwrap();
+    }
+    let (_, event_count) =
+        get_event_queue_position(&mut prg_test_ctx, &token_2022_aaob_accounts.event_queue).await;
+    assert_eq!(event_count, EVENT_QUEUE_CAPACITY as u64);
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![token_2022_swap(1)],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::EventQueueFull as u32)
+        )
+    );
+    // Crossing limit orders are rejected with the same error until the queue is cranked
+    let crossing_bid_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token_2022::ID,
+            system_program: &system_program::ID,
+            market: &token_2022_market_account.pubkey(),
+            orderbook: &token_2022_aaob_accounts.market,
+            event_queue: &token_2022_aaob_accounts.event_queue,
+            bids: &token_2022_aaob_accounts.bids,
+            asks: &token_2022_aaob_accounts.asks,
+            base_vault: &token_2022_base_vault,
+            quote_vault: &token_2022_quote_vault,
+            base_mint: &token_2022_base_mint,
+            quote_mint: &token_2022_quote_mint,
+            market_signer: &token_2022_market_signer,
+            user: &token_2022_user_account,
+            user_token_account: &token_2022_user_quote_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id: 0,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: [0; 2],
+            side: asset_agnostic_orderbook::state::Side::Bid as u8,
+            limit_price: 1_000 * tick_size,
+            max_base_qty: 1,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::ImmediateOrCancel as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![crossing_bid_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::EventQueueFull as u32)
+        )
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![token_2022_consume_events(EVENT_QUEUE_CAPACITY as u64)],
+        vec![],
+    )
+    .await
+    .unwrap();
+
+    // An order which was filled but whose events were not consumed yet is no longer in the orderbook
+    let remaining_ask_size = best_ask_size - (1..=EVENT_QUEUE_CAPACITY as u64).sum::<u64>();
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![token_2022_swap(remaining_ask_size)],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+    let mut token_2022_user_account_data = prg_test_ctx
+        .banks_client
+        .get_account(token_2022_user_account)
+        .await
+        .unwrap()
+        .unwrap()
+        .data;
+    let filled_ask_id = UserAccount::from_buffer(&mut token_2022_user_account_data)
+        .unwrap()
+        .read_order(0)
+        .unwrap()
+        .id;
+    let cancel_order_instruction = cancel_order(
+        dex_program_id,
+        cancel_order::Accounts {
+            market: &token_2022_market_account.pubkey(),
+            orderbook: &token_2022_aaob_accounts.market,
+            event_queue: &token_2022_aaob_accounts.event_queue,
+            bids: &token_2022_aaob_accounts.bids,
+            asks: &token_2022_aaob_accounts.asks,
+            user: &token_2022_user_account,
+            user_owner: &user_account_owner.pubkey(),
+        },
+        cancel_order::Params {
+            order_index: 0,
+            order_id: filled_ask_id,
+            is_client_id: false,
+            _padding: [0u8; 7],
+        },
+    );
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![cancel_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::OrderNotFound as u32)
+        )
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![token_2022_consume_events(EVENT_QUEUE_CAPACITY as u64 - 1)],
+        vec![],
+    )
+    .await
+    .unwrap();
+
+    // An order covered by the free balance of the user account is reposted without any user token account
+    let token_2022_user_account_header_before =
+        get_user_account_header(&mut prg_test_ctx, &token_2022_user_account).await;
+    let quote_balance_before =
+        get_token_balance(&mut prg_test_ctx, &token_2022_user_quote_account).await;
+    // The bid locks at most 8 scaled quote, well under the proceeds of the fills above
+    assert!(token_2022_user_account_header_before.quote_token_free >= 80_000);
+    let token_2022_repost_bid = |max_base_qty: u64| {
+        repost(
+            dex_program_id,
+            repost::Accounts {
+                spl_token_program: &spl_token_2022::ID,
+                system_program: &system_program::ID,
+                market: &token_2022_market_account.pubkey(),
+                orderbook: &token_2022_aaob_accounts.market,
+                event_queue: &token_2022_aaob_accounts.event_queue,
+                bids: &token_2022_aaob_accounts.bids,
+                asks: &token_2022_aaob_accounts.asks,
+                base_vault: &token_2022_base_vault,
+                quote_vault: &token_2022_quote_vault,
+                base_mint: &token_2022_base_mint,
+                quote_mint: &token_2022_quote_mint,
+                market_signer: &token_2022_market_signer,
+                user: &token_2022_user_account,
+                user_owner: &user_account_owner.pubkey(),
+                discount_token_account: None,
+                user_token_account: None,
+            },
+            repost::Params {
+                #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+                client_order_id: 0,
+                #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+                client_order_id: [0; 2],
+                side: asset_agnostic_orderbook::state::Side::Bid as u8,
+                limit_price: 800 * tick_size,
+                max_base_qty,
+                max_quote_qty: u64::MAX,
+                order_type: new_order::OrderType::PostOnly as u8,
+                self_trade_behavior:
+                    asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake as u8,
+                match_limit: 10,
+                has_discount_token_account: false as u8,
+                fok_fallback_ioc: 0,
+                reduce_only: 0,
+                duplicate_client_id_policy: 0,
+                _padding: [0; 1],
+            },
+        )
+    };
+    // A bid exceeding the free balance requires a user token account, each base unit locking more than 70_000 quote
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![token_2022_repost_bid(
+            token_2022_user_account_header_before.quote_token_free / 70_000 + 1,
+        )],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(0, InstructionError::NotEnoughAccountKeys)
+    );
+    let repost_bid_instruction = token_2022_repost_bid(1);
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![repost_bid_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        get_token_balance(&mut prg_test_ctx, &token_2022_user_quote_account).await,
+        quote_balance_before
+    );
+    let token_2022_user_account_header =
+        get_user_account_header(&mut prg_test_ctx, &token_2022_user_account).await;
+    let repost_bid_quote_qty = token_2022_user_account_header.quote_token_locked
+        - token_2022_user_account_header_before.quote_token_locked;
+    assert!(repost_bid_quote_qty > 0);
+    assert_eq!(
+        token_2022_user_account_header.quote_token_free,
+        token_2022_user_account_header_before.quote_token_free - repost_bid_quote_qty
+    );
+
+    // The market admin can cancel the orders of a user account, the unlocked funds going to its free balance
+    let token_2022_admin_cancel_orders = |market_admin: &Pubkey| {
+        admin_cancel_orders(
+            dex_program_id,
+            admin_cancel_orders::Accounts {
+                market: &token_2022_market_account.pubkey(),
+                orderbook: &token_2022_aaob_accounts.market,
+                event_queue: &token_2022_aaob_accounts.event_queue,
+                bids: &token_2022_aaob_accounts.bids,
+                asks: &token_2022_aaob_accounts.asks,
+                user: &token_2022_user_account,
+                market_admin,
+            },
+            admin_cancel_orders::Params { max_orders: 1 },
+        )
+    };
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![token_2022_admin_cancel_orders(&user_account_owner.pubkey())],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::InvalidMarketAdminAccount as u32)
+        )
+    );
+    // The bid posted above is the last order of the user account
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![token_2022_admin_cancel_orders(&market_admin.pubkey())],
+        vec![&market_admin],
+    )
+    .await
+    .unwrap();
+    let token_2022_user_account_header_after_cancel =
+        get_user_account_header(&mut prg_test_ctx, &token_2022_user_account).await;
+    assert_eq!(
+        token_2022_user_account_header_after_cancel.number_of_orders,
+        token_2022_user_account_header.number_of_orders - 1
+    );
+    assert_eq!(
+        token_2022_user_account_header_after_cancel.quote_token_free,
+        token_2022_user_account_header_before.quote_token_free
+    );
+    assert_eq!(
+        token_2022_user_account_header_after_cancel.quote_token_locked,
+        token_2022_user_account_header_before.quote_token_locked
+    );
+    assert_eq!(
+        token_2022_user_account_header_after_cancel.base_token_free,
+        token_2022_user_account_header.base_token_free
+    );
+
+    // The owner can then settle the freed amount
+    let settle_instruction = settle(
+        dex_program_id,
+        settle::Accounts {
+            spl_token_program: &spl_token_2022::ID,
+            market: &token_2022_market_account.pubkey(),
+            base_vault: &token_2022_base_vault,
+            quote_vault: &token_2022_quote_vault,
+            base_mint: &token_2022_base_mint,
+            quote_mint: &token_2022_quote_mint,
+            market_signer: &token_2022_market_signer,
+            user: &token_2022_user_account,
+            user_owner: &user_account_owner.pubkey(),
+            destination_base_account: &token_2022_user_base_account,
+            destination_quote_account: &token_2022_user_quote_account,
+        },
+        settle::Params {
+            base_amount: 0,
+            quote_amount: repost_bid_quote_qty,
+            unwrap_sol: 0,
+            _padding: [0; 7],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![settle_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        get_token_balance(&mut prg_test_ctx, &token_2022_user_quote_account).await,
+        quote_balance_before + repost_bid_quote_qty
+    );
+
+    // The market admin can set the name and ticker of a market, which must be valid UTF-8
+    let token_2022_set_market_metadata =
+        |market_admin: &Pubkey, name: [u8; 16], ticker: [u8; 8]| {
+            set_market_metadata(
+                dex_program_id,
+                set_market_metadata::Accounts {
+                    market: &token_2022_market_account.pubkey(),
+                    market_admin,
+                },
+                set_market_metadata::Params { name, ticker },
+            )
+        };
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![token_2022_set_market_metadata(
+            &user_account_owner.pubkey(),
+            market_label("T22/QUOTE"),
+            market_label("T22"),
+        )],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::InvalidMarketAdminAccount as u32)
+        )
+    );
+    let mut invalid_name: [u8; 16] = market_label("T22/QUOTE");
+    invalid_name[0] = 0xff;
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![token_2022_set_market_metadata(
+            &market_admin.pubkey(),
+            invalid_name,
+            market_label("T22"),
+        )],
+        vec![&market_admin],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(0, InstructionError::InvalidArgument)
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![token_2022_set_market_metadata(
+            &market_admin.pubkey(),
+            market_label("T22/QUOTE"),
+            market_label("T22"),
+        )],
+        vec![&market_admin],
+    )
+    .await
+    .unwrap();
+    let token_2022_market_state =
+        get_market_state(&mut prg_test_ctx, &token_2022_market_account.pubkey()).await;
+    assert_eq!(token_2022_market_state.name(), Some("T22/QUOTE"));
+    assert_eq!(token_2022_market_state.ticker(), Some("T22"));
+
+    // A referrer can register its quote token account to track its referral volume and fees
+    let referrer_owner = Keypair::new();
+    let referral_account = create_token_2022_account(
+        &mut prg_test_ctx,
+        &token_2022_quote_mint,
+        &referrer_owner.pubkey(),
+    )
+    .await
+    .unwrap();
+    let (referrer, _) = find_referrer(
+        &dex_program_id,
+        &token_2022_market_account.pubkey(),
+        &referral_account,
+    );
+    let fee_payer = prg_test_ctx.payer.pubkey();
+    let register_referrer_instruction = |referrer_owner: &Pubkey| {
+        register_referrer(
+            dex_program_id,
+            register_referrer::Accounts {
+                system_program: &system_program::ID,
+                market: &token_2022_market_account.pubkey(),
+                referrer: &referrer,
+                token_account: &referral_account,
+                referrer_owner,
+                fee_payer: &fee_payer,
+            },
+            register_referrer::Params {},
+        )
+    };
+    // Only the owner of the token account can register it
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![register_referrer_instruction(&user_account_owner.pubkey())],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(0, InstructionError::InvalidArgument)
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![register_referrer_instruction(&referrer_owner.pubkey())],
+        vec![&referrer_owner],
+    )
+    .await
+    .unwrap();
+    let get_referrer = |data: Vec<u8>| *bytemuck::from_bytes::<Referrer>(&data[..REFERRER_LEN]);
+    let referrer_state = get_referrer(
+        prg_test_ctx
+            .banks_client
+            .get_account(referrer)
+            .await
+            .unwrap()
+            .unwrap()
+            .data,
+    );
+    assert_eq!(referrer_state.market, token_2022_market_account.pubkey());
+    assert_eq!(referrer_state.token_account, referral_account);
+    assert_eq!(referrer_state.owner, referrer_owner.pubkey());
+    assert_eq!(referrer_state.total_referred_volume, 0);
+    assert_eq!(referrer_state.total_fees_earned, 0);
+
+    // Referred swaps against the remaining bid are credited to the referrer
+    let token_2022_referred_swap =
+        |base_qty: u64, fee_referral_account: &Pubkey, referrer: &Pubkey| {
+            swap(
+                dex_program_id,
+                swap::Accounts {
+                    spl_token_program: &spl_token_2022::ID,
+                    system_program: &system_program::ID,
+                    market: &token_2022_market_account.pubkey(),
+                    orderbook: &token_2022_aaob_accounts.market,
+                    event_queue: &token_2022_aaob_accounts.event_queue,
+                    bids: &token_2022_aaob_accounts.bids,
+                    asks: &token_2022_aaob_accounts.asks,
+                    base_vault: &token_2022_base_vault,
+                    quote_vault: &token_2022_quote_vault,
+                    base_mint: &token_2022_base_mint,
+                    quote_mint: &token_2022_quote_mint,
+                    market_signer: &token_2022_market_signer,
+                    user_base_account: &token_2022_user_base_account,
+                    user_quote_account: &token_2022_user_quote_account,
+                    user_owner: &user_account_owner.pubkey(),
+                    discount_token_account: None,
+                    fee_referral_account: Some(fee_referral_account),
+                    referrer: Some(referrer),
+                },
+                swap::Params {
+                    side: asset_agnostic_orderbook::state::Side::Ask as u8,
+                    base_qty,
+                    quote_qty: 0,
+                    match_limit: 10,
+                    has_discount_token_account: 0,
+                    max_slippage_bps: 0,
+                    exact_out: 0,
+                    _padding: [0; 3],
+                },
+            )
+        };
+    // The referrer account has to match the referral account
+    let other_referral_account = create_token_2022_account(
+        &mut prg_test_ctx,
+        &token_2022_quote_mint,
+        &referrer_owner.pubkey(),
+    )
+    .await
+    .unwrap();
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![token_2022_referred_swap(
+            10,
+            &other_referral_account,
+            &referrer,
+        )],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::InvalidReferrerAccount as u32)
+        )
+    );
+    let mut previous_referred_volume = 0;
+    for base_qty in [10, 20] {
+        sign_send_instructions(
+            &mut prg_test_ctx,
+            vec![token_2022_referred_swap(
+                base_qty,
+                &referral_account,
+                &referrer,
+            )],
+            vec![&user_account_owner],
+        )
+        .await
+        .unwrap();
+        let referrer_state = get_referrer(
+            prg_test_ctx
+                .banks_client
+                .get_account(referrer)
+                .await
+                .unwrap()
+                .unwrap()
+                .data,
+        );
+        assert!(referrer_state.total_referred_volume > previous_referred_volume);
+        previous_referred_volume = referrer_state.total_referred_volume;
+        assert!(referrer_state.total_fees_earned > 0);
+        assert_eq!(
+            referrer_state.total_fees_earned,
+            get_token_balance(&mut prg_test_ctx, &referral_account).await
+        );
+    }
+
+    // So are referred new orders, the referral fee being transferred out of the quote vault by the market signer
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token_2022::ID,
+            system_program: &system_program::ID,
+            market: &token_2022_market_account.pubkey(),
+            orderbook: &token_2022_aaob_accounts.market,
+            event_queue: &token_2022_aaob_accounts.event_queue,
+            bids: &token_2022_aaob_accounts.bids,
+            asks: &token_2022_aaob_accounts.asks,
+            base_vault: &token_2022_base_vault,
+            quote_vault: &token_2022_quote_vault,
+            base_mint: &token_2022_base_mint,
+            quote_mint: &token_2022_quote_mint,
+            market_signer: &token_2022_market_signer,
+            user: &token_2022_user_account,
+            user_token_account: &token_2022_user_base_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: Some(&referral_account),
+            referrer: Some(&referrer),
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id: 0,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: [0; 2],
+            side: asset_agnostic_orderbook::state::Side::Ask as u8,
+            limit_price: 900 * tick_size,
+            max_base_qty: 5,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::ImmediateOrCancel as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    let referral_balance_before = get_token_balance(&mut prg_test_ctx, &referral_account).await;
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+    let referrer_state = get_referrer(
+        prg_test_ctx
+            .banks_client
+            .get_account(referrer)
+            .await
+            .unwrap()
+            .unwrap()
+            .data,
+    );
+    assert!(referrer_state.total_referred_volume > previous_referred_volume);
+    assert!(
+        get_token_balance(&mut prg_test_ctx, &referral_account).await > referral_balance_before
+    );
+    assert_eq!(
+        referrer_state.total_fees_earned,
+        get_token_balance(&mut prg_test_ctx, &referral_account).await
+    );
+
+    // The remaining bid can be reduced in place, and is cancelled once reduced to zero
+    let mut token_2022_user_account_data = prg_test_ctx
+        .banks_client
+        .get_account(token_2022_user_account)
+        .await
+        .unwrap()
+        .unwrap()
+        .data;
+    let (remaining_bid_index, remaining_bid_id) =
+        UserAccount::from_buffer(&mut token_2022_user_account_data)
+            .unwrap()
+            .iter_orders()
+            .find(|(_, o)| o.id >> 64 == (900 * tick_size) as u128)
+            .map(|(i, o)| (i as u64, o.id))
+            .unwrap();
+    let token_2022_reduce_order = |reduce_by: u64| {
+        reduce_order(
+            dex_program_id,
+            reduce_order::Accounts {
+                market: &token_2022_market_account.pubkey(),
+                orderbook: &token_2022_aaob_accounts.market,
+                event_queue: &token_2022_aaob_accounts.event_queue,
+                bids: &token_2022_aaob_accounts.bids,
+                asks: &token_2022_aaob_accounts.asks,
+                user: &token_2022_user_account,
+                user_owner: &user_account_owner.pubkey(),
+            },
+            reduce_order::Params {
+                order_id: remaining_bid_id,
+                order_index: remaining_bid_index,
+                reduce_by,
+            },
+        )
+    };
+    let token_2022_user_account_header_before_reduce =
+        get_user_account_header(&mut prg_test_ctx, &token_2022_user_account).await;
+    let return_data = sign_send_instructions_with_return_data(
+        &mut prg_test_ctx,
+        vec![get_best_prices_instruction],
+        vec![&user_account_owner],
+        &dex_program_id,
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    let remaining_bid_size = get_best_prices::BestPrices::try_from_slice(&return_data)
+        .unwrap()
+        .best_bid_size;
+    assert!(remaining_bid_size > 5);
+    let return_data = sign_send_instructions_with_return_data(
+        &mut prg_test_ctx,
+        vec![token_2022_reduce_order(5)],
+        vec![&user_account_owner],
+        &dex_program_id,
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    let reduce_order_summary =
+        cancel_order::CancelOrderSummary::try_from_slice(&return_data).unwrap();
+    assert_eq!(reduce_order_summary.total_base_qty, 5);
+    assert!(reduce_order_summary.total_quote_qty > 0);
+    let token_2022_user_account_header =
+        get_user_account_header(&mut prg_test_ctx, &token_2022_user_account).await;
+    assert_eq!(
+        token_2022_user_account_header.number_of_orders,
+        token_2022_user_account_header_before_reduce.number_of_orders
+    );
+    assert_eq!(
+        token_2022_user_account_header.quote_token_free,
+        token_2022_user_account_header_before_reduce.quote_token_free
+            + reduce_order_summary.total_quote_qty
+    );
+    assert_eq!(
+        token_2022_user_account_header.quote_token_locked,
+        token_2022_user_account_header_before_reduce.quote_token_locked
+            - reduce_order_summary.total_quote_qty
+    );
+
+    // Reducing the same order by more than its remaining size behaves as a cancel
+    let return_data = sign_send_instructions_with_return_data(
+        &mut prg_test_ctx,
+        vec![token_2022_reduce_order(u64::MAX)],
+        vec![&user_account_owner],
+        &dex_program_id,
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    let reduce_order_summary =
+        cancel_order::CancelOrderSummary::try_from_slice(&return_data).unwrap();
+    assert_eq!(reduce_order_summary.total_base_qty, remaining_bid_size - 5);
+    let token_2022_user_account_header_after_reduce =
+        get_user_account_header(&mut prg_test_ctx, &token_2022_user_account).await;
+    assert_eq!(
+        token_2022_user_account_header_after_reduce.number_of_orders,
+        token_2022_user_account_header.number_of_orders - 1
+    );
+    assert_eq!(
+        token_2022_user_account_header_after_reduce.quote_token_free,
+        token_2022_user_account_header.quote_token_free + reduce_order_summary.total_quote_qty
+    );
+    assert_eq!(
+        token_2022_user_account_header_after_reduce.quote_token_locked,
+        token_2022_user_account_header.quote_token_locked - reduce_order_summary.total_quote_qty
+    );
+
+    // Takers pay no fees until the end of the promotional window set by the market admin
+    let promo_end_ts = clock.unix_timestamp + 60;
+    let token_2022_update_promo_end_ts = |market_admin: &Pubkey| {
+        update_promo_end_ts(
+            dex_program_id,
+            update_promo_end_ts::Accounts {
+                market: &token_2022_market_account.pubkey(),
+                market_admin,
+            },
+            update_promo_end_ts::Params { promo_end_ts },
+        )
+    };
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![token_2022_update_promo_end_ts(&user_account_owner.pubkey())],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::InvalidMarketAdminAccount as u32)
+        )
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![token_2022_update_promo_end_ts(&market_admin.pubkey())],
+        vec![&market_admin],
+    )
+    .await
+    .unwrap();
+    let token_2022_market_state =
+        get_market_state(&mut prg_test_ctx, &token_2022_market_account.pubkey()).await;
+    assert_eq!(token_2022_market_state.promo_end_ts, promo_end_ts);
+
+    let promo_ask_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token_2022::ID,
+            system_program: &system_program::ID,
+            market: &token_2022_market_account.pubkey(),
+            orderbook: &token_2022_aaob_accounts.market,
+            event_queue: &token_2022_aaob_accounts.event_queue,
+            bids: &token_2022_aaob_accounts.bids,
+            asks: &token_2022_aaob_accounts.asks,
+            base_vault: &token_2022_base_vault,
+            quote_vault: &token_2022_quote_vault,
+            base_mint: &token_2022_base_mint,
+            quote_mint: &token_2022_quote_mint,
+            market_signer: &token_2022_market_signer,
+            user: &token_2022_user_account,
+            user_token_account: &token_2022_user_base_account,
+            user_owner: &user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id: 0,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: [0; 2],
+            side: asset_agnostic_orderbook::state::Side::Ask as u8,
+            limit_price: 1_000 * tick_size,
+            max_base_qty: 23,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::PostOnly as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![promo_ask_instruction],
+        vec![&user_account_owner],
+    )
+    .await
+    .unwrap();
+    // The promotional window excludes its end, a trade at exactly promo_end_ts pays the regular fees.
+    // Royalties are charged either way.
+    for (timestamp, base_qty, fee_tier) in [
+        (promo_end_ts - 1, 11, FeeTier::Promo),
+        (promo_end_ts, 12, FeeTier::Stable),
+    ] {
+        clock.unix_timestamp = timestamp;
+        prg_test_ctx.set_sysvar(&clock);
+        let quote_balance_before =
+            get_token_balance(&mut prg_test_ctx, &token_2022_user_quote_account).await;
+        sign_send_instructions(
+            &mut prg_test_ctx,
+            vec![token_2022_swap(base_qty)],
+            vec![&user_account_owner],
+        )
+        .await
+        .unwrap();
+        let swap_quote_qty =
+            (((base_qty as u128 * (1_000 * tick_size) as u128) >> 32) as u64) * 10_000;
+        let taker_fee = fee_tier.taker_fee(&token_2022_market_state, swap_quote_qty);
+        assert_eq!(taker_fee == 0, timestamp < promo_end_ts);
+        assert_eq!(
+            quote_balance_before
+                - get_token_balance(&mut prg_test_ctx, &token_2022_user_quote_account).await,
+            swap_quote_qty
+                + taker_fee
+                + FeeTier::royalties_fee(&token_2022_market_state, swap_quote_qty)
+        );
+    }
+    // The fee-free fill is consumed along with the regular ones
+    let (_, event_count) =
+        get_event_queue_position(&mut prg_test_ctx, &token_2022_aaob_accounts.event_queue).await;
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![token_2022_consume_events(event_count)],
+        vec![],
+    )
+    .await
+    .unwrap();
+
+    // Instruction data too short to hold the instruction tag should be rejected without panicking
+    for data in [vec![], vec![DexInstruction::Settle as u8; 3]] {
+        let ix =
+            solana_program::instruction::Instruction::new_with_bytes(dex_program_id, &data, vec![]);
+        let err = sign_send_instructions(&mut prg_test_ctx, vec![ix], vec![])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.unwrap(),
+            TransactionError::InstructionError(0, InstructionError::InvalidInstructionData)
+        );
+    }
+
+    // A closed user account is refunded to the overriding target and can't be reused without re-initialization
+    let closed_user_account_owner = Keypair::new();
+    let (closed_user_account, _) = Pubkey::find_program_address(
+        &[
+            &wsol_market_account.pubkey().to_bytes(),
+            &closed_user_account_owner.pubkey().to_bytes(),
+        ],
+        &dex_program_id,
+    );
+    let create_user_account_instruction = initialize_account(
+        dex_program_id,
+        initialize_account::Accounts {
+            system_program: &system_program::ID,
+            user: &closed_user_account,
+            user_owner: &closed_user_account_owner.pubkey(),
+            fee_payer: &prg_test_ctx.payer.pubkey(),
+        },
+        initialize_account::Params {
+            market: wsol_market_account.pubkey(),
+            max_orders: 10,
+        },
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![create_user_account_instruction],
+        vec![&closed_user_account_owner],
+    )
+    .await
+    .unwrap();
+    let user_account_lamports = prg_test_ctx
+        .banks_client
+        .get_account(closed_user_account)
+        .await
+        .unwrap()
+        .unwrap()
+        .lamports;
+    let close_account_instruction = close_account(
+        dex_program_id,
+        close_account::Accounts {
+            user: &closed_user_account,
+            user_owner: &closed_user_account_owner.pubkey(),
+            rent_payer: &prg_test_ctx.payer.pubkey(),
+            target_lamports_account: Some(&closed_user_account_owner.pubkey()),
+        },
+        close_account::Params {},
+    );
+    let new_order_instruction = new_order(
+        dex_program_id,
+        new_order::Accounts {
+            spl_token_program: &spl_token::ID,
+            system_program: &system_program::ID,
+            market: &wsol_market_account.pubkey(),
+            orderbook: &wsol_aaob_accounts.market,
+            event_queue: &wsol_aaob_accounts.event_queue,
+            bids: &wsol_aaob_accounts.bids,
+            asks: &wsol_aaob_accounts.asks,
+            base_vault: &wsol_base_vault,
+            quote_vault: &wsol_quote_vault,
+            base_mint: &base_mint_key,
+            quote_mint: &spl_token::native_mint::ID,
+            market_signer: &wsol_market_signer,
+            user: &closed_user_account,
+            user_token_account: &wsol_token_account.pubkey(),
+            user_owner: &closed_user_account_owner.pubkey(),
+            discount_token_account: None,
+            fee_referral_account: None,
+            referrer: None,
+        },
+        new_order::Params {
+            #[cfg(not(any(feature = "aarch64-test", target_arch = "aarch64")))]
+            client_order_id: 0,
+            #[cfg(any(feature = "aarch64-test", target_arch = "aarch64"))]
+            client_order_id: bytemuck::cast(0u128),
+            side: asset_agnostic_orderbook::state::Side::Bid as u8,
+            limit_price: 1_000 * tick_size,
+            max_base_qty: 1_000,
+            max_quote_qty: u64::MAX,
+            order_type: new_order::OrderType::Limit as u8,
+            self_trade_behavior: asset_agnostic_orderbook::state::SelfTradeBehavior::DecrementTake
+                as u8,
+            match_limit: 10,
+            has_discount_token_account: false as u8,
+            fok_fallback_ioc: 0,
+            reduce_only: 0,
+            duplicate_client_id_policy: 0,
+            _padding: [0; 1],
+        },
+    );
+    // Within the closing transaction, the zeroed account is rejected as it is tagged as closed
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![
+            close_account_instruction.clone(),
+            new_order_instruction.clone(),
+        ],
+        vec![&closed_user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(1, InstructionError::InvalidAccountData)
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![close_account_instruction],
+        vec![&closed_user_account_owner],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        prg_test_ctx
+            .banks_client
+            .get_account(closed_user_account_owner.pubkey())
+            .await
+            .unwrap()
+            .unwrap()
+            .lamports,
+        user_account_lamports
+    );
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![new_order_instruction],
+        vec![&closed_user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::InvalidStateAccountOwner as u32)
+        )
+    );
+
+    // Without a target, the rent is refunded to the custodian which paid for the user account
+    let custodian = Keypair::new();
+    let custodied_user_account_owner = Keypair::new();
+    let (custodied_user_account, _) = Pubkey::find_program_address(
+        &[
+            &wsol_market_account.pubkey().to_bytes(),
+            &custodied_user_account_owner.pubkey().to_bytes(),
+        ],
+        &dex_program_id,
+    );
+    let ixs = vec![
+        solana_program::system_instruction::transfer(
+            &prg_test_ctx.payer.pubkey(),
+            &custodian.pubkey(),
+            1_000_000_000,
+        ),
+        initialize_account(
+            dex_program_id,
+            initialize_account::Accounts {
+                system_program: &system_program::ID,
+                user: &custodied_user_account,
+                user_owner: &custodied_user_account_owner.pubkey(),
+                fee_payer: &custodian.pubkey(),
+            },
+            initialize_account::Params {
+                market: wsol_market_account.pubkey(),
+                max_orders: 10,
+            },
+        ),
+    ];
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        ixs,
+        vec![&custodian, &custodied_user_account_owner],
+    )
+    .await
+    .unwrap();
+    let user_account_lamports = prg_test_ctx
+        .banks_client
+        .get_account(custodied_user_account)
+        .await
+        .unwrap()
+        .unwrap()
+        .lamports;
+    let custodian_lamports = prg_test_ctx
+        .banks_client
+        .get_balance(custodian.pubkey())
+        .await
+        .unwrap();
+    // The rent payer should match the one recorded in the user account
+    let close_account_instruction = close_account(
+        dex_program_id,
+        close_account::Accounts {
+            user: &custodied_user_account,
+            user_owner: &custodied_user_account_owner.pubkey(),
+            rent_payer: &custodied_user_account_owner.pubkey(),
+            target_lamports_account: None,
+        },
+        close_account::Params {},
+    );
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![close_account_instruction],
+        vec![&custodied_user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(DexError::InvalidRentPayerAccount as u32)
+        )
+    );
+    let close_account_instruction = close_account(
+        dex_program_id,
+        close_account::Accounts {
+            user: &custodied_user_account,
+            user_owner: &custodied_user_account_owner.pubkey(),
+            rent_payer: &custodian.pubkey(),
+            target_lamports_account: None,
+        },
+        close_account::Params {},
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![close_account_instruction],
+        vec![&custodied_user_account_owner],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        prg_test_ctx
+            .banks_client
+            .get_balance(custodian.pubkey())
+            .await
+            .unwrap(),
+        custodian_lamports + user_account_lamports
+    );
+
+    // A user account created under the legacy header layout is rejected until it is migrated, which moves its orders
+    // after the grown header
+    let legacy_user_account_owner = Keypair::new();
+    let legacy_user_account = Pubkey::new_unique();
+    let mut legacy_user_account_data = prg_test_ctx
+        .banks_client
+        .get_account(user_account)
+        .await
+        .unwrap()
+        .unwrap();
+    legacy_user_account_data.data = vec![0; LEGACY_USER_ACCOUNT_HEADER_LEN + 2 * Order::LEN];
+    legacy_user_account_data.lamports = rent.minimum_balance(legacy_user_account_data.data.len());
+    let legacy_order = Order {
+        id: 42,
+        client_id: 7,
+    };
+    {
+        let (header, orders) = legacy_user_account_data
+            .data
+            .split_at_mut(LEGACY_USER_ACCOUNT_HEADER_LEN);
+        let header: &mut LegacyUserAccountHeader = try_from_bytes_mut(header).unwrap();
+        header.tag = dex_v4::state::AccountTag::LegacyUserAccount as u64;
+        header.market = wsol_market_account.pubkey();
+        header.owner = legacy_user_account_owner.pubkey();
+        header.quote_token_locked = 1_000;
+        header.number_of_orders = 1;
+        orders[..Order::LEN].copy_from_slice(bytemuck::bytes_of(&legacy_order));
+    }
+    prg_test_ctx.set_account(&legacy_user_account, &legacy_user_account_data.into());
+    let close_account_instruction = close_account(
+        dex_program_id,
+        close_account::Accounts {
+            user: &legacy_user_account,
+            user_owner: &legacy_user_account_owner.pubkey(),
+            rent_payer: &legacy_user_account_owner.pubkey(),
+            target_lamports_account: None,
+        },
+        close_account::Params {},
+    );
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![close_account_instruction],
+        vec![&legacy_user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(0, InstructionError::InvalidAccountData)
+    );
+    let migrate_user_account_instruction = migrate_user_account(
+        dex_program_id,
+        migrate_user_account::Accounts {
+            system_program: &system_program::ID,
+            user: &legacy_user_account,
+            fee_payer: &prg_test_ctx.payer.pubkey(),
+        },
+        migrate_user_account::Params {},
+    );
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![migrate_user_account_instruction],
+        vec![],
+    )
+    .await
+    .unwrap();
+    let mut migrated_user_account_data = prg_test_ctx
+        .banks_client
+        .get_account(legacy_user_account)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        migrated_user_account_data.data.len(),
+        USER_ACCOUNT_HEADER_LEN + 2 * Order::LEN
+    );
+    assert!(
+        migrated_user_account_data.lamports
+            >= rent.minimum_balance(migrated_user_account_data.data.len())
+    );
+    let migrated_user_account =
+        UserAccount::from_buffer(&mut migrated_user_account_data.data).unwrap();
+    assert_eq!(
+        migrated_user_account.header.owner,
+        legacy_user_account_owner.pubkey()
+    );
+    assert_eq!(migrated_user_account.header.quote_token_locked, 1_000);
+    assert_eq!(migrated_user_account.header.delegate, Pubkey::default());
+    assert_eq!(migrated_user_account.orders().len(), 1);
+    assert_eq!(migrated_user_account.orders()[0].id, legacy_order.id);
+    assert_eq!(
+        migrated_user_account.orders()[0].client_id,
+        legacy_order.client_id
+    );
+
+    // Once its order is gone, the migrated account can only be closed to a target lamports account as its rent payer
+    // is unknown
+    migrated_user_account.header.number_of_orders = 0;
+    migrated_user_account.header.quote_token_locked = 0;
+    let migrated_user_account_lamports = migrated_user_account_data.lamports;
+    prg_test_ctx.set_account(&legacy_user_account, &migrated_user_account_data.into());
+    let close_migrated_account_instruction = |target_lamports_account: Option<&Pubkey>| {
+        close_account(
+            dex_program_id,
+            close_account::Accounts {
+                user: &legacy_user_account,
+                user_owner: &legacy_user_account_owner.pubkey(),
+                rent_payer: &Pubkey::new_unique(),
+                target_lamports_account,
+            },
+            close_account::Params {},
+        )
+    };
+    let err = sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![close_migrated_account_instruction(None)],
+        vec![&legacy_user_account_owner],
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        err.unwrap(),
+        TransactionError::InstructionError(0, InstructionError::NotEnoughAccountKeys)
+    );
+    let target_lamports_account = Pubkey::new_unique();
+    sign_send_instructions(
+        &mut prg_test_ctx,
+        vec![close_migrated_account_instruction(Some(
+            &target_lamports_account,
+        ))],
+        vec![&legacy_user_account_owner],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        prg_test_ctx
+            .banks_client
+            .get_balance(target_lamports_account)
+            .await
+            .unwrap(),
+        migrated_user_account_lamports
+    );
 }